@@ -0,0 +1,363 @@
+mod protocol;
+
+use crate::protocol::{AutonatMessage, DialResponse};
+use fnv::FnvHashMap;
+use futures::{future, prelude::*};
+use log::debug;
+use p2p::{
+    context::{ServiceContext, SessionContext},
+    multiaddr::Multiaddr,
+    traits::{ProtocolMeta, ServiceProtocol},
+    utils::{is_reachable, is_relayed, multiaddr_to_socketaddr},
+    PeerId, ProtocolId, SecioKeyPair, SessionId,
+};
+use secio::handshake::Config;
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+use tokio::{codec::length_delimited::LengthDelimitedCodec, net::TcpStream, prelude::FutureExt};
+
+const PROBE_TOKEN: u64 = 0;
+/// Max addresses in a request
+const MAX_ADDRS: usize = 8;
+
+/// Autonat configuration
+#[derive(Clone, Debug)]
+pub struct AutonatConfig {
+    /// Interval to ask a peer to dial us back
+    pub probe_interval: Duration,
+    /// Timeout of a dial back, the asker waits twice of it
+    pub dial_timeout: Duration,
+    /// Dial back private addresses, for LAN and tests
+    pub allow_private: bool,
+    /// Max dial backs in progress for peers
+    pub max_concurrent_dials: usize,
+    /// Requests of a session in this period after the last one are refused
+    pub throttle: Duration,
+}
+
+impl Default for AutonatConfig {
+    fn default() -> Self {
+        AutonatConfig {
+            probe_interval: Duration::from_secs(30),
+            dial_timeout: Duration::from_secs(10),
+            allow_private: false,
+            max_concurrent_dials: 8,
+            throttle: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Ask connected peers to dial us back on our addresses, and dial back the peers asking us.
+///
+/// The results are reported by `ServiceContext::report_reachability`, then the service
+/// decides `ServiceContext::reachability`
+pub struct AutonatProtocol {
+    id: ProtocolId,
+    config: AutonatConfig,
+}
+
+impl AutonatProtocol {
+    /// New autonat protocol
+    pub fn new(id: ProtocolId, config: AutonatConfig) -> Self {
+        AutonatProtocol { id, config }
+    }
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for AutonatProtocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        let handle = Box::new(AutonatHandler {
+            proto_id: self.id,
+            config: self.config.clone(),
+            peers: FnvHashMap::default(),
+            pending: FnvHashMap::default(),
+            served: FnvHashMap::default(),
+            dialing: Arc::new(AtomicUsize::new(0)),
+        });
+        Some(handle)
+    }
+}
+
+struct Peer {
+    peer_id: PeerId,
+    /// Remote ip of session
+    ip: IpAddr,
+    /// Last time we asked it
+    asked: Option<Instant>,
+}
+
+struct AutonatHandler {
+    proto_id: ProtocolId,
+    config: AutonatConfig,
+    /// Direct tcp sessions with secio
+    peers: FnvHashMap<SessionId, Peer>,
+    /// Our requests waiting for response
+    pending: FnvHashMap<SessionId, Instant>,
+    /// Requests of peers served recently
+    served: FnvHashMap<SessionId, Instant>,
+    /// Dial backs in progress, shared with the dial tasks
+    dialing: Arc<AtomicUsize>,
+}
+
+impl AutonatHandler {
+    fn send_message(
+        &self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: &AutonatMessage,
+    ) {
+        let _ = control.send_message(session_id, self.proto_id, message.encode());
+    }
+
+    fn accept_ip(&self, ip: IpAddr) -> bool {
+        self.config.allow_private || is_reachable(ip)
+    }
+
+    /// Our addresses to be dialed back
+    fn candidates(&self, control: &ServiceContext) -> Vec<Multiaddr> {
+        let mut addrs: Vec<Multiaddr> = Vec::new();
+        for address in control
+            .external_addrs()
+            .iter()
+            .chain(control.listens().iter())
+        {
+            let valid = !is_relayed(address)
+                && multiaddr_to_socketaddr(address)
+                    .map(|socket_addr| self.accept_ip(socket_addr.ip()))
+                    .unwrap_or(false);
+            if valid && !addrs.contains(address) {
+                addrs.push(address.clone());
+            }
+        }
+        addrs.truncate(MAX_ADDRS);
+        addrs
+    }
+
+    /// Ask the peer asked least recently
+    fn probe(&mut self, control: &mut ServiceContext) {
+        let now = Instant::now();
+        let timeout = self.config.dial_timeout * 2;
+        self.pending.retain(|_, asked| *asked + timeout > now);
+        let throttle = self.config.throttle;
+        self.served.retain(|_, served| *served + throttle > now);
+
+        // One request at a time
+        if !self.pending.is_empty() {
+            return;
+        }
+        let addrs = self.candidates(control);
+        if addrs.is_empty() {
+            return;
+        }
+        let session_id = match self
+            .peers
+            .iter()
+            .min_by_key(|(_, peer)| peer.asked)
+            .map(|(session_id, _)| *session_id)
+        {
+            Some(session_id) => session_id,
+            None => return,
+        };
+
+        debug!("ask session [{}] to dial back {:?}", session_id, addrs);
+        let request = AutonatMessage::Request {
+            addrs: addrs.iter().map(ToString::to_string).collect(),
+        };
+        self.send_message(control, session_id, &request);
+        if let Some(peer) = self.peers.get_mut(&session_id) {
+            peer.asked = Some(now);
+        }
+        self.pending.insert(session_id, now);
+    }
+
+    /// Dial back the peer on the addresses from its ip
+    fn dial_back(
+        &mut self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        addrs: Vec<String>,
+    ) {
+        let now = Instant::now();
+        let peer = match self.peers.get(&session_id) {
+            Some(peer) => peer,
+            None => {
+                let response = AutonatMessage::Response(DialResponse::Refused);
+                self.send_message(control, session_id, &response);
+                return;
+            }
+        };
+        let throttled = self
+            .served
+            .get(&session_id)
+            .map(|served| *served + self.config.throttle > now)
+            .unwrap_or(false);
+        // Only the ip of session, so we can't be used to attack others
+        let dials = addrs
+            .into_iter()
+            .take(MAX_ADDRS)
+            .filter_map(|address| address.parse::<Multiaddr>().ok())
+            .filter_map(|address| {
+                let socket_addr = multiaddr_to_socketaddr(&address).ok()?;
+                if is_relayed(&address)
+                    || socket_addr.ip() != peer.ip
+                    || !self.accept_ip(socket_addr.ip())
+                {
+                    return None;
+                }
+                Some(dial(
+                    address,
+                    socket_addr,
+                    peer.peer_id.clone(),
+                    self.config.dial_timeout,
+                ))
+            })
+            .collect::<Vec<_>>();
+        if throttled
+            || dials.is_empty()
+            || self.dialing.load(Ordering::SeqCst) >= self.config.max_concurrent_dials
+        {
+            let response = AutonatMessage::Response(DialResponse::Refused);
+            self.send_message(control, session_id, &response);
+            return;
+        }
+
+        self.served.insert(session_id, now);
+        self.dialing.fetch_add(1, Ordering::SeqCst);
+        let dialing = Arc::clone(&self.dialing);
+        let mut sender = control.control().clone();
+        let proto_id = self.proto_id;
+        let task = future::select_ok(dials).then(move |result| {
+            dialing.fetch_sub(1, Ordering::SeqCst);
+            let response = match result {
+                Ok((address, _)) => DialResponse::Ok(address.to_string()),
+                Err(_) => DialResponse::Failed,
+            };
+            debug!("dial back session [{}]: {:?}", session_id, response);
+            let _ = sender.send_message(
+                session_id,
+                proto_id,
+                AutonatMessage::Response(response).encode(),
+            );
+            Ok(())
+        });
+        if control.future_task(task).is_err() {
+            self.dialing.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+/// Connect and finish secio handshake, the remote must be the peer
+fn dial(
+    address: Multiaddr,
+    socket_addr: SocketAddr,
+    peer_id: PeerId,
+    timeout: Duration,
+) -> impl Future<Item = Multiaddr, Error = ()> {
+    // A new identity, so the peer doesn't take it as a duplicate connection of ours
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    TcpStream::connect(&socket_addr)
+        .map_err(|err| debug!("dial back error: {}", err))
+        .and_then(move |socket| {
+            Config::new(key_pair)
+                .handshake(socket)
+                .map_err(|err| debug!("dial back handshake error: {:?}", err))
+        })
+        .timeout(timeout)
+        .map_err(|_| ())
+        .and_then(move |(_, public_key, _)| {
+            if public_key.peer_id() == peer_id {
+                Ok(address)
+            } else {
+                debug!("dial back {} reached another peer", address);
+                Err(())
+            }
+        })
+}
+
+impl ServiceProtocol for AutonatHandler {
+    fn init(&mut self, control: &mut ServiceContext) {
+        control.set_service_notify(self.proto_id, self.config.probe_interval, PROBE_TOKEN);
+    }
+
+    fn connected(
+        &mut self,
+        _control: &mut ServiceContext,
+        session: &SessionContext,
+        version: &str,
+    ) {
+        debug!(
+            "proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
+            self.proto_id, session.id, session.address, session.ty, version
+        );
+        let peer_id = match session.remote_pubkey {
+            Some(ref key) if !is_relayed(&session.address) => key.peer_id(),
+            _ => return,
+        };
+        if let Ok(socket_addr) = multiaddr_to_socketaddr(&session.address) {
+            self.peers.insert(
+                session.id,
+                Peer {
+                    peer_id,
+                    ip: socket_addr.ip(),
+                    asked: None,
+                },
+            );
+        }
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        debug!(
+            "proto id [{}] close on session [{}]",
+            self.proto_id, session.id
+        );
+        self.peers.remove(&session.id);
+        self.pending.remove(&session.id);
+        self.served.remove(&session.id);
+    }
+
+    fn received(&mut self, control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        match AutonatMessage::decode(&data) {
+            Some(AutonatMessage::Request { addrs }) => self.dial_back(control, session.id, addrs),
+            Some(AutonatMessage::Response(response)) => {
+                if self.pending.remove(&session.id).is_none() {
+                    return;
+                }
+                debug!(
+                    "session [{}] dial back response: {:?}",
+                    session.id, response
+                );
+                match response {
+                    DialResponse::Ok(_) => {
+                        let _ = control.report_reachability(true);
+                    }
+                    DialResponse::Failed => {
+                        let _ = control.report_reachability(false);
+                    }
+                    DialResponse::Refused => (),
+                }
+            }
+            None => {
+                debug!("session [{}] sent invalid autonat message", session.id);
+                let _ = control.disconnect(session.id);
+            }
+        }
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, token: u64) {
+        if token == PROBE_TOKEN {
+            self.probe(control);
+        }
+    }
+}
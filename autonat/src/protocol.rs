@@ -0,0 +1,51 @@
+use serde_derive::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum AutonatMessage {
+    /// Ask the peer to dial us back on the addresses
+    Request {
+        addrs: Vec<String>,
+    },
+    Response(DialResponse),
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum DialResponse {
+    /// Dialed back on the address
+    Ok(String),
+    /// All the addresses failed
+    Failed,
+    /// No valid address, or the peer is busy
+    Refused,
+}
+
+impl AutonatMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serialize autonat message")
+    }
+
+    /// None means the message is invalid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{AutonatMessage, DialResponse};
+
+    #[test]
+    fn encode_and_decode() {
+        let messages = vec![
+            AutonatMessage::Request {
+                addrs: vec!["/ip4/1.1.1.1/tcp/1337".to_owned()],
+            },
+            AutonatMessage::Response(DialResponse::Ok("/ip4/1.1.1.1/tcp/1337".to_owned())),
+            AutonatMessage::Response(DialResponse::Refused),
+        ];
+        for message in messages {
+            assert_eq!(AutonatMessage::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(AutonatMessage::decode(b"invalid"), None);
+    }
+}
@@ -0,0 +1,178 @@
+// DNS messages of mDNS, only PTR and TXT records are used
+
+use trust_dns_proto::{
+    op::{Message, MessageType, Query},
+    rr::{rdata::TXT, DNSClass, Name, RData, Record as DnsRecord, RecordType},
+    serialize::binary::BinEncodable,
+};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum RecordData {
+    Ptr(String),
+    Txt(Vec<String>),
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub name: String,
+    pub ttl: u32,
+    pub data: RecordData,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Packet {
+    // Names of PTR questions
+    Query(Vec<String>),
+    // Answers and additional records
+    Response(Vec<Record>),
+}
+
+// The names decoded are fully qualified, without the root label here
+fn name_string(name: &Name) -> String {
+    name.to_string().trim_end_matches('.').to_owned()
+}
+
+pub(crate) fn encode_query(name: &str) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    // Id is zero in mDNS
+    message
+        .set_id(0)
+        .set_message_type(MessageType::Query)
+        .add_query(Query::query(Name::from_ascii(name).ok()?, RecordType::PTR));
+    message.to_bytes().ok()
+}
+
+pub(crate) fn encode_response(records: &[Record]) -> Option<Vec<u8>> {
+    let mut message = Message::new();
+    message
+        .set_id(0)
+        .set_message_type(MessageType::Response)
+        .set_authoritative(true);
+    for record in records {
+        let (ty, rdata) = match record.data {
+            RecordData::Ptr(ref name) => {
+                (RecordType::PTR, RData::PTR(Name::from_ascii(name).ok()?))
+            }
+            RecordData::Txt(ref strings) => {
+                let strings = strings
+                    .iter()
+                    .filter(|string| string.len() <= 255)
+                    .cloned()
+                    .collect();
+                (RecordType::TXT, RData::TXT(TXT::new(strings)))
+            }
+        };
+        let mut answer = DnsRecord::new();
+        answer
+            .set_name(Name::from_ascii(&record.name).ok()?)
+            .set_ttl(record.ttl)
+            .set_rr_type(ty)
+            .set_dns_class(DNSClass::IN)
+            .set_rdata(rdata);
+        message.add_answer(answer);
+    }
+    message.to_bytes().ok()
+}
+
+pub(crate) fn decode(data: &[u8]) -> Option<Packet> {
+    let message = Message::from_vec(data).ok()?;
+    if message.message_type() == MessageType::Query {
+        let names = message
+            .queries()
+            .iter()
+            .filter(|query| {
+                query.query_type() == RecordType::PTR || query.query_type() == RecordType::ANY
+            })
+            .map(|query| name_string(query.name()))
+            .collect();
+        return Some(Packet::Query(names));
+    }
+
+    // Answers, authorities and additional records
+    let records = message
+        .answers()
+        .iter()
+        .chain(message.name_servers())
+        .chain(message.additionals())
+        .filter_map(|record| {
+            let data = match record.rdata() {
+                RData::PTR(name) => RecordData::Ptr(name_string(name)),
+                RData::TXT(txt) => RecordData::Txt(
+                    txt.txt_data()
+                        .iter()
+                        .map(|string| String::from_utf8_lossy(string).into_owned())
+                        .collect(),
+                ),
+                _ => return None,
+            };
+            Some(Record {
+                name: name_string(record.name()),
+                ttl: record.ttl(),
+                data,
+            })
+        })
+        .collect();
+    Some(Packet::Response(records))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode_query, encode_response, Packet, Record, RecordData};
+
+    #[test]
+    fn encode_and_decode() {
+        let query = encode_query("_p2p._udp.local").unwrap();
+        assert_eq!(
+            decode(&query),
+            Some(Packet::Query(vec!["_p2p._udp.local".to_owned()]))
+        );
+
+        let records = vec![
+            Record {
+                name: "_p2p._udp.local".to_owned(),
+                ttl: 120,
+                data: RecordData::Ptr("peer._p2p._udp.local".to_owned()),
+            },
+            Record {
+                name: "peer._p2p._udp.local".to_owned(),
+                ttl: 120,
+                data: RecordData::Txt(vec![
+                    "dnsaddr=/ip4/127.0.0.1/tcp/1337".to_owned(),
+                    "dnsaddr=/ip4/127.0.0.1/tcp/1338".to_owned(),
+                ]),
+            },
+        ];
+        let response = encode_response(&records).unwrap();
+        assert_eq!(decode(&response), Some(Packet::Response(records)));
+        assert_eq!(decode(&response[..response.len() - 1]), None);
+    }
+
+    #[test]
+    fn decode_compressed_name() {
+        let mut packet = vec![0, 0, 0x84, 0, 0, 0, 0, 2, 0, 0, 0, 0];
+        // _p2p._udp.local at 12, PTR, IN, ttl 120
+        packet.extend_from_slice(b"\x04_p2p\x04_udp\x05local\x00");
+        packet.extend_from_slice(&[0, 12, 0, 1, 0, 0, 0, 120, 0, 7]);
+        // peer and a pointer to 12, at 39
+        packet.extend_from_slice(b"\x04peer\xc0\x0c");
+        // A pointer to 39, TXT, IN, ttl 120
+        packet.extend_from_slice(&[0xc0, 39, 0, 16, 0, 1, 0, 0, 0, 120, 0, 4]);
+        packet.extend_from_slice(b"\x03a=b");
+
+        assert_eq!(
+            decode(&packet),
+            Some(Packet::Response(vec![
+                Record {
+                    name: "_p2p._udp.local".to_owned(),
+                    ttl: 120,
+                    data: RecordData::Ptr("peer._p2p._udp.local".to_owned()),
+                },
+                Record {
+                    name: "peer._p2p._udp.local".to_owned(),
+                    ttl: 120,
+                    data: RecordData::Txt(vec!["a=b".to_owned()]),
+                },
+            ]))
+        );
+    }
+}
@@ -1,5 +1,6 @@
 use std::collections::VecDeque;
 use std::io;
+use std::net::SocketAddr;
 
 use fnv::{FnvHashMap, FnvHashSet};
 use futures::{
@@ -7,16 +8,25 @@ use futures::{
     Async, Poll, Stream,
 };
 use log::debug;
-use p2p::multiaddr::ToMultiaddr;
+use p2p::{
+    multiaddr::{Multiaddr, ToMultiaddr},
+    utils::multiaddr_to_socketaddr,
+    SessionId,
+};
 use rand::seq::SliceRandom;
 
 mod addr;
+mod dns;
+mod mdns;
 mod message;
+mod outbound;
 mod substream;
 
 pub use crate::{
     addr::{AddrKnown, AddressManager, RawAddr},
+    mdns::{MdnsConfig, MdnsEvent, MdnsProtocol},
     message::{DiscoveryMessage, Node, Nodes},
+    outbound::{OutboundManager, MISBEHAVE_DIAL_FAILED},
     substream::{Direction, Substream, SubstreamKey, SubstreamValue},
 };
 
@@ -40,17 +50,30 @@ pub struct Discovery<M> {
     // For add new substream to Discovery
     substream_receiver: Receiver<Substream>,
 
+    // For set remote listen addresses resolved by identify protocol
+    remote_listens_sender: Sender<(SessionId, Vec<Multiaddr>)>,
+    // For set remote listen addresses resolved by identify protocol
+    remote_listens_receiver: Receiver<(SessionId, Vec<Multiaddr>)>,
+    // Remote listen addresses received before the substream of the session
+    pending_remote_listens: FnvHashMap<SessionId, Vec<SocketAddr>>,
+
     err_keys: FnvHashSet<SubstreamKey>,
 }
 
 #[derive(Clone)]
 pub struct DiscoveryHandle {
     pub substream_sender: Sender<Substream>,
+    /// Listen addresses of the remote, such as the ones sent by identify protocol.
+    ///
+    /// Without them, the inbound remote address is unknown until the remote sends
+    /// its listen port in `GetNodes`.
+    pub remote_listens_sender: Sender<(SessionId, Vec<Multiaddr>)>,
 }
 
 impl<M: AddressManager> Discovery<M> {
     pub fn new(addr_mgr: M) -> Discovery<M> {
         let (substream_sender, substream_receiver) = channel(8);
+        let (remote_listens_sender, remote_listens_receiver) = channel(8);
         Discovery {
             max_known: DEFAULT_MAX_KNOWN,
             addr_mgr,
@@ -58,6 +81,9 @@ impl<M: AddressManager> Discovery<M> {
             substreams: FnvHashMap::default(),
             substream_sender,
             substream_receiver,
+            remote_listens_sender,
+            remote_listens_receiver,
+            pending_remote_listens: FnvHashMap::default(),
             err_keys: FnvHashSet::default(),
         }
     }
@@ -69,6 +95,7 @@ impl<M: AddressManager> Discovery<M> {
     pub fn handle(&self) -> DiscoveryHandle {
         DiscoveryHandle {
             substream_sender: self.substream_sender.clone(),
+            remote_listens_sender: self.remote_listens_sender.clone(),
         }
     }
 
@@ -78,13 +105,16 @@ impl<M: AddressManager> Discovery<M> {
                 Ok(Async::Ready(Some(substream))) => {
                     let key = substream.key();
                     debug!("Received a substream: key={:?}", key);
-                    let value = SubstreamValue::new(
+                    let mut value = SubstreamValue::new(
                         key.direction,
                         substream.stream,
                         self.max_known,
                         substream.remote_addr,
                         substream.listen_port,
                     );
+                    if let Some(listens) = self.pending_remote_listens.remove(&key.session_id) {
+                        value.set_remote_listens(&listens, &mut self.addr_mgr);
+                    }
                     self.substreams.insert(key, value);
                 }
                 Ok(Async::Ready(None)) => unreachable!(),
@@ -100,6 +130,39 @@ impl<M: AddressManager> Discovery<M> {
         }
         Ok(())
     }
+
+    fn recv_remote_listens(&mut self) -> Result<(), io::Error> {
+        loop {
+            match self.remote_listens_receiver.poll() {
+                Ok(Async::Ready(Some((session_id, listens)))) => {
+                    debug!("Received remote listens: session={}", session_id);
+                    let listens = listens
+                        .iter()
+                        .filter_map(|address| multiaddr_to_socketaddr(address).ok())
+                        .collect::<Vec<_>>();
+                    let mut found = false;
+                    for (key, value) in self.substreams.iter_mut() {
+                        if key.session_id == session_id {
+                            value.set_remote_listens(&listens, &mut self.addr_mgr);
+                            found = true;
+                        }
+                    }
+                    // The substream is not opened yet, the number is limited in case
+                    // it's never opened
+                    if !found && self.pending_remote_listens.len() < self.max_known {
+                        self.pending_remote_listens.insert(session_id, listens);
+                    }
+                }
+                Ok(Async::Ready(None)) => unreachable!(),
+                Ok(Async::NotReady) => break,
+                Err(err) => {
+                    debug!("receive remote listens error: {:?}", err);
+                    return Err(io::ErrorKind::Other.into());
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 impl<M: AddressManager> Stream for Discovery<M> {
@@ -109,6 +172,7 @@ impl<M: AddressManager> Stream for Discovery<M> {
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
         debug!("Discovery.poll()");
         self.recv_substreams()?;
+        self.recv_remote_listens()?;
 
         let mut announce_addrs = Vec::new();
         for (key, value) in self.substreams.iter_mut() {
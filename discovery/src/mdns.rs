@@ -0,0 +1,456 @@
+use fnv::FnvHashMap;
+use futures::{prelude::*, sync::mpsc};
+use generic_channel::Sender;
+use log::{debug, warn};
+use net2::{UdpBuilder, UdpSocketExt};
+use p2p::{
+    context::{ServiceContext, ServiceControl},
+    multiaddr::Multiaddr,
+    traits::{ProtocolMeta, ServiceProtocol},
+    utils::{extract_peer_id, is_relayed},
+    PeerId, ProtocolId,
+};
+use std::{
+    collections::VecDeque,
+    io,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
+    time::{Duration, Instant},
+};
+use tokio::{
+    codec::length_delimited::LengthDelimitedCodec, net::UdpSocket, reactor::Handle, timer::Interval,
+};
+
+use crate::{
+    addr::AddressManager,
+    dns::{self, Packet, Record, RecordData},
+};
+
+const LISTENS_TOKEN: u64 = 0;
+// Check the listen addresses of service, announce them if changed
+const LISTENS_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+const MAX_PACKET_SIZE: usize = 9000;
+const DNSADDR_PREFIX: &str = "dnsaddr=";
+
+/// mDNS configuration
+#[derive(Clone, Debug)]
+pub struct MdnsConfig {
+    /// Service name of our nodes, queried and announced
+    pub service_name: String,
+    /// Multicast group and port
+    pub multicast_addr: SocketAddrV4,
+    /// Interface to join the multicast group and send on, unspecified for the default one
+    pub interface: Ipv4Addr,
+    /// Interval of queries, the expired peers are dropped on it
+    pub query_interval: Duration,
+    /// TTL of our records, peers are expired if not announced again in it
+    pub ttl: Duration,
+    /// Dial the discovered peers, or the peers with changed addresses
+    pub auto_dial: bool,
+}
+
+impl Default for MdnsConfig {
+    fn default() -> Self {
+        MdnsConfig {
+            service_name: "_p2p._udp.local".to_owned(),
+            multicast_addr: SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), 5353),
+            interface: Ipv4Addr::UNSPECIFIED,
+            query_interval: Duration::from_secs(60),
+            ttl: Duration::from_secs(3 * 60),
+            auto_dial: false,
+        }
+    }
+}
+
+/// mDNS events
+#[derive(Clone, Debug, PartialEq)]
+pub enum MdnsEvent {
+    /// A new peer on local network, or its addresses changed.
+    /// The addresses end with `/p2p/<peer id>`, and are added to `AddressManager`
+    Discovered {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    /// Not announced in ttl, or said goodbye
+    Expired { peer_id: PeerId },
+}
+
+/// Discover peers on local network by mDNS, advertise our listen addresses as the TXT
+/// records of `<peer id>.<service name>`
+pub struct MdnsProtocol<M, S> {
+    id: ProtocolId,
+    peer_id: PeerId,
+    config: MdnsConfig,
+    addr_mgr: M,
+    event_sender: S,
+}
+
+impl<M, S> MdnsProtocol<M, S>
+where
+    M: AddressManager + Clone + Send + 'static,
+    S: Sender<MdnsEvent> + Clone + Send + 'static,
+{
+    pub fn new(
+        id: ProtocolId,
+        peer_id: PeerId,
+        config: MdnsConfig,
+        addr_mgr: M,
+        event_sender: S,
+    ) -> Self {
+        MdnsProtocol {
+            id,
+            peer_id,
+            config,
+            addr_mgr,
+            event_sender,
+        }
+    }
+}
+
+impl<M, S> ProtocolMeta<LengthDelimitedCodec> for MdnsProtocol<M, S>
+where
+    M: AddressManager + Clone + Send + 'static,
+    S: Sender<MdnsEvent> + Clone + Send + 'static,
+{
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        let handle = Box::new(MdnsHandler {
+            proto_id: self.id,
+            peer_id: self.peer_id.clone(),
+            config: self.config.clone(),
+            addr_mgr: Some(self.addr_mgr.clone()),
+            event_sender: Some(self.event_sender.clone()),
+            listens: Vec::new(),
+            listens_sender: None,
+        });
+        Some(handle)
+    }
+}
+
+struct MdnsHandler<M, S> {
+    proto_id: ProtocolId,
+    peer_id: PeerId,
+    config: MdnsConfig,
+    // Moved to the mdns task on init
+    addr_mgr: Option<M>,
+    event_sender: Option<S>,
+    listens: Vec<Multiaddr>,
+    listens_sender: Option<mpsc::UnboundedSender<Vec<Multiaddr>>>,
+}
+
+impl<M, S> MdnsHandler<M, S> {
+    fn update_listens(&mut self, control: &ServiceContext) {
+        let listens = control
+            .listens()
+            .iter()
+            .filter(|address| !is_relayed(address))
+            .cloned()
+            .collect::<Vec<_>>();
+        if listens == self.listens {
+            return;
+        }
+        if let Some(ref sender) = self.listens_sender {
+            let _ = sender.unbounded_send(listens.clone());
+        }
+        self.listens = listens;
+    }
+}
+
+impl<M, S> ServiceProtocol for MdnsHandler<M, S>
+where
+    M: AddressManager + Send + 'static,
+    S: Sender<MdnsEvent> + Send + 'static,
+{
+    fn init(&mut self, control: &mut ServiceContext) {
+        let (addr_mgr, event_sender) = match (self.addr_mgr.take(), self.event_sender.take()) {
+            (Some(addr_mgr), Some(event_sender)) => (addr_mgr, event_sender),
+            _ => return,
+        };
+        let socket = match bind(&self.config) {
+            Ok(socket) => socket,
+            Err(err) => {
+                warn!("mdns bind {} error: {}", self.config.multicast_addr, err);
+                return;
+            }
+        };
+        let (sender, receiver) = mpsc::unbounded();
+        let mdns = Mdns {
+            config: self.config.clone(),
+            peer_name: format!("{}.{}", self.peer_id.to_base58(), self.config.service_name),
+            peer_id: self.peer_id.clone(),
+            socket,
+            recv_buf: vec![0; MAX_PACKET_SIZE],
+            send_buf: VecDeque::new(),
+            listens: Vec::new(),
+            listens_receiver: receiver,
+            query_interval: Interval::new(Instant::now(), self.config.query_interval),
+            peers: FnvHashMap::default(),
+            control: control.control().clone(),
+            addr_mgr,
+            event_sender,
+        };
+        if control.future_task(mdns).is_err() {
+            return;
+        }
+        self.listens_sender = Some(sender);
+        self.update_listens(control);
+        control.set_service_notify(self.proto_id, LISTENS_CHECK_INTERVAL, LISTENS_TOKEN);
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, token: u64) {
+        if token == LISTENS_TOKEN {
+            self.update_listens(control);
+        }
+    }
+}
+
+// Multicast socket shared with other mDNS responders on the host
+fn bind(config: &MdnsConfig) -> io::Result<UdpSocket> {
+    let builder = UdpBuilder::new_v4()?;
+    builder.reuse_address(true)?;
+    #[cfg(unix)]
+    {
+        use net2::unix::UnixUdpBuilderExt;
+        builder.reuse_port(true)?;
+    }
+    let socket = builder.bind((Ipv4Addr::UNSPECIFIED, config.multicast_addr.port()))?;
+    socket.join_multicast_v4(config.multicast_addr.ip(), &config.interface)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_multicast_if_v4(&config.interface)?;
+    UdpSocket::from_std(socket, &Handle::default())
+}
+
+struct Mdns<M, S> {
+    config: MdnsConfig,
+    peer_id: PeerId,
+    // Our name in the PTR record
+    peer_name: String,
+    socket: UdpSocket,
+    recv_buf: Vec<u8>,
+    send_buf: VecDeque<Vec<u8>>,
+    listens: Vec<Multiaddr>,
+    // Closed when the service closed
+    listens_receiver: mpsc::UnboundedReceiver<Vec<Multiaddr>>,
+    query_interval: Interval,
+    // Discovered peers, their addresses and expire time
+    peers: FnvHashMap<PeerId, (Vec<Multiaddr>, Instant)>,
+    control: ServiceControl,
+    addr_mgr: M,
+    event_sender: S,
+}
+
+impl<M, S> Mdns<M, S>
+where
+    M: AddressManager,
+    S: Sender<MdnsEvent>,
+{
+    fn announce(&mut self) {
+        if self.listens.is_empty() {
+            return;
+        }
+        let ttl = self.config.ttl.as_secs() as u32;
+        let addresses = self
+            .listens
+            .iter()
+            .map(|address| {
+                format!(
+                    "{}{}/p2p/{}",
+                    DNSADDR_PREFIX,
+                    address,
+                    self.peer_id.to_base58()
+                )
+            })
+            .collect();
+        let records = [
+            Record {
+                name: self.config.service_name.clone(),
+                ttl,
+                data: RecordData::Ptr(self.peer_name.clone()),
+            },
+            Record {
+                name: self.peer_name.clone(),
+                ttl,
+                data: RecordData::Txt(addresses),
+            },
+        ];
+        match dns::encode_response(&records) {
+            Some(packet) => self.send_buf.push_back(packet),
+            None => warn!("encode mdns response failed"),
+        }
+    }
+
+    fn query(&mut self) {
+        match dns::encode_query(&self.config.service_name) {
+            Some(packet) => self.send_buf.push_back(packet),
+            None => warn!("invalid mdns service name {}", self.config.service_name),
+        }
+
+        let now = Instant::now();
+        let expired = self
+            .peers
+            .iter()
+            .filter(|(_, (_, expires))| *expires <= now)
+            .map(|(peer_id, _)| peer_id.clone())
+            .collect::<Vec<_>>();
+        for peer_id in expired {
+            self.expire(peer_id);
+        }
+    }
+
+    fn handle_packet(&mut self, data: &[u8]) {
+        match dns::decode(data) {
+            Some(Packet::Query(names)) => {
+                if names
+                    .iter()
+                    .any(|name| name.eq_ignore_ascii_case(&self.config.service_name))
+                {
+                    self.announce();
+                }
+            }
+            Some(Packet::Response(records)) => self.handle_records(&records),
+            None => debug!("invalid mdns packet"),
+        }
+    }
+
+    fn handle_records(&mut self, records: &[Record]) {
+        let now = Instant::now();
+        for record in records {
+            let peer_name = match record.data {
+                RecordData::Ptr(ref name)
+                    if record.name.eq_ignore_ascii_case(&self.config.service_name) =>
+                {
+                    name
+                }
+                _ => continue,
+            };
+            let addresses = records
+                .iter()
+                .filter(|txt| txt.name.eq_ignore_ascii_case(peer_name))
+                .filter_map(|txt| match txt.data {
+                    RecordData::Txt(ref strings) => Some(strings),
+                    _ => None,
+                })
+                .flatten()
+                .filter(|string| string.starts_with(DNSADDR_PREFIX))
+                .filter_map(|string| string[DNSADDR_PREFIX.len()..].parse::<Multiaddr>().ok())
+                .collect::<Vec<_>>();
+            // All addresses are of the peer
+            let peer_id = match addresses.first().and_then(extract_peer_id) {
+                Some(peer_id) => peer_id,
+                None => continue,
+            };
+            if peer_id == self.peer_id
+                || addresses
+                    .iter()
+                    .any(|address| extract_peer_id(address).as_ref() != Some(&peer_id))
+            {
+                continue;
+            }
+
+            if record.ttl == 0 {
+                // Goodbye
+                self.expire(peer_id);
+            } else {
+                let expires = now + Duration::from_secs(u64::from(record.ttl));
+                self.discover(peer_id, addresses, expires);
+            }
+        }
+    }
+
+    fn discover(&mut self, peer_id: PeerId, addresses: Vec<Multiaddr>, expires: Instant) {
+        let changed = match self.peers.get(&peer_id) {
+            Some((old_addresses, _)) => *old_addresses != addresses,
+            None => true,
+        };
+        self.peers
+            .insert(peer_id.clone(), (addresses.clone(), expires));
+        if !changed {
+            return;
+        }
+
+        debug!("mdns discovered {:?}: {:?}", peer_id, addresses);
+        for address in addresses.iter() {
+            self.addr_mgr.add_new(address.clone());
+        }
+        if self.config.auto_dial {
+            if let Err(err) = self.control.dial_any(addresses.clone()) {
+                debug!("mdns dial {:?} error: {}", peer_id, err);
+            }
+        }
+        let _ = self
+            .event_sender
+            .try_send(MdnsEvent::Discovered { peer_id, addresses });
+    }
+
+    fn expire(&mut self, peer_id: PeerId) {
+        if self.peers.remove(&peer_id).is_some() {
+            debug!("mdns peer {:?} expired", peer_id);
+            let _ = self.event_sender.try_send(MdnsEvent::Expired { peer_id });
+        }
+    }
+}
+
+impl<M, S> Future for Mdns<M, S>
+where
+    M: AddressManager,
+    S: Sender<MdnsEvent>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            match self.listens_receiver.poll() {
+                Ok(Async::Ready(Some(listens))) => {
+                    self.listens = listens;
+                    self.announce();
+                }
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        loop {
+            match self.query_interval.poll() {
+                Ok(Async::Ready(Some(_))) => self.query(),
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => {
+                    warn!("mdns query timer error: {:?}", err);
+                    break;
+                }
+            }
+        }
+
+        loop {
+            match self.socket.poll_recv_from(&mut self.recv_buf) {
+                Ok(Async::Ready((len, _))) => {
+                    let data = self.recv_buf[..len].to_vec();
+                    self.handle_packet(&data);
+                }
+                Ok(Async::NotReady) => break,
+                Err(err) => {
+                    debug!("mdns receive error: {}", err);
+                    break;
+                }
+            }
+        }
+
+        let target = SocketAddr::V4(self.config.multicast_addr);
+        while let Some(packet) = self.send_buf.front() {
+            match self.socket.poll_send_to(packet, &target) {
+                Ok(Async::Ready(_)) => (),
+                Ok(Async::NotReady) => break,
+                Err(err) => debug!("mdns send error: {}", err),
+            }
+            self.send_buf.pop_front();
+        }
+
+        Ok(Async::NotReady)
+    }
+}
@@ -0,0 +1,277 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use log::{debug, trace};
+use p2p::{
+    context::ServiceContext,
+    error::Error,
+    multiaddr::Multiaddr,
+    service::{ServiceError, ServiceEvent, ServiceTask},
+    utils::multiaddr_to_socketaddr,
+    SessionId, SessionType,
+};
+
+use crate::addr::{AddressManager, RawAddr};
+
+/// Misbehave type reported to `AddressManager::misbehave` when an outbound dial
+/// fails to connect. Like the types reported by the discovery substream, it only
+/// tells what happened, the score is decided by the address manager.
+pub const MISBEHAVE_DIAL_FAILED: u64 = 555;
+
+/// Keep `max_outbound` outbound sessions, the slots are filled with the addresses
+/// from `AddressManager::get_random`.
+///
+/// Call `handle_event` and `handle_error` from `ServiceHandle`, the slots are filled
+/// after sessions closed or dials failed. `fill` can be called at any time, such as
+/// on a service notify.
+pub struct OutboundManager<M> {
+    addr_mgr: M,
+    // Max outbound sessions
+    max_outbound: usize,
+    // Max dials at the same time
+    max_dialing: usize,
+    // Outbound sessions
+    outbound: FnvHashMap<SessionId, RawAddr>,
+    // Dials in progress
+    dialing: FnvHashSet<RawAddr>,
+}
+
+impl<M: AddressManager> OutboundManager<M> {
+    /// Keep `max_outbound` outbound sessions and dial at most `max_dialing` addresses
+    /// at the same time
+    pub fn new(addr_mgr: M, max_outbound: usize, max_dialing: usize) -> Self {
+        OutboundManager {
+            addr_mgr,
+            max_outbound,
+            max_dialing,
+            outbound: FnvHashMap::default(),
+            dialing: FnvHashSet::default(),
+        }
+    }
+
+    /// The address manager
+    pub fn addr_mgr(&mut self) -> &mut M {
+        &mut self.addr_mgr
+    }
+
+    /// Count of the outbound sessions opened by this manager
+    pub fn outbound_count(&self) -> usize {
+        self.outbound.len()
+    }
+
+    /// Count of the dials in progress
+    pub fn dialing_count(&self) -> usize {
+        self.dialing.len()
+    }
+
+    /// Track the outbound sessions, then fill the free slots
+    pub fn handle_event(&mut self, context: &mut ServiceContext, event: &ServiceEvent) {
+        match event {
+            ServiceEvent::SessionOpen {
+                id,
+                address,
+                ty: SessionType::Client,
+                ..
+            } => {
+                if let Some(key) = raw_addr(address) {
+                    if self.dialing.remove(&key) {
+                        self.outbound.insert(*id, key);
+                    }
+                }
+            }
+            ServiceEvent::SessionClose { id, .. } => {
+                if let Some(key) = self.outbound.remove(id) {
+                    debug!("outbound session [{}] closed, {:?}", id, key.socket_addr());
+                }
+            }
+            ServiceEvent::DialCanceled { address } => {
+                if let Some(key) = raw_addr(address) {
+                    self.dialing.remove(&key);
+                }
+            }
+            _ => (),
+        }
+        self.fill(context);
+    }
+
+    /// Release the slot of a failed dial, then fill the free slots.
+    ///
+    /// Only a failed connect is reported to the address manager, a dial refused by
+    /// us, such as a repeated connection or a denied address, is not the fault of
+    /// the address.
+    pub fn handle_error(&mut self, context: &mut ServiceContext, error: &ServiceError) {
+        if let ServiceError::DialerError { address, error } = error {
+            self.dial_failed(address, error);
+        }
+        self.fill(context);
+    }
+
+    fn dial_failed(&mut self, address: &Multiaddr, error: &Error<ServiceTask>) {
+        if let Some(key) = raw_addr(address) {
+            if self.dialing.remove(&key) {
+                debug!("dial {} failed: {:?}", address, error);
+                match error {
+                    Error::IoError(_) | Error::DNSResolverError(_) | Error::HandshakeError(_) => {
+                        self.addr_mgr
+                            .misbehave(address.clone(), MISBEHAVE_DIAL_FAILED);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+
+    /// Dial new addresses until outbound slots or dial concurrency are full
+    pub fn fill(&mut self, context: &mut ServiceContext) {
+        self.fill_with(|address| context.dial(address).is_ok())
+    }
+
+    fn fill_with<F>(&mut self, mut dial: F)
+    where
+        F: FnMut(Multiaddr) -> bool,
+    {
+        let slots = self
+            .max_outbound
+            .saturating_sub(self.outbound.len() + self.dialing.len());
+        let n = slots.min(self.max_dialing.saturating_sub(self.dialing.len()));
+        if n == 0 {
+            return;
+        }
+
+        // Take more candidates, some of them may be connected
+        let candidates = self.addr_mgr.get_random(n * 2);
+        for address in candidates {
+            if self.dialing.len() >= self.max_dialing
+                || self.outbound.len() + self.dialing.len() >= self.max_outbound
+            {
+                break;
+            }
+            let key = match raw_addr(&address) {
+                Some(key) => key,
+                None => continue,
+            };
+            if self.dialing.contains(&key) || self.outbound.values().any(|addr| addr == &key) {
+                continue;
+            }
+            trace!("fill outbound slot with {}", address);
+            if dial(address) {
+                self.dialing.insert(key);
+            }
+        }
+    }
+}
+
+#[inline]
+fn raw_addr(address: &Multiaddr) -> Option<RawAddr> {
+    multiaddr_to_socketaddr(address).ok().map(RawAddr::from)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{raw_addr, OutboundManager, MISBEHAVE_DIAL_FAILED};
+    use crate::addr::AddressManager;
+    use p2p::{config::DialDenied, error::Error, multiaddr::Multiaddr};
+    use std::io;
+
+    #[derive(Default)]
+    struct Addrs {
+        addrs: Vec<Multiaddr>,
+        misbehaves: Vec<(Multiaddr, u64)>,
+    }
+
+    impl AddressManager for Addrs {
+        fn add_new(&mut self, addr: Multiaddr) {
+            self.addrs.push(addr);
+        }
+
+        fn misbehave(&mut self, addr: Multiaddr, ty: u64) -> i32 {
+            self.misbehaves.push((addr, ty));
+            0
+        }
+
+        // All of them in order, the manager stops at its limits
+        fn get_random(&mut self, _n: usize) -> Vec<Multiaddr> {
+            self.addrs.clone()
+        }
+    }
+
+    fn address(port: u16) -> Multiaddr {
+        format!("/ip4/1.1.1.1/tcp/{}", port).parse().unwrap()
+    }
+
+    fn manager(addrs: usize) -> OutboundManager<Addrs> {
+        let mut addr_mgr = Addrs::default();
+        for port in 1..=addrs {
+            addr_mgr.add_new(address(port as u16));
+        }
+        OutboundManager::new(addr_mgr, 3, 2)
+    }
+
+    fn fill(manager: &mut OutboundManager<Addrs>) -> Vec<Multiaddr> {
+        let mut dialed = Vec::new();
+        manager.fill_with(|address| {
+            dialed.push(address);
+            true
+        });
+        dialed
+    }
+
+    #[test]
+    fn fill_limits() {
+        let mut manager = manager(10);
+        // Limited by max dialing
+        assert_eq!(fill(&mut manager), vec![address(1), address(2)]);
+        assert_eq!(fill(&mut manager), vec![]);
+        assert_eq!(manager.dialing_count(), 2);
+
+        // One dial connected, a dial slot and an outbound slot are free
+        let key = raw_addr(&address(1)).unwrap();
+        manager.dialing.remove(&key);
+        manager.outbound.insert(1, key);
+        assert_eq!(fill(&mut manager), vec![address(3)]);
+
+        // Limited by max outbound
+        let key = raw_addr(&address(2)).unwrap();
+        manager.dialing.remove(&key);
+        manager.outbound.insert(2, key);
+        assert_eq!(fill(&mut manager), vec![]);
+        assert_eq!(manager.outbound_count(), 2);
+        assert_eq!(manager.dialing_count(), 1);
+    }
+
+    #[test]
+    fn fill_failed_dial() {
+        let mut manager = manager(10);
+        manager.fill_with(|_| false);
+        assert_eq!(manager.dialing_count(), 0);
+        assert_eq!(fill(&mut manager), vec![address(1), address(2)]);
+    }
+
+    #[test]
+    fn dial_failed() {
+        let mut manager = manager(10);
+        fill(&mut manager);
+
+        // Not dialed by the manager
+        manager.dial_failed(&address(5), &Error::IoError(io::ErrorKind::Other.into()));
+        assert_eq!(manager.dialing_count(), 2);
+        assert!(manager.addr_mgr().misbehaves.is_empty());
+
+        // Refused by us, the address is not penalised
+        manager.dial_failed(&address(1), &Error::DialDenied(DialDenied::Private));
+        assert_eq!(manager.dialing_count(), 1);
+        assert!(manager.addr_mgr().misbehaves.is_empty());
+
+        manager.dial_failed(
+            &address(2),
+            &Error::IoError(io::ErrorKind::ConnectionRefused.into()),
+        );
+        assert_eq!(manager.dialing_count(), 0);
+        assert_eq!(
+            manager.addr_mgr().misbehaves,
+            vec![(address(2), MISBEHAVE_DIAL_FAILED)]
+        );
+
+        fill(&mut manager);
+        manager.dial_failed(&address(1), &Error::RepeatedConnection(1));
+        assert_eq!(manager.addr_mgr().misbehaves.len(), 1);
+    }
+}
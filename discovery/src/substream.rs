@@ -101,7 +101,8 @@ pub struct SubstreamValue {
     // received pending messages
     pub(crate) pending_messages: VecDeque<DiscoveryMessage>,
     pub(crate) addr_known: AddrKnown,
-    // FIXME: Remote listen address, resolved by id protocol
+    // Remote listen address, resolved by the listen port of GetNodes or by the
+    // listen addresses from identify protocol
     pub(crate) remote_addr: RemoteAddress,
     pub(crate) announce: bool,
     pub(crate) announce_addrs: Vec<RawAddr>,
@@ -150,6 +151,21 @@ impl SubstreamValue {
         }
     }
 
+    /// Resolve the inbound remote address with the remote listen addresses
+    pub(crate) fn set_remote_listens<M: AddressManager>(
+        &mut self,
+        listens: &[SocketAddr],
+        addr_mgr: &mut M,
+    ) {
+        if let RemoteAddress::Init(addr) = self.remote_addr {
+            if let Some(port) = listen_port(addr, listens) {
+                self.remote_addr = self.remote_addr.into_listen(port);
+                self.addr_known.insert(self.remote_addr.into_inner().into());
+                addr_mgr.add_new(self.remote_addr.into_multiaddr());
+            }
+        }
+    }
+
     pub(crate) fn check_timer(&mut self) -> Result<(), tokio::timer::Error> {
         loop {
             match self.timer_future.poll()? {
@@ -372,9 +388,23 @@ pub enum Direction {
     Outbound,
 }
 
+/// The listen port of the remote, the listen address on the same ip is preferred,
+/// otherwise the remote listens on all interfaces of the same family
+fn listen_port(remote_addr: SocketAddr, listens: &[SocketAddr]) -> Option<u16> {
+    listens
+        .iter()
+        .find(|listen| listen.ip() == remote_addr.ip())
+        .or_else(|| {
+            listens.iter().find(|listen| {
+                listen.ip().is_unspecified() && listen.is_ipv4() == remote_addr.is_ipv4()
+            })
+        })
+        .map(|listen| listen.port())
+}
+
 #[derive(Eq, PartialEq, Hash, Debug, Clone, Copy)]
 pub(crate) enum RemoteAddress {
-    /// Inbound init remote address
+    /// Inbound init remote address, until the listen address is resolved
     Init(SocketAddr),
     /// Outbound init remote address or Inbound listen address
     Listen(SocketAddr),
@@ -402,3 +432,26 @@ impl RemoteAddress {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::listen_port;
+
+    #[test]
+    fn resolve_listen_port() {
+        let remote_addr = "1.1.1.1:54321".parse().unwrap();
+        let listens = vec![
+            "[::]:1336".parse().unwrap(),
+            "0.0.0.0:1337".parse().unwrap(),
+            "1.1.1.1:1338".parse().unwrap(),
+        ];
+        assert_eq!(listen_port(remote_addr, &listens), Some(1338));
+        assert_eq!(listen_port(remote_addr, &listens[..2]), Some(1337));
+        assert_eq!(listen_port(remote_addr, &listens[..1]), None);
+        // Listen on another interface, such as a private address behind NAT
+        assert_eq!(
+            listen_port(remote_addr, &["10.0.0.1:1339".parse().unwrap()]),
+            None
+        );
+    }
+}
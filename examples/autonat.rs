@@ -0,0 +1,51 @@
+use env_logger;
+use log::{debug, info};
+
+use autonat::{AutonatConfig, AutonatProtocol};
+use futures::prelude::*;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+};
+
+fn main() {
+    env_logger::init();
+    // Loopback addresses are private
+    let config = AutonatConfig {
+        allow_private: true,
+        ..Default::default()
+    };
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(AutonatProtocol::new(1, config))
+        .forever(true)
+        .build(SimpleHandler {});
+
+    if std::env::args().nth(1) == Some("server".to_string()) {
+        debug!("Starting server ......");
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+    } else {
+        debug!("Starting client ......");
+        let _ = service.dial("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1338".parse().unwrap());
+    }
+
+    tokio::run(service.for_each(|_| Ok(())))
+}
+
+struct SimpleHandler {}
+
+impl ServiceHandle for SimpleHandler {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        debug!("service error: {:?}", error);
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::ReachabilityChanged { reachability } = event {
+            info!("reachability: {:?}", reachability);
+        } else {
+            debug!("service event: {:?}", event);
+        }
+    }
+}
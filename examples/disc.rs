@@ -24,7 +24,9 @@ use p2p::{
     ProtocolId, SessionId, SessionType,
 };
 
-use discovery::{AddressManager, Direction, Discovery, DiscoveryHandle, RawAddr, Substream};
+use discovery::{
+    AddressManager, Direction, Discovery, DiscoveryHandle, OutboundManager, RawAddr, Substream,
+};
 
 fn main() {
     env_logger::init();
@@ -34,18 +36,22 @@ fn main() {
         let mut service = ServiceBuilder::default()
             .insert_protocol(meta)
             .forever(true)
-            .build(SHandle {});
+            .build(SHandle { outbound: None });
         let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
         tokio::run(service.for_each(|_| Ok(())))
     } else {
         debug!("Starting client ......");
         let meta = create_meta(5000, 0);
+        let mut addr_mgr = SimpleAddressManager::default();
+        addr_mgr.add_new("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
         let mut service = ServiceBuilder::default()
             .insert_protocol(meta)
             .forever(true)
-            .build(SHandle {});
+            .build(SHandle {
+                outbound: Some(OutboundManager::new(addr_mgr, 8, 3)),
+            });
 
-        let _ = service.dial("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+        // Outbound slots are filled after the listen started
         let _ = service.listen("/ip4/127.0.0.1/tcp/1338".parse().unwrap());
         tokio::run(service.for_each(|_| Ok(())))
     }
@@ -194,15 +200,23 @@ impl ServiceProtocol for DiscoveryProtocol {
     }
 }
 
-struct SHandle {}
+struct SHandle {
+    outbound: Option<OutboundManager<SimpleAddressManager>>,
+}
 
 impl ServiceHandle for SHandle {
-    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+    fn handle_error(&mut self, env: &mut ServiceContext, error: ServiceError) {
         debug!("service error: {:?}", error);
+        if let Some(ref mut outbound) = self.outbound {
+            outbound.handle_error(env, &error);
+        }
     }
 
-    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
         debug!("service event: {:?}", event);
+        if let Some(ref mut outbound) = self.outbound {
+            outbound.handle_event(env, &event);
+        }
     }
 }
 
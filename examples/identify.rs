@@ -0,0 +1,50 @@
+use env_logger;
+use log::{debug, info};
+
+use futures::{future::lazy, prelude::*, sync::mpsc::channel};
+use identify::{Event, IdentifyProtocol};
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+};
+
+fn main() {
+    env_logger::init();
+    let (sender, receiver) = channel(256);
+    let protocol = IdentifyProtocol::new(1, "p2p-example/0.1.0".to_owned(), sender);
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .forever(true)
+        .build(SimpleHandler {});
+
+    if std::env::args().nth(1) == Some("server".to_string()) {
+        debug!("Starting server ......");
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+    } else {
+        debug!("Starting client ......");
+        let _ = service.dial("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1338".parse().unwrap());
+    }
+
+    tokio::run(lazy(|| {
+        tokio::spawn(receiver.for_each(|event: Event| {
+            info!("receive event: {:?}", event);
+            Ok(())
+        }));
+        service.for_each(|_| Ok(()))
+    }))
+}
+
+struct SimpleHandler {}
+
+impl ServiceHandle for SimpleHandler {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        debug!("service error: {:?}", error);
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        debug!("service event: {:?}", event);
+    }
+}
@@ -0,0 +1,74 @@
+use env_logger;
+use log::{debug, info};
+
+use std::time::{Duration, Instant};
+
+use futures::{future::lazy, prelude::*};
+use kad::{KadConfig, KadProtocol};
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+    SecioKeyPair,
+};
+use tokio::timer::Delay;
+
+/// `cargo run --example kad -- server` and `cargo run --example kad -- <server peer id>`
+fn main() {
+    env_logger::init();
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let protocol = KadProtocol::new(1, peer_id.clone(), KadConfig::default());
+    let mut handle = protocol.handle();
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(SimpleHandler {});
+
+    match std::env::args().nth(1) {
+        Some(ref arg) if arg == "server" => {
+            info!("Starting server, peer id: {}", peer_id.to_base58());
+            let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+            tokio::run(service.for_each(|_| Ok(())))
+        }
+        Some(server_peer_id) => {
+            debug!("Starting client ......");
+            let _ = service.listen("/ip4/127.0.0.1/tcp/1338".parse().unwrap());
+            let _ = handle.add_address(
+                server_peer_id.parse().expect("invalid peer id"),
+                "/ip4/127.0.0.1/tcp/1337".parse().unwrap(),
+            );
+            tokio::run(lazy(move || {
+                let task = Delay::new(Instant::now() + Duration::from_secs(1))
+                    .map_err(|_| ())
+                    .and_then(move |_| {
+                        handle
+                            .put_value(b"hello".to_vec(), b"kad".to_vec())
+                            .and_then(move |count| {
+                                info!("record stored to {} peers", count);
+                                handle.get_value(b"hello".to_vec())
+                            })
+                            .map(|record| info!("get record: {:?}", record))
+                            .map_err(|err| info!("query error: {}", err))
+                    });
+                tokio::spawn(task);
+                service.for_each(|_| Ok(()))
+            }))
+        }
+        None => println!("Usage: kad server | kad <server peer id>"),
+    }
+}
+
+struct SimpleHandler {}
+
+impl ServiceHandle for SimpleHandler {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        debug!("service error: {:?}", error);
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        debug!("service event: {:?}", event);
+    }
+}
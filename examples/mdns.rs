@@ -0,0 +1,53 @@
+use env_logger;
+use log::info;
+
+use discovery::{AddressManager, MdnsConfig, MdnsEvent, MdnsProtocol};
+use futures::{future::lazy, prelude::*, sync::mpsc::channel};
+use p2p::{builder::ServiceBuilder, multiaddr::Multiaddr, SecioKeyPair};
+
+/// Dial by mDNS, the discovered addresses are not kept
+#[derive(Clone)]
+struct EmptyAddressManager;
+
+impl AddressManager for EmptyAddressManager {
+    fn add_new(&mut self, _addr: Multiaddr) {}
+
+    fn misbehave(&mut self, _addr: Multiaddr, _ty: u64) -> i32 {
+        0
+    }
+
+    fn get_random(&mut self, _n: usize) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+}
+
+fn main() {
+    env_logger::init();
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let config = MdnsConfig {
+        auto_dial: true,
+        ..Default::default()
+    };
+    let (sender, receiver) = channel(16);
+    let protocol = MdnsProtocol::new(
+        1,
+        key_pair.to_peer_id(),
+        config,
+        EmptyAddressManager,
+        sender,
+    );
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(());
+    let _ = service.listen("/ip4/0.0.0.0/tcp/0".parse().unwrap());
+
+    tokio::run(lazy(|| {
+        tokio::spawn(receiver.for_each(|event: MdnsEvent| {
+            info!("mdns event: {:?}", event);
+            Ok(())
+        }));
+        service.for_each(|_| Ok(()))
+    }))
+}
@@ -0,0 +1,31 @@
+use env_logger;
+use log::info;
+
+use futures::{future::lazy, prelude::*, sync::mpsc::channel};
+use p2p::builder::ServiceBuilder;
+use portmap::{Event, PortMapConfig, PortMapping};
+
+fn main() {
+    env_logger::init();
+    let mut service = ServiceBuilder::default().forever(true).build(());
+    let listen_addr = service
+        .listen("/ip4/0.0.0.0/tcp/1337".parse().unwrap())
+        .unwrap();
+
+    let (sender, receiver) = channel(16);
+    let (mapping, _handle) = PortMapping::new(
+        PortMapConfig::default(),
+        service.control().clone(),
+        &[listen_addr],
+        sender,
+    );
+
+    tokio::run(lazy(|| {
+        tokio::spawn(mapping);
+        tokio::spawn(receiver.for_each(|event: Event| {
+            info!("port mapping event: {:?}", event);
+            Ok(())
+        }));
+        service.for_each(|_| Ok(()))
+    }))
+}
@@ -0,0 +1,68 @@
+use env_logger;
+use log::{debug, info};
+
+use std::time::{Duration, Instant};
+
+use futures::{future::lazy, prelude::*, sync::mpsc::channel};
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+    SecioKeyPair,
+};
+use pubsub::{Event, PubsubConfig, PubsubProtocol};
+use tokio::timer::Interval;
+
+fn main() {
+    env_logger::init();
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let (sender, receiver) = channel(256);
+    let protocol = PubsubProtocol::new(1, key_pair.clone(), PubsubConfig::default(), sender);
+    let mut handle = protocol.handle();
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(SimpleHandler {});
+
+    if std::env::args().nth(1) == Some("server".to_string()) {
+        debug!("Starting server ......");
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+    } else {
+        debug!("Starting client ......");
+        let _ = service.dial("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+        let _ = service.listen("/ip4/127.0.0.1/tcp/1338".parse().unwrap());
+    }
+
+    tokio::run(lazy(move || {
+        tokio::spawn(receiver.for_each(|event: Event| {
+            info!("receive event: {:?}", event);
+            Ok(())
+        }));
+        let _ = handle.subscribe("chat".to_owned());
+        let mut count = 0u32;
+        tokio::spawn(
+            Interval::new(Instant::now(), Duration::from_secs(3))
+                .for_each(move |_| {
+                    count += 1;
+                    let _ = handle.publish("chat".to_owned(), format!("hello {}", count).into());
+                    Ok(())
+                })
+                .map_err(|_| ()),
+        );
+        service.for_each(|_| Ok(()))
+    }))
+}
+
+struct SimpleHandler {}
+
+impl ServiceHandle for SimpleHandler {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        debug!("service error: {:?}", error);
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        debug!("service event: {:?}", event);
+    }
+}
@@ -0,0 +1,73 @@
+use env_logger;
+use log::{debug, info};
+
+use futures::{future::lazy, prelude::*, sync::mpsc::channel};
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+    SecioKeyPair,
+};
+use relay::{Event, RelayConfig, RelayProtocol};
+
+/// `cargo run --example relay -- server`,
+/// `cargo run --example relay -- target /ip4/127.0.0.1/tcp/1337/p2p/<relay peer id>` and
+/// `cargo run --example relay -- source <relayed address of target>`
+fn main() {
+    env_logger::init();
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let mode = std::env::args().nth(1).unwrap_or_default();
+    let config = RelayConfig {
+        hop: mode == "server",
+        ..Default::default()
+    };
+    let (sender, receiver) = channel(256);
+    let protocol = RelayProtocol::new(1, peer_id.clone(), config, sender);
+    let mut handle = protocol.handle();
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(SimpleHandler {});
+
+    match (mode.as_str(), std::env::args().nth(2)) {
+        ("server", _) => {
+            info!("Starting relay, peer id: {}", peer_id.to_base58());
+            let _ = service.listen("/ip4/127.0.0.1/tcp/1337".parse().unwrap());
+        }
+        ("target", Some(relay)) => {
+            let _ = handle.reserve(relay.parse().expect("invalid relay address"));
+        }
+        ("source", Some(target)) => {
+            let _ = handle.dial(target.parse().expect("invalid relayed address"));
+        }
+        _ => {
+            println!(
+                "Usage: relay server | relay target <relay addr> | relay source <relayed addr>"
+            );
+            return;
+        }
+    }
+
+    tokio::run(lazy(|| {
+        tokio::spawn(receiver.for_each(|event: Event| {
+            info!("relay event: {:?}", event);
+            Ok(())
+        }));
+        service.for_each(|_| Ok(()))
+    }))
+}
+
+struct SimpleHandler {}
+
+impl ServiceHandle for SimpleHandler {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        debug!("service error: {:?}", error);
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        info!("service event: {:?}", event);
+    }
+}
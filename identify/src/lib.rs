@@ -0,0 +1,220 @@
+mod protocol;
+
+pub use crate::protocol::IdentifyInfo;
+
+use crate::protocol::MAX_LISTEN_ADDRS;
+use fnv::FnvHashMap;
+use generic_channel::Sender;
+use log::debug;
+use p2p::{
+    context::{ServiceContext, SessionContext},
+    multiaddr::{Multiaddr, ToMultiaddr},
+    traits::{ProtocolMeta, ServiceProtocol},
+    utils::multiaddr_to_socketaddr,
+    ProtocolId, SessionId, SessionType,
+};
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+/// Identify protocol events
+#[derive(Debug)]
+pub enum Event {
+    /// Remote peer sent its information, the listen addresses can be sent to
+    /// discovery by `DiscoveryHandle::remote_listens_sender`
+    Identified(SessionId, IdentifyInfo),
+    /// Remote peer sent an invalid or repeated message, the session is disconnected
+    Misbehave(SessionId),
+}
+
+/// Exchange listen addresses, observed address, protocols and agent version on connect
+pub struct IdentifyProtocol<S: Sender<Event> + Send + Clone> {
+    id: ProtocolId,
+    agent_version: String,
+    event_sender: S,
+}
+
+impl<S> IdentifyProtocol<S>
+where
+    S: Sender<Event> + Send + Clone,
+{
+    /// Identify protocol with the agent version sent to remote, such as "ckb/0.1.0",
+    /// the remote information is reported to the event sender
+    pub fn new(id: ProtocolId, agent_version: String, event_sender: S) -> Self {
+        IdentifyProtocol {
+            id,
+            agent_version,
+            event_sender,
+        }
+    }
+}
+
+impl<S> ProtocolMeta<LengthDelimitedCodec> for IdentifyProtocol<S>
+where
+    S: Sender<Event> + Send + Clone + 'static,
+{
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        let handle = Box::new(IdentifyHandler {
+            proto_id: self.id,
+            agent_version: self.agent_version.clone(),
+            identified: Default::default(),
+            event_sender: self.event_sender.clone(),
+        });
+        Some(handle)
+    }
+}
+
+struct IdentifyHandler<S: Sender<Event>> {
+    proto_id: ProtocolId,
+    agent_version: String,
+    /// Connected sessions, true if remote information received
+    identified: FnvHashMap<SessionId, bool>,
+    event_sender: S,
+}
+
+impl<S> IdentifyHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn local_info(&self, control: &ServiceContext, session: &SessionContext) -> IdentifyInfo {
+        let mut listen_addrs: Vec<Multiaddr> = Vec::new();
+        for address in control.external_addrs().iter().chain(control.listens()) {
+            if !listen_addrs.contains(address) {
+                listen_addrs.push(address.clone());
+            }
+        }
+        listen_addrs.truncate(MAX_LISTEN_ADDRS);
+
+        let observed_addr = multiaddr_to_socketaddr(&session.address)
+            .ok()
+            .and_then(|address| address.to_multiaddr().ok())
+            .unwrap_or_else(|| session.address.clone());
+
+        let mut protocols = control.protocols().values().cloned().collect::<Vec<_>>();
+        protocols.sort_by(|a, b| a.name.cmp(&b.name));
+
+        IdentifyInfo {
+            listen_addrs,
+            observed_addr,
+            protocols,
+            agent_version: self.agent_version.clone(),
+        }
+    }
+}
+
+/// The remote observes the local port of outbound session, it's bound to our listen port
+/// only if `reuse_port` enabled, otherwise the port is random and the observation is useless
+fn observed_listen_addr(
+    observed: &Multiaddr,
+    ty: SessionType,
+    listens: &[Multiaddr],
+    reuse_port: bool,
+) -> Option<Multiaddr> {
+    let mut observed = multiaddr_to_socketaddr(observed).ok()?;
+    if ty == SessionType::Client {
+        if !reuse_port {
+            return None;
+        }
+        let port = listens
+            .iter()
+            .filter_map(|address| multiaddr_to_socketaddr(address).ok())
+            .find(|address| address.is_ipv4() == observed.is_ipv4())?
+            .port();
+        observed.set_port(port);
+    }
+    observed.to_multiaddr().ok()
+}
+
+impl<S> ServiceProtocol for IdentifyHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn init(&mut self, _control: &mut ServiceContext) {}
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        debug!(
+            "proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
+            self.proto_id, session.id, session.address, session.ty, version
+        );
+        self.identified.insert(session.id, false);
+        let data = self.local_info(control, session).encode();
+        let _ = control.send_message(session.id, self.proto_id, data);
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        self.identified.remove(&session.id);
+        debug!(
+            "proto id [{}] close on session [{}]",
+            self.proto_id, session.id
+        );
+    }
+
+    fn received(&mut self, control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        let info = match self.identified.get(&session.id) {
+            Some(false) => IdentifyInfo::decode(&data),
+            // Only one message for each session
+            _ => None,
+        };
+        match info {
+            Some(info) => {
+                self.identified.insert(session.id, true);
+                if let Some(address) = observed_listen_addr(
+                    &info.observed_addr,
+                    session.ty,
+                    control.listens(),
+                    control.reuse_port(),
+                ) {
+                    let _ = control.observed_addr(session.id, address);
+                }
+                let _ = self
+                    .event_sender
+                    .try_send(Event::Identified(session.id, info));
+            }
+            None => {
+                debug!(
+                    "proto id [{}] session [{}] sent invalid identify message",
+                    self.proto_id, session.id
+                );
+                let _ = control.disconnect(session.id);
+                let _ = self.event_sender.try_send(Event::Misbehave(session.id));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::observed_listen_addr;
+    use p2p::SessionType;
+
+    #[test]
+    fn translate_observed_port() {
+        let observed = "/ip4/1.1.1.1/tcp/54321".parse().unwrap();
+        let listens = vec![
+            "/ip6/::1/tcp/1336".parse().unwrap(),
+            "/ip4/127.0.0.1/tcp/1337".parse().unwrap(),
+        ];
+        assert_eq!(
+            observed_listen_addr(&observed, SessionType::Server, &listens, false),
+            Some(observed.clone())
+        );
+        assert_eq!(
+            observed_listen_addr(&observed, SessionType::Client, &listens, true),
+            Some("/ip4/1.1.1.1/tcp/1337".parse().unwrap())
+        );
+        assert_eq!(
+            observed_listen_addr(&observed, SessionType::Client, &[], true),
+            None
+        );
+        // The port of outbound session is random
+        assert_eq!(
+            observed_listen_addr(&observed, SessionType::Client, &listens, false),
+            None
+        );
+    }
+}
@@ -0,0 +1,116 @@
+use p2p::{multiaddr::Multiaddr, protocol_select::ProtocolInfo};
+use serde_derive::{Deserialize, Serialize};
+
+/// Max listen addresses in a message, the rest are ignored
+pub(crate) const MAX_LISTEN_ADDRS: usize = 10;
+/// Max protocols in a message
+const MAX_PROTOCOLS: usize = 64;
+/// Max bytes of agent version
+const MAX_AGENT_VERSION_LEN: usize = 256;
+
+/// Information of remote peer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct IdentifyInfo {
+    /// Listen addresses of remote peer
+    pub listen_addrs: Vec<Multiaddr>,
+    /// Our address observed by remote peer
+    pub observed_addr: Multiaddr,
+    /// Supported protocols of remote peer
+    pub protocols: Vec<ProtocolInfo>,
+    /// Agent name and version, such as `ckb/0.5.0`
+    pub agent_version: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct IdentifyMessage {
+    listen_addrs: Vec<String>,
+    observed_addr: String,
+    protocols: Vec<(String, Vec<String>)>,
+    agent_version: String,
+}
+
+impl IdentifyInfo {
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let message = IdentifyMessage {
+            listen_addrs: self
+                .listen_addrs
+                .iter()
+                .take(MAX_LISTEN_ADDRS)
+                .map(ToString::to_string)
+                .collect(),
+            observed_addr: self.observed_addr.to_string(),
+            protocols: self
+                .protocols
+                .iter()
+                .map(|info| (info.name.clone(), info.support_versions.clone()))
+                .collect(),
+            agent_version: self.agent_version.clone(),
+        };
+        bincode::serialize(&message).expect("serialize identify message")
+    }
+
+    /// None means the message is invalid
+    pub(crate) fn decode(data: &[u8]) -> Option<Self> {
+        let message: IdentifyMessage = bincode::deserialize(data).ok()?;
+        if message.listen_addrs.len() > MAX_LISTEN_ADDRS
+            || message.protocols.len() > MAX_PROTOCOLS
+            || message.agent_version.len() > MAX_AGENT_VERSION_LEN
+        {
+            return None;
+        }
+
+        let listen_addrs = message
+            .listen_addrs
+            .iter()
+            .map(|address| address.parse().ok())
+            .collect::<Option<Vec<Multiaddr>>>()?;
+        let observed_addr = message.observed_addr.parse().ok()?;
+        let protocols = message
+            .protocols
+            .into_iter()
+            .map(|(name, support_versions)| ProtocolInfo {
+                name,
+                support_versions,
+            })
+            .collect();
+
+        Some(IdentifyInfo {
+            listen_addrs,
+            observed_addr,
+            protocols,
+            agent_version: message.agent_version,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{IdentifyInfo, MAX_LISTEN_ADDRS};
+    use p2p::protocol_select::ProtocolInfo;
+
+    fn info() -> IdentifyInfo {
+        IdentifyInfo {
+            listen_addrs: vec!["/ip4/1.1.1.1/tcp/1337".parse().unwrap()],
+            observed_addr: "/ip4/2.2.2.2/tcp/1338".parse().unwrap(),
+            protocols: vec![ProtocolInfo::new("/p2p/ping", vec!["0.0.1".to_owned()])],
+            agent_version: "p2p/0.1.0".to_owned(),
+        }
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let info = info();
+        assert_eq!(IdentifyInfo::decode(&info.encode()), Some(info));
+        assert_eq!(IdentifyInfo::decode(b"invalid"), None);
+    }
+
+    #[test]
+    fn truncate_listen_addrs() {
+        let mut info = info();
+        info.listen_addrs = (0..MAX_LISTEN_ADDRS as u16 + 1)
+            .map(|port| format!("/ip4/1.1.1.1/tcp/{}", port).parse().unwrap())
+            .collect();
+        let decoded = IdentifyInfo::decode(&info.encode()).unwrap();
+        assert_eq!(decoded.listen_addrs.len(), MAX_LISTEN_ADDRS);
+    }
+}
@@ -0,0 +1,909 @@
+use fnv::{FnvHashMap, FnvHashSet};
+use futures::{
+    sync::{mpsc::Receiver, oneshot},
+    Async, Future, Poll, Stream,
+};
+use log::{debug, trace};
+use p2p::{
+    context::ServiceControl,
+    multiaddr::{multihash::Multihash, Multiaddr, Protocol},
+    utils::extract_peer_id,
+    PeerId, ProtocolId, SessionId,
+};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+use crate::{
+    kbucket::{Entry, KBucketsTable, Key, K_VALUE},
+    protocol::{
+        decode_addrs, decode_peers, encode_addrs, KadMessage, KadPeer, KadRequest, KadResponse,
+        WirePeer, WireRecord, MAX_KEY_LEN,
+    },
+    query::{ClosestPeers, QueryStep},
+    record::{ProviderRecord, Record, RecordStore},
+    KadConfig,
+};
+
+/// Events of the protocol handler
+pub(crate) enum Input {
+    Connected {
+        session_id: SessionId,
+        peer_id: PeerId,
+        /// Remote address of outbound session, the inbound one is not dialable
+        address: Option<Multiaddr>,
+    },
+    Disconnected {
+        session_id: SessionId,
+    },
+    Received {
+        session_id: SessionId,
+        data: Vec<u8>,
+    },
+    /// Listen and external addresses of local node
+    LocalAddrs(Vec<Multiaddr>),
+}
+
+/// Commands of `KadHandle`
+pub(crate) enum Command {
+    AddAddress {
+        peer_id: PeerId,
+        address: Multiaddr,
+    },
+    FindNode {
+        key: Key,
+        sender: oneshot::Sender<Vec<KadPeer>>,
+    },
+    GetValue {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Option<Record>>,
+    },
+    PutValue {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        sender: oneshot::Sender<usize>,
+    },
+    RemoveRecord {
+        key: Vec<u8>,
+    },
+    GetProviders {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Vec<KadPeer>>,
+    },
+    StartProviding {
+        key: Vec<u8>,
+        sender: oneshot::Sender<usize>,
+    },
+    StopProviding {
+        key: Vec<u8>,
+    },
+}
+
+enum QueryKind {
+    FindNode {
+        sender: oneshot::Sender<Vec<KadPeer>>,
+    },
+    GetValue {
+        key: Vec<u8>,
+        sender: oneshot::Sender<Option<Record>>,
+    },
+    /// Store the record to the closest peers found
+    PutValue {
+        record: Record,
+        sender: Option<oneshot::Sender<usize>>,
+    },
+    GetProviders {
+        key: Vec<u8>,
+        providers: Vec<KadPeer>,
+        sender: oneshot::Sender<Vec<KadPeer>>,
+    },
+    /// Announce to the closest peers found
+    AddProvider {
+        key: Vec<u8>,
+        sender: Option<oneshot::Sender<usize>>,
+    },
+}
+
+struct Query {
+    kind: QueryKind,
+    peers: ClosestPeers,
+    started: Instant,
+}
+
+impl Query {
+    fn request(&self) -> KadRequest {
+        match self.kind {
+            QueryKind::GetValue { ref key, .. } => KadRequest::FindValue { key: key.clone() },
+            QueryKind::GetProviders { ref key, .. } => {
+                KadRequest::GetProviders { key: key.clone() }
+            }
+            QueryKind::FindNode { .. }
+            | QueryKind::PutValue { .. }
+            | QueryKind::AddProvider { .. } => KadRequest::FindNode {
+                key: self.peers.target().as_bytes().to_vec(),
+            },
+        }
+    }
+}
+
+/// A request waiting for response
+struct Inflight {
+    peer_id: PeerId,
+    query_id: Option<u64>,
+    sent_at: Instant,
+}
+
+/// Routing table, records and queries, runs as a future task of the service
+pub(crate) struct Kademlia {
+    proto_id: ProtocolId,
+    local_peer_id: PeerId,
+    config: KadConfig,
+    control: ServiceControl,
+    table: KBucketsTable,
+    store: RecordStore,
+    /// Records published by local node
+    published: HashMap<Vec<u8>, Vec<u8>>,
+    /// Keys provided by local node
+    provided: FnvHashSet<Vec<u8>>,
+    local_addrs: Vec<Multiaddr>,
+
+    sessions: FnvHashMap<SessionId, PeerId>,
+    peer_sessions: FnvHashMap<PeerId, SessionId>,
+
+    queries: FnvHashMap<u64, Query>,
+    next_query_id: u64,
+    inflight: FnvHashMap<u64, Inflight>,
+    /// Requests waiting for the dial
+    pending_sends: FnvHashMap<PeerId, Vec<(u64, KadRequest, Instant)>>,
+    next_request_id: u64,
+
+    input_receiver: Receiver<Input>,
+    command_receiver: Receiver<Command>,
+    commands_closed: bool,
+    tick: Interval,
+    republish: Interval,
+}
+
+impl Kademlia {
+    pub fn new(
+        proto_id: ProtocolId,
+        local_peer_id: PeerId,
+        config: KadConfig,
+        control: ServiceControl,
+        input_receiver: Receiver<Input>,
+        command_receiver: Receiver<Command>,
+    ) -> Self {
+        let republish_interval = config.republish_interval;
+        Kademlia {
+            proto_id,
+            table: KBucketsTable::new(Key::from_peer_id(&local_peer_id)),
+            store: RecordStore::new(config.max_records, config.max_providers_per_key),
+            local_peer_id,
+            config,
+            control,
+            published: HashMap::new(),
+            provided: FnvHashSet::default(),
+            local_addrs: Vec::new(),
+            sessions: FnvHashMap::default(),
+            peer_sessions: FnvHashMap::default(),
+            queries: FnvHashMap::default(),
+            next_query_id: 0,
+            inflight: FnvHashMap::default(),
+            pending_sends: FnvHashMap::default(),
+            next_request_id: 0,
+            input_receiver,
+            command_receiver,
+            commands_closed: false,
+            tick: Interval::new_interval(Duration::from_secs(1)),
+            republish: Interval::new(Instant::now() + republish_interval, republish_interval),
+        }
+    }
+
+    fn handle_input(&mut self, input: Input) {
+        match input {
+            Input::Connected {
+                session_id,
+                peer_id,
+                address,
+            } => {
+                let addrs = address.into_iter().collect::<Vec<_>>();
+                if !addrs.is_empty() || self.table.get(&peer_id).is_some() {
+                    self.table.update(peer_id.clone(), addrs, true);
+                }
+                self.sessions.insert(session_id, peer_id.clone());
+                self.peer_sessions.insert(peer_id.clone(), session_id);
+
+                if let Some(requests) = self.pending_sends.remove(&peer_id) {
+                    for (id, request, _) in requests {
+                        self.send_message(session_id, &KadMessage::Request { id, request });
+                    }
+                }
+            }
+            Input::Disconnected { session_id } => {
+                if let Some(peer_id) = self.sessions.remove(&session_id) {
+                    if self.peer_sessions.get(&peer_id) == Some(&session_id) {
+                        self.peer_sessions.remove(&peer_id);
+                        self.table.set_disconnected(&peer_id);
+                        self.fail_requests(&peer_id);
+                    }
+                }
+            }
+            Input::Received { session_id, data } => {
+                let peer_id = match self.sessions.get(&session_id) {
+                    Some(peer_id) => peer_id.clone(),
+                    None => return,
+                };
+                match KadMessage::decode(&data) {
+                    Some(KadMessage::Request { id, request }) => {
+                        if let Some(response) = self.handle_request(&peer_id, request) {
+                            self.send_message(session_id, &KadMessage::Response { id, response });
+                        }
+                    }
+                    Some(KadMessage::Response { id, response }) => {
+                        self.handle_response(&peer_id, id, response)
+                    }
+                    None => {
+                        debug!("session [{}] sent invalid kad message", session_id);
+                        let _ = self.control.disconnect(session_id);
+                    }
+                }
+            }
+            Input::LocalAddrs(addrs) => self.local_addrs = addrs,
+        }
+    }
+
+    fn handle_command(&mut self, command: Command) {
+        let now = Instant::now();
+        match command {
+            Command::AddAddress { peer_id, address } => {
+                if !self.table.add_address(&peer_id, address.clone()) {
+                    let connected = self.peer_sessions.contains_key(&peer_id);
+                    self.table.update(peer_id, vec![address], connected);
+                }
+            }
+            Command::FindNode { key, sender } => {
+                self.start_query(key, QueryKind::FindNode { sender });
+            }
+            Command::GetValue { key, sender } => {
+                if let Some(record) = self.store.get(&key, now) {
+                    let _ = sender.send(Some(record.clone()));
+                    return;
+                }
+                let target = Key::from_record_key(&key);
+                self.start_query(target, QueryKind::GetValue { key, sender });
+            }
+            Command::PutValue { key, value, sender } => {
+                self.published.insert(key.clone(), value.clone());
+                self.put_value(key, value, Some(sender));
+            }
+            Command::RemoveRecord { key } => {
+                self.published.remove(&key);
+            }
+            Command::GetProviders { key, sender } => {
+                let providers = self
+                    .store
+                    .providers(&key, now)
+                    .into_iter()
+                    .map(KadPeer::from)
+                    .collect();
+                let target = Key::from_record_key(&key);
+                self.start_query(
+                    target,
+                    QueryKind::GetProviders {
+                        key,
+                        providers,
+                        sender,
+                    },
+                );
+            }
+            Command::StartProviding { key, sender } => {
+                self.provided.insert(key.clone());
+                self.add_provider(key, Some(sender));
+            }
+            Command::StopProviding { key } => {
+                self.provided.remove(&key);
+            }
+        }
+    }
+
+    fn put_value(&mut self, key: Vec<u8>, value: Vec<u8>, sender: Option<oneshot::Sender<usize>>) {
+        let record = Record {
+            key,
+            value,
+            publisher: Some(self.local_peer_id.clone()),
+            expires: Instant::now() + self.config.record_ttl,
+        };
+        self.store.put(record.clone());
+        let target = Key::from_record_key(&record.key);
+        self.start_query(target, QueryKind::PutValue { record, sender });
+    }
+
+    fn add_provider(&mut self, key: Vec<u8>, sender: Option<oneshot::Sender<usize>>) {
+        self.store.add_provider(ProviderRecord {
+            key: key.clone(),
+            provider: self.local_peer_id.clone(),
+            addrs: self.local_addrs.clone(),
+            expires: Instant::now() + self.config.provider_ttl,
+        });
+        let target = Key::from_record_key(&key);
+        self.start_query(target, QueryKind::AddProvider { key, sender });
+    }
+
+    fn start_query(&mut self, target: Key, kind: QueryKind) {
+        let known = self
+            .table
+            .closest(&target, K_VALUE)
+            .into_iter()
+            .map(|entry| KadPeer {
+                peer_id: entry.peer_id,
+                addrs: entry.addrs,
+            })
+            .collect();
+        let query = Query {
+            kind,
+            peers: ClosestPeers::new(
+                target,
+                known,
+                self.config.parallelism,
+                K_VALUE,
+                self.config.request_timeout,
+            ),
+            started: Instant::now(),
+        };
+        self.queries.insert(self.next_query_id, query);
+        self.next_query_id += 1;
+    }
+
+    /// The `n` closest dialable peers, except the requester
+    fn closer_peers(&self, target: &Key, except: &PeerId) -> Vec<WirePeer> {
+        self.table
+            .closest(target, K_VALUE + 1)
+            .into_iter()
+            .filter(|entry| !entry.addrs.is_empty() && &entry.peer_id != except)
+            .take(K_VALUE)
+            .map(|Entry { peer_id, addrs, .. }| WirePeer::encode(&KadPeer { peer_id, addrs }))
+            .collect()
+    }
+
+    fn handle_request(&mut self, peer_id: &PeerId, request: KadRequest) -> Option<KadResponse> {
+        let now = Instant::now();
+        let response = match request {
+            KadRequest::FindNode { key } => {
+                let target = Key::from_bytes(&key)?;
+                KadResponse::FindNode {
+                    closer: self.closer_peers(&target, peer_id),
+                }
+            }
+            KadRequest::FindValue { key } => {
+                if key.len() > MAX_KEY_LEN {
+                    return None;
+                }
+                KadResponse::FindValue {
+                    record: self
+                        .store
+                        .get(&key, now)
+                        .map(|record| WireRecord::encode(record, now)),
+                    closer: self.closer_peers(&Key::from_record_key(&key), peer_id),
+                }
+            }
+            KadRequest::PutValue { record } => {
+                let record = record.decode(now, self.config.record_ttl)?;
+                debug!("peer {:?} put record", peer_id);
+                self.store.put(record);
+                KadResponse::PutValue
+            }
+            KadRequest::GetProviders { key } => {
+                if key.len() > MAX_KEY_LEN {
+                    return None;
+                }
+                KadResponse::GetProviders {
+                    providers: self
+                        .store
+                        .providers(&key, now)
+                        .into_iter()
+                        .map(|record| WirePeer::encode(&record.into()))
+                        .collect(),
+                    closer: self.closer_peers(&Key::from_record_key(&key), peer_id),
+                }
+            }
+            KadRequest::AddProvider { key, addrs } => {
+                if key.len() > MAX_KEY_LEN {
+                    return None;
+                }
+                // Only the sender itself can be announced
+                let mut addrs = decode_addrs(&addrs);
+                if addrs.is_empty() {
+                    addrs = self
+                        .table
+                        .get(peer_id)
+                        .map(|entry| entry.addrs.clone())
+                        .unwrap_or_default();
+                }
+                self.store.add_provider(ProviderRecord {
+                    key,
+                    provider: peer_id.clone(),
+                    addrs,
+                    expires: now + self.config.provider_ttl,
+                });
+                KadResponse::AddProvider
+            }
+        };
+        Some(response)
+    }
+
+    fn handle_response(&mut self, peer_id: &PeerId, id: u64, response: KadResponse) {
+        let query_id = match self.inflight.get(&id) {
+            Some(inflight) if &inflight.peer_id == peer_id => inflight.query_id,
+            _ => return,
+        };
+        self.inflight.remove(&id);
+        let query_id = match query_id {
+            Some(query_id) => query_id,
+            None => return,
+        };
+        let now = Instant::now();
+        let record_ttl = self.config.record_ttl;
+        let query = match self.queries.get_mut(&query_id) {
+            Some(query) => query,
+            None => return,
+        };
+
+        let mut found = None;
+        let closer = match (&mut query.kind, response) {
+            (QueryKind::FindNode { .. }, KadResponse::FindNode { closer })
+            | (QueryKind::PutValue { .. }, KadResponse::FindNode { closer })
+            | (QueryKind::AddProvider { .. }, KadResponse::FindNode { closer }) => closer,
+            (QueryKind::GetValue { key, .. }, KadResponse::FindValue { record, closer }) => {
+                found = record
+                    .and_then(|record| record.decode(now, record_ttl))
+                    .filter(|record| &record.key == key);
+                closer
+            }
+            (
+                QueryKind::GetProviders { providers, .. },
+                KadResponse::GetProviders {
+                    providers: new_providers,
+                    closer,
+                },
+            ) => {
+                for provider in decode_peers(&new_providers) {
+                    if !providers
+                        .iter()
+                        .any(|known| known.peer_id == provider.peer_id)
+                    {
+                        providers.push(provider);
+                    }
+                }
+                closer
+            }
+            _ => {
+                debug!("peer {:?} sent unexpected response", peer_id);
+                query.peers.on_failure(peer_id);
+                return;
+            }
+        };
+
+        // The first record found finishes the query
+        if let Some(record) = found {
+            if let Some(Query {
+                kind: QueryKind::GetValue { sender, .. },
+                ..
+            }) = self.queries.remove(&query_id)
+            {
+                let _ = sender.send(Some(record));
+            }
+            return;
+        }
+
+        if query.peers.on_success(peer_id) {
+            let local_peer_id = &self.local_peer_id;
+            query.peers.add_peers(
+                decode_peers(&closer)
+                    .into_iter()
+                    .filter(|peer| &peer.peer_id != local_peer_id)
+                    .collect(),
+            );
+        }
+    }
+
+    /// Requests to the disconnected peer failed
+    fn fail_requests(&mut self, peer_id: &PeerId) {
+        let failed = self
+            .inflight
+            .iter()
+            .filter(|(_, inflight)| &inflight.peer_id == peer_id)
+            .map(|(id, inflight)| (*id, inflight.query_id))
+            .collect::<Vec<_>>();
+        for (id, query_id) in failed {
+            self.inflight.remove(&id);
+            if let Some(query) = query_id.and_then(|query_id| self.queries.get_mut(&query_id)) {
+                query.peers.on_failure(peer_id);
+            }
+        }
+    }
+
+    fn send_message(&mut self, session_id: SessionId, message: &KadMessage) {
+        trace!(
+            "send kad message to session [{}]: {:?}",
+            session_id,
+            message
+        );
+        let _ = self
+            .control
+            .send_message(session_id, self.proto_id, message.encode());
+    }
+
+    fn send_request(&mut self, peer: KadPeer, query_id: Option<u64>, request: KadRequest) {
+        let id = self.next_request_id;
+        self.next_request_id += 1;
+        let now = Instant::now();
+        self.inflight.insert(
+            id,
+            Inflight {
+                peer_id: peer.peer_id.clone(),
+                query_id,
+                sent_at: now,
+            },
+        );
+
+        if let Some(session_id) = self.peer_sessions.get(&peer.peer_id).cloned() {
+            self.send_message(session_id, &KadMessage::Request { id, request });
+            return;
+        }
+
+        let pending = self.pending_sends.entry(peer.peer_id.clone()).or_default();
+        pending.push((id, request, now));
+        if pending.len() == 1 {
+            let mut addrs = peer.addrs;
+            if let Some(entry) = self.table.get(&peer.peer_id) {
+                addrs.extend(entry.addrs.iter().cloned());
+            }
+            let addrs = addrs
+                .into_iter()
+                .map(|address| with_peer_id(address, &peer.peer_id))
+                .collect::<Vec<_>>();
+            if !addrs.is_empty() {
+                let _ = self.control.dial_any(addrs);
+            }
+        }
+    }
+
+    fn handle_tick(&mut self, now: Instant) {
+        let timeout = self.config.request_timeout;
+        let timeout_peers = self
+            .inflight
+            .values()
+            .filter(|inflight| now >= inflight.sent_at + timeout)
+            .map(|inflight| inflight.peer_id.clone())
+            .collect::<FnvHashSet<_>>();
+        self.inflight
+            .retain(|_, inflight| now < inflight.sent_at + timeout);
+        for requests in self.pending_sends.values_mut() {
+            requests.retain(|(_, _, sent_at)| now < *sent_at + timeout);
+        }
+        self.pending_sends
+            .retain(|_, requests| !requests.is_empty());
+
+        // Unresponsive peers leave the routing table
+        for peer_id in timeout_peers {
+            if !self.peer_sessions.contains_key(&peer_id) {
+                self.table.remove(&peer_id);
+            }
+        }
+
+        self.store.remove_expired(now);
+    }
+
+    fn handle_republish(&mut self) {
+        debug!(
+            "republish {} records and {} provided keys, {} peers in routing table",
+            self.published.len(),
+            self.provided.len(),
+            self.table.len()
+        );
+        let published = self
+            .published
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect::<Vec<_>>();
+        for (key, value) in published {
+            self.put_value(key, value, None);
+        }
+        let provided = self.provided.iter().cloned().collect::<Vec<_>>();
+        for key in provided {
+            self.add_provider(key, None);
+        }
+    }
+
+    fn progress_queries(&mut self, now: Instant) {
+        let mut contacts = Vec::new();
+        let mut finished = Vec::new();
+        for (query_id, query) in self.queries.iter_mut() {
+            if now >= query.started + self.config.query_timeout {
+                finished.push(*query_id);
+                continue;
+            }
+            loop {
+                match query.peers.next(now) {
+                    QueryStep::Contact(peer) => contacts.push((*query_id, peer, query.request())),
+                    QueryStep::Waiting => break,
+                    QueryStep::Finished => {
+                        finished.push(*query_id);
+                        break;
+                    }
+                }
+            }
+        }
+
+        for (query_id, peer, request) in contacts {
+            self.send_request(peer, Some(query_id), request);
+        }
+        for query_id in finished {
+            if let Some(query) = self.queries.remove(&query_id) {
+                self.finish_query(query);
+            }
+        }
+    }
+
+    fn finish_query(&mut self, query: Query) {
+        let now = Instant::now();
+        let peers = query.peers.into_result();
+        match query.kind {
+            QueryKind::FindNode { sender } => {
+                let _ = sender.send(peers);
+            }
+            QueryKind::GetValue { sender, .. } => {
+                let _ = sender.send(None);
+            }
+            QueryKind::GetProviders {
+                providers, sender, ..
+            } => {
+                let _ = sender.send(providers);
+            }
+            QueryKind::PutValue { record, sender } => {
+                let count = peers.len();
+                for peer in peers {
+                    let record = WireRecord::encode(&record, now);
+                    self.send_request(peer, None, KadRequest::PutValue { record });
+                }
+                if let Some(sender) = sender {
+                    let _ = sender.send(count);
+                }
+            }
+            QueryKind::AddProvider { key, sender } => {
+                let count = peers.len();
+                let addrs = encode_addrs(&self.local_addrs);
+                for peer in peers {
+                    self.send_request(
+                        peer,
+                        None,
+                        KadRequest::AddProvider {
+                            key: key.clone(),
+                            addrs: addrs.clone(),
+                        },
+                    );
+                }
+                if let Some(sender) = sender {
+                    let _ = sender.send(count);
+                }
+            }
+        }
+    }
+}
+
+fn with_peer_id(address: Multiaddr, peer_id: &PeerId) -> Multiaddr {
+    if extract_peer_id(&address).is_some() {
+        address
+    } else {
+        let mut address = address;
+        address.append(Protocol::P2p(
+            Multihash::from_bytes(peer_id.as_bytes().to_vec()).expect("Invalid peer id"),
+        ));
+        address
+    }
+}
+
+impl Future for Kademlia {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            match self.input_receiver.poll() {
+                Ok(Async::Ready(Some(input))) => self.handle_input(input),
+                // The protocol handler is dropped with the service
+                Ok(Async::Ready(None)) | Err(_) => return Ok(Async::Ready(())),
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        // Keep serving remote peers after all the handles are dropped
+        while !self.commands_closed {
+            match self.command_receiver.poll() {
+                Ok(Async::Ready(Some(command))) => self.handle_command(command),
+                Ok(Async::Ready(None)) | Err(_) => self.commands_closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+
+        while let Ok(Async::Ready(Some(now))) = self.tick.poll() {
+            self.handle_tick(now);
+        }
+        while let Ok(Async::Ready(Some(_))) = self.republish.poll() {
+            self.handle_republish();
+        }
+
+        self.progress_queries(Instant::now());
+        Ok(Async::NotReady)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Command, Input, Kademlia};
+    use crate::{
+        kbucket::Key,
+        protocol::{decode_peers, KadMessage, KadRequest, KadResponse, WireRecord},
+        record::Record,
+        KadConfig,
+    };
+    use futures::sync::{mpsc::channel, oneshot};
+    use p2p::{builder::ServiceBuilder, service::Service, PeerId, SecioKeyPair};
+    use std::time::{Duration, Instant};
+    use tokio::codec::LengthDelimitedCodec;
+
+    fn random_peer_id() -> PeerId {
+        SecioKeyPair::secp256k1_generated().to_peer_id()
+    }
+
+    // The messages to the dropped service are discarded
+    fn kademlia() -> Kademlia {
+        let mut service: Service<(), LengthDelimitedCodec> = ServiceBuilder::default().build(());
+        let (_, input_receiver) = channel(1);
+        let (_, command_receiver) = channel(1);
+        Kademlia::new(
+            1,
+            random_peer_id(),
+            KadConfig::default(),
+            service.control().clone(),
+            input_receiver,
+            command_receiver,
+        )
+    }
+
+    fn add_peer(kad: &mut Kademlia) -> PeerId {
+        let peer_id = random_peer_id();
+        kad.handle_command(Command::AddAddress {
+            peer_id: peer_id.clone(),
+            address: "/ip4/127.0.0.1/tcp/1337".parse().unwrap(),
+        });
+        peer_id
+    }
+
+    fn record(publisher: PeerId) -> Record {
+        Record {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            publisher: Some(publisher),
+            expires: Instant::now() + Duration::from_secs(100),
+        }
+    }
+
+    #[test]
+    fn find_node_without_requester() {
+        let mut kad = kademlia();
+        let first = add_peer(&mut kad);
+        let second = add_peer(&mut kad);
+        let requester = add_peer(&mut kad);
+
+        let key = Key::from_peer_id(&first).as_bytes().to_vec();
+        match kad.handle_request(&requester, KadRequest::FindNode { key }) {
+            Some(KadResponse::FindNode { closer }) => {
+                let peers = decode_peers(&closer)
+                    .into_iter()
+                    .map(|peer| peer.peer_id)
+                    .collect::<Vec<_>>();
+                assert_eq!(peers.len(), 2);
+                assert!(peers.contains(&first));
+                assert!(peers.contains(&second));
+            }
+            _ => panic!("unexpected response"),
+        }
+
+        // Invalid key
+        let request = KadRequest::FindNode { key: vec![0; 3] };
+        assert!(kad.handle_request(&requester, request).is_none());
+    }
+
+    #[test]
+    fn put_and_find_value() {
+        let mut kad = kademlia();
+        let peer_id = add_peer(&mut kad);
+        let record = record(peer_id.clone());
+
+        let request = KadRequest::PutValue {
+            record: WireRecord::encode(&record, Instant::now()),
+        };
+        match kad.handle_request(&peer_id, request) {
+            Some(KadResponse::PutValue) => (),
+            _ => panic!("unexpected response"),
+        }
+
+        let request = KadRequest::FindValue {
+            key: record.key.clone(),
+        };
+        match kad.handle_request(&peer_id, request) {
+            Some(KadResponse::FindValue {
+                record: Some(found),
+                ..
+            }) => {
+                let found = found
+                    .decode(Instant::now(), Duration::from_secs(100))
+                    .unwrap();
+                assert_eq!(found.value, record.value);
+                assert_eq!(found.publisher, record.publisher);
+            }
+            _ => panic!("unexpected response"),
+        }
+    }
+
+    #[test]
+    fn get_value_finishes_on_record() {
+        let mut kad = kademlia();
+        let peer_id = add_peer(&mut kad);
+        let other = add_peer(&mut kad);
+        let record = record(peer_id.clone());
+
+        let (sender, mut receiver) = oneshot::channel();
+        kad.handle_command(Command::GetValue {
+            key: record.key.clone(),
+            sender,
+        });
+        // Both peers are contacted, the requests wait for the dials
+        kad.progress_queries(Instant::now());
+        assert!(kad.pending_sends.contains_key(&peer_id));
+        assert!(kad.pending_sends.contains_key(&other));
+        let request_id = kad
+            .inflight
+            .iter()
+            .find(|(_, inflight)| inflight.peer_id == peer_id)
+            .map(|(id, _)| *id)
+            .unwrap();
+
+        kad.handle_input(Input::Connected {
+            session_id: 1,
+            peer_id,
+            address: None,
+        });
+        kad.handle_input(Input::Connected {
+            session_id: 2,
+            peer_id: other,
+            address: None,
+        });
+        assert!(kad.pending_sends.is_empty());
+
+        let response = KadMessage::Response {
+            id: request_id,
+            response: KadResponse::FindValue {
+                record: Some(WireRecord::encode(&record, Instant::now())),
+                closer: Vec::new(),
+            },
+        };
+        // The response to another peer's request is ignored
+        kad.handle_input(Input::Received {
+            session_id: 2,
+            data: response.encode(),
+        });
+        assert_eq!(receiver.try_recv().map(|found| found.is_some()), Ok(false));
+
+        kad.handle_input(Input::Received {
+            session_id: 1,
+            data: response.encode(),
+        });
+        let found = receiver.try_recv().unwrap().unwrap().unwrap();
+        assert_eq!(found.value, record.value);
+        assert!(kad.queries.is_empty());
+    }
+}
@@ -0,0 +1,320 @@
+use p2p::{multiaddr::Multiaddr, PeerId};
+use sha2::{Digest, Sha256};
+use std::fmt;
+
+/// Max entries of a bucket, and the number of closest peers a query looks for
+pub const K_VALUE: usize = 20;
+/// Max addresses of an entry
+pub(crate) const MAX_ADDRS: usize = 8;
+
+/// A point of the 256 bits key space
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Key([u8; 32]);
+
+impl Key {
+    /// Peer key, the digest of peer id
+    pub fn from_peer_id(peer_id: &PeerId) -> Key {
+        let digest = peer_id.digest();
+        if digest.len() == 32 {
+            let mut key = [0; 32];
+            key.copy_from_slice(digest);
+            Key(key)
+        } else {
+            Key::hash(digest)
+        }
+    }
+
+    /// Record key, the sha256 of bytes
+    pub fn from_record_key(key: &[u8]) -> Key {
+        Key::hash(key)
+    }
+
+    pub(crate) fn from_bytes(bytes: &[u8]) -> Option<Key> {
+        if bytes.len() == 32 {
+            let mut key = [0; 32];
+            key.copy_from_slice(bytes);
+            Some(Key(key))
+        } else {
+            None
+        }
+    }
+
+    fn hash(bytes: &[u8]) -> Key {
+        let mut key = [0; 32];
+        key.copy_from_slice(&Sha256::digest(bytes));
+        Key(key)
+    }
+
+    /// Raw bytes of key
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    /// XOR distance between keys
+    pub fn distance(&self, other: &Key) -> Distance {
+        let mut distance = [0; 32];
+        for (i, byte) in distance.iter_mut().enumerate() {
+            *byte = self.0[i] ^ other.0[i];
+        }
+        Distance(distance)
+    }
+}
+
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Key(")?;
+        for byte in &self.0[..4] {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+/// XOR distance, compared as a big endian number
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct Distance([u8; 32]);
+
+impl Distance {
+    /// Index of the bucket for the distance, the position of the highest set bit.
+    /// None means the same key.
+    fn bucket_index(&self) -> Option<usize> {
+        let mut zeros = 0;
+        for byte in &self.0 {
+            if *byte == 0 {
+                zeros += 8;
+            } else {
+                zeros += byte.leading_zeros() as usize;
+                return Some(255 - zeros);
+            }
+        }
+        None
+    }
+}
+
+/// A peer in the routing table
+#[derive(Clone, Debug)]
+pub struct Entry {
+    /// Peer id
+    pub peer_id: PeerId,
+    /// Dialable addresses of the peer
+    pub addrs: Vec<Multiaddr>,
+    /// Whether the peer is connected now
+    pub connected: bool,
+}
+
+#[derive(Default)]
+struct KBucket {
+    /// Least recently seen first
+    entries: Vec<Entry>,
+    /// Replace a disconnected entry when the bucket is full
+    pending: Option<Entry>,
+}
+
+impl KBucket {
+    fn position(&self, peer_id: &PeerId) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| &entry.peer_id == peer_id)
+    }
+}
+
+/// Routing table of 256 buckets, bucket `i` contains peers at distance `[2^i, 2^(i+1))`
+pub(crate) struct KBucketsTable {
+    local_key: Key,
+    buckets: Vec<KBucket>,
+}
+
+impl KBucketsTable {
+    pub fn new(local_key: Key) -> Self {
+        KBucketsTable {
+            local_key,
+            buckets: (0..256).map(|_| KBucket::default()).collect(),
+        }
+    }
+
+    fn bucket_mut(&mut self, peer_id: &PeerId) -> Option<&mut KBucket> {
+        let index = self
+            .local_key
+            .distance(&Key::from_peer_id(peer_id))
+            .bucket_index()?;
+        Some(&mut self.buckets[index])
+    }
+
+    /// Insert or update a peer, return false if the bucket is full and the peer becomes pending
+    pub fn update(&mut self, peer_id: PeerId, addrs: Vec<Multiaddr>, connected: bool) -> bool {
+        let bucket = match self.bucket_mut(&peer_id) {
+            Some(bucket) => bucket,
+            None => return false,
+        };
+
+        if let Some(index) = bucket.position(&peer_id) {
+            let mut entry = bucket.entries.remove(index);
+            for address in addrs {
+                if !entry.addrs.contains(&address) {
+                    entry.addrs.insert(0, address);
+                }
+            }
+            entry.addrs.truncate(MAX_ADDRS);
+            entry.connected = connected;
+            // Seen just now, move to the tail
+            bucket.entries.push(entry);
+            return true;
+        }
+
+        let mut entry = Entry {
+            peer_id,
+            addrs,
+            connected,
+        };
+        entry.addrs.truncate(MAX_ADDRS);
+
+        if bucket.entries.len() < K_VALUE {
+            bucket.entries.push(entry);
+            true
+        } else if let Some(index) = bucket.entries.iter().position(|entry| !entry.connected) {
+            // Long-lived peers are preferred, only disconnected ones are replaced
+            bucket.entries.remove(index);
+            bucket.entries.push(entry);
+            true
+        } else {
+            bucket.pending = Some(entry);
+            false
+        }
+    }
+
+    /// Add an address of the peer already in the table
+    pub fn add_address(&mut self, peer_id: &PeerId, address: Multiaddr) -> bool {
+        match self.get_mut(peer_id) {
+            Some(entry) => {
+                if !entry.addrs.contains(&address) {
+                    entry.addrs.insert(0, address);
+                    entry.addrs.truncate(MAX_ADDRS);
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn set_disconnected(&mut self, peer_id: &PeerId) {
+        if let Some(entry) = self.get_mut(peer_id) {
+            entry.connected = false;
+        }
+    }
+
+    /// Remove an unresponsive peer, the pending one takes its place
+    pub fn remove(&mut self, peer_id: &PeerId) -> Option<Entry> {
+        let bucket = self.bucket_mut(peer_id)?;
+        let index = bucket.position(peer_id)?;
+        let entry = bucket.entries.remove(index);
+        if let Some(pending) = bucket.pending.take() {
+            bucket.entries.push(pending);
+        }
+        Some(entry)
+    }
+
+    pub fn get(&self, peer_id: &PeerId) -> Option<&Entry> {
+        let index = self
+            .local_key
+            .distance(&Key::from_peer_id(peer_id))
+            .bucket_index()?;
+        self.buckets[index]
+            .entries
+            .iter()
+            .find(|entry| &entry.peer_id == peer_id)
+    }
+
+    fn get_mut(&mut self, peer_id: &PeerId) -> Option<&mut Entry> {
+        let bucket = self.bucket_mut(peer_id)?;
+        bucket
+            .entries
+            .iter_mut()
+            .find(|entry| &entry.peer_id == peer_id)
+    }
+
+    /// The `n` closest peers to the target
+    pub fn closest(&self, target: &Key, n: usize) -> Vec<Entry> {
+        let mut entries = self
+            .buckets
+            .iter()
+            .flat_map(|bucket| bucket.entries.iter())
+            .collect::<Vec<_>>();
+        entries.sort_by_key(|entry| target.distance(&Key::from_peer_id(&entry.peer_id)));
+        entries.into_iter().take(n).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.iter().map(|bucket| bucket.entries.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KBucketsTable, Key, K_VALUE};
+    use p2p::{PeerId, SecioKeyPair};
+
+    fn random_peer_id() -> PeerId {
+        SecioKeyPair::secp256k1_generated().to_peer_id()
+    }
+
+    #[test]
+    fn distance_bucket_index() {
+        let zero = Key([0; 32]);
+        let mut one = [0; 32];
+        one[31] = 1;
+        let mut high = [0; 32];
+        high[0] = 0x80;
+
+        assert_eq!(zero.distance(&zero).bucket_index(), None);
+        assert_eq!(zero.distance(&Key(one)).bucket_index(), Some(0));
+        assert_eq!(zero.distance(&Key(high)).bucket_index(), Some(255));
+    }
+
+    #[test]
+    fn closest_sorted_by_distance() {
+        let mut table = KBucketsTable::new(Key::from_peer_id(&random_peer_id()));
+        for _ in 0..50 {
+            table.update(random_peer_id(), Vec::new(), true);
+        }
+        let target = Key::from_peer_id(&random_peer_id());
+        let closest = table.closest(&target, 10);
+        assert_eq!(closest.len(), 10);
+        for pair in closest.windows(2) {
+            assert!(
+                target.distance(&Key::from_peer_id(&pair[0].peer_id))
+                    < target.distance(&Key::from_peer_id(&pair[1].peer_id))
+            );
+        }
+    }
+
+    #[test]
+    fn full_bucket_keeps_connected_peers() {
+        let local = Key([0; 32]);
+        let mut table = KBucketsTable::new(local);
+        // Half of the peers fall in the farthest bucket
+        let mut peers = Vec::new();
+        while peers.len() <= K_VALUE {
+            let peer_id = random_peer_id();
+            if Key::from_peer_id(&peer_id).0[0] >= 0x80 {
+                peers.push(peer_id);
+            }
+        }
+        let last = peers.pop().unwrap();
+        for peer_id in &peers {
+            assert!(table.update(peer_id.clone(), Vec::new(), true));
+        }
+
+        assert!(!table.update(last.clone(), Vec::new(), true));
+        assert!(table.get(&last).is_none());
+
+        // The pending one takes the place of removed one
+        table.remove(&peers[0]);
+        assert!(table.get(&last).is_some());
+
+        // Disconnected peer is replaced
+        table.set_disconnected(&peers[1]);
+        assert!(table.update(peers[0].clone(), Vec::new(), true));
+        assert!(table.get(&peers[1]).is_none());
+        assert_eq!(table.len(), K_VALUE);
+    }
+}
@@ -0,0 +1,333 @@
+mod kademlia;
+mod kbucket;
+mod protocol;
+mod query;
+mod record;
+
+pub use crate::{
+    kbucket::{Key, K_VALUE},
+    protocol::KadPeer,
+    record::Record,
+};
+
+use crate::kademlia::{Command, Input, Kademlia};
+use futures::{
+    sync::{
+        mpsc::{channel, Receiver, Sender},
+        oneshot,
+    },
+    Future, IntoFuture,
+};
+use log::debug;
+use p2p::{
+    context::{ServiceContext, SessionContext},
+    multiaddr::{Multiaddr, ToMultiaddr},
+    traits::{ProtocolMeta, ServiceProtocol},
+    utils::multiaddr_to_socketaddr,
+    PeerId, ProtocolId, SessionType,
+};
+use std::{error, fmt, sync::Mutex, time::Duration};
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+/// Kademlia configuration
+#[derive(Clone, Debug)]
+pub struct KadConfig {
+    /// Max requests in flight of a query
+    pub parallelism: usize,
+    /// A peer fails if it doesn't respond in time
+    pub request_timeout: Duration,
+    /// A query finishes with the result so far when timeout
+    pub query_timeout: Duration,
+    /// Max time to live of records
+    pub record_ttl: Duration,
+    /// Time to live of provider records
+    pub provider_ttl: Duration,
+    /// Interval of republishing local records and provider records
+    pub republish_interval: Duration,
+    /// Max records stored, also max keys of provider records
+    pub max_records: usize,
+    /// Max providers of a key
+    pub max_providers_per_key: usize,
+}
+
+impl Default for KadConfig {
+    fn default() -> Self {
+        KadConfig {
+            parallelism: 3,
+            request_timeout: Duration::from_secs(10),
+            query_timeout: Duration::from_secs(60),
+            record_ttl: Duration::from_secs(36 * 60 * 60),
+            provider_ttl: Duration::from_secs(24 * 60 * 60),
+            republish_interval: Duration::from_secs(60 * 60),
+            max_records: 1024,
+            max_providers_per_key: K_VALUE,
+        }
+    }
+}
+
+/// Error of query API
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KadError {
+    /// Too many commands waiting
+    Busy,
+    /// The service is stopped
+    Stopped,
+}
+
+impl fmt::Display for KadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KadError::Busy => write!(f, "Kademlia is busy"),
+            KadError::Stopped => write!(f, "Kademlia is stopped"),
+        }
+    }
+}
+
+impl error::Error for KadError {}
+
+/// Kademlia DHT protocol, peers need secio to be identified
+pub struct KadProtocol {
+    id: ProtocolId,
+    local_peer_id: PeerId,
+    config: KadConfig,
+    command_sender: Sender<Command>,
+    command_receiver: Mutex<Option<Receiver<Command>>>,
+}
+
+impl KadProtocol {
+    pub fn new(id: ProtocolId, local_peer_id: PeerId, config: KadConfig) -> Self {
+        let (command_sender, command_receiver) = channel(256);
+        KadProtocol {
+            id,
+            local_peer_id,
+            config,
+            command_sender,
+            command_receiver: Mutex::new(Some(command_receiver)),
+        }
+    }
+
+    /// Query API, works after the service started
+    pub fn handle(&self) -> KadHandle {
+        KadHandle {
+            local_key: Key::from_peer_id(&self.local_peer_id),
+            command_sender: self.command_sender.clone(),
+        }
+    }
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for KadProtocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        // Only one handle serves the commands
+        let command_receiver = self.command_receiver.lock().unwrap().take()?;
+        let handle = Box::new(KadHandler {
+            proto_id: self.id,
+            local_peer_id: self.local_peer_id.clone(),
+            config: self.config.clone(),
+            command_receiver: Some(command_receiver),
+            input_sender: None,
+            local_addrs: Vec::new(),
+        });
+        Some(handle)
+    }
+}
+
+struct KadHandler {
+    proto_id: ProtocolId,
+    local_peer_id: PeerId,
+    config: KadConfig,
+    command_receiver: Option<Receiver<Command>>,
+    input_sender: Option<Sender<Input>>,
+    local_addrs: Vec<Multiaddr>,
+}
+
+impl KadHandler {
+    fn send_input(&mut self, input: Input) {
+        if let Some(ref mut sender) = self.input_sender {
+            if let Err(err) = sender.try_send(input) {
+                debug!("send kad input failed: {:?}", err.is_full());
+            }
+        }
+    }
+
+    fn update_local_addrs(&mut self, control: &ServiceContext) {
+        let addrs = control
+            .external_addrs()
+            .iter()
+            .chain(control.listens())
+            .cloned()
+            .collect::<Vec<_>>();
+        if addrs != self.local_addrs {
+            self.local_addrs = addrs.clone();
+            self.send_input(Input::LocalAddrs(addrs));
+        }
+    }
+}
+
+impl ServiceProtocol for KadHandler {
+    fn init(&mut self, control: &mut ServiceContext) {
+        if let Some(command_receiver) = self.command_receiver.take() {
+            let (input_sender, input_receiver) = channel(256);
+            self.input_sender = Some(input_sender);
+            let kademlia = Kademlia::new(
+                self.proto_id,
+                self.local_peer_id.clone(),
+                self.config.clone(),
+                control.control().clone(),
+                input_receiver,
+                command_receiver,
+            );
+            let _ = control.future_task(kademlia);
+            self.update_local_addrs(control);
+        }
+    }
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        debug!(
+            "proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
+            self.proto_id, session.id, session.address, session.ty, version
+        );
+        self.update_local_addrs(control);
+
+        let peer_id = match session.remote_pubkey {
+            Some(ref key) => key.peer_id(),
+            None => return,
+        };
+        let address = if session.ty == SessionType::Client {
+            multiaddr_to_socketaddr(&session.address)
+                .ok()
+                .and_then(|address| address.to_multiaddr().ok())
+        } else {
+            None
+        };
+        self.send_input(Input::Connected {
+            session_id: session.id,
+            peer_id,
+            address,
+        });
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        debug!(
+            "proto id [{}] close on session [{}]",
+            self.proto_id, session.id
+        );
+        self.send_input(Input::Disconnected {
+            session_id: session.id,
+        });
+    }
+
+    fn received(&mut self, _control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        self.send_input(Input::Received {
+            session_id: session.id,
+            data,
+        });
+    }
+}
+
+/// Query API of Kademlia, the futures resolve when the queries finish
+#[derive(Clone)]
+pub struct KadHandle {
+    local_key: Key,
+    command_sender: Sender<Command>,
+}
+
+impl KadHandle {
+    fn send(&mut self, command: Command) -> Result<(), KadError> {
+        self.command_sender.try_send(command).map_err(|err| {
+            if err.is_full() {
+                KadError::Busy
+            } else {
+                KadError::Stopped
+            }
+        })
+    }
+
+    fn query<T>(
+        &mut self,
+        command: impl FnOnce(oneshot::Sender<T>) -> Command,
+    ) -> impl Future<Item = T, Error = KadError> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(command(sender))
+            .into_future()
+            .and_then(|_| receiver.map_err(|_| KadError::Stopped))
+    }
+
+    /// Add a dialable address of the peer, such as the listen addresses from identify protocol
+    pub fn add_address(&mut self, peer_id: PeerId, address: Multiaddr) -> Result<(), KadError> {
+        self.send(Command::AddAddress { peer_id, address })
+    }
+
+    /// Look up the closest peers to local node, fills the routing table.
+    ///
+    /// Add some addresses of known peers first.
+    pub fn bootstrap(&mut self) -> impl Future<Item = Vec<KadPeer>, Error = KadError> {
+        let key = self.local_key;
+        self.find_node(key)
+    }
+
+    /// The closest peers to the key
+    pub fn find_node(&mut self, key: Key) -> impl Future<Item = Vec<KadPeer>, Error = KadError> {
+        self.query(|sender| Command::FindNode { key, sender })
+    }
+
+    /// Find the addresses of a peer
+    pub fn find_peer(
+        &mut self,
+        peer_id: PeerId,
+    ) -> impl Future<Item = Option<KadPeer>, Error = KadError> {
+        self.find_node(Key::from_peer_id(&peer_id))
+            .map(move |peers| peers.into_iter().find(|peer| peer.peer_id == peer_id))
+    }
+
+    /// Get the record of the key, local store first
+    pub fn get_value(
+        &mut self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = Option<Record>, Error = KadError> {
+        self.query(|sender| Command::GetValue { key, sender })
+    }
+
+    /// Store the record to the closest peers, resolves to the number of peers.
+    ///
+    /// The record is republished until `remove_record`.
+    pub fn put_value(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> impl Future<Item = usize, Error = KadError> {
+        self.query(|sender| Command::PutValue { key, value, sender })
+    }
+
+    /// Stop republishing the record, it expires on other peers
+    pub fn remove_record(&mut self, key: Vec<u8>) -> Result<(), KadError> {
+        self.send(Command::RemoveRecord { key })
+    }
+
+    /// Get the providers of the key
+    pub fn get_providers(
+        &mut self,
+        key: Vec<u8>,
+    ) -> impl Future<Item = Vec<KadPeer>, Error = KadError> {
+        self.query(|sender| Command::GetProviders { key, sender })
+    }
+
+    /// Announce local node as a provider of the key to the closest peers,
+    /// resolves to the number of peers.
+    ///
+    /// The announcement is repeated until `stop_providing`.
+    pub fn start_providing(&mut self, key: Vec<u8>) -> impl Future<Item = usize, Error = KadError> {
+        self.query(|sender| Command::StartProviding { key, sender })
+    }
+
+    /// Stop announcing the key, the provider records expire on other peers
+    pub fn stop_providing(&mut self, key: Vec<u8>) -> Result<(), KadError> {
+        self.send(Command::StopProviding { key })
+    }
+}
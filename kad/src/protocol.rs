@@ -0,0 +1,233 @@
+use p2p::{multiaddr::Multiaddr, PeerId};
+use serde_derive::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::{
+    kbucket::{K_VALUE, MAX_ADDRS},
+    record::{ProviderRecord, Record},
+};
+
+/// Max bytes of record key
+pub(crate) const MAX_KEY_LEN: usize = 256;
+/// Max bytes of record value
+pub(crate) const MAX_VALUE_LEN: usize = 64 * 1024;
+
+/// A peer and its addresses
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KadPeer {
+    /// Peer id
+    pub peer_id: PeerId,
+    /// Dialable addresses of the peer
+    pub addrs: Vec<Multiaddr>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum KadMessage {
+    Request { id: u64, request: KadRequest },
+    Response { id: u64, response: KadResponse },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) enum KadRequest {
+    /// Closest peers to the key
+    FindNode {
+        key: Vec<u8>,
+    },
+    /// The record, or closest peers if not found
+    FindValue {
+        key: Vec<u8>,
+    },
+    PutValue {
+        record: WireRecord,
+    },
+    /// Providers of the key and closest peers
+    GetProviders {
+        key: Vec<u8>,
+    },
+    /// The sender provides the key
+    AddProvider {
+        key: Vec<u8>,
+        addrs: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum KadResponse {
+    FindNode {
+        closer: Vec<WirePeer>,
+    },
+    FindValue {
+        record: Option<WireRecord>,
+        closer: Vec<WirePeer>,
+    },
+    PutValue,
+    GetProviders {
+        providers: Vec<WirePeer>,
+        closer: Vec<WirePeer>,
+    },
+    AddProvider,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct WirePeer {
+    id: Vec<u8>,
+    addrs: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct WireRecord {
+    key: Vec<u8>,
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    /// Time to live in seconds
+    ttl: u64,
+}
+
+impl KadMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serialize kad message")
+    }
+
+    /// None means the message is invalid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+pub(crate) fn encode_addrs(addrs: &[Multiaddr]) -> Vec<String> {
+    addrs
+        .iter()
+        .take(MAX_ADDRS)
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// Invalid addresses are ignored
+pub(crate) fn decode_addrs(addrs: &[String]) -> Vec<Multiaddr> {
+    addrs
+        .iter()
+        .take(MAX_ADDRS)
+        .filter_map(|address| address.parse().ok())
+        .collect()
+}
+
+impl WirePeer {
+    pub fn encode(peer: &KadPeer) -> Self {
+        WirePeer {
+            id: peer.peer_id.as_bytes().to_vec(),
+            addrs: encode_addrs(&peer.addrs),
+        }
+    }
+
+    pub fn decode(&self) -> Option<KadPeer> {
+        Some(KadPeer {
+            peer_id: PeerId::from_bytes(self.id.clone()).ok()?,
+            addrs: decode_addrs(&self.addrs),
+        })
+    }
+}
+
+/// Decode peers, too many or invalid peers are ignored
+pub(crate) fn decode_peers(peers: &[WirePeer]) -> Vec<KadPeer> {
+    peers
+        .iter()
+        .take(K_VALUE)
+        .filter_map(WirePeer::decode)
+        .collect()
+}
+
+impl WireRecord {
+    pub fn encode(record: &Record, now: Instant) -> Self {
+        WireRecord {
+            key: record.key.clone(),
+            value: record.value.clone(),
+            publisher: record
+                .publisher
+                .as_ref()
+                .map(|peer_id| peer_id.as_bytes().to_vec()),
+            ttl: record.ttl(now).as_secs(),
+        }
+    }
+
+    /// The ttl is capped by `max_ttl`
+    pub fn decode(&self, now: Instant, max_ttl: Duration) -> Option<Record> {
+        if self.key.len() > MAX_KEY_LEN || self.value.len() > MAX_VALUE_LEN {
+            return None;
+        }
+        let publisher = match self.publisher {
+            Some(ref bytes) => Some(PeerId::from_bytes(bytes.clone()).ok()?),
+            None => None,
+        };
+        Some(Record {
+            key: self.key.clone(),
+            value: self.value.clone(),
+            publisher,
+            expires: now + Duration::from_secs(self.ttl).min(max_ttl),
+        })
+    }
+}
+
+impl From<ProviderRecord> for KadPeer {
+    fn from(record: ProviderRecord) -> KadPeer {
+        KadPeer {
+            peer_id: record.provider,
+            addrs: record.addrs,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{KadMessage, KadPeer, KadRequest, KadResponse, WirePeer, WireRecord};
+    use crate::record::Record;
+    use p2p::SecioKeyPair;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn encode_and_decode() {
+        let now = Instant::now();
+        let peer = KadPeer {
+            peer_id: SecioKeyPair::secp256k1_generated().to_peer_id(),
+            addrs: vec!["/ip4/1.1.1.1/tcp/1337".parse().unwrap()],
+        };
+        let record = Record {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            publisher: Some(peer.peer_id.clone()),
+            expires: now + Duration::from_secs(100),
+        };
+        let message = KadMessage::Response {
+            id: 1,
+            response: KadResponse::FindValue {
+                record: Some(WireRecord::encode(&record, now)),
+                closer: vec![WirePeer::encode(&peer)],
+            },
+        };
+
+        match KadMessage::decode(&message.encode()) {
+            Some(KadMessage::Response {
+                id: 1,
+                response:
+                    KadResponse::FindValue {
+                        record: Some(wire),
+                        closer,
+                    },
+            }) => {
+                let decoded = wire.decode(now, Duration::from_secs(10)).unwrap();
+                assert_eq!(decoded.value, record.value);
+                assert_eq!(decoded.publisher, record.publisher);
+                // ttl is capped
+                assert_eq!(decoded.expires, now + Duration::from_secs(10));
+                assert_eq!(closer[0].decode(), Some(peer));
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+
+        let request = KadMessage::Request {
+            id: 2,
+            request: KadRequest::FindNode { key: vec![0; 32] },
+        };
+        assert!(KadMessage::decode(&request.encode()).is_some());
+        assert!(KadMessage::decode(b"invalid").is_none());
+    }
+}
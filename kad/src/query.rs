@@ -0,0 +1,242 @@
+use fnv::FnvHashSet;
+use p2p::PeerId;
+use std::time::{Duration, Instant};
+
+use crate::{
+    kbucket::{Distance, Key},
+    protocol::KadPeer,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum PeerState {
+    NotContacted,
+    Waiting(Instant),
+    Succeeded,
+    Failed,
+}
+
+struct QueryPeer {
+    distance: Distance,
+    peer: KadPeer,
+    state: PeerState,
+}
+
+/// What a query should do next
+#[derive(Debug, PartialEq)]
+pub(crate) enum QueryStep {
+    /// Send the request to the peer
+    Contact(KadPeer),
+    /// Wait for responses
+    Waiting,
+    /// The closest peers are all contacted
+    Finished,
+}
+
+/// Iterative lookup of the closest peers to a key
+///
+/// At most `parallelism` requests are in flight. The lookup finishes when the `num_results`
+/// closest peers known have all responded, or no peer is left to contact.
+pub(crate) struct ClosestPeers {
+    target: Key,
+    peers: Vec<QueryPeer>,
+    seen: FnvHashSet<PeerId>,
+    parallelism: usize,
+    num_results: usize,
+    timeout: Duration,
+}
+
+impl ClosestPeers {
+    pub fn new(
+        target: Key,
+        known: Vec<KadPeer>,
+        parallelism: usize,
+        num_results: usize,
+        timeout: Duration,
+    ) -> Self {
+        let mut closest = ClosestPeers {
+            target,
+            peers: Vec::new(),
+            seen: FnvHashSet::default(),
+            parallelism,
+            num_results,
+            timeout,
+        };
+        closest.add_peers(known);
+        closest
+    }
+
+    pub fn target(&self) -> &Key {
+        &self.target
+    }
+
+    /// Add peers learned from responses, the seen ones are ignored
+    pub fn add_peers(&mut self, peers: Vec<KadPeer>) {
+        for peer in peers {
+            if !self.seen.insert(peer.peer_id.clone()) {
+                continue;
+            }
+            let distance = self.target.distance(&Key::from_peer_id(&peer.peer_id));
+            let index = match self
+                .peers
+                .binary_search_by_key(&distance, |peer| peer.distance)
+            {
+                Ok(index) | Err(index) => index,
+            };
+            self.peers.insert(
+                index,
+                QueryPeer {
+                    distance,
+                    peer,
+                    state: PeerState::NotContacted,
+                },
+            );
+        }
+    }
+
+    /// Return false if the peer is not waited
+    pub fn on_success(&mut self, peer_id: &PeerId) -> bool {
+        self.set_state(peer_id, PeerState::Succeeded)
+    }
+
+    pub fn on_failure(&mut self, peer_id: &PeerId) -> bool {
+        self.set_state(peer_id, PeerState::Failed)
+    }
+
+    fn set_state(&mut self, peer_id: &PeerId, state: PeerState) -> bool {
+        match self
+            .peers
+            .iter_mut()
+            .find(|peer| &peer.peer.peer_id == peer_id && peer.state.is_waiting())
+        {
+            Some(peer) => {
+                peer.state = state;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn next(&mut self, now: Instant) -> QueryStep {
+        let timeout = self.timeout;
+        for peer in self.peers.iter_mut() {
+            if let PeerState::Waiting(started) = peer.state {
+                if now >= started + timeout {
+                    peer.state = PeerState::Failed;
+                }
+            }
+        }
+
+        let waiting = self
+            .peers
+            .iter()
+            .filter(|peer| peer.state.is_waiting())
+            .count();
+        let mut succeeded = 0;
+        for peer in self
+            .peers
+            .iter_mut()
+            .filter(|peer| peer.state != PeerState::Failed)
+        {
+            match peer.state {
+                PeerState::Succeeded => {
+                    succeeded += 1;
+                    if succeeded >= self.num_results {
+                        return QueryStep::Finished;
+                    }
+                }
+                PeerState::NotContacted if waiting < self.parallelism => {
+                    peer.state = PeerState::Waiting(now);
+                    return QueryStep::Contact(peer.peer.clone());
+                }
+                _ => (),
+            }
+        }
+
+        if waiting > 0 {
+            QueryStep::Waiting
+        } else {
+            QueryStep::Finished
+        }
+    }
+
+    /// The closest peers responded
+    pub fn into_result(self) -> Vec<KadPeer> {
+        let num_results = self.num_results;
+        self.peers
+            .into_iter()
+            .filter(|peer| peer.state == PeerState::Succeeded)
+            .take(num_results)
+            .map(|peer| peer.peer)
+            .collect()
+    }
+}
+
+impl PeerState {
+    fn is_waiting(self) -> bool {
+        match self {
+            PeerState::Waiting(_) => true,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ClosestPeers, QueryStep};
+    use crate::{kbucket::Key, protocol::KadPeer};
+    use p2p::SecioKeyPair;
+    use std::time::{Duration, Instant};
+
+    fn random_peer() -> KadPeer {
+        KadPeer {
+            peer_id: SecioKeyPair::secp256k1_generated().to_peer_id(),
+            addrs: Vec::new(),
+        }
+    }
+
+    fn contact(query: &mut ClosestPeers, now: Instant) -> KadPeer {
+        match query.next(now) {
+            QueryStep::Contact(peer) => peer,
+            step => panic!("unexpected step {:?}", step),
+        }
+    }
+
+    #[test]
+    fn lookup_until_closest_responded() {
+        let now = Instant::now();
+        let target = Key::from_peer_id(&random_peer().peer_id);
+        let mut query = ClosestPeers::new(
+            target,
+            vec![random_peer(), random_peer()],
+            1,
+            2,
+            Duration::from_secs(10),
+        );
+
+        let first = contact(&mut query, now);
+        // Only one request in flight
+        assert_eq!(query.next(now), QueryStep::Waiting);
+        assert!(query.on_success(&first.peer_id));
+
+        let second = contact(&mut query, now);
+        query.add_peers(vec![first.clone(), random_peer()]);
+        // Timeout
+        let third = contact(&mut query, now + Duration::from_secs(10));
+        assert_ne!(third.peer_id, second.peer_id);
+        assert!(!query.on_success(&second.peer_id));
+        assert!(query.on_success(&third.peer_id));
+
+        assert_eq!(query.next(now), QueryStep::Finished);
+        let result = query.into_result();
+        assert_eq!(result.len(), 2);
+        assert!(result.contains(&first) && result.contains(&third));
+    }
+
+    #[test]
+    fn finish_without_peers() {
+        let target = Key::from_peer_id(&random_peer().peer_id);
+        let mut query = ClosestPeers::new(target, Vec::new(), 3, 20, Duration::from_secs(10));
+        assert_eq!(query.next(Instant::now()), QueryStep::Finished);
+        assert!(query.into_result().is_empty());
+    }
+}
@@ -0,0 +1,174 @@
+use p2p::{multiaddr::Multiaddr, PeerId};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// A value stored in the DHT
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Record {
+    /// Key of record
+    pub key: Vec<u8>,
+    /// Value of record
+    pub value: Vec<u8>,
+    /// The peer that published the record
+    pub publisher: Option<PeerId>,
+    /// The record is dropped after it expires
+    pub expires: Instant,
+}
+
+impl Record {
+    /// Time to live from now
+    pub fn ttl(&self, now: Instant) -> Duration {
+        if self.expires > now {
+            self.expires - now
+        } else {
+            Duration::from_secs(0)
+        }
+    }
+
+    fn is_expired(&self, now: Instant) -> bool {
+        self.expires <= now
+    }
+}
+
+/// A peer provides the value of a key
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProviderRecord {
+    /// Key of provided value
+    pub key: Vec<u8>,
+    /// Provider peer id
+    pub provider: PeerId,
+    /// Addresses of provider
+    pub addrs: Vec<Multiaddr>,
+    /// The record is dropped after it expires
+    pub expires: Instant,
+}
+
+/// In memory records and provider records
+pub(crate) struct RecordStore {
+    records: HashMap<Vec<u8>, Record>,
+    providers: HashMap<Vec<u8>, Vec<ProviderRecord>>,
+    max_records: usize,
+    max_providers_per_key: usize,
+}
+
+impl RecordStore {
+    pub fn new(max_records: usize, max_providers_per_key: usize) -> Self {
+        RecordStore {
+            records: HashMap::new(),
+            providers: HashMap::new(),
+            max_records,
+            max_providers_per_key,
+        }
+    }
+
+    /// Return false if the store is full
+    pub fn put(&mut self, record: Record) -> bool {
+        if !self.records.contains_key(&record.key) && self.records.len() >= self.max_records {
+            return false;
+        }
+        self.records.insert(record.key.clone(), record);
+        true
+    }
+
+    pub fn get(&self, key: &[u8], now: Instant) -> Option<&Record> {
+        self.records
+            .get(key)
+            .filter(|record| !record.is_expired(now))
+    }
+
+    /// Return false if the providers of the key are full
+    pub fn add_provider(&mut self, record: ProviderRecord) -> bool {
+        let max_providers = self.max_providers_per_key;
+        if !self.providers.contains_key(&record.key) && self.providers.len() >= self.max_records {
+            return false;
+        }
+        let providers = self.providers.entry(record.key.clone()).or_default();
+        match providers
+            .iter_mut()
+            .find(|provider| provider.provider == record.provider)
+        {
+            Some(provider) => *provider = record,
+            None if providers.len() < max_providers => providers.push(record),
+            None => return false,
+        }
+        true
+    }
+
+    pub fn providers(&self, key: &[u8], now: Instant) -> Vec<ProviderRecord> {
+        self.providers
+            .get(key)
+            .map(|providers| {
+                providers
+                    .iter()
+                    .filter(|provider| provider.expires > now)
+                    .cloned()
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    pub fn remove_expired(&mut self, now: Instant) {
+        self.records.retain(|_, record| !record.is_expired(now));
+        for providers in self.providers.values_mut() {
+            providers.retain(|provider| provider.expires > now);
+        }
+        self.providers.retain(|_, providers| !providers.is_empty());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{ProviderRecord, Record, RecordStore};
+    use p2p::SecioKeyPair;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn expire_records() {
+        let now = Instant::now();
+        let mut store = RecordStore::new(1, 1);
+        let record = Record {
+            key: b"key".to_vec(),
+            value: b"value".to_vec(),
+            publisher: None,
+            expires: now + Duration::from_secs(10),
+        };
+        assert!(store.put(record.clone()));
+        assert!(!store.put(Record {
+            key: b"other".to_vec(),
+            ..record.clone()
+        }));
+        assert_eq!(store.get(b"key", now), Some(&record));
+        assert_eq!(store.get(b"key", now + Duration::from_secs(10)), None);
+
+        store.remove_expired(now + Duration::from_secs(10));
+        assert!(store.put(Record {
+            key: b"other".to_vec(),
+            ..record
+        }));
+    }
+
+    #[test]
+    fn limit_providers() {
+        let now = Instant::now();
+        let mut store = RecordStore::new(1, 1);
+        let provider = ProviderRecord {
+            key: b"key".to_vec(),
+            provider: SecioKeyPair::secp256k1_generated().to_peer_id(),
+            addrs: Vec::new(),
+            expires: now + Duration::from_secs(10),
+        };
+        assert!(store.add_provider(provider.clone()));
+        // Update the same provider
+        assert!(store.add_provider(provider.clone()));
+        assert!(!store.add_provider(ProviderRecord {
+            provider: SecioKeyPair::secp256k1_generated().to_peer_id(),
+            ..provider.clone()
+        }));
+        assert_eq!(store.providers(b"key", now), vec![provider]);
+        assert!(store
+            .providers(b"key", now + Duration::from_secs(10))
+            .is_empty());
+    }
+}
@@ -175,6 +175,7 @@ where
                 {
                     if let Some(ps) = self.connected_session_ids.get_mut(&session.id) {
                         ps.processing = false;
+                        let _ = control.set_session_rtt(session.id, ps.elapsed());
                         let _ = self
                             .event_sender
                             .try_send(Event::Pong(session.id, ps.elapsed()));
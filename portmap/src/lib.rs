@@ -0,0 +1,443 @@
+mod natpmp;
+mod upnp;
+
+use futures::{future, prelude::*, sync::mpsc};
+use generic_channel::Sender;
+use log::debug;
+use p2p::{
+    context::ServiceControl,
+    multiaddr::{Multiaddr, ToMultiaddr},
+    utils::{is_relayed, multiaddr_to_socketaddr},
+};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::{Duration, Instant},
+};
+use tokio::{prelude::FutureExt, timer::Delay};
+
+/// Port mapping configuration
+#[derive(Clone, Debug)]
+pub struct PortMapConfig {
+    /// Try NAT-PMP
+    pub natpmp: bool,
+    /// NAT-PMP gateway, the default gateways of system if not set
+    pub natpmp_gateway: Option<SocketAddr>,
+    /// Try UPnP IGD if NAT-PMP not available
+    pub upnp: bool,
+    /// Where to send the SSDP search of UPnP gateway
+    pub ssdp_addr: SocketAddr,
+    /// Lifetime of mappings, they are renewed at half time
+    pub lease: Duration,
+    /// Timeout of each request to gateway
+    pub timeout: Duration,
+    /// Retry after no gateway found or mapping failed
+    pub retry_interval: Duration,
+    /// Description of UPnP mappings
+    pub description: String,
+}
+
+impl Default for PortMapConfig {
+    fn default() -> Self {
+        PortMapConfig {
+            natpmp: true,
+            natpmp_gateway: None,
+            upnp: true,
+            ssdp_addr: SocketAddr::from(upnp::SSDP_ADDR),
+            lease: Duration::from_secs(60 * 60),
+            timeout: Duration::from_secs(3),
+            retry_interval: Duration::from_secs(5 * 60),
+            description: "p2p".to_owned(),
+        }
+    }
+}
+
+/// Port mapping events
+#[derive(Debug)]
+pub enum Event {
+    /// The listen port is mapped, the address is added to external addresses
+    Mapped {
+        /// Local port
+        port: u16,
+        /// Mapped address on gateway
+        address: Multiaddr,
+    },
+    /// The mapping is removed or lost, the address is removed from external addresses
+    Unmapped {
+        /// Local port
+        port: u16,
+        /// Mapped address on gateway
+        address: Multiaddr,
+    },
+    /// No gateway found or it refused all mappings, retry later
+    Failed {
+        /// The last error
+        error: io::Error,
+    },
+}
+
+type BoxFuture<T> = Box<dyn Future<Item = T, Error = io::Error> + Send>;
+
+/// Timeout as an io error
+pub(crate) fn with_timeout<F>(
+    task: F,
+    timeout: Duration,
+) -> impl Future<Item = F::Item, Error = io::Error>
+where
+    F: Future<Error = io::Error>,
+{
+    task.timeout(timeout).map_err(|err| {
+        if err.is_elapsed() {
+            io::ErrorKind::TimedOut.into()
+        } else {
+            err.into_inner()
+                .unwrap_or_else(|| io::Error::new(io::ErrorKind::Other, "port mapping timer error"))
+        }
+    })
+}
+
+#[derive(Clone, Debug)]
+enum Gateway {
+    NatPmp(SocketAddr),
+    Upnp(upnp::Device),
+}
+
+impl Gateway {
+    fn discover(config: &PortMapConfig) -> BoxFuture<Gateway> {
+        let timeout = config.timeout;
+        let mut natpmp_gateways = Vec::new();
+        if config.natpmp {
+            match config.natpmp_gateway {
+                Some(gateway) => natpmp_gateways.push(gateway),
+                None => natpmp_gateways.extend(natpmp::default_gateways()),
+            }
+        }
+        let natpmp: BoxFuture<Gateway> = if natpmp_gateways.is_empty() {
+            Box::new(future::err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no NAT-PMP gateway",
+            )))
+        } else {
+            // The first gateway answered
+            let requests = natpmp_gateways.into_iter().map(|gateway| {
+                natpmp::external_ip(gateway, timeout).map(move |_| Gateway::NatPmp(gateway))
+            });
+            Box::new(future::select_ok(requests).map(|(gateway, _)| gateway))
+        };
+
+        let upnp = config.upnp;
+        let ssdp_addr = config.ssdp_addr;
+        Box::new(natpmp.or_else(move |err| {
+            debug!("NAT-PMP not available: {}", err);
+            if upnp {
+                future::Either::A(upnp::search(ssdp_addr, timeout).map(Gateway::Upnp))
+            } else {
+                future::Either::B(future::err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "no gateway found",
+                )))
+            }
+        }))
+    }
+
+    fn address(&self) -> SocketAddr {
+        match self {
+            Gateway::NatPmp(addr) => *addr,
+            Gateway::Upnp(device) => device.addr,
+        }
+    }
+
+    fn external_ip(&self, timeout: Duration) -> BoxFuture<IpAddr> {
+        match self {
+            Gateway::NatPmp(addr) => Box::new(natpmp::external_ip(*addr, timeout).map(IpAddr::V4)),
+            Gateway::Upnp(device) => Box::new(device.external_ip(timeout)),
+        }
+    }
+
+    /// Map the tcp port, return the external port
+    fn map(&self, port: u16, config: &PortMapConfig) -> BoxFuture<u16> {
+        match self {
+            Gateway::NatPmp(addr) => Box::new(natpmp::map_tcp(
+                *addr,
+                port,
+                port,
+                config.lease.as_secs() as u32,
+                config.timeout,
+            )),
+            Gateway::Upnp(device) => Box::new(device.add_port_mapping(
+                port,
+                config.lease,
+                &config.description,
+                config.timeout,
+            )),
+        }
+    }
+
+    fn unmap(&self, port: u16, external_port: u16, timeout: Duration) -> BoxFuture<()> {
+        match self {
+            Gateway::NatPmp(addr) => {
+                Box::new(natpmp::map_tcp(*addr, port, 0, 0, timeout).map(|_| ()))
+            }
+            Gateway::Upnp(device) => Box::new(device.delete_port_mapping(external_port, timeout)),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Mapping {
+    port: u16,
+    external_port: u16,
+    address: Multiaddr,
+}
+
+enum State {
+    Discover(BoxFuture<Gateway>),
+    Map(BoxFuture<Vec<Mapping>>),
+    /// Wait to renew or retry
+    Wait(Delay),
+    Unmap(BoxFuture<()>),
+}
+
+/// Map the tcp listen ports on gateway by NAT-PMP or UPnP IGD, and add the mapped addresses
+/// to the external addresses of service.
+///
+/// The mappings are renewed until `PortMappingHandle::shutdown` called or the handle dropped,
+/// then they are removed and the task finishes.
+pub struct PortMapping<S> {
+    config: PortMapConfig,
+    control: ServiceControl,
+    /// Local ports to map
+    ports: Vec<u16>,
+    event_sender: S,
+    shutdown: mpsc::UnboundedReceiver<()>,
+    gateway: Option<Gateway>,
+    mapped: Vec<Mapping>,
+    state: State,
+}
+
+/// Stop the port mapping
+pub struct PortMappingHandle {
+    sender: mpsc::UnboundedSender<()>,
+}
+
+impl PortMappingHandle {
+    /// Remove the mappings and stop
+    pub fn shutdown(&self) {
+        let _ = self.sender.unbounded_send(());
+    }
+}
+
+impl<S> PortMapping<S>
+where
+    S: Sender<Event>,
+{
+    /// Map the ports of listen addresses, only ipv4 tcp ones except loopback are mapped
+    pub fn new(
+        config: PortMapConfig,
+        control: ServiceControl,
+        listens: &[Multiaddr],
+        event_sender: S,
+    ) -> (Self, PortMappingHandle) {
+        let mut ports: Vec<u16> = listens
+            .iter()
+            .filter(|address| !is_relayed(address))
+            .filter_map(|address| multiaddr_to_socketaddr(address).ok())
+            .filter(|socket_addr| socket_addr.is_ipv4() && !socket_addr.ip().is_loopback())
+            .map(|socket_addr| socket_addr.port())
+            .collect();
+        ports.sort();
+        ports.dedup();
+
+        let (sender, shutdown) = mpsc::unbounded();
+        let state = State::Discover(Gateway::discover(&config));
+        let mapping = PortMapping {
+            config,
+            control,
+            ports,
+            event_sender,
+            shutdown,
+            gateway: None,
+            mapped: Vec::new(),
+            state,
+        };
+        (mapping, PortMappingHandle { sender })
+    }
+
+    fn map_all(&self, gateway: &Gateway) -> BoxFuture<Vec<Mapping>> {
+        let maps = self
+            .ports
+            .iter()
+            .map(|&port| {
+                gateway
+                    .map(port, &self.config)
+                    .then(move |result| Ok::<_, io::Error>((port, result)))
+            })
+            .collect::<Vec<_>>();
+        let task = gateway
+            .external_ip(self.config.timeout)
+            .and_then(move |ip| {
+                future::join_all(maps)
+                    .map(move |results: Vec<(u16, io::Result<u16>)>| (ip, results))
+            })
+            .and_then(|(ip, results)| {
+                let mut mappings = Vec::new();
+                let mut last_error = None;
+                for (port, result) in results {
+                    match result {
+                        Ok(external_port) => mappings.push(Mapping {
+                            port,
+                            external_port,
+                            address: SocketAddr::new(ip, external_port).to_multiaddr().unwrap(),
+                        }),
+                        Err(err) => {
+                            debug!("map port {} error: {}", port, err);
+                            last_error = Some(err);
+                        }
+                    }
+                }
+                match last_error {
+                    Some(err) if mappings.is_empty() => Err(err),
+                    _ => Ok(mappings),
+                }
+            });
+        Box::new(task)
+    }
+
+    fn unmap_all(&self) -> BoxFuture<()> {
+        let gateway = match self.gateway {
+            Some(ref gateway) => gateway,
+            None => return Box::new(future::ok(())),
+        };
+        let unmaps = self
+            .mapped
+            .iter()
+            .map(|mapping| {
+                let port = mapping.port;
+                gateway
+                    .unmap(port, mapping.external_port, self.config.timeout)
+                    .then(move |result| {
+                        if let Err(err) = result {
+                            debug!("unmap port {} error: {}", port, err);
+                        }
+                        Ok::<_, io::Error>(())
+                    })
+            })
+            .collect::<Vec<_>>();
+        Box::new(future::join_all(unmaps).map(|_| ()))
+    }
+
+    /// Update the external addresses of service by the new mappings
+    fn update(&mut self, mappings: Vec<Mapping>) {
+        for old in self.mapped.iter() {
+            if !mappings
+                .iter()
+                .any(|mapping| mapping.address == old.address)
+            {
+                let _ = self.control.remove_external_addr(old.address.clone());
+                let _ = self.event_sender.try_send(Event::Unmapped {
+                    port: old.port,
+                    address: old.address.clone(),
+                });
+            }
+        }
+        for new in mappings.iter() {
+            if !self
+                .mapped
+                .iter()
+                .any(|mapping| mapping.address == new.address)
+            {
+                debug!("port {} mapped to {}", new.port, new.address);
+                let _ = self.control.add_external_addr(new.address.clone());
+                let _ = self.event_sender.try_send(Event::Mapped {
+                    port: new.port,
+                    address: new.address.clone(),
+                });
+            }
+        }
+        self.mapped = mappings;
+    }
+
+    fn wait(&self, duration: Duration) -> State {
+        State::Wait(Delay::new(Instant::now() + duration))
+    }
+}
+
+impl<S> Future for PortMapping<S>
+where
+    S: Sender<Event>,
+{
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        if let State::Unmap(ref mut task) = self.state {
+            return match task.poll() {
+                Ok(Async::NotReady) => Ok(Async::NotReady),
+                _ => Ok(Async::Ready(())),
+            };
+        }
+
+        match self.shutdown.poll() {
+            Ok(Async::NotReady) => (),
+            // Shutdown, or the handle dropped
+            _ => {
+                debug!("port mapping shutdown");
+                let task = self.unmap_all();
+                self.update(Vec::new());
+                self.state = State::Unmap(task);
+                return self.poll();
+            }
+        }
+
+        if self.ports.is_empty() {
+            return Ok(Async::NotReady);
+        }
+
+        loop {
+            let result = match self.state {
+                State::Discover(ref mut task) => match task.poll() {
+                    Ok(Async::Ready(gateway)) => {
+                        debug!("found gateway: {:?}", gateway.address());
+                        self.gateway = Some(gateway);
+                        None
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => Some(Err(err)),
+                },
+                State::Map(ref mut task) => match task.poll() {
+                    Ok(Async::Ready(mappings)) => Some(Ok(mappings)),
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => Some(Err(err)),
+                },
+                State::Wait(ref mut delay) => match delay.poll() {
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Ok(Async::Ready(())) => None,
+                    Err(err) => {
+                        debug!("port mapping timer error: {:?}", err);
+                        None
+                    }
+                },
+                State::Unmap(_) => unreachable!(),
+            };
+
+            self.state = match result {
+                Some(Ok(mappings)) => {
+                    self.update(mappings);
+                    self.wait(self.config.lease / 2)
+                }
+                Some(Err(err)) => {
+                    debug!("port mapping failed: {}", err);
+                    self.gateway = None;
+                    self.update(Vec::new());
+                    let _ = self.event_sender.try_send(Event::Failed { error: err });
+                    self.wait(self.config.retry_interval)
+                }
+                // Discovered, or time to renew
+                None => match self.gateway {
+                    Some(ref gateway) => State::Map(self.map_all(gateway)),
+                    None => State::Discover(Gateway::discover(&self.config)),
+                },
+            };
+        }
+    }
+}
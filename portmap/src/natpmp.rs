@@ -0,0 +1,222 @@
+use futures::{future, prelude::*};
+use std::{
+    fs, io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+use tokio::net::UdpSocket;
+
+use crate::with_timeout;
+
+/// NAT-PMP port of gateway
+pub const NATPMP_PORT: u16 = 5351;
+
+const VERSION: u8 = 0;
+const OP_EXTERNAL_ADDRESS: u8 = 0;
+const OP_MAP_TCP: u8 = 2;
+/// Opcode of response is the one of request plus it
+const OP_RESPONSE: u8 = 128;
+
+/// NAT-PMP request, IPv4 only
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Request {
+    ExternalAddress,
+    /// Zero lifetime removes the mapping
+    MapTcp {
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+}
+
+/// NAT-PMP response with success result
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum Response {
+    ExternalAddress(Ipv4Addr),
+    MapTcp {
+        internal_port: u16,
+        external_port: u16,
+        lifetime: u32,
+    },
+}
+
+impl Request {
+    pub fn encode(&self) -> Vec<u8> {
+        match self {
+            Request::ExternalAddress => vec![VERSION, OP_EXTERNAL_ADDRESS],
+            Request::MapTcp {
+                internal_port,
+                external_port,
+                lifetime,
+            } => {
+                let mut data = vec![VERSION, OP_MAP_TCP, 0, 0];
+                data.extend_from_slice(&internal_port.to_be_bytes());
+                data.extend_from_slice(&external_port.to_be_bytes());
+                data.extend_from_slice(&lifetime.to_be_bytes());
+                data
+            }
+        }
+    }
+
+    fn opcode(&self) -> u8 {
+        match self {
+            Request::ExternalAddress => OP_EXTERNAL_ADDRESS,
+            Request::MapTcp { .. } => OP_MAP_TCP,
+        }
+    }
+}
+
+impl Response {
+    /// Decode the response of request with the opcode, a non-zero result code is an error
+    pub fn decode(opcode: u8, data: &[u8]) -> Result<Self, io::Error> {
+        if data.len() < 8 || data[0] != VERSION || data[1] != opcode + OP_RESPONSE {
+            return Err(invalid_data("invalid NAT-PMP response"));
+        }
+        let result = u16::from_be_bytes([data[2], data[3]]);
+        if result != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("NAT-PMP result code: {}", result),
+            ));
+        }
+        // Bytes 4..8 are seconds since the gateway started
+        match opcode {
+            OP_EXTERNAL_ADDRESS if data.len() >= 12 => Ok(Response::ExternalAddress(
+                Ipv4Addr::new(data[8], data[9], data[10], data[11]),
+            )),
+            OP_MAP_TCP if data.len() >= 16 => Ok(Response::MapTcp {
+                internal_port: u16::from_be_bytes([data[8], data[9]]),
+                external_port: u16::from_be_bytes([data[10], data[11]]),
+                lifetime: u32::from_be_bytes([data[12], data[13], data[14], data[15]]),
+            }),
+            _ => Err(invalid_data("invalid NAT-PMP response")),
+        }
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Send the request to gateway and wait for its response
+pub(crate) fn request(
+    gateway: SocketAddr,
+    request: Request,
+    timeout: Duration,
+) -> impl Future<Item = Response, Error = io::Error> + Send {
+    let opcode = request.opcode();
+    let task = future::result(UdpSocket::bind(&SocketAddr::from(([0, 0, 0, 0], 0))))
+        .and_then(move |socket| socket.send_dgram(request.encode(), &gateway))
+        .and_then(|(socket, _)| socket.recv_dgram(vec![0; 16]))
+        .and_then(move |(_, buf, len, from)| {
+            if from.ip() != gateway.ip() {
+                return Err(invalid_data("NAT-PMP response from other address"));
+            }
+            Response::decode(opcode, &buf[..len])
+        });
+    with_timeout(task, timeout)
+}
+
+/// Our public ip known by gateway
+pub(crate) fn external_ip(
+    gateway: SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = Ipv4Addr, Error = io::Error> + Send {
+    request(gateway, Request::ExternalAddress, timeout).and_then(|response| match response {
+        Response::ExternalAddress(ip) => Ok(ip),
+        _ => Err(invalid_data("invalid NAT-PMP response")),
+    })
+}
+
+/// Map the tcp port, return the external port given by gateway.
+///
+/// Zero lifetime removes the mapping
+pub(crate) fn map_tcp(
+    gateway: SocketAddr,
+    internal_port: u16,
+    external_port: u16,
+    lifetime: u32,
+    timeout: Duration,
+) -> impl Future<Item = u16, Error = io::Error> + Send {
+    let map = Request::MapTcp {
+        internal_port,
+        external_port,
+        lifetime,
+    };
+    request(gateway, map, timeout).and_then(move |response| match response {
+        Response::MapTcp {
+            internal_port: port,
+            external_port,
+            ..
+        } if port == internal_port => Ok(external_port),
+        _ => Err(invalid_data("invalid NAT-PMP response")),
+    })
+}
+
+/// Default gateways in the route table, linux only
+pub(crate) fn default_gateways() -> Vec<SocketAddr> {
+    let table = match fs::read_to_string("/proc/net/route") {
+        Ok(table) => table,
+        Err(_) => return Vec::new(),
+    };
+    // Iface Destination Gateway ..., in hex of the bytes on little endian
+    table
+        .lines()
+        .skip(1)
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace().skip(1);
+            match (fields.next(), fields.next()) {
+                (Some("00000000"), Some(gateway)) => u32::from_str_radix(gateway, 16).ok(),
+                _ => None,
+            }
+        })
+        .filter(|gateway| *gateway != 0)
+        .map(|gateway| {
+            let ip = Ipv4Addr::from(gateway.to_le_bytes());
+            SocketAddr::from((ip, NATPMP_PORT))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Request, Response, OP_EXTERNAL_ADDRESS, OP_MAP_TCP};
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn encode_and_decode() {
+        assert_eq!(Request::ExternalAddress.encode(), vec![0, 0]);
+        let map = Request::MapTcp {
+            internal_port: 1337,
+            external_port: 1338,
+            lifetime: 3600,
+        };
+        assert_eq!(
+            map.encode(),
+            vec![0, 2, 0, 0, 0x05, 0x39, 0x05, 0x3a, 0, 0, 0x0e, 0x10]
+        );
+
+        let response = [0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4];
+        assert_eq!(
+            Response::decode(OP_EXTERNAL_ADDRESS, &response).unwrap(),
+            Response::ExternalAddress(Ipv4Addr::new(1, 2, 3, 4))
+        );
+        let response = [
+            0, 130, 0, 0, 0, 0, 0, 1, 0x05, 0x39, 0x05, 0x3a, 0, 0, 0x0e, 0x10,
+        ];
+        assert_eq!(
+            Response::decode(OP_MAP_TCP, &response).unwrap(),
+            Response::MapTcp {
+                internal_port: 1337,
+                external_port: 1338,
+                lifetime: 3600,
+            }
+        );
+
+        // Refused by gateway
+        let response = [0, 130, 0, 2, 0, 0, 0, 1, 0x05, 0x39, 0, 0, 0, 0, 0, 0];
+        assert!(Response::decode(OP_MAP_TCP, &response).is_err());
+        // Response of another request
+        assert!(Response::decode(OP_EXTERNAL_ADDRESS, &response).is_err());
+    }
+}
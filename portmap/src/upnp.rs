@@ -0,0 +1,312 @@
+use futures::{future, prelude::*};
+use std::{
+    io,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use tokio::{
+    io::{read_to_end, write_all},
+    net::{TcpStream, UdpSocket},
+};
+
+use crate::with_timeout;
+
+/// Default SSDP multicast address
+pub const SSDP_ADDR: ([u8; 4], u16) = ([239, 255, 255, 250], 1900);
+
+const SEARCH_TARGET: &str = "urn:schemas-upnp-org:device:InternetGatewayDevice:1";
+/// Services able to map ports, any version
+const WAN_SERVICES: [&str; 2] = [
+    "urn:schemas-upnp-org:service:WANIPConnection:",
+    "urn:schemas-upnp-org:service:WANPPPConnection:",
+];
+
+/// The WAN connection service of an internet gateway device
+#[derive(Clone, Debug)]
+pub(crate) struct Device {
+    /// Address of control url
+    pub addr: SocketAddr,
+    control_path: String,
+    service_type: String,
+    /// Our ip in the network of gateway
+    local_ip: IpAddr,
+}
+
+impl Device {
+    /// Our public ip known by gateway
+    pub fn external_ip(&self, timeout: Duration) -> impl Future<Item = IpAddr, Error = io::Error> {
+        self.soap("GetExternalIPAddress", &[], timeout)
+            .and_then(|body| {
+                tag(&body, "NewExternalIPAddress")
+                    .and_then(|ip| ip.parse().ok())
+                    .ok_or_else(|| invalid_data("invalid external ip"))
+            })
+    }
+
+    /// Map the tcp port of our ip to the same port of gateway
+    pub fn add_port_mapping(
+        &self,
+        port: u16,
+        lease: Duration,
+        description: &str,
+        timeout: Duration,
+    ) -> impl Future<Item = u16, Error = io::Error> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", "TCP".to_owned()),
+            ("NewInternalPort", port.to_string()),
+            ("NewInternalClient", self.local_ip.to_string()),
+            ("NewEnabled", "1".to_owned()),
+            ("NewPortMappingDescription", description.to_owned()),
+            ("NewLeaseDuration", lease.as_secs().to_string()),
+        ];
+        self.soap("AddPortMapping", &args, timeout)
+            .map(move |_| port)
+    }
+
+    /// Remove the tcp mapping on the port of gateway
+    pub fn delete_port_mapping(
+        &self,
+        port: u16,
+        timeout: Duration,
+    ) -> impl Future<Item = (), Error = io::Error> {
+        let args = [
+            ("NewRemoteHost", String::new()),
+            ("NewExternalPort", port.to_string()),
+            ("NewProtocol", "TCP".to_owned()),
+        ];
+        self.soap("DeletePortMapping", &args, timeout).map(|_| ())
+    }
+
+    /// Call the action of service, return the response body
+    fn soap(
+        &self,
+        action: &str,
+        args: &[(&str, String)],
+        timeout: Duration,
+    ) -> impl Future<Item = String, Error = io::Error> {
+        let args = args
+            .iter()
+            .map(|(name, value)| format!("<{0}>{1}</{0}>", name, value))
+            .collect::<String>();
+        let body = format!(
+            "<?xml version=\"1.0\"?>\
+             <s:Envelope xmlns:s=\"http://schemas.xmlsoap.org/soap/envelope/\" \
+             s:encodingStyle=\"http://schemas.xmlsoap.org/soap/encoding/\">\
+             <s:Body><u:{0} xmlns:u=\"{1}\">{2}</u:{0}></s:Body></s:Envelope>",
+            action, self.service_type, args
+        );
+        let request = format!(
+            "POST {} HTTP/1.0\r\nHost: {}\r\nContent-Type: text/xml; charset=\"utf-8\"\r\n\
+             Content-Length: {}\r\nSOAPAction: \"{}#{}\"\r\n\r\n{}",
+            self.control_path,
+            self.addr,
+            body.len(),
+            self.service_type,
+            action,
+            body
+        );
+        let action = action.to_owned();
+        with_timeout(http(self.addr, request), timeout).and_then(move |(_, status, body)| {
+            if status == 200 {
+                Ok(body)
+            } else {
+                let code = tag(&body, "errorCode").unwrap_or_default();
+                Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    format!("UPnP {} error: {} {}", action, status, code),
+                ))
+            }
+        })
+    }
+}
+
+/// Search internet gateway device by SSDP, and get its WAN connection service
+pub(crate) fn search(
+    ssdp_addr: SocketAddr,
+    timeout: Duration,
+) -> impl Future<Item = Device, Error = io::Error> + Send {
+    let request = format!(
+        "M-SEARCH * HTTP/1.1\r\nHOST: {}\r\nST: {}\r\nMAN: \"ssdp:discover\"\r\nMX: 2\r\n\r\n",
+        ssdp_addr, SEARCH_TARGET
+    );
+    let search = future::result(UdpSocket::bind(&SocketAddr::from(([0, 0, 0, 0], 0))))
+        .and_then(move |socket| socket.send_dgram(request.into_bytes(), &ssdp_addr))
+        .and_then(|(socket, _)| socket.recv_dgram(vec![0; 2048]))
+        .and_then(|(_, buf, len, _)| {
+            let response = String::from_utf8_lossy(&buf[..len]);
+            response
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.splitn(2, ':');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(value))
+                            if name.trim().eq_ignore_ascii_case("location") =>
+                        {
+                            parse_url(value.trim())
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+                .ok_or_else(|| invalid_data("SSDP response without location"))
+        });
+    with_timeout(search, timeout)
+        .and_then(move |(addr, path)| {
+            let request = format!(
+                "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+                path, addr
+            );
+            with_timeout(http(addr, request), timeout).map(move |response| (addr, response))
+        })
+        .and_then(|(addr, (local_ip, status, body))| {
+            if status != 200 {
+                return Err(invalid_data("get device description failed"));
+            }
+            let (service_type, control_url) = parse_description(&body)
+                .ok_or_else(|| invalid_data("no WAN connection service"))?;
+            let (addr, control_path) = if control_url.starts_with("http://") {
+                parse_url(&control_url).ok_or_else(|| invalid_data("invalid control url"))?
+            } else if control_url.starts_with('/') {
+                (addr, control_url)
+            } else {
+                (addr, format!("/{}", control_url))
+            };
+            Ok(Device {
+                addr,
+                control_path,
+                service_type,
+                local_ip,
+            })
+        })
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Send a HTTP/1.0 request, return our local ip, the status code and body of response
+fn http(
+    addr: SocketAddr,
+    request: String,
+) -> impl Future<Item = (IpAddr, u16, String), Error = io::Error> + Send {
+    TcpStream::connect(&addr)
+        .and_then(|stream| {
+            let local_ip = stream.local_addr().map(|addr| addr.ip());
+            future::result(local_ip).and_then(move |local_ip| {
+                write_all(stream, request.into_bytes()).map(move |(stream, _)| (stream, local_ip))
+            })
+        })
+        // The server closes the connection after response
+        .and_then(|(stream, local_ip)| {
+            read_to_end(stream, Vec::new()).map(move |(_, data)| (local_ip, data))
+        })
+        .and_then(|(local_ip, data)| {
+            let response = String::from_utf8_lossy(&data);
+            let mut parts = response.splitn(2, "\r\n\r\n");
+            let status = parts
+                .next()
+                .and_then(|head| head.split_whitespace().nth(1))
+                .and_then(|status| status.parse().ok())
+                .ok_or_else(|| invalid_data("invalid HTTP response"))?;
+            let body = parts.next().unwrap_or_default().to_owned();
+            Ok((local_ip, status, body))
+        })
+}
+
+/// Parse `http://host:port/path`, the host must be an ip
+fn parse_url(url: &str) -> Option<(SocketAddr, String)> {
+    let rest = url.trim_start_matches("http://");
+    let (host, path) = match rest.find('/') {
+        Some(index) => (&rest[..index], rest[index..].to_owned()),
+        None => (rest, "/".to_owned()),
+    };
+    let addr = host.parse::<SocketAddr>().ok().or_else(|| {
+        host.parse::<IpAddr>()
+            .ok()
+            .map(|ip| SocketAddr::new(ip, 80))
+    })?;
+    Some((addr, path))
+}
+
+/// Text of the first element with the name
+fn tag<'a>(text: &'a str, name: &str) -> Option<&'a str> {
+    let open = format!("<{}>", name);
+    let start = text.find(&open)? + open.len();
+    let end = text[start..].find(&format!("</{}>", name))? + start;
+    Some(text[start..end].trim())
+}
+
+/// Find the type and control url of WAN connection service in the device description
+fn parse_description(xml: &str) -> Option<(String, String)> {
+    let mut rest = xml;
+    while let Some(start) = rest.find("<service>") {
+        let end = rest[start..].find("</service>")? + start;
+        let service = &rest[start..end];
+        if let (Some(service_type), Some(control_url)) =
+            (tag(service, "serviceType"), tag(service, "controlURL"))
+        {
+            if WAN_SERVICES.iter().any(|ty| service_type.starts_with(ty)) {
+                return Some((service_type.to_owned(), control_url.to_owned()));
+            }
+        }
+        rest = &rest[end..];
+    }
+    None
+}
+
+#[cfg(test)]
+mod test {
+    use super::{parse_description, parse_url, tag};
+
+    #[test]
+    fn parse_device_description() {
+        let xml = r#"<?xml version="1.0"?>
+<root xmlns="urn:schemas-upnp-org:device-1-0">
+  <device>
+    <serviceList>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:Layer3Forwarding:1</serviceType>
+        <controlURL>/ctl/L3F</controlURL>
+      </service>
+      <service>
+        <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>
+        <controlURL>/ctl/IPConn</controlURL>
+      </service>
+    </serviceList>
+  </device>
+</root>"#;
+        assert_eq!(
+            parse_description(xml),
+            Some((
+                "urn:schemas-upnp-org:service:WANIPConnection:1".to_owned(),
+                "/ctl/IPConn".to_owned()
+            ))
+        );
+        assert_eq!(parse_description("<root></root>"), None);
+    }
+
+    #[test]
+    fn parse_location() {
+        assert_eq!(
+            parse_url("http://192.168.1.1:5000/rootDesc.xml"),
+            Some((
+                "192.168.1.1:5000".parse().unwrap(),
+                "/rootDesc.xml".to_owned()
+            ))
+        );
+        assert_eq!(
+            parse_url("http://192.168.1.1"),
+            Some(("192.168.1.1:80".parse().unwrap(), "/".to_owned()))
+        );
+        assert_eq!(parse_url("http://router.local/desc.xml"), None);
+        assert_eq!(
+            tag(
+                "<NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>",
+                "NewExternalIPAddress"
+            ),
+            Some("1.2.3.4")
+        );
+    }
+}
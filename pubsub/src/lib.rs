@@ -0,0 +1,643 @@
+mod mcache;
+mod message;
+
+pub use crate::message::{Message, MessageId};
+
+use crate::{
+    mcache::{MessageCache, SeenCache},
+    message::{ControlMessage, Rpc, WireMessage},
+};
+use fnv::FnvHashMap;
+use generic_channel::Sender;
+use log::debug;
+use p2p::{
+    context::{ServiceContext, ServiceControl, SessionContext},
+    error::Error,
+    service::ServiceTask,
+    traits::{ProtocolMeta, ServiceProtocol},
+    PeerId, ProtocolId, SecioKeyPair, SessionId,
+};
+use rand::seq::SliceRandom;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+const HEARTBEAT_TOKEN: u64 = 0;
+const COMMAND_TOKEN: u64 = 1;
+/// Max message ids requested by an IWANT
+const MAX_IWANT: usize = 500;
+
+/// Pubsub configuration
+#[derive(Clone, Debug)]
+pub struct PubsubConfig {
+    /// Target number of peers in the mesh of a topic
+    pub mesh_n: usize,
+    /// Add peers to the mesh below this
+    pub mesh_n_low: usize,
+    /// Remove peers from the mesh above this
+    pub mesh_n_high: usize,
+    /// Number of peers out of the mesh to send IHAVE
+    pub gossip_lazy: usize,
+    /// Interval of mesh maintenance and gossip
+    pub heartbeat_interval: Duration,
+    /// Heartbeats a message stays in cache for IWANT
+    pub history_length: usize,
+    /// Heartbeats a message is announced by IHAVE
+    pub history_gossip: usize,
+    /// Duplicated messages in this period are dropped
+    pub seen_ttl: Duration,
+    /// Fanout peers of a topic not subscribed are dropped if no publish in this period
+    pub fanout_ttl: Duration,
+    /// Max bytes of message payload
+    pub max_transmit_size: usize,
+    /// Sign published messages and drop unsigned messages
+    pub sign_messages: bool,
+}
+
+impl Default for PubsubConfig {
+    fn default() -> Self {
+        PubsubConfig {
+            mesh_n: 6,
+            mesh_n_low: 4,
+            mesh_n_high: 12,
+            gossip_lazy: 6,
+            heartbeat_interval: Duration::from_secs(1),
+            history_length: 5,
+            history_gossip: 3,
+            seen_ttl: Duration::from_secs(120),
+            fanout_ttl: Duration::from_secs(60),
+            max_transmit_size: 64 * 1024,
+            sign_messages: true,
+        }
+    }
+}
+
+/// Pubsub protocol events
+#[derive(Debug)]
+pub enum Event {
+    /// A new message of subscribed topic
+    Message(Message),
+    /// The session subscribed the topic
+    Subscribed(SessionId, String),
+    /// The session unsubscribed the topic
+    Unsubscribed(SessionId, String),
+}
+
+enum Command {
+    Subscribe(String),
+    Unsubscribe(String),
+    Publish(String, Vec<u8>),
+}
+
+/// Topic based publish/subscribe, messages are relayed through a mesh of peers per topic
+pub struct PubsubProtocol<S: Sender<Event> + Send + Clone> {
+    id: ProtocolId,
+    key_pair: SecioKeyPair,
+    config: PubsubConfig,
+    event_sender: S,
+    command_sender: Mutex<mpsc::Sender<Command>>,
+    command_receiver: Mutex<Option<mpsc::Receiver<Command>>>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+}
+
+impl<S> PubsubProtocol<S>
+where
+    S: Sender<Event> + Send + Clone,
+{
+    /// The key pair identifies the source of published messages
+    pub fn new(
+        id: ProtocolId,
+        key_pair: SecioKeyPair,
+        config: PubsubConfig,
+        event_sender: S,
+    ) -> Self {
+        let (command_sender, command_receiver) = mpsc::channel();
+        PubsubProtocol {
+            id,
+            key_pair,
+            config,
+            event_sender,
+            command_sender: Mutex::new(command_sender),
+            command_receiver: Mutex::new(Some(command_receiver)),
+            control: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Subscribe and publish, works after the service started
+    pub fn handle(&self) -> PubsubHandle {
+        PubsubHandle {
+            proto_id: self.id,
+            command_sender: self.command_sender.lock().unwrap().clone(),
+            control: Arc::clone(&self.control),
+        }
+    }
+}
+
+impl<S> ProtocolMeta<LengthDelimitedCodec> for PubsubProtocol<S>
+where
+    S: Sender<Event> + Send + Clone + 'static,
+{
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        // Only one handle serves the commands
+        let command_receiver = self.command_receiver.lock().unwrap().take()?;
+        let handle = Box::new(PubsubHandler {
+            proto_id: self.id,
+            local_peer_id: self.key_pair.to_peer_id(),
+            key_pair: self.key_pair.clone(),
+            mcache: MessageCache::new(self.config.history_length, self.config.history_gossip),
+            seen: SeenCache::new(self.config.seen_ttl),
+            config: self.config.clone(),
+            event_sender: self.event_sender.clone(),
+            command_receiver,
+            control: Arc::clone(&self.control),
+            seqno: rand::random(),
+            subscriptions: HashSet::new(),
+            peers: FnvHashMap::default(),
+            mesh: HashMap::new(),
+            fanout: HashMap::new(),
+            fanout_last_publish: HashMap::new(),
+        });
+        Some(handle)
+    }
+}
+
+/// Subscribe, unsubscribe and publish
+#[derive(Clone)]
+pub struct PubsubHandle {
+    proto_id: ProtocolId,
+    command_sender: mpsc::Sender<Command>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+}
+
+impl PubsubHandle {
+    fn send(&mut self, command: Command) -> Result<(), Error<ServiceTask>> {
+        self.command_sender
+            .send(command)
+            .map_err(|_| Error::TaskDisconnect)?;
+        // Wake up the protocol handler, the commands before service started are handled on init
+        match *self.control.lock().unwrap() {
+            Some(ref mut control) => control.send(ServiceTask::ProtocolNotify {
+                proto_id: self.proto_id,
+                token: COMMAND_TOKEN,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Subscribe the topic
+    pub fn subscribe(&mut self, topic: String) -> Result<(), Error<ServiceTask>> {
+        self.send(Command::Subscribe(topic))
+    }
+
+    /// Unsubscribe the topic
+    pub fn unsubscribe(&mut self, topic: String) -> Result<(), Error<ServiceTask>> {
+        self.send(Command::Unsubscribe(topic))
+    }
+
+    /// Publish a message to the topic, subscribed or not
+    pub fn publish(&mut self, topic: String, data: Vec<u8>) -> Result<(), Error<ServiceTask>> {
+        self.send(Command::Publish(topic, data))
+    }
+}
+
+struct PeerState {
+    topics: HashSet<String>,
+}
+
+struct PubsubHandler<S: Sender<Event>> {
+    proto_id: ProtocolId,
+    local_peer_id: PeerId,
+    key_pair: SecioKeyPair,
+    config: PubsubConfig,
+    event_sender: S,
+    command_receiver: mpsc::Receiver<Command>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+    seqno: u64,
+    mcache: MessageCache,
+    seen: SeenCache,
+    /// Local subscriptions
+    subscriptions: HashSet<String>,
+    peers: FnvHashMap<SessionId, PeerState>,
+    /// Peers relaying full messages of subscribed topics
+    mesh: HashMap<String, HashSet<SessionId>>,
+    /// Peers receiving our messages of topics not subscribed
+    fanout: HashMap<String, HashSet<SessionId>>,
+    fanout_last_publish: HashMap<String, Instant>,
+}
+
+impl<S> PubsubHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn send_rpc(&self, control: &mut ServiceContext, session_id: SessionId, rpc: &Rpc) {
+        let _ = control.send_message(session_id, self.proto_id, rpc.encode());
+    }
+
+    fn send_control(
+        &self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: ControlMessage,
+    ) {
+        let rpc = Rpc {
+            control: message,
+            ..Default::default()
+        };
+        self.send_rpc(control, session_id, &rpc);
+    }
+
+    /// Random peers subscribed the topic, except some
+    fn topic_peers(&self, topic: &str, n: usize, except: &HashSet<SessionId>) -> Vec<SessionId> {
+        let mut peers = self
+            .peers
+            .iter()
+            .filter(|(id, peer)| peer.topics.contains(topic) && !except.contains(*id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        peers.shuffle(&mut rand::thread_rng());
+        peers.truncate(n);
+        peers
+    }
+
+    fn handle_commands(&mut self, control: &mut ServiceContext) {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                Command::Subscribe(topic) => self.subscribe(control, topic),
+                Command::Unsubscribe(topic) => self.unsubscribe(control, topic),
+                Command::Publish(topic, data) => self.publish(control, topic, data),
+            }
+        }
+    }
+
+    fn subscribe(&mut self, control: &mut ServiceContext, topic: String) {
+        if !self.subscriptions.insert(topic.clone()) {
+            return;
+        }
+        let rpc = Rpc {
+            subscriptions: vec![(true, topic.clone())],
+            ..Default::default()
+        };
+        for session_id in self.peers.keys() {
+            self.send_rpc(control, *session_id, &rpc);
+        }
+
+        // Fanout peers join the mesh first
+        let mut mesh = self.fanout.remove(&topic).unwrap_or_default();
+        self.fanout_last_publish.remove(&topic);
+        mesh.extend(self.topic_peers(&topic, self.config.mesh_n.saturating_sub(mesh.len()), &mesh));
+        for session_id in &mesh {
+            self.send_control(
+                control,
+                *session_id,
+                ControlMessage {
+                    graft: vec![topic.clone()],
+                    ..Default::default()
+                },
+            );
+        }
+        self.mesh.insert(topic, mesh);
+    }
+
+    fn unsubscribe(&mut self, control: &mut ServiceContext, topic: String) {
+        if !self.subscriptions.remove(&topic) {
+            return;
+        }
+        let rpc = Rpc {
+            subscriptions: vec![(false, topic.clone())],
+            ..Default::default()
+        };
+        for session_id in self.peers.keys() {
+            self.send_rpc(control, *session_id, &rpc);
+        }
+
+        for session_id in self.mesh.remove(&topic).unwrap_or_default() {
+            self.send_control(
+                control,
+                session_id,
+                ControlMessage {
+                    prune: vec![topic.clone()],
+                    ..Default::default()
+                },
+            );
+        }
+    }
+
+    fn publish(&mut self, control: &mut ServiceContext, topic: String, data: Vec<u8>) {
+        self.seqno = self.seqno.wrapping_add(1);
+        let key_pair = if self.config.sign_messages {
+            Some(&self.key_pair)
+        } else {
+            None
+        };
+        let message = WireMessage::new(
+            &self.local_peer_id,
+            self.seqno,
+            topic.clone(),
+            data,
+            key_pair,
+        );
+        let id = message.id();
+        self.seen.insert(id.clone(), Instant::now());
+        self.mcache.put(id, message.clone());
+
+        let peers = match self.mesh.get(&topic) {
+            Some(mesh) => mesh.clone(),
+            None => {
+                let mesh_n = self.config.mesh_n;
+                if self
+                    .fanout
+                    .get(&topic)
+                    .map(HashSet::is_empty)
+                    .unwrap_or(true)
+                {
+                    let peers = self.topic_peers(&topic, mesh_n, &HashSet::new());
+                    self.fanout
+                        .insert(topic.clone(), peers.into_iter().collect());
+                }
+                self.fanout_last_publish
+                    .insert(topic.clone(), Instant::now());
+                self.fanout[&topic].clone()
+            }
+        };
+
+        let rpc = Rpc {
+            messages: vec![message],
+            ..Default::default()
+        };
+        for session_id in peers {
+            self.send_rpc(control, session_id, &rpc);
+        }
+    }
+
+    fn handle_message(
+        &mut self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: WireMessage,
+    ) {
+        let id = message.id();
+        if self.seen.contains(&id) {
+            return;
+        }
+        let source =
+            match message.validate(self.config.max_transmit_size, self.config.sign_messages) {
+                Ok(source) => source,
+                Err(err) => {
+                    debug!("session [{}] sent invalid message: {:?}", session_id, err);
+                    return;
+                }
+            };
+        self.seen.insert(id.clone(), Instant::now());
+        self.mcache.put(id, message.clone());
+
+        if self.subscriptions.contains(&message.topic) {
+            let _ = self.event_sender.try_send(Event::Message(
+                message.clone().into_message(source, session_id),
+            ));
+        }
+
+        // Forward to the mesh
+        if let Some(mesh) = self.mesh.get(&message.topic) {
+            let rpc = Rpc {
+                messages: vec![message],
+                ..Default::default()
+            };
+            for peer in mesh.iter().filter(|peer| **peer != session_id) {
+                self.send_rpc(control, *peer, &rpc);
+            }
+        }
+    }
+
+    fn handle_control(
+        &mut self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: ControlMessage,
+    ) {
+        let mut response = ControlMessage::default();
+
+        for (topic, ids) in message.ihave {
+            if !self.subscriptions.contains(&topic) {
+                continue;
+            }
+            for id in ids {
+                if response.iwant.len() < MAX_IWANT && !self.seen.contains(&id) {
+                    response.iwant.push(id);
+                }
+            }
+        }
+
+        let messages = message
+            .iwant
+            .iter()
+            .take(MAX_IWANT)
+            .filter_map(|id| self.mcache.get(id).cloned())
+            .collect::<Vec<_>>();
+
+        for topic in message.graft {
+            match self.mesh.get_mut(&topic) {
+                Some(mesh) => {
+                    mesh.insert(session_id);
+                }
+                // Not subscribed
+                None => response.prune.push(topic),
+            }
+        }
+
+        for topic in message.prune {
+            if let Some(mesh) = self.mesh.get_mut(&topic) {
+                mesh.remove(&session_id);
+            }
+        }
+
+        if !response.is_empty() || !messages.is_empty() {
+            let rpc = Rpc {
+                messages,
+                control: response,
+                ..Default::default()
+            };
+            self.send_rpc(control, session_id, &rpc);
+        }
+    }
+
+    fn heartbeat(&mut self, control: &mut ServiceContext) {
+        let now = Instant::now();
+        let mut grafts: HashMap<SessionId, Vec<String>> = HashMap::new();
+        let mut prunes: HashMap<SessionId, Vec<String>> = HashMap::new();
+
+        let topics = self.mesh.keys().cloned().collect::<Vec<_>>();
+        for topic in topics {
+            let mesh = self.mesh[&topic].clone();
+            if mesh.len() < self.config.mesh_n_low {
+                for session_id in
+                    self.topic_peers(&topic, self.config.mesh_n.saturating_sub(mesh.len()), &mesh)
+                {
+                    grafts.entry(session_id).or_default().push(topic.clone());
+                    self.mesh.get_mut(&topic).unwrap().insert(session_id);
+                }
+            } else if mesh.len() > self.config.mesh_n_high {
+                let mut peers = mesh.into_iter().collect::<Vec<_>>();
+                peers.shuffle(&mut rand::thread_rng());
+                for session_id in peers.into_iter().skip(self.config.mesh_n) {
+                    prunes.entry(session_id).or_default().push(topic.clone());
+                    self.mesh.get_mut(&topic).unwrap().remove(&session_id);
+                }
+            }
+        }
+
+        // Fanout of the topics not published recently are dropped
+        let fanout_ttl = self.config.fanout_ttl;
+        let expired = self
+            .fanout_last_publish
+            .iter()
+            .filter(|(_, last)| **last + fanout_ttl <= now)
+            .map(|(topic, _)| topic.clone())
+            .collect::<Vec<_>>();
+        for topic in expired {
+            self.fanout.remove(&topic);
+            self.fanout_last_publish.remove(&topic);
+        }
+
+        // IHAVE to the peers out of mesh and fanout
+        let mut ihaves: HashMap<SessionId, Vec<(String, Vec<_>)>> = HashMap::new();
+        for (topic, peers) in self.mesh.iter().chain(self.fanout.iter()) {
+            let ids = self.mcache.gossip_ids(topic);
+            if ids.is_empty() {
+                continue;
+            }
+            for session_id in self.topic_peers(topic, self.config.gossip_lazy, peers) {
+                ihaves
+                    .entry(session_id)
+                    .or_default()
+                    .push((topic.clone(), ids.clone()));
+            }
+        }
+
+        let sessions = grafts
+            .keys()
+            .chain(prunes.keys())
+            .chain(ihaves.keys())
+            .cloned()
+            .collect::<HashSet<_>>();
+        for session_id in sessions {
+            let message = ControlMessage {
+                ihave: ihaves.remove(&session_id).unwrap_or_default(),
+                graft: grafts.remove(&session_id).unwrap_or_default(),
+                prune: prunes.remove(&session_id).unwrap_or_default(),
+                ..Default::default()
+            };
+            self.send_control(control, session_id, message);
+        }
+
+        self.mcache.shift();
+        self.seen.expire(now);
+    }
+}
+
+impl<S> ServiceProtocol for PubsubHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn init(&mut self, control: &mut ServiceContext) {
+        *self.control.lock().unwrap() = Some(control.control().clone());
+        control.set_service_notify(
+            self.proto_id,
+            self.config.heartbeat_interval,
+            HEARTBEAT_TOKEN,
+        );
+        self.handle_commands(control);
+    }
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        debug!(
+            "proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
+            self.proto_id, session.id, session.address, session.ty, version
+        );
+        self.peers.insert(
+            session.id,
+            PeerState {
+                topics: HashSet::new(),
+            },
+        );
+        if !self.subscriptions.is_empty() {
+            let rpc = Rpc {
+                subscriptions: self
+                    .subscriptions
+                    .iter()
+                    .map(|topic| (true, topic.clone()))
+                    .collect(),
+                ..Default::default()
+            };
+            self.send_rpc(control, session.id, &rpc);
+        }
+    }
+
+    fn disconnected(&mut self, _control: &mut ServiceContext, session: &SessionContext) {
+        debug!(
+            "proto id [{}] close on session [{}]",
+            self.proto_id, session.id
+        );
+        self.peers.remove(&session.id);
+        for peers in self.mesh.values_mut().chain(self.fanout.values_mut()) {
+            peers.remove(&session.id);
+        }
+    }
+
+    fn received(&mut self, control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        let rpc = match Rpc::decode(&data) {
+            Some(rpc) => rpc,
+            None => {
+                debug!("session [{}] sent invalid rpc", session.id);
+                let _ = control.disconnect(session.id);
+                return;
+            }
+        };
+
+        if let Some(peer) = self.peers.get_mut(&session.id) {
+            for (subscribe, topic) in rpc.subscriptions {
+                if subscribe {
+                    if peer.topics.insert(topic.clone()) {
+                        let _ = self
+                            .event_sender
+                            .try_send(Event::Subscribed(session.id, topic));
+                    }
+                } else if peer.topics.remove(&topic) {
+                    if let Some(mesh) = self.mesh.get_mut(&topic) {
+                        mesh.remove(&session.id);
+                    }
+                    let _ = self
+                        .event_sender
+                        .try_send(Event::Unsubscribed(session.id, topic));
+                }
+            }
+        }
+
+        for message in rpc.messages {
+            self.handle_message(control, session.id, message);
+        }
+        if !rpc.control.is_empty() {
+            self.handle_control(control, session.id, rpc.control);
+        }
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, token: u64) {
+        match token {
+            HEARTBEAT_TOKEN => {
+                self.handle_commands(control);
+                self.heartbeat(control);
+            }
+            COMMAND_TOKEN => self.handle_commands(control),
+            _ => panic!("unknown token {}", token),
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    time::{Duration, Instant},
+};
+
+use crate::message::{MessageId, WireMessage};
+
+/// Recent messages for IWANT, in windows shifted by heartbeat
+pub(crate) struct MessageCache {
+    messages: HashMap<MessageId, WireMessage>,
+    /// The newest window first
+    history: VecDeque<Vec<(MessageId, String)>>,
+    /// Windows announced by IHAVE
+    gossip: usize,
+}
+
+impl MessageCache {
+    pub fn new(history_length: usize, history_gossip: usize) -> Self {
+        MessageCache {
+            messages: HashMap::new(),
+            history: (0..history_length.max(1)).map(|_| Vec::new()).collect(),
+            gossip: history_gossip,
+        }
+    }
+
+    pub fn put(&mut self, id: MessageId, message: WireMessage) {
+        if let Some(window) = self.history.front_mut() {
+            window.push((id.clone(), message.topic.clone()));
+        }
+        self.messages.insert(id, message);
+    }
+
+    pub fn get(&self, id: &MessageId) -> Option<&WireMessage> {
+        self.messages.get(id)
+    }
+
+    /// Message ids of the topic in the gossip windows
+    pub fn gossip_ids(&self, topic: &str) -> Vec<MessageId> {
+        self.history
+            .iter()
+            .take(self.gossip)
+            .flat_map(|window| window.iter())
+            .filter(|(_, message_topic)| message_topic == topic)
+            .map(|(id, _)| id.clone())
+            .collect()
+    }
+
+    /// Drop the oldest window and start a new one
+    pub fn shift(&mut self) {
+        if let Some(window) = self.history.pop_back() {
+            for (id, _) in window {
+                self.messages.remove(&id);
+            }
+        }
+        self.history.push_front(Vec::new());
+    }
+}
+
+/// Message ids seen in a period, for deduplication
+pub(crate) struct SeenCache {
+    ttl: Duration,
+    ids: HashSet<MessageId>,
+    queue: VecDeque<(Instant, MessageId)>,
+}
+
+impl SeenCache {
+    pub fn new(ttl: Duration) -> Self {
+        SeenCache {
+            ttl,
+            ids: HashSet::new(),
+            queue: VecDeque::new(),
+        }
+    }
+
+    /// Return false if seen
+    pub fn insert(&mut self, id: MessageId, now: Instant) -> bool {
+        if self.ids.insert(id.clone()) {
+            self.queue.push_back((now, id));
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn contains(&self, id: &MessageId) -> bool {
+        self.ids.contains(id)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        while let Some((seen_at, _)) = self.queue.front() {
+            if *seen_at + self.ttl > now {
+                break;
+            }
+            if let Some((_, id)) = self.queue.pop_front() {
+                self.ids.remove(&id);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{MessageCache, SeenCache};
+    use crate::message::WireMessage;
+    use p2p::SecioKeyPair;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn shift_windows() {
+        let source = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let message = |seqno, topic: &str| {
+            WireMessage::new(&source, seqno, topic.to_owned(), Vec::new(), None)
+        };
+        let mut cache = MessageCache::new(3, 2);
+        let first = message(1, "a");
+        cache.put(first.id(), first.clone());
+        cache.put(message(2, "b").id(), message(2, "b"));
+        assert_eq!(cache.gossip_ids("a"), vec![first.id()]);
+
+        cache.shift();
+        cache.put(message(3, "a").id(), message(3, "a"));
+        assert_eq!(cache.gossip_ids("a").len(), 2);
+
+        // Out of gossip windows, still in cache
+        cache.shift();
+        assert_eq!(cache.gossip_ids("a"), vec![message(3, "a").id()]);
+        assert!(cache.get(&first.id()).is_some());
+
+        cache.shift();
+        assert!(cache.get(&first.id()).is_none());
+    }
+
+    #[test]
+    fn expire_seen() {
+        let source = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let id = WireMessage::new(&source, 1, "a".to_owned(), Vec::new(), None).id();
+        let now = Instant::now();
+        let mut seen = SeenCache::new(Duration::from_secs(10));
+        assert!(seen.insert(id.clone(), now));
+        assert!(!seen.insert(id.clone(), now));
+
+        seen.expire(now + Duration::from_secs(9));
+        assert!(seen.contains(&id));
+        seen.expire(now + Duration::from_secs(10));
+        assert!(!seen.contains(&id));
+    }
+}
@@ -0,0 +1,210 @@
+use p2p::{PeerId, PublicKey, SecioKeyPair, SessionId};
+use serde_derive::{Deserialize, Serialize};
+
+/// Unique id of a message, the source peer id and the sequence number
+#[derive(Clone, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub struct MessageId(Vec<u8>);
+
+/// A message published to a topic
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Message {
+    /// Message id
+    pub id: MessageId,
+    /// The peer that published the message
+    pub source: PeerId,
+    /// Sequence number of the source
+    pub seqno: u64,
+    /// Topic
+    pub topic: String,
+    /// Payload
+    pub data: Vec<u8>,
+    /// The session that forwarded the message to us
+    pub propagation_source: SessionId,
+}
+
+/// Why a received message is dropped
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum InvalidMessage {
+    InvalidSource,
+    MissingSignature,
+    InvalidSignature,
+    TooLarge,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+pub(crate) struct WireMessage {
+    from: Vec<u8>,
+    seqno: u64,
+    pub topic: String,
+    data: Vec<u8>,
+    signature: Option<Vec<u8>>,
+    /// Raw secp256k1 public key of source
+    key: Option<Vec<u8>>,
+}
+
+impl WireMessage {
+    /// Build a local message, signed if the key pair is given
+    pub fn new(
+        source: &PeerId,
+        seqno: u64,
+        topic: String,
+        data: Vec<u8>,
+        key_pair: Option<&SecioKeyPair>,
+    ) -> Self {
+        let mut message = WireMessage {
+            from: source.as_bytes().to_vec(),
+            seqno,
+            topic,
+            data,
+            signature: None,
+            key: None,
+        };
+        if let Some(key_pair) = key_pair {
+            message.signature = Some(key_pair.sign(&message.signed_bytes()));
+            message.key = Some(key_pair.to_public_key().inner_ref().clone());
+        }
+        message
+    }
+
+    pub fn id(&self) -> MessageId {
+        let mut id = self.from.clone();
+        id.extend_from_slice(&self.seqno.to_be_bytes());
+        MessageId(id)
+    }
+
+    fn signed_bytes(&self) -> Vec<u8> {
+        bincode::serialize(&(&self.from, self.seqno, &self.topic, &self.data))
+            .expect("serialize signed fields")
+    }
+
+    /// Check size and signature
+    pub fn validate(
+        &self,
+        max_size: usize,
+        require_signature: bool,
+    ) -> Result<PeerId, InvalidMessage> {
+        if self.data.len() > max_size {
+            return Err(InvalidMessage::TooLarge);
+        }
+        let source =
+            PeerId::from_bytes(self.from.clone()).map_err(|_| InvalidMessage::InvalidSource)?;
+        match (&self.signature, &self.key) {
+            (Some(signature), Some(key)) => {
+                let key = PublicKey::Secp256k1(key.clone());
+                if !source.is_public_key(&key) || !key.verify(&self.signed_bytes(), signature) {
+                    return Err(InvalidMessage::InvalidSignature);
+                }
+            }
+            (None, None) if !require_signature => (),
+            (None, None) => return Err(InvalidMessage::MissingSignature),
+            _ => return Err(InvalidMessage::InvalidSignature),
+        }
+        Ok(source)
+    }
+
+    pub fn into_message(self, source: PeerId, propagation_source: SessionId) -> Message {
+        Message {
+            id: self.id(),
+            source,
+            seqno: self.seqno,
+            topic: self.topic,
+            data: self.data,
+            propagation_source,
+        }
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub(crate) struct ControlMessage {
+    /// Message ids recently seen of topics
+    pub ihave: Vec<(String, Vec<MessageId>)>,
+    /// Request the messages
+    pub iwant: Vec<MessageId>,
+    /// Join the mesh of topics
+    pub graft: Vec<String>,
+    /// Leave the mesh of topics
+    pub prune: Vec<String>,
+}
+
+impl ControlMessage {
+    pub fn is_empty(&self) -> bool {
+        self.ihave.is_empty()
+            && self.iwant.is_empty()
+            && self.graft.is_empty()
+            && self.prune.is_empty()
+    }
+}
+
+#[derive(Default, Serialize, Deserialize, Debug)]
+pub(crate) struct Rpc {
+    /// Subscribe(true) or unsubscribe(false) topics
+    pub subscriptions: Vec<(bool, String)>,
+    pub messages: Vec<WireMessage>,
+    pub control: ControlMessage,
+}
+
+impl Rpc {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serialize rpc")
+    }
+
+    /// None means the rpc is invalid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{InvalidMessage, Rpc, WireMessage};
+    use p2p::SecioKeyPair;
+
+    #[test]
+    fn sign_and_validate() {
+        let key_pair = SecioKeyPair::secp256k1_generated();
+        let source = key_pair.to_peer_id();
+        let message = WireMessage::new(&source, 1, "topic".to_owned(), vec![1], Some(&key_pair));
+        assert_eq!(message.validate(1, true), Ok(source.clone()));
+        assert_eq!(message.validate(0, true), Err(InvalidMessage::TooLarge));
+
+        let mut forged = message.clone();
+        forged.data = vec![2];
+        assert_eq!(
+            forged.validate(1, true),
+            Err(InvalidMessage::InvalidSignature)
+        );
+
+        // Signed by another key
+        let other = SecioKeyPair::secp256k1_generated();
+        let forged = WireMessage::new(&source, 1, "topic".to_owned(), vec![1], Some(&other));
+        assert_eq!(
+            forged.validate(1, true),
+            Err(InvalidMessage::InvalidSignature)
+        );
+
+        let unsigned = WireMessage::new(&source, 2, "topic".to_owned(), vec![1], None);
+        assert_eq!(unsigned.validate(1, false), Ok(source));
+        assert_eq!(
+            unsigned.validate(1, true),
+            Err(InvalidMessage::MissingSignature)
+        );
+        assert_ne!(unsigned.id(), message.id());
+    }
+
+    #[test]
+    fn encode_and_decode() {
+        let key_pair = SecioKeyPair::secp256k1_generated();
+        let message =
+            WireMessage::new(&key_pair.to_peer_id(), 1, "topic".to_owned(), vec![1], None);
+        let mut rpc = Rpc::default();
+        rpc.subscriptions.push((true, "topic".to_owned()));
+        rpc.control.iwant.push(message.id());
+        rpc.messages.push(message);
+
+        let decoded = Rpc::decode(&rpc.encode()).unwrap();
+        assert_eq!(decoded.subscriptions, rpc.subscriptions);
+        assert_eq!(decoded.control.iwant, rpc.control.iwant);
+        assert_eq!(decoded.messages[0].id(), rpc.messages[0].id());
+        assert!(Rpc::decode(b"invalid").is_none());
+    }
+}
@@ -0,0 +1,1108 @@
+mod protocol;
+mod stream;
+
+pub use crate::protocol::Reason;
+
+use crate::{
+    protocol::{CircuitId, RelayMessage},
+    stream::{CircuitForward, CircuitStream},
+};
+use fnv::FnvHashMap;
+use futures::sync::mpsc::UnboundedSender;
+use generic_channel::Sender;
+use log::debug;
+use p2p::{
+    context::{ServiceContext, ServiceControl, SessionContext},
+    error::Error,
+    multiaddr::{multihash::Multihash, Multiaddr, Protocol},
+    service::ServiceTask,
+    traits::{ProtocolMeta, ServiceProtocol},
+    utils::{extract_peer_id, is_relayed},
+    PeerId, ProtocolId, SessionId, SessionType,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    io, iter,
+    sync::{mpsc, Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::codec::length_delimited::LengthDelimitedCodec;
+
+const TICK_TOKEN: u64 = 0;
+const COMMAND_TOKEN: u64 = 1;
+const TICK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Relay configuration
+#[derive(Clone, Debug)]
+pub struct RelayConfig {
+    /// Serve as relay for other peers
+    pub hop: bool,
+    /// Max peers holding reservation on us
+    pub max_reservations: usize,
+    /// Reservation expires after this, the client renews it at half time
+    pub reservation_ttl: Duration,
+    /// Max circuits relayed by us
+    pub max_circuits: usize,
+    /// Max circuits of the same peer, as source or target
+    pub max_circuits_per_peer: usize,
+    /// A circuit is closed after this
+    pub max_circuit_duration: Duration,
+    /// A circuit is closed after relayed these bytes in its lifetime, both directions
+    pub max_circuit_bytes: u64,
+    /// Max bytes relayed per second by a circuit, both directions,
+    /// the excess is delayed to the next seconds
+    pub max_circuit_rate: u64,
+    /// Timeout of dialing through relay and reserving
+    pub connect_timeout: Duration,
+}
+
+impl Default for RelayConfig {
+    fn default() -> Self {
+        RelayConfig {
+            hop: false,
+            max_reservations: 128,
+            reservation_ttl: Duration::from_secs(60 * 60),
+            max_circuits: 16,
+            max_circuits_per_peer: 4,
+            max_circuit_duration: Duration::from_secs(2 * 60),
+            max_circuit_bytes: 1 << 20,
+            max_circuit_rate: 64 * 1024,
+            connect_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Relay protocol events
+#[derive(Debug)]
+pub enum Event {
+    /// The relay accepts circuits to us, others dial us by the relayed address
+    Reserved {
+        /// Relay peer id
+        relay: PeerId,
+        /// Our relayed address
+        address: Multiaddr,
+    },
+    /// Reservation refused, timeout or lost with the session to relay
+    ReservationFailed {
+        /// Relay peer id
+        relay: PeerId,
+        /// Set if refused by relay
+        reason: Option<Reason>,
+    },
+    /// Dial through relay failed before the circuit opened
+    DialFailed {
+        /// Relayed address
+        address: Multiaddr,
+        /// Set if refused by relay
+        reason: Option<Reason>,
+    },
+    /// We relay a circuit from source to target
+    CircuitOpened {
+        /// Source peer id
+        source: PeerId,
+        /// Target peer id
+        target: PeerId,
+    },
+    /// A circuit we relay is closed
+    CircuitClosed {
+        /// Source peer id
+        source: PeerId,
+        /// Target peer id
+        target: PeerId,
+        /// Set if closed by limits
+        reason: Option<Reason>,
+    },
+}
+
+enum Command {
+    Reserve(PeerId, Multiaddr),
+    CancelReservation(PeerId),
+    Dial(PeerId, Multiaddr),
+}
+
+/// Circuit relay, dial peers behind NAT through a relay by
+/// `/p2p/<relay>/p2p-circuit/p2p/<target>`, and serve as relay if `hop` is set.
+///
+/// A relayed stream is upgraded to a new session by secio and yamux, its address
+/// contains `/p2p-circuit`
+pub struct RelayProtocol<S: Sender<Event> + Send + Clone> {
+    id: ProtocolId,
+    local_peer_id: PeerId,
+    config: RelayConfig,
+    event_sender: S,
+    command_sender: Mutex<mpsc::Sender<Command>>,
+    command_receiver: Mutex<Option<mpsc::Receiver<Command>>>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+}
+
+impl<S> RelayProtocol<S>
+where
+    S: Sender<Event> + Send + Clone,
+{
+    /// New relay protocol
+    pub fn new(
+        id: ProtocolId,
+        local_peer_id: PeerId,
+        config: RelayConfig,
+        event_sender: S,
+    ) -> Self {
+        let (command_sender, command_receiver) = mpsc::channel();
+        RelayProtocol {
+            id,
+            local_peer_id,
+            config,
+            event_sender,
+            command_sender: Mutex::new(command_sender),
+            command_receiver: Mutex::new(Some(command_receiver)),
+            control: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Reserve and dial, works after the service started
+    pub fn handle(&self) -> RelayHandle {
+        RelayHandle {
+            proto_id: self.id,
+            command_sender: self.command_sender.lock().unwrap().clone(),
+            control: Arc::clone(&self.control),
+        }
+    }
+}
+
+impl<S> ProtocolMeta<LengthDelimitedCodec> for RelayProtocol<S>
+where
+    S: Sender<Event> + Send + Clone + 'static,
+{
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        // Only one handle serves the commands
+        let command_receiver = self.command_receiver.lock().unwrap().take()?;
+        let handle = Box::new(RelayHandler {
+            proto_id: self.id,
+            local_peer_id: self.local_peer_id.clone(),
+            config: self.config.clone(),
+            event_sender: self.event_sender.clone(),
+            command_receiver,
+            control: Arc::clone(&self.control),
+            sessions: FnvHashMap::default(),
+            next_id: 0,
+            reservations: HashMap::new(),
+            requests: HashMap::new(),
+            streams: HashMap::new(),
+            reserved: HashMap::new(),
+            circuits: HashMap::new(),
+        });
+        Some(handle)
+    }
+}
+
+/// Reserve on relays and dial through relays
+#[derive(Clone)]
+pub struct RelayHandle {
+    proto_id: ProtocolId,
+    command_sender: mpsc::Sender<Command>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+}
+
+impl RelayHandle {
+    fn send(&mut self, command: Command) -> Result<(), Error<ServiceTask>> {
+        self.command_sender
+            .send(command)
+            .map_err(|_| Error::TaskDisconnect)?;
+        // Wake up the protocol handler, the commands before service started are handled on init
+        match *self.control.lock().unwrap() {
+            Some(ref mut control) => control.send(ServiceTask::ProtocolNotify {
+                proto_id: self.proto_id,
+                token: COMMAND_TOKEN,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Ask the relay to accept circuits to us and keep renewing it, the address must
+    /// contain relay peer id. The relay is dialed if not connected
+    pub fn reserve(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        let relay = extract_peer_id(&address).ok_or_else(invalid_input)?;
+        self.send(Command::Reserve(relay, address))
+    }
+
+    /// Stop renewing the reservation
+    pub fn cancel_reservation(&mut self, relay: PeerId) -> Result<(), Error<ServiceTask>> {
+        self.send(Command::CancelReservation(relay))
+    }
+
+    /// Dial the target through relay, the address is `/p2p/<relay>/p2p-circuit/p2p/<target>`
+    /// and may start with the address of relay, the relay is dialed if not connected.
+    ///
+    /// The new session is reported by `ServiceEvent::SessionOpen` as direct dial, failures
+    /// before the circuit opened are reported by `Event::DialFailed`
+    pub fn dial(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        let relay = relay_addr(&address)
+            .as_ref()
+            .and_then(extract_peer_id)
+            .ok_or_else(invalid_input)?;
+        extract_peer_id(&address).ok_or_else(invalid_input)?;
+        self.send(Command::Dial(relay, address))
+    }
+}
+
+fn invalid_input() -> io::Error {
+    io::ErrorKind::InvalidInput.into()
+}
+
+/// The part of relayed address before `/p2p-circuit`
+fn relay_addr(address: &Multiaddr) -> Option<Multiaddr> {
+    if is_relayed(address) {
+        Some(
+            address
+                .iter()
+                .take_while(|proto| *proto != Protocol::P2pCircuit)
+                .collect(),
+        )
+    } else {
+        None
+    }
+}
+
+/// Relayed address of the peer
+fn circuit_addr(mut relay: Multiaddr, peer_id: Option<&PeerId>) -> Multiaddr {
+    relay.append(Protocol::P2pCircuit);
+    if let Some(peer_id) = peer_id {
+        relay.append(Protocol::P2p(
+            Multihash::from_bytes(peer_id.as_bytes().to_vec()).expect("Invalid peer id"),
+        ));
+    }
+    relay
+}
+
+/// Our reservation on a relay
+struct Reservation {
+    address: Multiaddr,
+    /// The session it reserved on
+    session_id: Option<SessionId>,
+    /// Reserve requested at
+    requested: Option<Instant>,
+    renew_at: Option<Instant>,
+}
+
+/// Our dial through relay, waiting for the circuit
+struct Request {
+    relay: PeerId,
+    address: Multiaddr,
+    /// The session sent connect
+    session_id: Option<SessionId>,
+    started: Instant,
+}
+
+/// A circuit relayed by us
+struct Circuit {
+    source: (SessionId, PeerId),
+    target: (SessionId, PeerId),
+    opened: Instant,
+    bytes: u64,
+    /// Bytes relayed in the current tick
+    tick_bytes: u64,
+    /// Data delayed by the rate limit, with the session to send to
+    delayed: VecDeque<(SessionId, Vec<u8>)>,
+}
+
+impl Circuit {
+    /// The other side of the session
+    fn other(&self, session_id: SessionId) -> Option<SessionId> {
+        if self.source.0 == session_id {
+            Some(self.target.0)
+        } else if self.target.0 == session_id {
+            Some(self.source.0)
+        } else {
+            None
+        }
+    }
+
+    fn contains_peer(&self, peer_id: &PeerId) -> bool {
+        &self.source.1 == peer_id || &self.target.1 == peer_id
+    }
+
+    /// Whether the data can be relayed in this tick, a tick relays at least one message
+    fn allow(&self, len: u64, rate: u64) -> bool {
+        self.tick_bytes == 0 || self.tick_bytes + len <= rate
+    }
+
+    /// Relay the data now if the rate allows, or delay it in order
+    fn relay(&mut self, other: SessionId, data: Vec<u8>, rate: u64) -> Option<Vec<u8>> {
+        let len = data.len() as u64;
+        if self.delayed.is_empty() && self.allow(len, rate) {
+            self.tick_bytes += len;
+            Some(data)
+        } else {
+            self.delayed.push_back((other, data));
+            None
+        }
+    }
+
+    /// A new tick starts, return the delayed data the rate allows now
+    fn next_tick(&mut self, rate: u64) -> Vec<(SessionId, Vec<u8>)> {
+        self.tick_bytes = 0;
+        let mut ready = Vec::new();
+        while let Some(len) = self.delayed.front().map(|(_, data)| data.len() as u64) {
+            if !self.allow(len, rate) {
+                break;
+            }
+            self.tick_bytes += len;
+            ready.extend(self.delayed.pop_front());
+        }
+        ready
+    }
+}
+
+struct RelayHandler<S: Sender<Event>> {
+    proto_id: ProtocolId,
+    local_peer_id: PeerId,
+    config: RelayConfig,
+    event_sender: S,
+    command_receiver: mpsc::Receiver<Command>,
+    control: Arc<Mutex<Option<ServiceControl>>>,
+    /// Direct sessions with secio
+    sessions: FnvHashMap<SessionId, PeerId>,
+    /// Request id as client, circuit id as relay
+    next_id: u64,
+
+    reservations: HashMap<PeerId, Reservation>,
+    requests: HashMap<u64, Request>,
+    /// Data receivers of our circuit streams
+    streams: HashMap<(SessionId, CircuitId), UnboundedSender<Vec<u8>>>,
+
+    /// Reservations on us, with the session and expiration
+    reserved: HashMap<PeerId, (SessionId, Instant)>,
+    circuits: HashMap<CircuitId, Circuit>,
+}
+
+impl<S> RelayHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn send_message(
+        &self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: &RelayMessage,
+    ) -> bool {
+        control
+            .send_message(session_id, self.proto_id, message.encode())
+            .is_ok()
+    }
+
+    fn peer_session(&self, peer_id: &PeerId) -> Option<SessionId> {
+        self.sessions
+            .iter()
+            .find(|(_, id)| *id == peer_id)
+            .map(|(session_id, _)| *session_id)
+    }
+
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn handle_commands(&mut self, control: &mut ServiceContext) {
+        while let Ok(command) = self.command_receiver.try_recv() {
+            match command {
+                Command::Reserve(relay, address) => self.reserve(control, relay, address),
+                Command::CancelReservation(relay) => {
+                    self.reservations.remove(&relay);
+                }
+                Command::Dial(relay, address) => self.dial(control, relay, address),
+            }
+        }
+    }
+
+    fn reserve(&mut self, control: &mut ServiceContext, relay: PeerId, address: Multiaddr) {
+        if self.reservations.contains_key(&relay) {
+            return;
+        }
+        let session_id = self.peer_session(&relay);
+        let mut reservation = Reservation {
+            address: address.clone(),
+            session_id,
+            requested: Some(Instant::now()),
+            renew_at: None,
+        };
+        match session_id {
+            Some(session_id) => {
+                self.send_message(control, session_id, &RelayMessage::Reserve);
+            }
+            None => {
+                if control.dial(address).is_err() {
+                    reservation.requested = None;
+                }
+            }
+        }
+        if reservation.requested.is_some() {
+            self.reservations.insert(relay, reservation);
+        } else {
+            let _ = self.event_sender.try_send(Event::ReservationFailed {
+                relay,
+                reason: None,
+            });
+        }
+    }
+
+    fn dial(&mut self, control: &mut ServiceContext, relay: PeerId, address: Multiaddr) {
+        let id = self.next_id();
+        let session_id = self.peer_session(&relay);
+        match session_id {
+            Some(session_id) => {
+                self.send_connect(control, session_id, id, &address);
+            }
+            None => {
+                let dialed = relay_addr(&address)
+                    .filter(|relay| multiaddr_has_transport(relay))
+                    .map(|relay| control.dial(relay).is_ok())
+                    .unwrap_or(false);
+                if !dialed {
+                    debug!("relay of {} is not connected", address);
+                    let _ = self.event_sender.try_send(Event::DialFailed {
+                        address,
+                        reason: None,
+                    });
+                    return;
+                }
+            }
+        }
+        self.requests.insert(
+            id,
+            Request {
+                relay,
+                address,
+                session_id,
+                started: Instant::now(),
+            },
+        );
+    }
+
+    fn send_connect(
+        &self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        request: u64,
+        address: &Multiaddr,
+    ) {
+        let target = extract_peer_id(address).expect("checked by handle");
+        self.send_message(
+            control,
+            session_id,
+            &RelayMessage::Connect {
+                request,
+                target: target.as_bytes().to_vec(),
+            },
+        );
+    }
+
+    /// Upgrade a circuit stream to session
+    fn open_stream(
+        &mut self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        circuit: CircuitId,
+        address: Multiaddr,
+        ty: SessionType,
+    ) {
+        let (stream, incoming, outgoing) = CircuitStream::new();
+        let forward = CircuitForward::new(
+            outgoing,
+            control.control().clone(),
+            session_id,
+            self.proto_id,
+            circuit,
+        );
+        if control.future_task(forward).is_err()
+            || control.upgrade_stream(stream, address, ty).is_err()
+        {
+            self.send_message(
+                control,
+                session_id,
+                &RelayMessage::Close {
+                    circuit,
+                    reason: None,
+                },
+            );
+            return;
+        }
+        self.streams.insert((session_id, circuit), incoming);
+    }
+
+    /// Handle the messages of client
+    fn handle_client_message(
+        &mut self,
+        control: &mut ServiceContext,
+        session_id: SessionId,
+        message: RelayMessage,
+    ) {
+        let relay = match self.sessions.get(&session_id) {
+            Some(relay) => relay.clone(),
+            None => return,
+        };
+        match message {
+            RelayMessage::ReserveOk { ttl } => {
+                if let Some(reservation) = self.reservations.get_mut(&relay) {
+                    let ttl = Duration::from_secs(ttl);
+                    reservation.session_id = Some(session_id);
+                    reservation.requested = None;
+                    reservation.renew_at = Some(Instant::now() + ttl / 2);
+                    let address =
+                        circuit_addr(reservation.address.clone(), Some(&self.local_peer_id));
+                    debug!("reserved on relay, address: {}", address);
+                    let _ = self
+                        .event_sender
+                        .try_send(Event::Reserved { relay, address });
+                }
+            }
+            RelayMessage::ReserveRefused { reason } => {
+                if self.reservations.remove(&relay).is_some() {
+                    let _ = self.event_sender.try_send(Event::ReservationFailed {
+                        relay,
+                        reason: Some(reason),
+                    });
+                }
+            }
+            RelayMessage::Connected { request, circuit } => {
+                match self
+                    .requests
+                    .remove(&request)
+                    .filter(|request| request.session_id == Some(session_id))
+                {
+                    Some(request) => {
+                        self.open_stream(
+                            control,
+                            session_id,
+                            circuit,
+                            request.address,
+                            SessionType::Client,
+                        );
+                    }
+                    None => {
+                        // Timeout
+                        self.send_message(
+                            control,
+                            session_id,
+                            &RelayMessage::Close {
+                                circuit,
+                                reason: None,
+                            },
+                        );
+                    }
+                }
+            }
+            RelayMessage::ConnectRefused { request, reason } => {
+                if let Some(request) = self.requests.remove(&request) {
+                    let _ = self.event_sender.try_send(Event::DialFailed {
+                        address: request.address,
+                        reason: Some(reason),
+                    });
+                }
+            }
+            RelayMessage::Incoming { circuit } => {
+                let reserved = self
+                    .reservations
+                    .get(&relay)
+                    .map(|reservation| reservation.session_id == Some(session_id))
+                    .unwrap_or(false);
+                if reserved {
+                    let relay_address = iter::once(Protocol::P2p(
+                        Multihash::from_bytes(relay.as_bytes().to_vec()).expect("Invalid peer id"),
+                    ))
+                    .collect();
+                    let address = circuit_addr(relay_address, None);
+                    // Peer id of the source is appended after handshake
+                    self.open_stream(control, session_id, circuit, address, SessionType::Server);
+                } else {
+                    self.send_message(
+                        control,
+                        session_id,
+                        &RelayMessage::Close {
+                            circuit,
+                            reason: Some(Reason::NoReservation),
+                        },
+                    );
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Handle the messages to relay
+    fn handle_hop_message(
+        &mut self,
+        control: &mut ServiceContext,
+        session: &SessionContext,
+        message: RelayMessage,
+    ) {
+        let peer_id = match self.sessions.get(&session.id) {
+            Some(peer_id) if self.config.hop => peer_id.clone(),
+            _ => {
+                let reply = match message {
+                    RelayMessage::Reserve => RelayMessage::ReserveRefused {
+                        reason: Reason::NotRelay,
+                    },
+                    RelayMessage::Connect { request, .. } => RelayMessage::ConnectRefused {
+                        request,
+                        reason: Reason::NotRelay,
+                    },
+                    _ => return,
+                };
+                self.send_message(control, session.id, &reply);
+                return;
+            }
+        };
+        match message {
+            RelayMessage::Reserve => {
+                let reply = if !self.reserved.contains_key(&peer_id)
+                    && self.reserved.len() >= self.config.max_reservations
+                {
+                    RelayMessage::ReserveRefused {
+                        reason: Reason::ResourceLimit,
+                    }
+                } else {
+                    debug!("session [{}] reserved", session.id);
+                    self.reserved.insert(
+                        peer_id,
+                        (session.id, Instant::now() + self.config.reservation_ttl),
+                    );
+                    RelayMessage::ReserveOk {
+                        ttl: self.config.reservation_ttl.as_secs(),
+                    }
+                };
+                self.send_message(control, session.id, &reply);
+            }
+            RelayMessage::Connect { request, target } => {
+                match self.connect(session.id, peer_id, target) {
+                    Ok(circuit) => {
+                        let target = self.circuits[&circuit].target.clone();
+                        self.send_message(control, target.0, &RelayMessage::Incoming { circuit });
+                        self.send_message(
+                            control,
+                            session.id,
+                            &RelayMessage::Connected { request, circuit },
+                        );
+                        let source = self.circuits[&circuit].source.1.clone();
+                        let _ = self.event_sender.try_send(Event::CircuitOpened {
+                            source,
+                            target: target.1,
+                        });
+                    }
+                    Err(reason) => {
+                        self.send_message(
+                            control,
+                            session.id,
+                            &RelayMessage::ConnectRefused { request, reason },
+                        );
+                    }
+                }
+            }
+            RelayMessage::Data { circuit, data } => {
+                let rate = self.config.max_circuit_rate;
+                let (other, exceeded, data) = match self.circuits.get_mut(&circuit) {
+                    Some(relayed) => match relayed.other(session.id) {
+                        Some(other) => {
+                            relayed.bytes += data.len() as u64;
+                            let exceeded = relayed.bytes > self.config.max_circuit_bytes;
+                            let data = if exceeded {
+                                None
+                            } else {
+                                relayed.relay(other, data, rate)
+                            };
+                            (other, exceeded, data)
+                        }
+                        None => return,
+                    },
+                    None => return,
+                };
+                if exceeded {
+                    self.close_circuit(control, circuit, Some(Reason::DataLimit), None);
+                } else if let Some(data) = data {
+                    if !self.send_message(control, other, &RelayMessage::Data { circuit, data }) {
+                        self.close_circuit(control, circuit, None, None);
+                    }
+                }
+            }
+            RelayMessage::Close { circuit, .. } => {
+                let closed_by = self
+                    .circuits
+                    .get(&circuit)
+                    .and_then(|relayed| relayed.other(session.id).map(|_| session.id));
+                if closed_by.is_some() {
+                    self.close_circuit(control, circuit, None, closed_by);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Open a circuit from the source to target
+    fn connect(
+        &mut self,
+        session_id: SessionId,
+        source: PeerId,
+        target: Vec<u8>,
+    ) -> Result<CircuitId, Reason> {
+        let now = Instant::now();
+        let target = PeerId::from_bytes(target).map_err(|_| Reason::NoReservation)?;
+        let target_session = match self.reserved.get(&target) {
+            Some(&(target_session, expires)) if expires > now && target != source => target_session,
+            _ => return Err(Reason::NoReservation),
+        };
+        let peer_circuits = |peer_id: &PeerId| {
+            self.circuits
+                .values()
+                .filter(|circuit| circuit.contains_peer(peer_id))
+                .count()
+        };
+        if self.circuits.len() >= self.config.max_circuits
+            || peer_circuits(&source) >= self.config.max_circuits_per_peer
+            || peer_circuits(&target) >= self.config.max_circuits_per_peer
+        {
+            return Err(Reason::ResourceLimit);
+        }
+
+        let circuit = self.next_id();
+        debug!(
+            "open circuit [{}] from session [{}] to session [{}]",
+            circuit, session_id, target_session
+        );
+        self.circuits.insert(
+            circuit,
+            Circuit {
+                source: (session_id, source),
+                target: (target_session, target),
+                opened: now,
+                bytes: 0,
+                tick_bytes: 0,
+                delayed: VecDeque::new(),
+            },
+        );
+        Ok(circuit)
+    }
+
+    /// Close the circuit relayed by us, tell both sides except the one closed it
+    fn close_circuit(
+        &mut self,
+        control: &mut ServiceContext,
+        circuit: CircuitId,
+        reason: Option<Reason>,
+        closed_by: Option<SessionId>,
+    ) {
+        let relayed = match self.circuits.remove(&circuit) {
+            Some(relayed) => relayed,
+            None => return,
+        };
+        debug!("close circuit [{}], reason: {:?}", circuit, reason);
+        for session_id in &[relayed.source.0, relayed.target.0] {
+            if Some(*session_id) != closed_by {
+                self.send_message(
+                    control,
+                    *session_id,
+                    &RelayMessage::Close { circuit, reason },
+                );
+            }
+        }
+        let _ = self.event_sender.try_send(Event::CircuitClosed {
+            source: relayed.source.1,
+            target: relayed.target.1,
+            reason,
+        });
+    }
+
+    fn tick(&mut self, control: &mut ServiceContext) {
+        let now = Instant::now();
+
+        // Client
+        let timeout = self.config.connect_timeout;
+        let mut renew = Vec::new();
+        let mut failed = Vec::new();
+        for (relay, reservation) in self.reservations.iter_mut() {
+            if let Some(requested) = reservation.requested {
+                if requested + timeout <= now {
+                    failed.push(relay.clone());
+                }
+            } else if reservation.renew_at.map(|at| at <= now).unwrap_or(false) {
+                if let Some(session_id) = reservation.session_id {
+                    reservation.requested = Some(now);
+                    reservation.renew_at = None;
+                    renew.push(session_id);
+                }
+            }
+        }
+        for session_id in renew {
+            self.send_message(control, session_id, &RelayMessage::Reserve);
+        }
+        for relay in failed {
+            self.reservations.remove(&relay);
+            let _ = self.event_sender.try_send(Event::ReservationFailed {
+                relay,
+                reason: None,
+            });
+        }
+        let expired = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.started + timeout <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in expired {
+            if let Some(request) = self.requests.remove(&id) {
+                let _ = self.event_sender.try_send(Event::DialFailed {
+                    address: request.address,
+                    reason: None,
+                });
+            }
+        }
+
+        // Relay
+        self.reserved.retain(|_, (_, expires)| *expires > now);
+        let max_duration = self.config.max_circuit_duration;
+        let expired = self
+            .circuits
+            .iter()
+            .filter(|(_, circuit)| circuit.opened + max_duration <= now)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for circuit in expired {
+            self.close_circuit(control, circuit, Some(Reason::DurationLimit), None);
+        }
+
+        // Relay the data delayed by the rate limit
+        let rate = self.config.max_circuit_rate;
+        let mut ready = Vec::new();
+        for (id, circuit) in self.circuits.iter_mut() {
+            ready.extend(
+                circuit
+                    .next_tick(rate)
+                    .into_iter()
+                    .map(|(other, data)| (*id, other, data)),
+            );
+        }
+        for (circuit, other, data) in ready {
+            if self.circuits.contains_key(&circuit)
+                && !self.send_message(control, other, &RelayMessage::Data { circuit, data })
+            {
+                self.close_circuit(control, circuit, None, None);
+            }
+        }
+    }
+}
+
+/// Whether the address can be dialed directly
+fn multiaddr_has_transport(address: &Multiaddr) -> bool {
+    address.iter().any(|proto| match proto {
+        Protocol::Ip4(_) | Protocol::Ip6(_) | Protocol::Dns4(_) | Protocol::Dns6(_) => true,
+        _ => false,
+    })
+}
+
+impl<S> ServiceProtocol for RelayHandler<S>
+where
+    S: Sender<Event>,
+{
+    fn init(&mut self, control: &mut ServiceContext) {
+        *self.control.lock().unwrap() = Some(control.control().clone());
+        control.set_service_notify(self.proto_id, TICK_INTERVAL, TICK_TOKEN);
+        self.handle_commands(control);
+    }
+
+    fn connected(&mut self, control: &mut ServiceContext, session: &SessionContext, version: &str) {
+        debug!(
+            "proto id [{}] open on session [{}], address: [{}], type: [{:?}], version: {}",
+            self.proto_id, session.id, session.address, session.ty, version
+        );
+        // Circuits over relayed session are not supported
+        let peer_id = match session.remote_pubkey {
+            Some(ref key) if !is_relayed(&session.address) => key.peer_id(),
+            _ => return,
+        };
+        self.sessions.insert(session.id, peer_id.clone());
+
+        if let Some(reservation) = self.reservations.get_mut(&peer_id) {
+            if reservation.session_id.is_none() {
+                reservation.session_id = Some(session.id);
+                self.send_message(control, session.id, &RelayMessage::Reserve);
+            }
+        }
+        let waiting = self
+            .requests
+            .iter_mut()
+            .filter(|(_, request)| request.relay == peer_id && request.session_id.is_none())
+            .map(|(id, request)| {
+                request.session_id = Some(session.id);
+                (*id, request.address.clone())
+            })
+            .collect::<Vec<_>>();
+        for (id, address) in waiting {
+            self.send_connect(control, session.id, id, &address);
+        }
+    }
+
+    fn disconnected(&mut self, control: &mut ServiceContext, session: &SessionContext) {
+        debug!(
+            "proto id [{}] close on session [{}]",
+            self.proto_id, session.id
+        );
+        let peer_id = match self.sessions.remove(&session.id) {
+            Some(peer_id) => peer_id,
+            None => return,
+        };
+
+        // Client, streams see EOF
+        self.streams
+            .retain(|(session_id, _), _| *session_id != session.id);
+        let lost = self
+            .reservations
+            .get(&peer_id)
+            .map(|reservation| reservation.session_id == Some(session.id))
+            .unwrap_or(false);
+        if lost {
+            self.reservations.remove(&peer_id);
+            let _ = self.event_sender.try_send(Event::ReservationFailed {
+                relay: peer_id.clone(),
+                reason: None,
+            });
+        }
+        let failed = self
+            .requests
+            .iter()
+            .filter(|(_, request)| request.session_id == Some(session.id))
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in failed {
+            if let Some(request) = self.requests.remove(&id) {
+                let _ = self.event_sender.try_send(Event::DialFailed {
+                    address: request.address,
+                    reason: None,
+                });
+            }
+        }
+
+        // Relay
+        if self
+            .reserved
+            .get(&peer_id)
+            .map(|(session_id, _)| *session_id == session.id)
+            .unwrap_or(false)
+        {
+            self.reserved.remove(&peer_id);
+        }
+        let closed = self
+            .circuits
+            .iter()
+            .filter(|(_, circuit)| circuit.other(session.id).is_some())
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for circuit in closed {
+            self.close_circuit(control, circuit, None, Some(session.id));
+        }
+    }
+
+    fn received(&mut self, control: &mut ServiceContext, session: &SessionContext, data: Vec<u8>) {
+        let message = match RelayMessage::decode(&data) {
+            Some(message) => message,
+            None => {
+                debug!("session [{}] sent invalid relay message", session.id);
+                let _ = control.disconnect(session.id);
+                return;
+            }
+        };
+
+        match message {
+            RelayMessage::Data { circuit, data } => {
+                if let Some(sender) = self.streams.get(&(session.id, circuit)) {
+                    if sender.unbounded_send(data).is_err() {
+                        // Stream closed, its forward sends close
+                        self.streams.remove(&(session.id, circuit));
+                    }
+                } else {
+                    self.handle_hop_message(control, session, RelayMessage::Data { circuit, data });
+                }
+            }
+            RelayMessage::Close { circuit, reason } => {
+                if self.streams.remove(&(session.id, circuit)).is_none() {
+                    self.handle_hop_message(
+                        control,
+                        session,
+                        RelayMessage::Close { circuit, reason },
+                    );
+                } else {
+                    debug!("circuit [{}] closed, reason: {:?}", circuit, reason);
+                }
+            }
+            RelayMessage::Reserve | RelayMessage::Connect { .. } => {
+                self.handle_hop_message(control, session, message)
+            }
+            message => self.handle_client_message(control, session.id, message),
+        }
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, token: u64) {
+        match token {
+            TICK_TOKEN => {
+                self.handle_commands(control);
+                self.tick(control);
+            }
+            COMMAND_TOKEN => self.handle_commands(control),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{circuit_addr, relay_addr, Circuit};
+    use p2p::{multiaddr::Multiaddr, utils::extract_peer_id, SecioKeyPair};
+    use std::{collections::VecDeque, time::Instant};
+
+    #[test]
+    fn parse_relayed_address() {
+        let relay = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let target = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let relay_address: Multiaddr = format!("/ip4/127.0.0.1/tcp/1337/p2p/{}", relay.to_base58())
+            .parse()
+            .unwrap();
+        let address = circuit_addr(relay_address.clone(), Some(&target));
+        assert_eq!(
+            address.to_string(),
+            format!("{}/p2p-circuit/p2p/{}", relay_address, target.to_base58())
+        );
+        assert_eq!(relay_addr(&address), Some(relay_address.clone()));
+        assert_eq!(extract_peer_id(&address), Some(target));
+        assert_eq!(relay_addr(&relay_address), None);
+    }
+
+    #[test]
+    fn delay_data_over_rate() {
+        let peer_id = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let mut circuit = Circuit {
+            source: (1, peer_id.clone()),
+            target: (2, peer_id),
+            opened: Instant::now(),
+            bytes: 0,
+            tick_bytes: 0,
+            delayed: VecDeque::new(),
+        };
+        let target = 2;
+        assert_eq!(circuit.relay(target, vec![0; 6], 10), Some(vec![0; 6]));
+        assert_eq!(circuit.relay(target, vec![1; 6], 10), None);
+        // Keep the order after delayed
+        assert_eq!(circuit.relay(target, vec![2; 1], 10), None);
+
+        assert_eq!(
+            circuit.next_tick(10),
+            vec![(target, vec![1; 6]), (target, vec![2; 1])]
+        );
+        // A message larger than the rate is relayed alone in a tick
+        assert_eq!(circuit.relay(target, vec![3; 20], 10), None);
+        assert_eq!(circuit.next_tick(10), vec![(target, vec![3; 20])]);
+        assert!(circuit.next_tick(10).is_empty());
+    }
+}
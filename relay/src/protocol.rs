@@ -0,0 +1,97 @@
+use serde_derive::{Deserialize, Serialize};
+
+/// Circuit id, assigned by the relay
+pub(crate) type CircuitId = u64;
+
+/// Why the relay refused or closed a circuit or a reservation
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reason {
+    /// The peer doesn't serve as relay
+    NotRelay,
+    /// The target has no reservation on the relay
+    NoReservation,
+    /// Too many reservations or circuits
+    ResourceLimit,
+    /// The circuit relayed too many bytes
+    DataLimit,
+    /// The circuit lasted too long
+    DurationLimit,
+}
+
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub(crate) enum RelayMessage {
+    /// Ask the relay to accept circuits for us
+    Reserve,
+    /// Reservation accepted, valid for seconds
+    ReserveOk {
+        ttl: u64,
+    },
+    ReserveRefused {
+        reason: Reason,
+    },
+    /// Open a circuit to target, the request id matches the reply
+    Connect {
+        request: u64,
+        target: Vec<u8>,
+    },
+    Connected {
+        request: u64,
+        circuit: CircuitId,
+    },
+    ConnectRefused {
+        request: u64,
+        reason: Reason,
+    },
+    /// A circuit from another peer, sent to the target
+    Incoming {
+        circuit: CircuitId,
+    },
+    Data {
+        circuit: CircuitId,
+        data: Vec<u8>,
+    },
+    /// The reason is set if the relay closed it
+    Close {
+        circuit: CircuitId,
+        reason: Option<Reason>,
+    },
+}
+
+impl RelayMessage {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("serialize relay message")
+    }
+
+    /// None means the message is invalid
+    pub fn decode(data: &[u8]) -> Option<Self> {
+        bincode::deserialize(data).ok()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reason, RelayMessage};
+
+    #[test]
+    fn encode_and_decode() {
+        let messages = vec![
+            RelayMessage::Reserve,
+            RelayMessage::Connect {
+                request: 1,
+                target: vec![1, 2, 3],
+            },
+            RelayMessage::Data {
+                circuit: 2,
+                data: vec![4, 5],
+            },
+            RelayMessage::Close {
+                circuit: 2,
+                reason: Some(Reason::DataLimit),
+            },
+        ];
+        for message in messages {
+            assert_eq!(RelayMessage::decode(&message.encode()), Some(message));
+        }
+        assert_eq!(RelayMessage::decode(b"invalid"), None);
+    }
+}
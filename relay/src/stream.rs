@@ -0,0 +1,237 @@
+use futures::{prelude::*, sync::mpsc, AsyncSink};
+use log::debug;
+use p2p::{context::ServiceControl, error::Error, service::ServiceTask, ProtocolId, SessionId};
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+use tokio::{
+    prelude::{AsyncRead, AsyncWrite},
+    timer::Delay,
+};
+
+use crate::protocol::{CircuitId, RelayMessage};
+
+/// Chunks buffered for sending on a circuit
+const CIRCUIT_BUFFER_SIZE: usize = 16;
+/// Wait for the service task channel full
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Byte stream of a circuit, carried by the data messages of relay protocol
+pub(crate) struct CircuitStream {
+    incoming: mpsc::UnboundedReceiver<Vec<u8>>,
+    read_buf: Vec<u8>,
+    read_pos: usize,
+    /// None after shutdown
+    outgoing: Option<mpsc::Sender<Vec<u8>>>,
+}
+
+impl CircuitStream {
+    /// Return the stream, the sender of received data and the receiver of data to send
+    pub fn new() -> (
+        Self,
+        mpsc::UnboundedSender<Vec<u8>>,
+        mpsc::Receiver<Vec<u8>>,
+    ) {
+        let (incoming_sender, incoming) = mpsc::unbounded();
+        let (outgoing, outgoing_receiver) = mpsc::channel(CIRCUIT_BUFFER_SIZE);
+        let stream = CircuitStream {
+            incoming,
+            read_buf: Vec::new(),
+            read_pos: 0,
+            outgoing: Some(outgoing),
+        };
+        (stream, incoming_sender, outgoing_receiver)
+    }
+}
+
+impl io::Read for CircuitStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.read_pos < self.read_buf.len() {
+                let n = buf.len().min(self.read_buf.len() - self.read_pos);
+                buf[..n].copy_from_slice(&self.read_buf[self.read_pos..self.read_pos + n]);
+                self.read_pos += n;
+                return Ok(n);
+            }
+            match self.incoming.poll() {
+                Ok(Async::Ready(Some(data))) => {
+                    self.read_buf = data;
+                    self.read_pos = 0;
+                }
+                // Circuit closed
+                Ok(Async::Ready(None)) | Err(_) => return Ok(0),
+                Ok(Async::NotReady) => return Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+}
+
+impl AsyncRead for CircuitStream {}
+
+impl io::Write for CircuitStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let sender = match self.outgoing {
+            Some(ref mut sender) => sender,
+            None => return Err(io::ErrorKind::BrokenPipe.into()),
+        };
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match sender.start_send(buf.to_vec()) {
+            Ok(AsyncSink::Ready) => Ok(buf.len()),
+            Ok(AsyncSink::NotReady(_)) => Err(io::ErrorKind::WouldBlock.into()),
+            Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.outgoing {
+            Some(ref mut sender) => match sender.poll_complete() {
+                Ok(Async::Ready(())) => Ok(()),
+                Ok(Async::NotReady) => Err(io::ErrorKind::WouldBlock.into()),
+                Err(_) => Err(io::ErrorKind::BrokenPipe.into()),
+            },
+            None => Ok(()),
+        }
+    }
+}
+
+impl AsyncWrite for CircuitStream {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        // The circuit is closed after the buffered data sent
+        self.outgoing.take();
+        Ok(Async::Ready(()))
+    }
+}
+
+/// Send the data of circuit stream as relay messages, close the circuit when the stream closed
+pub(crate) struct CircuitForward {
+    outgoing: mpsc::Receiver<Vec<u8>>,
+    control: ServiceControl,
+    session_id: SessionId,
+    proto_id: ProtocolId,
+    circuit: CircuitId,
+    /// Task waiting for the full service task channel
+    pending: Option<ServiceTask>,
+    retry: Option<Delay>,
+    closed: bool,
+}
+
+impl CircuitForward {
+    pub fn new(
+        outgoing: mpsc::Receiver<Vec<u8>>,
+        control: ServiceControl,
+        session_id: SessionId,
+        proto_id: ProtocolId,
+        circuit: CircuitId,
+    ) -> Self {
+        CircuitForward {
+            outgoing,
+            control,
+            session_id,
+            proto_id,
+            circuit,
+            pending: None,
+            retry: None,
+            closed: false,
+        }
+    }
+
+    fn task(&self, message: RelayMessage) -> ServiceTask {
+        ServiceTask::ProtocolMessage {
+            session_ids: Some(vec![self.session_id]),
+            proto_id: self.proto_id,
+            data: message.encode(),
+        }
+    }
+}
+
+impl Future for CircuitForward {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        loop {
+            if let Some(ref mut retry) = self.retry {
+                if let Ok(Async::NotReady) = retry.poll() {
+                    return Ok(Async::NotReady);
+                }
+            }
+            self.retry = None;
+
+            if let Some(task) = self.pending.take() {
+                match self.control.send(task) {
+                    Ok(()) => (),
+                    Err(Error::TaskFull(task)) => {
+                        self.pending = Some(task);
+                        self.retry = Some(Delay::new(Instant::now() + RETRY_INTERVAL));
+                        continue;
+                    }
+                    Err(err) => {
+                        debug!("circuit [{}] send error: {}", self.circuit, err);
+                        return Err(());
+                    }
+                }
+            }
+            if self.closed {
+                return Ok(Async::Ready(()));
+            }
+
+            let message = match self.outgoing.poll() {
+                Ok(Async::Ready(Some(data))) => RelayMessage::Data {
+                    circuit: self.circuit,
+                    data,
+                },
+                Ok(Async::Ready(None)) | Err(_) => {
+                    self.closed = true;
+                    RelayMessage::Close {
+                        circuit: self.circuit,
+                        reason: None,
+                    }
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+            };
+            self.pending = Some(self.task(message));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::CircuitStream;
+    use futures::{future::lazy, prelude::*};
+    use std::io::{self, Read, Write};
+    use tokio::prelude::AsyncWrite;
+
+    #[test]
+    fn read_and_write() {
+        lazy(|| {
+            let (mut stream, incoming, mut outgoing) = CircuitStream::new();
+            let mut buf = [0; 3];
+            assert_eq!(
+                stream.read(&mut buf).unwrap_err().kind(),
+                io::ErrorKind::WouldBlock
+            );
+
+            incoming.unbounded_send(vec![1, 2, 3, 4]).unwrap();
+            assert_eq!(stream.read(&mut buf).unwrap(), 3);
+            assert_eq!(buf, [1, 2, 3]);
+            assert_eq!(stream.read(&mut buf).unwrap(), 1);
+            assert_eq!(buf[0], 4);
+
+            assert_eq!(stream.write(&[5, 6]).unwrap(), 2);
+            assert_eq!(outgoing.poll(), Ok(Async::Ready(Some(vec![5, 6]))));
+
+            // Closed by remote
+            drop(incoming);
+            assert_eq!(stream.read(&mut buf).unwrap(), 0);
+
+            stream.shutdown().unwrap();
+            assert_eq!(outgoing.poll(), Ok(Async::Ready(None)));
+            Ok::<_, ()>(())
+        })
+        .wait()
+        .unwrap();
+    }
+}
@@ -41,6 +41,8 @@ pub struct SecureStream<T> {
     event_sender: Sender<StreamEvent>,
     // For receive events from sub streams
     event_receiver: Receiver<StreamEvent>,
+    // Capacity of the event channel and the frame channel
+    channel_size: usize,
 
     notify: Option<Task>,
 }
@@ -57,8 +59,9 @@ where
         encode_cipher: StreamCipher,
         encode_hmac: Hmac,
         nonce: Vec<u8>,
+        channel_size: usize,
     ) -> Self {
-        let (event_sender, event_receiver) = mpsc::channel(128);
+        let (event_sender, event_receiver) = mpsc::channel(channel_size);
         SecureStream {
             socket,
             dead: false,
@@ -72,6 +75,7 @@ where
             frame_sender: None,
             event_sender,
             event_receiver,
+            channel_size,
             notify: None,
         }
     }
@@ -83,7 +87,7 @@ where
         if self.frame_sender.is_some() {
             return Err(());
         }
-        let (frame_sender, frame_receiver) = mpsc::channel(128);
+        let (frame_sender, frame_receiver) = mpsc::channel(self.channel_size);
         self.frame_sender = Some(frame_sender);
         Ok(StreamHandle::new(frame_receiver, self.event_sender.clone()))
     }
@@ -381,6 +385,7 @@ mod tests {
                     ctr_init(cipher, &cipher_key_clone[..key_size], &NULL_IV[..]),
                     Hmac::from_key(Digest::Sha256, &hmac_key_clone),
                     nonce2,
+                    128,
                 );
                 let handle = secure.create_handle().unwrap();
 
@@ -404,6 +409,7 @@ mod tests {
                     ctr_init(cipher, &cipher_key_clone[..key_size], &NULL_IV[..]),
                     Hmac::from_key(Digest::Sha256, &hmac_key_clone),
                     Vec::new(),
+                    128,
                 );
                 let mut handle = secure.create_handle().unwrap();
                 tokio::spawn(secure.for_each(|_| Ok(())).map_err(|_| ()));
@@ -2,9 +2,10 @@ use crate::handshake::handshake_generated::p2p::handshake::{
     Exchange as FBSExchange, ExchangeBuilder, Propose as FBSPropose, ProposeBuilder,
     PublicKey as FBSPublicKey, PublicKeyBuilder, Type,
 };
-use crate::peer_id::PeerId;
+use crate::{peer_id::PeerId, SECP256K1};
 
 use flatbuffers::{get_root, FlatBufferBuilder};
+use sha2::{Digest, Sha256};
 
 #[derive(Clone, Default, PartialEq, Ord, PartialOrd, Eq, Debug)]
 pub struct Propose {
@@ -150,6 +151,25 @@ impl PublicKey {
     pub fn peer_id(&self) -> PeerId {
         PeerId::from_public_key(self)
     }
+
+    /// Verify the DER encoded signature of the sha256 digest of data
+    pub fn verify(&self, data: &[u8], signature: &[u8]) -> bool {
+        let digest = Sha256::digest(data);
+        let message = match secp256k1::Message::from_slice(digest.as_ref()) {
+            Ok(message) => message,
+            Err(_) => return false,
+        };
+        let signature = secp256k1::Signature::from_der(signature);
+        let public_key = match self {
+            PublicKey::Secp256k1(ref key) => secp256k1::key::PublicKey::from_slice(key),
+        };
+        match (signature, public_key) {
+            (Ok(signature), Ok(public_key)) => {
+                SECP256K1.verify(&message, &signature, &public_key).is_ok()
+            }
+            _ => false,
+        }
+    }
 }
 
 #[cfg(test)]
@@ -166,6 +186,19 @@ mod tests {
         assert_eq!(raw, PublicKey::decode(&byte).unwrap())
     }
 
+    #[test]
+    fn sign_and_verify() {
+        let key_pair = SecioKeyPair::secp256k1_generated();
+        let signature = key_pair.sign(b"data");
+
+        let public_key = key_pair.to_public_key();
+        assert!(public_key.verify(b"data", &signature));
+        assert!(!public_key.verify(b"other data", &signature));
+        assert!(!SecioKeyPair::secp256k1_generated()
+            .to_public_key()
+            .verify(b"data", &signature));
+    }
+
     #[test]
     fn decode_encode_propose() {
         let nonce: [u8; 16] = rand::random();
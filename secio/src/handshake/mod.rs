@@ -23,6 +23,7 @@ pub struct Config {
     pub(crate) ciphers_proposal: Option<String>,
     pub(crate) digests_proposal: Option<String>,
     pub(crate) max_frame_length: usize,
+    pub(crate) channel_size: usize,
 }
 
 impl Config {
@@ -34,6 +35,7 @@ impl Config {
             ciphers_proposal: None,
             digests_proposal: None,
             max_frame_length: 1024 * 1024 * 8,
+            channel_size: 128,
         }
     }
 
@@ -43,6 +45,12 @@ impl Config {
         self
     }
 
+    /// Capacity of the channels inside the secure stream, default 128
+    pub fn channel_size(mut self, size: usize) -> Self {
+        self.channel_size = size;
+        self
+    }
+
     /// Override the default set of supported key agreement algorithms.
     pub fn key_agreements<'a, I>(mut self, xs: I) -> Self
     where
@@ -24,7 +24,7 @@ use crate::{
         handshake_struct::{Exchange, PublicKey},
     },
     stream_cipher::ctr_init,
-    EphemeralPublicKey, KeyPairInner,
+    EphemeralPublicKey, KeyPairInner, SECP256K1,
 };
 
 /// Performs a handshake on the given socket.
@@ -43,6 +43,7 @@ pub(in crate::handshake) fn handshake<T>(
 where
     T: AsyncRead + AsyncWrite + Send + 'static,
 {
+    let channel_size = config.channel_size;
     // The handshake messages all start with a 4-bytes message length prefix.
     let socket = Builder::new()
         .big_endian()
@@ -124,10 +125,9 @@ where
                     }
                 };
 
-                let secp256k1_key = secp256k1::Secp256k1::signing_only();
                 let signature = match ephemeral_context.config.key.inner {
                     KeyPairInner::Secp256k1 { ref private } => {
-                        secp256k1_key.sign(&message, private).serialize_der()
+                        SECP256K1.sign(&message, private).serialize_der()
                     }
                 };
 
@@ -229,7 +229,7 @@ where
             )
             .map(move |key_material| (socket, pub_ephemeral_context, key_material))
         })
-        .and_then(|(socket, pub_ephemeral_context, key_material)| {
+        .and_then(move |(socket, pub_ephemeral_context, key_material)| {
             // Generate a key from the local ephemeral private key and the remote ephemeral public key,
             // derive from it a cipher key, an iv, and a hmac key, and build the encoder/decoder.
 
@@ -285,6 +285,7 @@ where
                 encode_cipher,
                 encode_hmac,
                 pub_ephemeral_context.state.remote.local.nonce.to_vec(),
+                channel_size,
             );
             Ok((secure_stream, pub_ephemeral_context))
         })
@@ -2,6 +2,7 @@
 
 #![deny(missing_docs)]
 
+use lazy_static::lazy_static;
 use secp256k1::key::SecretKey;
 
 pub use crate::{handshake::handshake_struct::PublicKey, peer_id::PeerId};
@@ -24,6 +25,12 @@ mod support;
 /// Public key generated temporarily during the handshake
 pub type EphemeralPublicKey = Vec<u8>;
 
+lazy_static! {
+    /// Shared secp256k1 context, creating one for every signature is expensive
+    pub(crate) static ref SECP256K1: secp256k1::Secp256k1<secp256k1::All> =
+        secp256k1::Secp256k1::new();
+}
+
 /// Key pair of asymmetric encryption algorithm
 #[derive(Clone, Debug)]
 pub struct SecioKeyPair {
@@ -61,8 +68,7 @@ impl SecioKeyPair {
     pub fn to_public_key(&self) -> PublicKey {
         match self.inner {
             KeyPairInner::Secp256k1 { ref private } => {
-                let secp = secp256k1::Secp256k1::signing_only();
-                let pubkey = secp256k1::key::PublicKey::from_secret_key(&secp, private);
+                let pubkey = secp256k1::key::PublicKey::from_secret_key(&SECP256K1, private);
                 PublicKey::Secp256k1(pubkey.serialize().to_vec())
             }
         }
@@ -72,6 +78,18 @@ impl SecioKeyPair {
     pub fn to_peer_id(&self) -> PeerId {
         self.to_public_key().peer_id()
     }
+
+    /// Sign the sha256 digest of data, returns DER encoded signature
+    pub fn sign(&self, data: &[u8]) -> Vec<u8> {
+        let digest = <sha2::Sha256 as sha2::Digest>::digest(data);
+        let message =
+            secp256k1::Message::from_slice(digest.as_ref()).expect("sha256 digest is 32 bytes");
+        match self.inner {
+            KeyPairInner::Secp256k1 { ref private } => {
+                SECP256K1.sign(&message, private).serialize_der()
+            }
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
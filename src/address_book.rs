@@ -0,0 +1,108 @@
+use multiaddr::Multiaddr;
+use std::{
+    collections::{HashMap, HashSet},
+    net::IpAddr,
+};
+
+/// Max observed addresses waiting for confirmation
+const MAX_OBSERVED_ADDRS: usize = 64;
+
+/// Our public addresses, configured by user or confirmed by the observations of peers
+pub(crate) struct AddressBook {
+    manual: Vec<Multiaddr>,
+    /// Observed addresses and the ips of observers
+    observed: HashMap<Multiaddr, HashSet<IpAddr>>,
+    /// Observers needed to confirm an address
+    threshold: usize,
+}
+
+impl AddressBook {
+    pub fn new(threshold: usize) -> Self {
+        AddressBook {
+            manual: Vec::new(),
+            observed: HashMap::new(),
+            threshold,
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    pub fn add(&mut self, address: Multiaddr) {
+        if !self.manual.contains(&address) {
+            self.manual.push(address);
+        }
+    }
+
+    /// Remove both configured and observed address
+    pub fn remove(&mut self, address: &Multiaddr) {
+        self.manual.retain(|addr| addr != address);
+        self.observed.remove(address);
+    }
+
+    /// The address is observed by a peer, the peers from one ip count once
+    pub fn observe(&mut self, address: Multiaddr, observer: IpAddr) {
+        if !self.observed.contains_key(&address) && self.observed.len() >= MAX_OBSERVED_ADDRS {
+            // Drop the one with least observers
+            if let Some(addr) = self
+                .observed
+                .iter()
+                .min_by_key(|(_, observers)| observers.len())
+                .map(|(addr, _)| addr.clone())
+            {
+                self.observed.remove(&addr);
+            }
+        }
+        self.observed.entry(address).or_default().insert(observer);
+    }
+
+    /// Configured addresses, then the confirmed ones by observers count
+    pub fn addrs(&self) -> Vec<Multiaddr> {
+        let mut confirmed = self
+            .observed
+            .iter()
+            .filter(|(addr, observers)| {
+                observers.len() >= self.threshold && !self.manual.contains(addr)
+            })
+            .collect::<Vec<_>>();
+        confirmed.sort_by(|(a, a_observers), (b, b_observers)| {
+            b_observers
+                .len()
+                .cmp(&a_observers.len())
+                .then_with(|| a.to_string().cmp(&b.to_string()))
+        });
+
+        self.manual
+            .iter()
+            .cloned()
+            .chain(confirmed.into_iter().map(|(addr, _)| addr.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AddressBook;
+    use multiaddr::Multiaddr;
+
+    #[test]
+    fn confirm_by_observers() {
+        let mut book = AddressBook::new(2);
+        let manual: Multiaddr = "/ip4/1.1.1.1/tcp/1337".parse().unwrap();
+        let observed: Multiaddr = "/ip4/2.2.2.2/tcp/1337".parse().unwrap();
+        book.add(manual.clone());
+
+        book.observe(observed.clone(), "3.3.3.3".parse().unwrap());
+        // The same observer counts once
+        book.observe(observed.clone(), "3.3.3.3".parse().unwrap());
+        assert_eq!(book.addrs(), vec![manual.clone()]);
+
+        book.observe(observed.clone(), "4.4.4.4".parse().unwrap());
+        assert_eq!(book.addrs(), vec![manual.clone(), observed.clone()]);
+
+        book.remove(&observed);
+        book.remove(&manual);
+        assert!(book.addrs().is_empty());
+    }
+}
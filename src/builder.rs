@@ -6,7 +6,10 @@ use tokio::codec::{Decoder, Encoder};
 use yamux::Config;
 
 use crate::{
-    service::Service,
+    config::{BufferConfig, DialFilter, RetryPolicy, TcpConfig},
+    eviction::{DefaultEvictionScorer, EvictionScorer},
+    hello::Hello,
+    service::{Service, DEFAULT_SLOW_HANDLER_THRESHOLD},
     traits::{ProtocolMeta, ServiceHandle},
 };
 
@@ -18,6 +21,17 @@ pub struct ServiceBuilder<U> {
     timeout: Duration,
     yamux_config: Config,
     max_frame_length: usize,
+    slow_handler_threshold: Option<Duration>,
+    buffer_config: BufferConfig,
+    persistent_peer_policy: RetryPolicy,
+    max_inbound: Option<usize>,
+    eviction_scorer: Box<dyn EvictionScorer>,
+    max_connections_per_peer: usize,
+    dial_filter: DialFilter,
+    tcp_config: TcpConfig,
+    observed_addr_threshold: usize,
+    reachability_threshold: usize,
+    hello: Option<Hello>,
 }
 
 impl<U> ServiceBuilder<U>
@@ -42,9 +56,20 @@ where
             self.key_pair,
             self.forever,
             self.timeout,
+            self.buffer_config,
         )
         .max_frame_length(self.max_frame_length)
         .yamux_config(self.yamux_config)
+        .slow_handler_threshold(self.slow_handler_threshold)
+        .persistent_peer_policy(self.persistent_peer_policy)
+        .max_inbound(self.max_inbound)
+        .eviction_scorer(self.eviction_scorer)
+        .max_connections_per_peer(self.max_connections_per_peer)
+        .dial_filter(self.dial_filter)
+        .tcp_config(self.tcp_config)
+        .observed_addr_threshold(self.observed_addr_threshold)
+        .reachability_threshold(self.reachability_threshold)
+        .hello(self.hello)
     }
 
     /// Insert a custom protocol
@@ -100,6 +125,111 @@ where
         self
     }
 
+    /// Handle callbacks that take longer than threshold will be reported
+    /// by `ServiceEvent::SlowHandler`, None means no check
+    ///
+    /// Default 100 millisecond
+    pub fn slow_handler_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_handler_threshold = threshold;
+        self
+    }
+
+    /// Capacities of the internal channels and the budget of buffered messages
+    pub fn buffer_config(mut self, config: BufferConfig) -> Self {
+        self.buffer_config = config;
+        self
+    }
+
+    /// Retry policy to reconnect persistent peers
+    ///
+    /// Default `RetryPolicy::persistent()`
+    pub fn persistent_peer_policy(mut self, policy: RetryPolicy) -> Self {
+        self.persistent_peer_policy = policy;
+        self
+    }
+
+    /// Max inbound sessions, None means no limit.
+    ///
+    /// When inbound slots are full, a new inbound session evicts the one chosen by
+    /// eviction scorer, or it's refused with `Error::InboundFull`.
+    ///
+    /// Default None
+    pub fn max_inbound(mut self, max: Option<usize>) -> Self {
+        self.max_inbound = max;
+        self
+    }
+
+    /// Choose the inbound session to evict when inbound slots are full
+    ///
+    /// Default `DefaultEvictionScorer`
+    pub fn eviction_scorer<S>(mut self, scorer: S) -> Self
+    where
+        S: EvictionScorer + 'static,
+    {
+        self.eviction_scorer = Box::new(scorer);
+        self
+    }
+
+    /// Max sessions to the same peer, it needs secio to know the peer of sessions.
+    ///
+    /// When a new session exceeds the limit, both sides keep the connections dialed
+    /// by the lower peer id: the new session replaces an existing one dialed by the
+    /// other side, or it's refused with `Error::RepeatedConnection`.
+    ///
+    /// Default 1, panic when max is 0
+    pub fn max_connections_per_peer(mut self, max: usize) -> Self {
+        assert!(max > 0);
+        self.max_connections_per_peer = max;
+        self
+    }
+
+    /// Refuse dial addresses before connect, the refused dial is reported as
+    /// `Error::DialDenied`
+    ///
+    /// Default `DialFilter::default()`, allows all routable addresses
+    pub fn dial_filter(mut self, filter: DialFilter) -> Self {
+        self.dial_filter = filter;
+        self
+    }
+
+    /// TCP socket options of listeners and connections, such as TCP_NODELAY,
+    /// local ip of outbound connections and SO_REUSEPORT
+    pub fn tcp_config(mut self, config: TcpConfig) -> Self {
+        self.tcp_config = config;
+        self
+    }
+
+    /// Observers from different ips needed to confirm an address observed by peers
+    /// as our public address, see `ServiceContext::observed_addr`
+    ///
+    /// Default 3
+    pub fn observed_addr_threshold(mut self, threshold: usize) -> Self {
+        self.observed_addr_threshold = threshold;
+        self
+    }
+
+    /// Dial back results needed to decide our reachability, see
+    /// `ServiceContext::report_reachability`
+    ///
+    /// Default 3
+    pub fn reachability_threshold(mut self, threshold: usize) -> Self {
+        self.reachability_threshold = threshold;
+        self
+    }
+
+    /// Exchange hello with remote after the secio handshake and before any protocol
+    /// is opened, the session is refused with `Error::HelloMismatch` if the network id
+    /// or the version of remote is different.
+    ///
+    /// Panic on build when the key pair is not set, a service without secio
+    /// would skip the hello and accept peers of any network.
+    ///
+    /// Default None, no hello
+    pub fn hello(mut self, hello: Hello) -> Self {
+        self.hello = Some(hello);
+        self
+    }
+
     /// Clear all protocols
     pub fn clear(&mut self) {
         self.inner.clear();
@@ -120,6 +250,17 @@ where
             timeout: Duration::from_secs(10),
             yamux_config: Config::default(),
             max_frame_length: 1024 * 1024 * 8,
+            slow_handler_threshold: Some(DEFAULT_SLOW_HANDLER_THRESHOLD),
+            buffer_config: BufferConfig::default(),
+            persistent_peer_policy: RetryPolicy::persistent(),
+            max_inbound: None,
+            eviction_scorer: Box::new(DefaultEvictionScorer::default()),
+            max_connections_per_peer: 1,
+            dial_filter: DialFilter::default(),
+            tcp_config: TcpConfig::default(),
+            observed_addr_threshold: 3,
+            reachability_threshold: 3,
+            hello: None,
         }
     }
 }
@@ -0,0 +1,578 @@
+use multiaddr::Multiaddr;
+use net2::TcpBuilder;
+use std::{
+    cmp, error, fmt, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    net::{tcp::ConnectFuture, TcpListener, TcpStream},
+    reactor::Handle,
+};
+
+use crate::{
+    error::Error,
+    protocol_handle_stream::BudgetCharge,
+    utils::{is_reachable, multiaddr_to_socketaddr},
+};
+
+/// Default capacity of the channel from outside to the service
+pub const DEFAULT_SERVICE_TASK_CHANNEL_SIZE: usize = 256;
+/// Default capacity of the channel from sessions to the service
+pub const DEFAULT_SESSION_EVENT_CHANNEL_SIZE: usize = 256;
+/// Default capacity of the channel from the service to each session
+pub const DEFAULT_SESSION_CHANNEL_SIZE: usize = 32;
+/// Default capacity of the channel from the service to each protocol handle
+pub const DEFAULT_PROTO_HANDLE_CHANNEL_SIZE: usize = 32;
+/// Default capacity of the channel from sub streams to their session
+pub const DEFAULT_PROTO_EVENT_CHANNEL_SIZE: usize = 256;
+/// Default capacity of the channel from the session to each sub stream
+pub const DEFAULT_SUB_STREAM_CHANNEL_SIZE: usize = 32;
+/// Default capacity of the channels inside secio secure stream
+pub const DEFAULT_SECURE_STREAM_CHANNEL_SIZE: usize = 128;
+
+/// What the service does when the buffered messages are out of budget
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum OverflowPolicy {
+    /// Drop the message that can't be buffered, the session keeps running
+    DropMessage,
+    /// Close the session whose message can't be buffered
+    CloseSession,
+}
+
+/// Capacities of the internal channels and the budget of buffered messages
+///
+/// When a channel is full, the message waits in the buffer of the sender side,
+/// `max_buffered_bytes` limits the total size of protocol messages waiting in
+/// the buffers of the service and all sessions. A received message is counted
+/// once until all its protocol handles process it.
+#[derive(Clone, Copy, Debug)]
+pub struct BufferConfig {
+    /// Capacity of the channel from outside(`ServiceControl`) to the service
+    pub service_task_channel_size: usize,
+    /// Capacity of the channel from sessions to the service
+    pub session_event_channel_size: usize,
+    /// Capacity of the channel from the service to each session
+    pub session_channel_size: usize,
+    /// Capacity of the channel from the service to each protocol handle
+    pub proto_handle_channel_size: usize,
+    /// Capacity of the channel from sub streams to their session
+    pub proto_event_channel_size: usize,
+    /// Capacity of the channel from the session to each sub stream
+    pub sub_stream_channel_size: usize,
+    /// Capacity of the channels inside secio secure stream
+    pub secure_stream_channel_size: usize,
+    /// Max total bytes of buffered protocol messages, None means no limit
+    ///
+    /// A message is always accepted when nothing is buffered
+    pub max_buffered_bytes: Option<usize>,
+    /// Policy applied when `max_buffered_bytes` exceeded
+    pub overflow_policy: OverflowPolicy,
+}
+
+impl Default for BufferConfig {
+    fn default() -> Self {
+        BufferConfig {
+            service_task_channel_size: DEFAULT_SERVICE_TASK_CHANNEL_SIZE,
+            session_event_channel_size: DEFAULT_SESSION_EVENT_CHANNEL_SIZE,
+            session_channel_size: DEFAULT_SESSION_CHANNEL_SIZE,
+            proto_handle_channel_size: DEFAULT_PROTO_HANDLE_CHANNEL_SIZE,
+            proto_event_channel_size: DEFAULT_PROTO_EVENT_CHANNEL_SIZE,
+            sub_stream_channel_size: DEFAULT_SUB_STREAM_CHANNEL_SIZE,
+            secure_stream_channel_size: DEFAULT_SECURE_STREAM_CHANNEL_SIZE,
+            max_buffered_bytes: None,
+            overflow_policy: OverflowPolicy::DropMessage,
+        }
+    }
+}
+
+/// Bytes of buffered messages, shared by the service and all sessions
+#[derive(Clone)]
+pub(crate) struct BufferBudget {
+    used: Arc<AtomicUsize>,
+    limit: Option<usize>,
+    policy: OverflowPolicy,
+}
+
+impl BufferBudget {
+    pub fn new(limit: Option<usize>, policy: OverflowPolicy) -> Self {
+        BufferBudget {
+            used: Arc::new(AtomicUsize::new(0)),
+            limit,
+            policy,
+        }
+    }
+
+    /// Take size bytes from budget, return false if out of budget
+    #[inline]
+    pub fn acquire(&self, size: usize) -> bool {
+        match self.limit {
+            Some(limit) => {
+                let used = self.used.fetch_add(size, Ordering::SeqCst);
+                if used != 0 && used + size > limit {
+                    self.used.fetch_sub(size, Ordering::SeqCst);
+                    false
+                } else {
+                    true
+                }
+            }
+            None => true,
+        }
+    }
+
+    /// Give back size bytes
+    #[inline]
+    pub fn release(&self, size: usize) {
+        if self.limit.is_some() {
+            self.used.fetch_sub(size, Ordering::SeqCst);
+        }
+    }
+
+    /// Take size bytes from budget as a charge, None if out of budget
+    #[inline]
+    pub fn charge(&self, size: usize) -> Option<Arc<BudgetCharge>> {
+        if self.acquire(size) {
+            Some(Arc::new(BudgetCharge::new(self.clone(), size)))
+        } else {
+            None
+        }
+    }
+
+    #[inline]
+    pub fn policy(&self) -> OverflowPolicy {
+        self.policy
+    }
+}
+
+/// Retry policy of a failed dial, the delay before the nth retry is
+/// `min(base_delay * 2^(n-1), max_delay)`, reduced by a random jitter
+#[derive(Clone, Debug)]
+pub struct RetryPolicy {
+    /// Max dial attempts, include the first one
+    pub max_attempts: usize,
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Max delay between two attempts
+    pub max_delay: Duration,
+    /// Random jitter ratio of delay, in range 0.0 to 1.0
+    pub jitter: f64,
+    /// IO error kinds to retry, other errors such as `PeerIdNotMatch` and `ConnectSelf` are never retried
+    pub retry_kinds: Vec<io::ErrorKind>,
+}
+
+impl RetryPolicy {
+    /// Whether the dial error is worth a retry
+    pub fn should_retry<T: fmt::Debug>(&self, error: &Error<T>) -> bool {
+        match error {
+            Error::IoError(err) | Error::DNSResolverError(err) => {
+                self.retry_kinds.contains(&err.kind())
+            }
+            _ => false,
+        }
+    }
+
+    /// Delay before the nth retry, start from 1
+    pub fn delay(&self, retry: usize) -> Duration {
+        let base = duration_to_millis(self.base_delay);
+        let max = duration_to_millis(self.max_delay);
+        let exp = cmp::min(retry.saturating_sub(1), 32) as u32;
+        let delay = cmp::min(base.saturating_mul(1 << exp), max);
+        let jitter = self.jitter.max(0.0).min(1.0) * rand::random::<f64>();
+        Duration::from_millis((delay as f64 * (1.0 - jitter)) as u64)
+    }
+}
+
+impl RetryPolicy {
+    /// Default policy to reconnect persistent peers, give up after about an hour
+    pub fn persistent() -> Self {
+        RetryPolicy {
+            max_attempts: 64,
+            max_delay: Duration::from_secs(60),
+            ..Default::default()
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(60),
+            jitter: 0.1,
+            retry_kinds: vec![
+                io::ErrorKind::TimedOut,
+                io::ErrorKind::ConnectionRefused,
+                io::ErrorKind::ConnectionReset,
+                io::ErrorKind::ConnectionAborted,
+            ],
+        }
+    }
+}
+
+/// Why a dial is refused by `DialFilter`
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DialDenied {
+    /// Port 0, unspecified, broadcast or multicast address, never dialed
+    Unroutable,
+    /// Loopback address
+    Loopback,
+    /// Private, link local or other address not globally reachable
+    Private,
+    /// One of our listen addresses
+    SelfAddress,
+    /// Refused by the user predicate
+    Predicate,
+}
+
+impl error::Error for DialDenied {}
+
+impl fmt::Display for DialDenied {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DialDenied::Unroutable => write!(f, "Unroutable address"),
+            DialDenied::Loopback => write!(f, "Loopback address"),
+            DialDenied::Private => write!(f, "Private address"),
+            DialDenied::SelfAddress => write!(f, "Self listen address"),
+            DialDenied::Predicate => write!(f, "Refused by predicate"),
+        }
+    }
+}
+
+/// Filter of dial addresses, checked before the TCP connect.
+///
+/// Default allows all routable addresses
+#[derive(Clone)]
+pub struct DialFilter {
+    allow_loopback: bool,
+    allow_private: bool,
+    allow_self: bool,
+    predicate: Option<Arc<dyn Fn(&Multiaddr) -> bool + Send + Sync>>,
+}
+
+impl DialFilter {
+    /// Only allow globally reachable addresses which are not ours
+    pub fn reachable() -> Self {
+        DialFilter {
+            allow_loopback: false,
+            allow_private: false,
+            allow_self: false,
+            predicate: None,
+        }
+    }
+
+    /// Allow loopback addresses
+    pub fn allow_loopback(mut self, allow: bool) -> Self {
+        self.allow_loopback = allow;
+        self
+    }
+
+    /// Allow private, link local and other addresses not globally reachable
+    pub fn allow_private(mut self, allow: bool) -> Self {
+        self.allow_private = allow;
+        self
+    }
+
+    /// Allow our own listen addresses
+    pub fn allow_self(mut self, allow: bool) -> Self {
+        self.allow_self = allow;
+        self
+    }
+
+    /// Addresses that predicate returns false are refused
+    pub fn predicate<F>(mut self, predicate: F) -> Self
+    where
+        F: Fn(&Multiaddr) -> bool + Send + Sync + 'static,
+    {
+        self.predicate = Some(Arc::new(predicate));
+        self
+    }
+
+    /// Check the address with our listen addresses
+    pub fn check(&self, address: &Multiaddr, listens: &[Multiaddr]) -> Result<(), DialDenied> {
+        if let Ok(socket_address) = multiaddr_to_socketaddr(address) {
+            let ip = socket_address.ip();
+            let broadcast = match ip {
+                IpAddr::V4(ipv4) => ipv4.is_broadcast(),
+                IpAddr::V6(_) => false,
+            };
+            if socket_address.port() == 0 || ip.is_unspecified() || ip.is_multicast() || broadcast {
+                return Err(DialDenied::Unroutable);
+            }
+            if ip.is_loopback() {
+                if !self.allow_loopback {
+                    return Err(DialDenied::Loopback);
+                }
+            } else if !self.allow_private && !is_reachable(ip) {
+                return Err(DialDenied::Private);
+            }
+            if !self.allow_self
+                && listens
+                    .iter()
+                    .any(|listen| multiaddr_to_socketaddr(listen) == Ok(socket_address))
+            {
+                return Err(DialDenied::SelfAddress);
+            }
+        }
+
+        match self.predicate {
+            Some(ref predicate) if !predicate(address) => Err(DialDenied::Predicate),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Default for DialFilter {
+    fn default() -> Self {
+        DialFilter {
+            allow_loopback: true,
+            allow_private: true,
+            allow_self: true,
+            predicate: None,
+        }
+    }
+}
+
+impl fmt::Debug for DialFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("DialFilter")
+            .field("allow_loopback", &self.allow_loopback)
+            .field("allow_private", &self.allow_private)
+            .field("allow_self", &self.allow_self)
+            .field("predicate", &self.predicate.is_some())
+            .finish()
+    }
+}
+
+/// TCP socket options of listeners and connections
+#[derive(Clone, Debug, Default)]
+pub struct TcpConfig {
+    /// Set TCP_NODELAY
+    pub nodelay: bool,
+    /// Set SO_KEEPALIVE with the idle time, None means system default
+    pub keepalive: Option<Duration>,
+    /// SO_SNDBUF, None means system default
+    pub send_buffer_size: Option<usize>,
+    /// SO_RCVBUF, None means system default
+    pub recv_buffer_size: Option<usize>,
+    /// Local ip of outbound connections, only used by connections of the same ip version
+    pub bind_ip: Option<IpAddr>,
+    /// Set SO_REUSEADDR and SO_REUSEPORT on listeners and outbound connections,
+    /// outbound connections are bound to the listen port of the same ip version,
+    /// so that NATs map them consistently.
+    ///
+    /// SO_REUSEPORT is only set on unix
+    pub reuse_port: bool,
+}
+
+impl TcpConfig {
+    /// Bind a listener
+    pub(crate) fn listen(&self, address: &SocketAddr) -> io::Result<TcpListener> {
+        if !self.reuse_port {
+            return TcpListener::bind(address);
+        }
+        let builder = tcp_builder(address)?;
+        reuse(&builder)?;
+        builder.bind(address)?;
+        let listener = builder.listen(1024)?;
+        TcpListener::from_std(listener, &Handle::default())
+    }
+
+    /// Connect to address, listen port is used when `reuse_port` enabled
+    pub(crate) fn connect(
+        &self,
+        address: &SocketAddr,
+        listen_port: Option<u16>,
+    ) -> io::Result<ConnectFuture> {
+        let ip = self.bind_ip.filter(|ip| ip.is_ipv4() == address.is_ipv4());
+        let port = listen_port.filter(|_| self.reuse_port);
+        if ip.is_none() && port.is_none() {
+            return Ok(TcpStream::connect(address));
+        }
+
+        let builder = tcp_builder(address)?;
+        if port.is_some() {
+            reuse(&builder)?;
+        }
+        let ip = ip.unwrap_or_else(|| {
+            if address.is_ipv4() {
+                Ipv4Addr::UNSPECIFIED.into()
+            } else {
+                Ipv6Addr::UNSPECIFIED.into()
+            }
+        });
+        builder.bind(&SocketAddr::new(ip, port.unwrap_or(0)))?;
+        let stream = builder.to_tcp_stream()?;
+        Ok(TcpStream::connect_std(stream, address, &Handle::default()))
+    }
+
+    /// Set options of an established connection
+    pub(crate) fn apply(&self, socket: &TcpStream) -> io::Result<()> {
+        if self.nodelay {
+            socket.set_nodelay(true)?;
+        }
+        if self.keepalive.is_some() {
+            socket.set_keepalive(self.keepalive)?;
+        }
+        if let Some(size) = self.send_buffer_size {
+            socket.set_send_buffer_size(size)?;
+        }
+        if let Some(size) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(size)?;
+        }
+        Ok(())
+    }
+}
+
+#[inline]
+fn tcp_builder(address: &SocketAddr) -> io::Result<TcpBuilder> {
+    if address.is_ipv4() {
+        TcpBuilder::new_v4()
+    } else {
+        TcpBuilder::new_v6()
+    }
+}
+
+#[inline]
+fn reuse(builder: &TcpBuilder) -> io::Result<()> {
+    builder.reuse_address(true)?;
+    #[cfg(unix)]
+    {
+        use net2::unix::UnixTcpBuilderExt;
+        builder.reuse_port(true)?;
+    }
+    Ok(())
+}
+
+#[inline]
+fn duration_to_millis(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1000)
+        .saturating_add(u64::from(duration.subsec_millis()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{BufferBudget, DialDenied, DialFilter, OverflowPolicy, RetryPolicy};
+    use crate::error::Error;
+    use multiaddr::Multiaddr;
+    use std::{io, time::Duration};
+
+    #[test]
+    fn budget_limit() {
+        let budget = BufferBudget::new(Some(10), OverflowPolicy::DropMessage);
+        // A message is always accepted when nothing is buffered
+        assert!(budget.acquire(20));
+        assert!(!budget.acquire(1));
+        budget.release(20);
+        assert!(budget.acquire(6));
+        assert!(budget.acquire(4));
+        assert!(!budget.acquire(1));
+        budget.release(4);
+        assert!(budget.acquire(1));
+    }
+
+    #[test]
+    fn budget_charge() {
+        let budget = BufferBudget::new(Some(10), OverflowPolicy::DropMessage);
+        let charge = budget.charge(8).unwrap();
+        let shared = charge.clone();
+        assert!(budget.charge(4).is_none());
+        drop(charge);
+        // Still held by the other holder
+        assert!(budget.charge(4).is_none());
+        drop(shared);
+        assert!(budget.charge(10).is_some());
+    }
+
+    #[test]
+    fn no_limit() {
+        let budget = BufferBudget::new(None, OverflowPolicy::CloseSession);
+        assert!(budget.acquire(usize::max_value() / 2));
+        assert!(budget.acquire(usize::max_value() / 2));
+        assert_eq!(budget.policy(), OverflowPolicy::CloseSession);
+    }
+
+    #[test]
+    fn retry_delay() {
+        let policy = RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_millis(1000),
+            jitter: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(policy.delay(1), Duration::from_millis(100));
+        assert_eq!(policy.delay(2), Duration::from_millis(200));
+        assert_eq!(policy.delay(4), Duration::from_millis(800));
+        assert_eq!(policy.delay(5), Duration::from_millis(1000));
+        assert_eq!(policy.delay(100), Duration::from_millis(1000));
+
+        let policy = RetryPolicy {
+            jitter: 0.5,
+            ..policy
+        };
+        let delay = policy.delay(1);
+        assert!(delay >= Duration::from_millis(50) && delay <= Duration::from_millis(100));
+    }
+
+    #[test]
+    fn retry_errors() {
+        let policy = RetryPolicy::default();
+        let refused: Error<()> = io::Error::from(io::ErrorKind::ConnectionRefused).into();
+        let other: Error<()> = io::Error::from(io::ErrorKind::Other).into();
+        assert!(policy.should_retry(&refused));
+        assert!(!policy.should_retry(&other));
+        assert!(!policy.should_retry(&Error::<()>::PeerIdNotMatch));
+        assert!(!policy.should_retry(&Error::<()>::ConnectSelf));
+    }
+
+    #[test]
+    fn dial_filter() {
+        let check = |filter: &DialFilter, address: &str| {
+            let listens: Vec<Multiaddr> = vec!["/ip4/1.1.1.1/tcp/1337".parse().unwrap()];
+            filter.check(&address.parse().unwrap(), &listens)
+        };
+
+        let filter = DialFilter::default();
+        assert_eq!(check(&filter, "/ip4/127.0.0.1/tcp/1337"), Ok(()));
+        assert_eq!(check(&filter, "/ip4/1.1.1.1/tcp/1337"), Ok(()));
+        assert_eq!(
+            check(&filter, "/ip4/127.0.0.1/tcp/0"),
+            Err(DialDenied::Unroutable)
+        );
+        assert_eq!(
+            check(&filter, "/ip4/0.0.0.0/tcp/1337"),
+            Err(DialDenied::Unroutable)
+        );
+
+        let filter =
+            DialFilter::reachable().predicate(|address| !address.to_string().contains("/tcp/1338"));
+        assert_eq!(check(&filter, "/ip4/8.8.8.8/tcp/1337"), Ok(()));
+        assert_eq!(check(&filter, "/dns4/localhost/tcp/1337"), Ok(()));
+        assert_eq!(
+            check(&filter, "/ip4/127.0.0.1/tcp/1337"),
+            Err(DialDenied::Loopback)
+        );
+        assert_eq!(
+            check(&filter, "/ip4/192.168.0.1/tcp/1337"),
+            Err(DialDenied::Private)
+        );
+        assert_eq!(
+            check(&filter, "/ip6/fe80::1/tcp/1337"),
+            Err(DialDenied::Private)
+        );
+        assert_eq!(
+            check(&filter, "/ip4/1.1.1.1/tcp/1337"),
+            Err(DialDenied::SelfAddress)
+        );
+        assert_eq!(
+            check(&filter, "/ip4/8.8.8.8/tcp/1338"),
+            Err(DialDenied::Predicate)
+        );
+    }
+}
@@ -4,17 +4,28 @@ use futures::{
 };
 use log::{debug, warn};
 use multiaddr::Multiaddr;
-use secio::PublicKey;
+use secio::{PeerId, PublicKey};
 use std::{
     collections::HashMap,
     sync::Arc,
     time::{Duration, Instant},
 };
-use tokio::timer::{self, Interval};
+use tokio::{
+    prelude::{AsyncRead, AsyncWrite},
+    timer::{self, Interval},
+};
 use yamux::session::SessionType;
 
 use crate::protocol_select::ProtocolInfo;
-use crate::{error::Error, service::ServiceTask, session::SessionEvent, ProtocolId, SessionId};
+use crate::{
+    config::RetryPolicy,
+    error::Error,
+    hello::Hello,
+    reachability::Reachability,
+    service::{DisconnectReason, ServiceTask},
+    session::SessionEvent,
+    ProtocolId, SessionId,
+};
 
 /// Session context
 #[derive(Clone)]
@@ -29,6 +40,8 @@ pub struct SessionContext {
     // TODO: use reference?
     /// Remote public key
     pub remote_pubkey: Option<PublicKey>,
+    /// Remote hello, None if hello is not enabled
+    pub remote_hello: Option<Hello>,
 }
 
 /// The Service runtime can send some instructions to the inside of the handle.
@@ -38,6 +51,10 @@ pub struct ServiceContext {
     // For tell notify finished
     session_notify_senders: HashMap<(SessionId, ProtocolId), Vec<oneshot::Sender<()>>>,
     listens: Vec<Multiaddr>,
+    bind_addrs: Vec<Multiaddr>,
+    external_addrs: Vec<Multiaddr>,
+    reachability: Reachability,
+    reuse_port: bool,
     inner: ServiceControl,
 }
 
@@ -51,6 +68,10 @@ impl ServiceContext {
             inner: ServiceControl::new(service_task_sender, proto_infos),
             session_notify_senders: HashMap::default(),
             listens: Vec::new(),
+            bind_addrs: Vec::new(),
+            external_addrs: Vec::new(),
+            reachability: Reachability::Unknown,
+            reuse_port: false,
         }
     }
 
@@ -66,12 +87,142 @@ impl ServiceContext {
         self.inner.dial(address)
     }
 
+    /// Initiate a connection request to any of the addresses, the first one completes
+    /// the handshake wins
+    #[inline]
+    pub fn dial_any(&mut self, addresses: Vec<Multiaddr>) -> Result<(), Error<ServiceTask>> {
+        self.inner.dial_any(addresses)
+    }
+
+    /// Initiate a connection request to address, the future resolves to the new session
+    #[inline]
+    pub fn dial_with_result(
+        &mut self,
+        address: Multiaddr,
+    ) -> impl Future<Item = (SessionId, Option<PeerId>), Error = Error<ServiceTask>> {
+        self.inner.dial_with_result(address)
+    }
+
+    /// Initiate a connection request to address, failed dial is retried by the policy
+    #[inline]
+    pub fn dial_with_policy(
+        &mut self,
+        address: Multiaddr,
+        policy: RetryPolicy,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.inner.dial_with_policy(address, policy)
+    }
+
+    /// Stop listening on the address
+    #[inline]
+    pub fn unlisten(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.unlisten(address)
+    }
+
+    /// Keep connected to the peer, the address must contain peer id
+    #[inline]
+    pub fn add_persistent_peer(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.add_persistent_peer(address)
+    }
+
+    /// Stop keeping connected to the peer
+    #[inline]
+    pub fn remove_persistent_peer(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.remove_persistent_peer(address)
+    }
+
+    /// Cancel a pending dial to address
+    #[inline]
+    pub fn cancel_dial(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.cancel_dial(address)
+    }
+
     /// Disconnect a connection
     #[inline]
     pub fn disconnect(&mut self, session_id: SessionId) -> Result<(), Error<ServiceTask>> {
         self.inner.disconnect(session_id)
     }
 
+    /// Send the reason to remote and disconnect, see `ServiceControl::disconnect_with_reason`
+    #[inline]
+    pub fn disconnect_with_reason(
+        &mut self,
+        session_id: SessionId,
+        reason: DisconnectReason,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.inner.disconnect_with_reason(session_id, reason)
+    }
+
+    /// Never evict sessions of the peer
+    #[inline]
+    pub fn protect_peer(&mut self, peer_id: PeerId) -> Result<(), Error<ServiceTask>> {
+        self.inner.protect_peer(peer_id)
+    }
+
+    /// Add a public address, such as the address of port forwarding
+    #[inline]
+    pub fn add_external_addr(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.add_external_addr(address)
+    }
+
+    /// Remove a public address, configured or observed
+    #[inline]
+    pub fn remove_external_addr(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.inner.remove_external_addr(address)
+    }
+
+    /// The remote of session observed our address
+    #[inline]
+    pub fn observed_addr(
+        &mut self,
+        session_id: SessionId,
+        address: Multiaddr,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.inner.observed_addr(session_id, address)
+    }
+
+    /// A peer tried to dial us back, the result decides our reachability
+    #[inline]
+    pub fn report_reachability(&mut self, reachable: bool) -> Result<(), Error<ServiceTask>> {
+        self.inner.report_reachability(reachable)
+    }
+
+    /// Sessions of the peer can be evicted again
+    #[inline]
+    pub fn unprotect_peer(&mut self, peer_id: PeerId) -> Result<(), Error<ServiceTask>> {
+        self.inner.unprotect_peer(peer_id)
+    }
+
+    /// Update ping round trip time of the session
+    #[inline]
+    pub fn set_session_rtt(
+        &mut self,
+        session_id: SessionId,
+        rtt: Duration,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.inner.set_session_rtt(session_id, rtt)
+    }
+
+    /// The session is useful recently, such as it sent new data
+    #[inline]
+    pub fn mark_session_useful(&mut self, session_id: SessionId) -> Result<(), Error<ServiceTask>> {
+        self.inner.mark_session_useful(session_id)
+    }
+
+    /// Open a session on the raw stream, such as a relayed circuit
+    #[inline]
+    pub fn upgrade_stream<S>(
+        &mut self,
+        stream: S,
+        address: Multiaddr,
+        ty: SessionType,
+    ) -> Result<(), Error<ServiceTask>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.inner.upgrade_stream(stream, address, ty)
+    }
+
     /// Send message
     #[inline]
     pub fn send_message(
@@ -167,11 +318,39 @@ impl ServiceContext {
     }
 
     /// Get service listen address list
+    ///
+    /// The unspecified ip of listen address, such as `0.0.0.0`, is replaced with
+    /// the ips of local interfaces
     #[inline]
     pub fn listens(&self) -> &Vec<Multiaddr> {
         &self.listens
     }
 
+    /// Get the addresses listeners bound to, they may be unspecified ip
+    #[inline]
+    pub fn bind_addrs(&self) -> &Vec<Multiaddr> {
+        &self.bind_addrs
+    }
+
+    /// Get our public addresses, configured by `add_external_addr` or confirmed
+    /// by the observations of peers
+    #[inline]
+    pub fn external_addrs(&self) -> &Vec<Multiaddr> {
+        &self.external_addrs
+    }
+
+    /// Whether peers can dial us, decided by the dial back results
+    #[inline]
+    pub fn reachability(&self) -> Reachability {
+        self.reachability
+    }
+
+    /// Whether outbound connections are bound to the listen port, see `TcpConfig::reuse_port`
+    #[inline]
+    pub fn reuse_port(&self) -> bool {
+        self.reuse_port
+    }
+
     /// Send raw event
     #[inline]
     pub fn send(&mut self, event: ServiceTask) -> Result<(), Error<ServiceTask>> {
@@ -180,8 +359,31 @@ impl ServiceContext {
 
     /// Update listen list
     #[inline]
-    pub(crate) fn update_listens(&mut self, address_list: Vec<Multiaddr>) {
+    pub(crate) fn update_listens(
+        &mut self,
+        address_list: Vec<Multiaddr>,
+        bind_addrs: Vec<Multiaddr>,
+    ) {
         self.listens = address_list;
+        self.bind_addrs = bind_addrs;
+    }
+
+    /// Update public address list
+    #[inline]
+    pub(crate) fn update_external_addrs(&mut self, address_list: Vec<Multiaddr>) {
+        self.external_addrs = address_list;
+    }
+
+    /// Update reachability
+    #[inline]
+    pub(crate) fn update_reachability(&mut self, reachability: Reachability) {
+        self.reachability = reachability;
+    }
+
+    /// Update reuse port option
+    #[inline]
+    pub(crate) fn set_reuse_port(&mut self, reuse_port: bool) {
+        self.reuse_port = reuse_port;
     }
 
     pub(crate) fn remove_session_notify_senders(
@@ -242,12 +444,178 @@ impl ServiceControl {
         self.send(ServiceTask::Dial { address })
     }
 
+    /// Initiate a connection request to any of the addresses, the first one completes
+    /// the handshake wins
+    #[inline]
+    pub fn dial_any(&mut self, addresses: Vec<Multiaddr>) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::DialAny { addresses })
+    }
+
+    /// Initiate a connection request to address, the future resolves to the new session.
+    ///
+    /// Dial error goes to the future instead of `ServiceHandle::handle_error`
+    pub fn dial_with_result(
+        &mut self,
+        address: Multiaddr,
+    ) -> impl Future<Item = (SessionId, Option<PeerId>), Error = Error<ServiceTask>> {
+        let (sender, receiver) = oneshot::channel();
+        self.send(ServiceTask::DialWithResult { address, sender })
+            .into_future()
+            .and_then(|_| {
+                receiver.then(|result| match result {
+                    Ok(result) => result,
+                    // Service has been dropped
+                    Err(_) => Err(Error::TaskDisconnect),
+                })
+            })
+    }
+
+    /// Initiate a connection request to address, failed dial is retried by the policy,
+    /// use `cancel_dial` to stop retrying. Each failed attempt goes to
+    /// `ServiceError::DialerError`
+    #[inline]
+    pub fn dial_with_policy(
+        &mut self,
+        address: Multiaddr,
+        policy: RetryPolicy,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::DialWithPolicy { address, policy })
+    }
+
+    /// Stop listening on the address
+    #[inline]
+    pub fn unlisten(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::Unlisten { address })
+    }
+
+    /// Keep connected to the peer, the address must contain peer id.
+    ///
+    /// The peer is redialed with backoff after the session closed, the state changes are
+    /// reported by `ServiceEvent::PersistentPeerUpdate`. It needs secio to know the peer id
+    /// of sessions.
+    #[inline]
+    pub fn add_persistent_peer(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::AddPersistentPeer { address })
+    }
+
+    /// Stop keeping connected to the peer, the connection is not closed
+    #[inline]
+    pub fn remove_persistent_peer(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::RemovePersistentPeer { address })
+    }
+
+    /// Cancel a pending dial to address
+    #[inline]
+    pub fn cancel_dial(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::CancelDial { address })
+    }
+
     /// Disconnect a connection
     #[inline]
     pub fn disconnect(&mut self, session_id: SessionId) -> Result<(), Error<ServiceTask>> {
         self.send(ServiceTask::Disconnect { session_id })
     }
 
+    /// Send the reason to remote before disconnect, remote gets it by
+    /// `CloseReason::RemoteDisconnect`. The session is closed as soon as the reason
+    /// is sent or fails to send, such as remote doesn't support it, and at the latest
+    /// after the timeout.
+    #[inline]
+    pub fn disconnect_with_reason(
+        &mut self,
+        session_id: SessionId,
+        reason: DisconnectReason,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::DisconnectWithReason { session_id, reason })
+    }
+
+    /// Never evict sessions of the peer when inbound slots are full
+    #[inline]
+    pub fn protect_peer(&mut self, peer_id: PeerId) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::ProtectPeer { peer_id })
+    }
+
+    /// Add a public address, such as the address of port forwarding
+    #[inline]
+    pub fn add_external_addr(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::AddExternalAddr { address })
+    }
+
+    /// Remove a public address, configured or observed
+    #[inline]
+    pub fn remove_external_addr(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::RemoveExternalAddr { address })
+    }
+
+    /// The remote of session observed our address, such as the address reported by
+    /// identify protocol. It becomes a public address when enough peers from
+    /// different ips observed it
+    #[inline]
+    pub fn observed_addr(
+        &mut self,
+        session_id: SessionId,
+        address: Multiaddr,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::ObservedAddr {
+            session_id,
+            address,
+        })
+    }
+
+    /// A peer tried to dial us back on our addresses, such as the result of autonat
+    /// protocol. The reachability is decided when enough results agree, and falls back
+    /// to unknown after the results expired
+    #[inline]
+    pub fn report_reachability(&mut self, reachable: bool) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::ReachabilityProbe { reachable })
+    }
+
+    /// Sessions of the peer can be evicted again
+    #[inline]
+    pub fn unprotect_peer(&mut self, peer_id: PeerId) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::UnprotectPeer { peer_id })
+    }
+
+    /// Update ping round trip time of the session, the eviction scorer prefers to keep
+    /// sessions with low rtt
+    #[inline]
+    pub fn set_session_rtt(
+        &mut self,
+        session_id: SessionId,
+        rtt: Duration,
+    ) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::SetSessionRtt { session_id, rtt })
+    }
+
+    /// The session is useful recently, such as it sent new data, the eviction scorer
+    /// prefers to keep sessions useful recently
+    #[inline]
+    pub fn mark_session_useful(&mut self, session_id: SessionId) -> Result<(), Error<ServiceTask>> {
+        self.send(ServiceTask::MarkSessionUseful { session_id })
+    }
+
+    /// Open a session on the raw stream, such as a relayed circuit.
+    ///
+    /// The stream goes through secio handshake and yamux as a tcp connection, the address
+    /// becomes `SessionContext.address`. Client stream is reported as a dial, the peer id
+    /// in address is checked and the failure goes to `ServiceError::DialerError`
+    #[inline]
+    pub fn upgrade_stream<S>(
+        &mut self,
+        stream: S,
+        address: Multiaddr,
+        ty: SessionType,
+    ) -> Result<(), Error<ServiceTask>>
+    where
+        S: AsyncRead + AsyncWrite + Send + 'static,
+    {
+        self.send(ServiceTask::UpgradeStream {
+            stream: Box::new(stream),
+            address,
+            ty,
+        })
+    }
+
     /// Send message
     #[inline]
     pub fn send_message(
@@ -292,6 +660,9 @@ impl Clone for ServiceContext {
             inner: self.inner.clone(),
             session_notify_senders: HashMap::default(),
             listens: self.listens.clone(),
+            bind_addrs: self.bind_addrs.clone(),
+            external_addrs: self.external_addrs.clone(),
+            reachability: self.reachability,
         }
     }
 }
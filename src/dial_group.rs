@@ -0,0 +1,111 @@
+use futures::prelude::*;
+use multiaddr::Multiaddr;
+use std::{
+    collections::{HashSet, VecDeque},
+    io,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+use crate::{error::Error, service::ServiceTask, utils::happy_eyeballs_sort};
+
+/// Delay between two connection attempts of `dial_any`, RFC 8305 recommends 250ms
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+/// Connection attempts over the candidate addresses of one peer
+pub(crate) struct DialGroup {
+    /// Dial result is reported by this address
+    key: Multiaddr,
+    /// Addresses not tried yet, in happy eyeballs order
+    candidates: VecDeque<Multiaddr>,
+    /// Addresses in connecting or handshaking
+    attempts: HashSet<Multiaddr>,
+    /// The next attempt starts when fired
+    delay: Delay,
+    /// The group has a winner, or canceled
+    done: bool,
+    /// Error of the last failed attempt
+    error: Option<Error<ServiceTask>>,
+}
+
+impl DialGroup {
+    pub fn new(key: Multiaddr, addresses: Vec<Multiaddr>) -> Self {
+        DialGroup {
+            key,
+            candidates: happy_eyeballs_sort(addresses).into_iter().collect(),
+            attempts: HashSet::new(),
+            delay: Delay::new(Instant::now()),
+            done: false,
+            error: None,
+        }
+    }
+
+    pub fn key(&self) -> &Multiaddr {
+        &self.key
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.done
+    }
+
+    /// The address is an attempt in connecting or handshaking
+    pub fn contains(&self, address: &Multiaddr) -> bool {
+        self.attempts.contains(address)
+    }
+
+    /// The next address to dial, when the delay fired or all attempts failed
+    pub fn next_candidate(&mut self) -> Option<Multiaddr> {
+        if self.done {
+            return None;
+        }
+        let ready = self.attempts.is_empty()
+            || match self.delay.poll() {
+                Ok(Async::Ready(_)) | Err(_) => true,
+                Ok(Async::NotReady) => false,
+            };
+        if ready {
+            self.candidates.pop_front()
+        } else {
+            None
+        }
+    }
+
+    pub fn attempt_started(&mut self, address: Multiaddr) {
+        self.attempts.insert(address);
+        self.delay.reset(Instant::now() + CONNECTION_ATTEMPT_DELAY);
+    }
+
+    /// The attempt failed, or it can't start
+    pub fn attempt_failed(&mut self, address: &Multiaddr, error: Error<ServiceTask>) {
+        self.attempts.remove(address);
+        self.error = Some(error);
+    }
+
+    /// Return true if the address is an attempt of the group
+    pub fn remove_attempt(&mut self, address: &Multiaddr) -> bool {
+        self.attempts.remove(address)
+    }
+
+    /// Stop starting new attempts
+    pub fn stop(&mut self) {
+        self.done = true;
+        self.candidates.clear();
+    }
+
+    /// No attempt is in progress and no more will start
+    pub fn is_finished(&self) -> bool {
+        self.attempts.is_empty() && (self.done || self.candidates.is_empty())
+    }
+
+    /// The error of the finished group, None if it has a winner or canceled
+    pub fn into_error(self) -> Option<Error<ServiceTask>> {
+        if self.done {
+            None
+        } else {
+            Some(
+                self.error
+                    .unwrap_or_else(|| io::Error::from(io::ErrorKind::NotConnected).into()),
+            )
+        }
+    }
+}
@@ -0,0 +1,239 @@
+use futures::{prelude::*, sync::oneshot};
+use multiaddr::Multiaddr;
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
+use tokio::timer::Delay;
+
+use crate::{
+    config::RetryPolicy,
+    error::Error,
+    service::{DialResult, ServiceTask},
+};
+
+/// A dial waits for result or retry
+pub(crate) struct DialTrack {
+    /// The address passed to dial
+    pub origin: Multiaddr,
+    /// Result sender of `dial_with_result`
+    pub sender: Option<oneshot::Sender<DialResult>>,
+    /// Retry policy and attempts of `dial_with_policy`
+    pub retry: Option<(RetryPolicy, usize)>,
+}
+
+impl DialTrack {
+    pub fn new(
+        origin: Multiaddr,
+        sender: Option<oneshot::Sender<DialResult>>,
+        retry: Option<RetryPolicy>,
+    ) -> Self {
+        DialTrack {
+            origin,
+            sender,
+            retry: retry.map(|policy| (policy, 1)),
+        }
+    }
+
+    /// Attach the result sender and retry policy to the dial in progress,
+    /// only one result sender is allowed
+    pub fn attach(
+        &mut self,
+        sender: Option<oneshot::Sender<DialResult>>,
+        retry: Option<RetryPolicy>,
+    ) {
+        if let Some(sender) = sender {
+            if self.sender.is_some() {
+                let error = io::Error::new(io::ErrorKind::AlreadyExists, "dial in progress");
+                let _ = sender.send(Err(error.into()));
+            } else {
+                self.sender = Some(sender);
+            }
+        }
+        if self.retry.is_none() {
+            self.retry = retry.map(|policy| (policy, 1));
+        }
+    }
+}
+
+/// What happens to a failed dial
+pub(crate) enum DialFail {
+    /// Retry after the delay, with the failed attempts since the dial started
+    Retry { attempts: usize, delay: Duration },
+    /// No more retry, the result sender gets the error
+    GiveUp(Option<oneshot::Sender<DialResult>>),
+}
+
+/// Dials waiting for result or retry
+pub(crate) struct DialTracker {
+    /// Key is the address in dialing
+    tracks: HashMap<Multiaddr, DialTrack>,
+    /// Dials waiting for retry
+    retry_pending: Vec<(Delay, DialTrack)>,
+}
+
+impl DialTracker {
+    pub fn new() -> Self {
+        DialTracker {
+            tracks: HashMap::new(),
+            retry_pending: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, address: Multiaddr, track: DialTrack) {
+        self.tracks.insert(address, track);
+    }
+
+    pub fn remove(&mut self, address: &Multiaddr) -> Option<DialTrack> {
+        self.tracks.remove(address)
+    }
+
+    /// The dial in progress or waiting for retry
+    pub fn get_mut(&mut self, address: &Multiaddr) -> Option<&mut DialTrack> {
+        match self.tracks.get_mut(address) {
+            Some(track) => Some(track),
+            None => self
+                .retry_pending
+                .iter_mut()
+                .map(|(_, track)| track)
+                .find(|track| &track.origin == address),
+        }
+    }
+
+    pub fn contains(&self, address: &Multiaddr) -> bool {
+        self.tracks.contains_key(address) || self.is_retry_pending(address)
+    }
+
+    /// The dial of address is waiting for retry
+    pub fn is_retry_pending(&self, address: &Multiaddr) -> bool {
+        self.retry_pending
+            .iter()
+            .any(|(_, track)| &track.origin == address)
+    }
+
+    pub fn has_retry_pending(&self) -> bool {
+        !self.retry_pending.is_empty()
+    }
+
+    /// The dial of `from` continues as `to`, such as DNS resolved
+    pub fn move_track(&mut self, from: &Multiaddr, to: &Multiaddr) {
+        if from != to {
+            if let Some(track) = self.tracks.remove(from) {
+                self.tracks.insert(to.clone(), track);
+            }
+        }
+    }
+
+    /// Dial the track again after the delay
+    pub fn retry_later(&mut self, track: DialTrack, delay: Duration) {
+        self.retry_pending
+            .push((Delay::new(Instant::now() + delay), track));
+    }
+
+    /// Stop waiting for retry, return the track
+    pub fn cancel_retry(&mut self, address: &Multiaddr) -> Option<DialTrack> {
+        let index = self
+            .retry_pending
+            .iter()
+            .position(|(_, track)| &track.origin == address)?;
+        Some(self.retry_pending.remove(index).1)
+    }
+
+    /// The dial of address failed, schedule a retry if the policy allows.
+    ///
+    /// Return the address passed to dial, None if the dial is not tracked
+    pub fn fail(
+        &mut self,
+        address: &Multiaddr,
+        error: &Error<ServiceTask>,
+    ) -> Option<(Multiaddr, DialFail)> {
+        let mut track = self.tracks.remove(address)?;
+        let retry = track.retry.as_mut().and_then(|(policy, attempts)| {
+            if *attempts < policy.max_attempts && policy.should_retry(error) {
+                *attempts += 1;
+                Some((*attempts - 1, policy.delay(*attempts - 1)))
+            } else {
+                None
+            }
+        });
+        let origin = track.origin.clone();
+        match retry {
+            Some((attempts, delay)) => {
+                self.retry_later(track, delay);
+                Some((origin, DialFail::Retry { attempts, delay }))
+            }
+            None => Some((origin, DialFail::GiveUp(track.sender))),
+        }
+    }
+
+    /// The dials whose retry delay fired
+    pub fn poll_retry(&mut self) -> Vec<DialTrack> {
+        let mut ready = Vec::new();
+        for (mut delay, track) in self.retry_pending.split_off(0) {
+            match delay.poll() {
+                Ok(Async::NotReady) => self.retry_pending.push((delay, track)),
+                _ => ready.push(track),
+            }
+        }
+        ready
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DialFail, DialTrack, DialTracker};
+    use crate::{config::RetryPolicy, error::Error};
+    use multiaddr::Multiaddr;
+    use std::{io, time::Duration};
+
+    #[test]
+    fn retry_until_max_attempts() {
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1337".parse().unwrap();
+        let policy = RetryPolicy {
+            max_attempts: 2,
+            jitter: 0.0,
+            ..Default::default()
+        };
+        let refused = || Error::IoError(io::ErrorKind::ConnectionRefused.into());
+        let mut tracker = DialTracker::new();
+        tracker.insert(
+            address.clone(),
+            DialTrack::new(address.clone(), None, Some(policy)),
+        );
+
+        match tracker.fail(&address, &refused()) {
+            Some((origin, DialFail::Retry { attempts, delay })) => {
+                assert_eq!(origin, address);
+                assert_eq!(attempts, 1);
+                assert_eq!(delay, Duration::from_secs(1));
+            }
+            _ => panic!("not retried"),
+        }
+        assert!(tracker.is_retry_pending(&address));
+
+        // The second attempt is the last one
+        let track = tracker.cancel_retry(&address).unwrap();
+        tracker.insert(address.clone(), track);
+        match tracker.fail(&address, &refused()) {
+            Some((_, DialFail::GiveUp(None))) => (),
+            _ => panic!("retried over max attempts"),
+        }
+        assert!(!tracker.contains(&address));
+        assert!(tracker.fail(&address, &refused()).is_none());
+    }
+
+    #[test]
+    fn not_retry_other_errors() {
+        let address: Multiaddr = "/ip4/127.0.0.1/tcp/1337".parse().unwrap();
+        let mut tracker = DialTracker::new();
+        tracker.insert(
+            address.clone(),
+            DialTrack::new(address.clone(), None, Some(RetryPolicy::default())),
+        );
+        match tracker.fail(&address, &Error::PeerIdNotMatch) {
+            Some((_, DialFail::GiveUp(None))) => (),
+            _ => panic!("retried"),
+        }
+    }
+}
@@ -1,4 +1,4 @@
-use crate::SessionId;
+use crate::{config::DialDenied, hello::HelloMismatch, SessionId};
 use futures::sync::mpsc;
 use secio::error::SecioError;
 use std::{error, fmt, io};
@@ -22,6 +22,12 @@ pub enum Error<T: fmt::Debug> {
     HandshakeError(SecioError),
     /// DNS resolver error
     DNSResolverError(io::Error),
+    /// Inbound slots are full and no session can be evicted
+    InboundFull,
+    /// Dial address refused by the dial filter
+    DialDenied(DialDenied),
+    /// Remote hello doesn't match ours
+    HelloMismatch(HelloMismatch),
 }
 
 impl<T> PartialEq for Error<T>
@@ -33,9 +39,12 @@ where
         match (self, other) {
             (TaskDisconnect, TaskDisconnect)
             | (ConnectSelf, ConnectSelf)
-            | (PeerIdNotMatch, PeerIdNotMatch) => true,
+            | (PeerIdNotMatch, PeerIdNotMatch)
+            | (InboundFull, InboundFull) => true,
             (RepeatedConnection(i), RepeatedConnection(j)) => i == j,
             (HandshakeError(i), HandshakeError(j)) => i == j,
+            (DialDenied(i), DialDenied(j)) => i == j,
+            (HelloMismatch(i), HelloMismatch(j)) => i == j,
             _ => false,
         }
     }
@@ -92,6 +101,9 @@ where
             Error::PeerIdNotMatch => "When dial remote, peer id does not match",
             Error::HandshakeError(e) => error::Error::description(e),
             Error::DNSResolverError(_) => "DNS resolver error",
+            Error::InboundFull => "Inbound slots are full",
+            Error::DialDenied(_) => "Dial address refused by the dial filter",
+            Error::HelloMismatch(_) => "Remote hello doesn't match",
         }
     }
 }
@@ -112,6 +124,9 @@ where
             Error::PeerIdNotMatch => write!(f, "When dial remote, peer id does not match"),
             Error::HandshakeError(e) => fmt::Display::fmt(e, f),
             Error::DNSResolverError(e) => write!(f, "DNs resolver error: {:?}", e),
+            Error::InboundFull => write!(f, "Inbound slots are full"),
+            Error::DialDenied(reason) => write!(f, "Dial address refused: {}", reason),
+            Error::HelloMismatch(reason) => write!(f, "Remote refused by hello: {}", reason),
         }
     }
 }
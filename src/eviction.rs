@@ -0,0 +1,198 @@
+use multiaddr::Multiaddr;
+use secio::PeerId;
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    net::IpAddr,
+    time::{Duration, Instant},
+};
+
+use crate::{utils::multiaddr_to_socketaddr, SessionId};
+
+/// An inbound session that may be evicted
+#[derive(Clone, Debug)]
+pub struct EvictionCandidate {
+    /// Session id
+    pub id: SessionId,
+    /// Remote address
+    pub address: Multiaddr,
+    /// Remote peer id, only sessions with secio have
+    pub peer_id: Option<PeerId>,
+    /// When the session opened
+    pub connected_at: Instant,
+    /// Latest ping round trip time, set by `ServiceControl::set_session_rtt`
+    pub ping_rtt: Option<Duration>,
+    /// Latest time the session is useful, set by `ServiceControl::mark_session_useful`
+    pub last_useful: Option<Instant>,
+}
+
+impl EvictionCandidate {
+    /// Network group of the remote ip, /16 for IPv4 and /32 for IPv6
+    pub fn net_group(&self) -> Vec<u8> {
+        match multiaddr_to_socketaddr(&self.address).map(|addr| addr.ip()) {
+            Ok(IpAddr::V4(ip)) => {
+                let octets = ip.octets();
+                vec![4, octets[0], octets[1]]
+            }
+            Ok(IpAddr::V6(ip)) => {
+                let mut group = vec![6];
+                group.extend_from_slice(&ip.octets()[..4]);
+                group
+            }
+            Err(()) => Vec::new(),
+        }
+    }
+}
+
+/// Choose an inbound session to evict when inbound slots are full
+///
+/// The candidates don't contain sessions of protected peers.
+pub trait EvictionScorer: Send {
+    /// The session to evict for a new inbound session, None means refuse the new one
+    fn select(&mut self, candidates: &[EvictionCandidate]) -> Option<SessionId>;
+}
+
+/// Eviction policy like bitcoin
+///
+/// Some candidates are protected in turn: distinct network groups, lowest ping rtt,
+/// recently useful, then the longest connected half of the rest. The youngest session
+/// in the network group with most sessions is evicted from the remaining.
+pub struct DefaultEvictionScorer {
+    // Random key, remote can't predict which network groups are protected
+    net_group_key: u64,
+    protect_net_group: usize,
+    protect_ping: usize,
+    protect_useful: usize,
+}
+
+impl DefaultEvictionScorer {
+    /// Number of candidates protected by network group, ping rtt and usefulness
+    pub fn new(protect_net_group: usize, protect_ping: usize, protect_useful: usize) -> Self {
+        DefaultEvictionScorer {
+            net_group_key: rand::random(),
+            protect_net_group,
+            protect_ping,
+            protect_useful,
+        }
+    }
+
+    fn keyed_net_group(&self, candidate: &EvictionCandidate) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        self.net_group_key.hash(&mut hasher);
+        candidate.net_group().hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+impl Default for DefaultEvictionScorer {
+    fn default() -> Self {
+        DefaultEvictionScorer::new(4, 8, 4)
+    }
+}
+
+impl EvictionScorer for DefaultEvictionScorer {
+    fn select(&mut self, candidates: &[EvictionCandidate]) -> Option<SessionId> {
+        let mut candidates = candidates.iter().collect::<Vec<_>>();
+
+        // Keep the last ones after sort
+        let protect = |candidates: &mut Vec<&EvictionCandidate>, n: usize| {
+            let len = candidates.len();
+            candidates.truncate(len - n.min(len));
+        };
+
+        candidates.sort_by_key(|candidate| Reverse(self.keyed_net_group(candidate)));
+        protect(&mut candidates, self.protect_net_group);
+
+        // None rtt means no ping finished, sort it to the front
+        candidates.sort_by_key(|candidate| {
+            Reverse(
+                candidate
+                    .ping_rtt
+                    .unwrap_or(Duration::from_secs(u64::max_value())),
+            )
+        });
+        protect(&mut candidates, self.protect_ping);
+
+        candidates.sort_by_key(|candidate| candidate.last_useful);
+        protect(&mut candidates, self.protect_useful);
+
+        candidates.sort_by_key(|candidate| Reverse(candidate.connected_at));
+        let half = candidates.len() / 2;
+        protect(&mut candidates, half);
+
+        let mut groups: HashMap<Vec<u8>, Vec<&EvictionCandidate>> = HashMap::new();
+        for candidate in candidates {
+            groups
+                .entry(candidate.net_group())
+                .or_default()
+                .push(candidate);
+        }
+
+        // The most sessions, then the youngest session
+        groups
+            .values()
+            .max_by_key(|group| {
+                (
+                    group.len(),
+                    group.iter().map(|candidate| candidate.connected_at).max(),
+                )
+            })
+            .and_then(|group| group.iter().max_by_key(|candidate| candidate.connected_at))
+            .map(|candidate| candidate.id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{DefaultEvictionScorer, EvictionCandidate, EvictionScorer};
+    use std::time::{Duration, Instant};
+
+    fn candidate(id: usize, ip: &str, age: u64) -> EvictionCandidate {
+        EvictionCandidate {
+            id,
+            address: format!("/ip4/{}/tcp/1337", ip).parse().unwrap(),
+            peer_id: None,
+            connected_at: Instant::now() - Duration::from_secs(age),
+            ping_rtt: None,
+            last_useful: None,
+        }
+    }
+
+    #[test]
+    fn evict_youngest_in_largest_group() {
+        let candidates = vec![
+            candidate(1, "1.1.0.1", 100),
+            candidate(2, "2.2.0.1", 90),
+            candidate(3, "1.1.0.2", 50),
+            candidate(4, "1.1.0.3", 10),
+            candidate(5, "2.2.0.2", 5),
+        ];
+        // 1 and 2 are protected as the longest connected half
+        let mut scorer = DefaultEvictionScorer::new(0, 0, 0);
+        assert_eq!(scorer.select(&candidates), Some(4));
+    }
+
+    #[test]
+    fn protect_low_ping_and_useful() {
+        let mut candidates = vec![
+            candidate(1, "1.1.0.1", 100),
+            candidate(2, "1.1.0.2", 50),
+            candidate(3, "1.1.0.3", 10),
+        ];
+        candidates[2].ping_rtt = Some(Duration::from_millis(10));
+        let mut scorer = DefaultEvictionScorer::new(0, 1, 0);
+        assert_eq!(scorer.select(&candidates), Some(2));
+
+        candidates[1].last_useful = Some(Instant::now());
+        let mut scorer = DefaultEvictionScorer::new(0, 1, 1);
+        assert_eq!(scorer.select(&candidates), Some(1));
+    }
+
+    #[test]
+    fn protect_all() {
+        let candidates = vec![candidate(1, "1.1.0.1", 100)];
+        let mut scorer = DefaultEvictionScorer::default();
+        assert_eq!(scorer.select(&candidates), None);
+    }
+}
@@ -0,0 +1,175 @@
+use futures::prelude::*;
+use std::{error, fmt, io};
+use tokio::io::{flush, read_exact, write_all};
+use tokio::prelude::{AsyncRead, AsyncWrite};
+
+use crate::{error::Error, service::ServiceTask};
+
+/// Max length of the hello message
+const MAX_HELLO_LENGTH: usize = 1024;
+
+/// Exchanged after the secio handshake and before any protocol is opened,
+/// so nodes of different networks refuse each other
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Hello {
+    /// Network id or genesis hash, must be the same on both sides
+    pub network_id: Vec<u8>,
+    /// Version of the protocol suite, must be the same on both sides
+    pub version: u32,
+    /// Capability flags, not checked
+    pub capabilities: u64,
+}
+
+impl Hello {
+    /// Hello of the network, with version 0 and no capabilities
+    pub fn new<T: Into<Vec<u8>>>(network_id: T) -> Self {
+        Hello {
+            network_id: network_id.into(),
+            version: 0,
+            capabilities: 0,
+        }
+    }
+
+    /// Version of the protocol suite
+    pub fn version(mut self, version: u32) -> Self {
+        self.version = version;
+        self
+    }
+
+    /// Capability flags
+    pub fn capabilities(mut self, capabilities: u64) -> Self {
+        self.capabilities = capabilities;
+        self
+    }
+
+    /// Check the hello of remote
+    pub fn check(&self, remote: &Hello) -> Result<(), HelloMismatch> {
+        if self.network_id != remote.network_id {
+            Err(HelloMismatch::NetworkId)
+        } else if self.version != remote.version {
+            Err(HelloMismatch::Version {
+                local: self.version,
+                remote: remote.version,
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Encode as length, version, capabilities and network id, big endian
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(16 + self.network_id.len());
+        buf.extend_from_slice(&(12 + self.network_id.len() as u32).to_be_bytes());
+        buf.extend_from_slice(&self.version.to_be_bytes());
+        buf.extend_from_slice(&self.capabilities.to_be_bytes());
+        buf.extend_from_slice(&self.network_id);
+        buf
+    }
+
+    /// Decode the encoded hello without the length, None if it's too short
+    fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 12 {
+            return None;
+        }
+        let mut version = [0; 4];
+        version.copy_from_slice(&data[..4]);
+        let mut capabilities = [0; 8];
+        capabilities.copy_from_slice(&data[4..12]);
+        Some(Hello {
+            network_id: data[12..].to_vec(),
+            version: u32::from_be_bytes(version),
+            capabilities: u64::from_be_bytes(capabilities),
+        })
+    }
+}
+
+/// Why a session is refused by hello
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HelloMismatch {
+    /// Remote is on another network
+    NetworkId,
+    /// Remote runs another version of the protocol suite
+    Version {
+        /// Our version
+        local: u32,
+        /// Version of remote
+        remote: u32,
+    },
+}
+
+impl error::Error for HelloMismatch {}
+
+impl fmt::Display for HelloMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HelloMismatch::NetworkId => write!(f, "Network id mismatch"),
+            HelloMismatch::Version { local, remote } => write!(
+                f,
+                "Protocol suite version mismatch, local: {}, remote: {}",
+                local, remote
+            ),
+        }
+    }
+}
+
+/// Send our hello and read the remote one, both sides send first so it doesn't
+/// matter who dials. Only the hello is read, the data after it is left in the handle.
+pub(crate) fn exchange<H>(
+    handle: H,
+    local: Hello,
+) -> impl Future<Item = (H, Hello), Error = Error<ServiceTask>>
+where
+    H: AsyncRead + AsyncWrite,
+{
+    write_all(handle, local.encode())
+        .and_then(|(handle, _)| flush(handle))
+        .and_then(|handle| read_exact(handle, [0; 4]))
+        .and_then(|(handle, length)| {
+            let length = u32::from_be_bytes(length) as usize;
+            if length > MAX_HELLO_LENGTH {
+                Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "hello is too long",
+                ))
+            } else {
+                Ok((handle, length))
+            }
+        })
+        .and_then(|(handle, length)| read_exact(handle, vec![0; length]))
+        .map_err(Error::from)
+        .and_then(move |(handle, data)| {
+            let remote = Hello::decode(&data).ok_or_else(|| {
+                Error::IoError(io::Error::new(io::ErrorKind::InvalidData, "invalid hello"))
+            })?;
+            local.check(&remote).map_err(Error::HelloMismatch)?;
+            Ok((handle, remote))
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Hello, HelloMismatch};
+
+    #[test]
+    fn encode_and_check() {
+        let hello = Hello::new(&b"mainnet"[..]).version(2).capabilities(0b101);
+        let data = hello.encode();
+        assert_eq!(&data[..4], &[0, 0, 0, 19]);
+        assert_eq!(Hello::decode(&data[4..]), Some(hello.clone()));
+        assert_eq!(Hello::decode(&data[4..15]), None);
+
+        let remote = Hello::new(&b"mainnet"[..]).version(2);
+        assert_eq!(hello.check(&remote), Ok(()));
+        assert_eq!(
+            hello.check(&Hello::new(&b"testnet"[..]).version(2)),
+            Err(HelloMismatch::NetworkId)
+        );
+        assert_eq!(
+            hello.check(&remote.version(3)),
+            Err(HelloMismatch::Version {
+                local: 2,
+                remote: 3
+            })
+        );
+    }
+}
@@ -0,0 +1,59 @@
+use futures::prelude::*;
+use log::warn;
+use std::{net::IpAddr, time::Duration};
+use tokio::timer::Interval;
+
+use crate::utils::interface_ips;
+
+/// Interval to check the changes of local interfaces, when listen on unspecified ip
+const INTERFACE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Ips of local interfaces, used to expand unspecified listen ip
+pub(crate) struct InterfaceWatcher {
+    ips: Vec<IpAddr>,
+    /// Check the changes of local interfaces
+    check: Interval,
+}
+
+impl InterfaceWatcher {
+    pub fn new() -> Self {
+        InterfaceWatcher {
+            ips: Vec::new(),
+            check: Interval::new_interval(INTERFACE_CHECK_INTERVAL),
+        }
+    }
+
+    pub fn ips(&self) -> &[IpAddr] {
+        &self.ips
+    }
+
+    /// Refresh ips of local interfaces, return true if they changed
+    pub fn refresh(&mut self) -> bool {
+        match interface_ips() {
+            Ok(ips) => {
+                if ips != self.ips {
+                    self.ips = ips;
+                    return true;
+                }
+            }
+            Err(err) => warn!("get interface addresses error: {:?}", err),
+        }
+        false
+    }
+
+    /// Refresh the ips when the check interval fired, return true if they changed
+    pub fn poll_changed(&mut self) -> bool {
+        let mut tick = false;
+        loop {
+            match self.check.poll() {
+                Ok(Async::Ready(Some(_))) => tick = true,
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => {
+                    warn!("interface check timer error: {:?}", err);
+                    break;
+                }
+            }
+        }
+        tick && self.refresh()
+    }
+}
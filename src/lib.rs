@@ -9,18 +9,36 @@ pub use multiaddr;
 /// Re-pub some useful structures in secio
 pub use secio::{error::SecioError, PeerId, PublicKey, SecioKeyPair};
 /// Re-pub some useful structures in yamux
-pub use yamux::{session::SessionType, Config as YamuxConfig, Session};
+pub use yamux::{frame::GoAwayCode, session::SessionType, Config as YamuxConfig, Session};
 
+/// Our public addresses
+pub(crate) mod address_book;
 /// Some gadgets that help create a service
 pub mod builder;
+/// Configuration of channels, buffers, dials and TCP sockets
+pub mod config;
 /// Context for Session and Service
 pub mod context;
+/// Connection attempts of `dial_any`
+pub(crate) mod dial_group;
+/// Dials waiting for result or retry
+pub(crate) mod dial_track;
 /// Error
 pub mod error;
+/// Inbound eviction when inbound slots are full
+pub mod eviction;
+/// Hello exchanged before any protocol is opened
+pub mod hello;
+/// Ips of local interfaces
+pub(crate) mod interface;
+/// Peers keep connected
+pub(crate) mod persistent_peer;
 /// Protocol handle callback stream
 pub(crate) mod protocol_handle_stream;
 /// Protocol select
 pub mod protocol_select;
+/// Reachability of our node, decided by the dial back results of peers
+pub mod reachability;
 /// An abstraction of p2p service
 pub mod service;
 /// Wrapper for real data streams
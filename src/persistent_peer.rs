@@ -0,0 +1,93 @@
+use multiaddr::Multiaddr;
+use secio::PeerId;
+use std::{collections::HashMap, time::Duration};
+
+use crate::{config::RetryPolicy, dial_track::DialTrack, utils::extract_peer_id};
+
+/// Peers keep connected, they are redialed with backoff after the session closed
+pub(crate) struct PersistentPeers {
+    /// Dial addresses of the peers
+    peers: HashMap<PeerId, Multiaddr>,
+    /// Retry policy to reconnect the peers
+    policy: RetryPolicy,
+}
+
+impl PersistentPeers {
+    pub fn new(policy: RetryPolicy) -> Self {
+        PersistentPeers {
+            peers: HashMap::new(),
+            policy,
+        }
+    }
+
+    pub fn set_policy(&mut self, policy: RetryPolicy) {
+        self.policy = policy;
+    }
+
+    /// Add the peer by an address containing peer id, return the peer id
+    pub fn add(&mut self, address: Multiaddr) -> Option<PeerId> {
+        let peer_id = extract_peer_id(&address)?;
+        self.peers.insert(peer_id.clone(), address);
+        Some(peer_id)
+    }
+
+    /// Remove the peer of the address, return its dial address
+    pub fn remove(&mut self, address: &Multiaddr) -> Option<Multiaddr> {
+        extract_peer_id(address).and_then(|peer_id| self.peers.remove(&peer_id))
+    }
+
+    pub fn contains(&self, peer_id: &PeerId) -> bool {
+        self.peers.contains_key(peer_id)
+    }
+
+    pub fn address(&self, peer_id: &PeerId) -> Option<&Multiaddr> {
+        self.peers.get(peer_id)
+    }
+
+    /// The persistent peer dialed by the address
+    pub fn peer(&self, address: &Multiaddr) -> Option<PeerId> {
+        extract_peer_id(address).filter(|peer_id| self.peers.get(peer_id) == Some(address))
+    }
+
+    /// Track of a new dial to the address, and the delay before it when backing off
+    pub fn dial_track(&self, address: Multiaddr) -> (DialTrack, Duration) {
+        let delay = self.policy.delay(1);
+        (
+            DialTrack::new(address, None, Some(self.policy.clone())),
+            delay,
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::PersistentPeers;
+    use crate::config::RetryPolicy;
+    use multiaddr::Multiaddr;
+    use secio::SecioKeyPair;
+
+    #[test]
+    fn peer_of_address() {
+        let mut peers = PersistentPeers::new(RetryPolicy::persistent());
+        let peer_id = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let no_peer_id: Multiaddr = "/ip4/1.1.1.1/tcp/1337".parse().unwrap();
+        assert_eq!(peers.add(no_peer_id), None);
+
+        let address: Multiaddr = format!("/ip4/1.1.1.1/tcp/1337/p2p/{}", peer_id.to_base58())
+            .parse()
+            .unwrap();
+        assert_eq!(peers.add(address.clone()), Some(peer_id.clone()));
+        assert!(peers.contains(&peer_id));
+        assert_eq!(peers.peer(&address), Some(peer_id.clone()));
+
+        // Another address of the peer
+        let other: Multiaddr = format!("/ip4/2.2.2.2/tcp/1337/p2p/{}", peer_id.to_base58())
+            .parse()
+            .unwrap();
+        assert_eq!(peers.peer(&other), None);
+
+        assert_eq!(peers.remove(&other), Some(address.clone()));
+        assert!(!peers.contains(&peer_id));
+        assert_eq!(peers.peer(&address), None);
+    }
+}
@@ -1,14 +1,78 @@
 use futures::{prelude::*, sync::mpsc};
 use log::warn;
 use multiaddr::Multiaddr;
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use crate::{
+    config::BufferBudget,
     context::{ServiceContext, SessionContext},
+    reachability::Reachability,
+    session::SessionEvent,
     traits::{ServiceProtocol, SessionProtocol},
     ProtocolId, SessionId,
 };
 
+/// Bytes taken from budget by a received message, shared by the events of its handles
+/// and given back when the last one is dropped
+pub struct BudgetCharge {
+    budget: BufferBudget,
+    size: usize,
+}
+
+impl BudgetCharge {
+    pub(crate) fn new(budget: BufferBudget, size: usize) -> Self {
+        BudgetCharge { budget, size }
+    }
+}
+
+impl Drop for BudgetCharge {
+    fn drop(&mut self) {
+        self.budget.release(self.size);
+    }
+}
+
+/// Measure how long a handle callback takes, report the slow one to service
+#[derive(Clone)]
+pub(crate) struct HandleTimer {
+    threshold: Option<Duration>,
+    sender: mpsc::Sender<SessionEvent>,
+}
+
+impl HandleTimer {
+    pub fn new(threshold: Option<Duration>, sender: mpsc::Sender<SessionEvent>) -> Self {
+        HandleTimer { threshold, sender }
+    }
+
+    #[inline]
+    fn check(
+        &mut self,
+        start: Instant,
+        proto_id: ProtocolId,
+        session_id: Option<SessionId>,
+        callback: &'static str,
+    ) {
+        if let Some(threshold) = self.threshold {
+            let elapsed = start.elapsed();
+            if elapsed >= threshold {
+                warn!(
+                    "proto [{}] handle callback [{}] took {:?}",
+                    proto_id, callback, elapsed
+                );
+                let _ = self.sender.try_send(SessionEvent::SlowHandler {
+                    proto_id,
+                    session_id,
+                    callback,
+                    elapsed,
+                });
+            }
+        }
+    }
+}
+
 pub enum ServiceProtocolEvent {
     Init,
     Connected {
@@ -24,6 +88,8 @@ pub enum ServiceProtocolEvent {
         id: SessionId,
         /// Data
         data: bytes::Bytes,
+        /// Buffer budget taken by the data, shared with the session handle's event
+        charge: Arc<BudgetCharge>,
     },
     Notify {
         /// Notify token
@@ -31,7 +97,29 @@ pub enum ServiceProtocolEvent {
     },
     Update {
         listen_addrs: Vec<Multiaddr>,
+        bind_addrs: Vec<Multiaddr>,
+    },
+    UpdateExternalAddrs {
+        external_addrs: Vec<Multiaddr>,
     },
+    UpdateReachability {
+        reachability: Reachability,
+    },
+}
+
+impl ServiceProtocolEvent {
+    /// The name of the callback which handles this event
+    fn callback(&self) -> &'static str {
+        use self::ServiceProtocolEvent::*;
+        match self {
+            Init => "init",
+            Connected { .. } => "connected",
+            Disconnected { .. } => "disconnected",
+            Received { .. } => "received",
+            Notify { .. } => "notify",
+            Update { .. } | UpdateExternalAddrs { .. } | UpdateReachability { .. } => "update",
+        }
+    }
 }
 
 pub struct ServiceProtocolStream {
@@ -41,6 +129,11 @@ pub struct ServiceProtocolStream {
     service_context: ServiceContext,
     sessions: HashMap<SessionId, SessionContext>,
     receiver: mpsc::Receiver<ServiceProtocolEvent>,
+    timer: HandleTimer,
+    /// Run callbacks on the blocking pool
+    blocking: bool,
+    /// Event waiting for a blocking pool slot
+    pending_event: Option<ServiceProtocolEvent>,
 }
 
 impl ServiceProtocolStream {
@@ -49,6 +142,8 @@ impl ServiceProtocolStream {
         service_context: ServiceContext,
         receiver: mpsc::Receiver<ServiceProtocolEvent>,
         proto_id: ProtocolId,
+        timer: HandleTimer,
+        blocking: bool,
     ) -> Self {
         ServiceProtocolStream {
             handle,
@@ -56,12 +151,40 @@ impl ServiceProtocolStream {
             service_context,
             sessions: HashMap::default(),
             receiver,
+            timer,
+            blocking,
+            pending_event: None,
+        }
+    }
+
+    /// Handle event, on the blocking pool if necessary
+    #[inline]
+    fn dispatch(&mut self, event: ServiceProtocolEvent) -> Async<()> {
+        if !self.blocking {
+            self.handle_event(event);
+            return Async::Ready(());
+        }
+
+        let mut event = Some(event);
+        match tokio_threadpool::blocking(|| self.handle_event(event.take().unwrap())) {
+            Ok(Async::Ready(_)) => Async::Ready(()),
+            Ok(Async::NotReady) => {
+                self.pending_event = event;
+                Async::NotReady
+            }
+            Err(_) => {
+                // Not on the multi-thread runtime, run it directly
+                self.handle_event(event.take().unwrap());
+                Async::Ready(())
+            }
         }
     }
 
     #[inline]
     fn handle_event(&mut self, event: ServiceProtocolEvent) {
         use self::ServiceProtocolEvent::*;
+        let start = Instant::now();
+        let callback = event.callback();
         match event {
             Init => self.handle.init(&mut self.service_context),
             Connected { session, version } => {
@@ -77,7 +200,7 @@ impl ServiceProtocolStream {
                 self.service_context
                     .remove_session_notify_senders(id, self.proto_id);
             }
-            Received { id, data } => {
+            Received { id, data, .. } => {
                 if let Some(session) = self.sessions.get_mut(&id) {
                     self.handle
                         .received(&mut self.service_context, session, data.to_vec());
@@ -86,10 +209,21 @@ impl ServiceProtocolStream {
             Notify { token } => {
                 self.handle.notify(&mut self.service_context, token);
             }
-            Update { listen_addrs } => {
-                self.service_context.update_listens(listen_addrs);
+            Update {
+                listen_addrs,
+                bind_addrs,
+            } => {
+                self.service_context
+                    .update_listens(listen_addrs, bind_addrs);
+            }
+            UpdateExternalAddrs { external_addrs } => {
+                self.service_context.update_external_addrs(external_addrs);
+            }
+            UpdateReachability { reachability } => {
+                self.service_context.update_reachability(reachability);
             }
         }
+        self.timer.check(start, self.proto_id, None, callback);
     }
 }
 
@@ -98,9 +232,19 @@ impl Stream for ServiceProtocolStream {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(event) = self.pending_event.take() {
+            if let Async::NotReady = self.dispatch(event) {
+                return Ok(Async::NotReady);
+            }
+        }
+
         loop {
             match self.receiver.poll() {
-                Ok(Async::Ready(Some(event))) => self.handle_event(event),
+                Ok(Async::Ready(Some(event))) => {
+                    if let Async::NotReady = self.dispatch(event) {
+                        return Ok(Async::NotReady);
+                    }
+                }
                 Ok(Async::Ready(None)) => {
                     for id in self.sessions.keys() {
                         self.service_context
@@ -132,6 +276,8 @@ pub enum SessionProtocolEvent {
     Received {
         /// Data
         data: bytes::Bytes,
+        /// Buffer budget taken by the data, shared with the service handle's event
+        charge: Arc<BudgetCharge>,
     },
     Notify {
         /// Notify token
@@ -139,7 +285,28 @@ pub enum SessionProtocolEvent {
     },
     Update {
         listen_addrs: Vec<Multiaddr>,
+        bind_addrs: Vec<Multiaddr>,
+    },
+    UpdateExternalAddrs {
+        external_addrs: Vec<Multiaddr>,
     },
+    UpdateReachability {
+        reachability: Reachability,
+    },
+}
+
+impl SessionProtocolEvent {
+    /// The name of the callback which handles this event
+    fn callback(&self) -> &'static str {
+        use self::SessionProtocolEvent::*;
+        match self {
+            Connected { .. } => "connected",
+            Disconnected => "disconnected",
+            Received { .. } => "received",
+            Notify { .. } => "notify",
+            Update { .. } | UpdateExternalAddrs { .. } | UpdateReachability { .. } => "update",
+        }
+    }
 }
 
 pub struct SessionProtocolStream {
@@ -149,6 +316,11 @@ pub struct SessionProtocolStream {
     context: SessionContext,
     proto_id: ProtocolId,
     receiver: mpsc::Receiver<SessionProtocolEvent>,
+    timer: HandleTimer,
+    /// Run callbacks on the blocking pool
+    blocking: bool,
+    /// Event waiting for a blocking pool slot
+    pending_event: Option<SessionProtocolEvent>,
 }
 
 impl SessionProtocolStream {
@@ -158,6 +330,8 @@ impl SessionProtocolStream {
         context: SessionContext,
         receiver: mpsc::Receiver<SessionProtocolEvent>,
         proto_id: ProtocolId,
+        timer: HandleTimer,
+        blocking: bool,
     ) -> Self {
         SessionProtocolStream {
             handle,
@@ -165,12 +339,40 @@ impl SessionProtocolStream {
             service_context,
             receiver,
             context,
+            timer,
+            blocking,
+            pending_event: None,
+        }
+    }
+
+    /// Handle event, on the blocking pool if necessary
+    #[inline]
+    fn dispatch(&mut self, event: SessionProtocolEvent) -> Async<()> {
+        if !self.blocking {
+            self.handle_event(event);
+            return Async::Ready(());
+        }
+
+        let mut event = Some(event);
+        match tokio_threadpool::blocking(|| self.handle_event(event.take().unwrap())) {
+            Ok(Async::Ready(_)) => Async::Ready(()),
+            Ok(Async::NotReady) => {
+                self.pending_event = event;
+                Async::NotReady
+            }
+            Err(_) => {
+                // Not on the multi-thread runtime, run it directly
+                self.handle_event(event.take().unwrap());
+                Async::Ready(())
+            }
         }
     }
 
     #[inline]
     fn handle_event(&mut self, event: SessionProtocolEvent) {
         use self::SessionProtocolEvent::*;
+        let start = Instant::now();
+        let callback = event.callback();
         match event {
             Connected { version } => {
                 self.handle
@@ -180,17 +382,29 @@ impl SessionProtocolStream {
                 self.handle.disconnected(&mut self.service_context);
                 self.close();
             }
-            Received { data } => {
+            Received { data, .. } => {
                 self.handle
                     .received(&mut self.service_context, data.to_vec());
             }
             Notify { token } => {
                 self.handle.notify(&mut self.service_context, token);
             }
-            Update { listen_addrs } => {
-                self.service_context.update_listens(listen_addrs);
+            Update {
+                listen_addrs,
+                bind_addrs,
+            } => {
+                self.service_context
+                    .update_listens(listen_addrs, bind_addrs);
+            }
+            UpdateExternalAddrs { external_addrs } => {
+                self.service_context.update_external_addrs(external_addrs);
+            }
+            UpdateReachability { reachability } => {
+                self.service_context.update_reachability(reachability);
             }
         }
+        self.timer
+            .check(start, self.proto_id, Some(self.context.id), callback);
     }
 
     #[inline(always)]
@@ -206,9 +420,19 @@ impl Stream for SessionProtocolStream {
     type Error = ();
 
     fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        if let Some(event) = self.pending_event.take() {
+            if let Async::NotReady = self.dispatch(event) {
+                return Ok(Async::NotReady);
+            }
+        }
+
         loop {
             match self.receiver.poll() {
-                Ok(Async::Ready(Some(event))) => self.handle_event(event),
+                Ok(Async::Ready(Some(event))) => {
+                    if let Async::NotReady = self.dispatch(event) {
+                        return Ok(Async::NotReady);
+                    }
+                }
                 Ok(Async::Ready(None)) => {
                     self.close();
                     return Ok(Async::Ready(None));
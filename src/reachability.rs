@@ -0,0 +1,144 @@
+use futures::prelude::*;
+use log::warn;
+use std::{
+    collections::VecDeque,
+    time::{Duration, Instant},
+};
+use tokio::timer::Interval;
+
+/// Max dial back results kept
+const MAX_RESULTS: usize = 16;
+
+/// Interval to drop the expired dial back results
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Whether our node is reachable by peers, decided by the dial back results
+/// reported by `ServiceControl::report_reachability`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Reachability {
+    /// Not enough results, or they disagree
+    Unknown,
+    /// Peers can dial us
+    Public,
+    /// Peers can't dial us, such as behind NAT
+    Private,
+}
+
+impl Default for Reachability {
+    fn default() -> Self {
+        Reachability::Unknown
+    }
+}
+
+/// Recent dial back results
+pub(crate) struct ReachabilityTracker {
+    /// The oldest first
+    results: VecDeque<(Instant, bool)>,
+    /// Results needed to decide the reachability
+    threshold: usize,
+    /// Results are dropped after this
+    ttl: Duration,
+    /// Drop the expired results
+    check: Interval,
+}
+
+impl ReachabilityTracker {
+    pub fn new(threshold: usize, ttl: Duration) -> Self {
+        ReachabilityTracker {
+            results: VecDeque::new(),
+            threshold,
+            ttl,
+            check: Interval::new_interval(CHECK_INTERVAL),
+        }
+    }
+
+    pub fn set_threshold(&mut self, threshold: usize) {
+        self.threshold = threshold;
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+
+    pub fn report(&mut self, reachable: bool, now: Instant) {
+        if self.results.len() >= MAX_RESULTS {
+            self.results.pop_front();
+        }
+        self.results.push_back((now, reachable));
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(&(reported_at, _)) = self.results.front() {
+            if reported_at + self.ttl > now {
+                break;
+            }
+            self.results.pop_front();
+        }
+    }
+
+    /// Whether the check interval fired, the expired results should be dropped
+    pub fn poll_check(&mut self) -> bool {
+        if self.results.is_empty() {
+            return false;
+        }
+        let mut tick = false;
+        loop {
+            match self.check.poll() {
+                Ok(Async::Ready(Some(_))) => tick = true,
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
+                Err(err) => {
+                    warn!("reachability check timer error: {:?}", err);
+                    break;
+                }
+            }
+        }
+        tick
+    }
+
+    /// Enough results and most of them agree
+    pub fn status(&self) -> Reachability {
+        let successes = self
+            .results
+            .iter()
+            .filter(|(_, reachable)| *reachable)
+            .count();
+        let failures = self.results.len() - successes;
+        if successes >= self.threshold && successes > failures {
+            Reachability::Public
+        } else if failures >= self.threshold && failures > successes {
+            Reachability::Private
+        } else {
+            Reachability::Unknown
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Reachability, ReachabilityTracker};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn decide_by_threshold() {
+        let now = Instant::now();
+        let mut tracker = ReachabilityTracker::new(2, Duration::from_secs(10));
+        assert_eq!(tracker.status(), Reachability::Unknown);
+
+        tracker.report(true, now);
+        assert_eq!(tracker.status(), Reachability::Unknown);
+        tracker.report(true, now);
+        assert_eq!(tracker.status(), Reachability::Public);
+
+        // Disagree
+        tracker.report(false, now + Duration::from_secs(5));
+        tracker.report(false, now + Duration::from_secs(5));
+        assert_eq!(tracker.status(), Reachability::Unknown);
+
+        tracker.expire(now + Duration::from_secs(10));
+        assert_eq!(tracker.status(), Reachability::Private);
+
+        tracker.expire(now + Duration::from_secs(15));
+        assert!(tracker.is_empty());
+        assert_eq!(tracker.status(), Reachability::Unknown);
+    }
+}
@@ -1,39 +1,57 @@
 use futures::{
+    future,
     prelude::*,
-    sync::mpsc,
+    sync::{mpsc, oneshot},
     task::{self, Task},
 };
 use log::{debug, error, trace, warn};
 use multiaddr::{multihash::Multihash, Multiaddr, Protocol, ToMultiaddr};
-use secio::{handshake::Config, PublicKey, SecioKeyPair};
+use secio::{handshake::Config, PeerId, PublicKey, SecioKeyPair};
 use std::collections::{HashMap, HashSet, VecDeque};
 use std::sync::Arc;
 use std::{
     error::{self, Error as ErrorTrait},
-    fmt, io,
-    time::Duration,
+    fmt, io, thread,
+    time::{Duration, Instant},
 };
 use tokio::net::{
     tcp::{ConnectFuture, Incoming},
-    TcpListener, TcpStream,
+    TcpStream,
 };
 use tokio::{
     codec::{Decoder, Encoder},
     prelude::{AsyncRead, AsyncWrite, FutureExt},
+    runtime::current_thread,
     timer::Timeout,
 };
-use yamux::{session::SessionType, Config as YamuxConfig};
+use yamux::{
+    frame::GoAwayCode,
+    session::{CloseReason as YamuxCloseReason, SessionType},
+    Config as YamuxConfig,
+};
 
 use crate::{
+    address_book::AddressBook,
+    config::{BufferBudget, BufferConfig, DialFilter, OverflowPolicy, RetryPolicy, TcpConfig},
     context::{ServiceContext, ServiceControl, SessionContext},
+    dial_group::DialGroup,
+    dial_track::{DialFail, DialTrack, DialTracker},
     error::Error,
+    eviction::{DefaultEvictionScorer, EvictionCandidate, EvictionScorer},
+    hello::{self, Hello},
+    interface::InterfaceWatcher,
+    persistent_peer::PersistentPeers,
     protocol_handle_stream::{
-        ServiceProtocolEvent, ServiceProtocolStream, SessionProtocolEvent, SessionProtocolStream,
+        HandleTimer, ServiceProtocolEvent, ServiceProtocolStream, SessionProtocolEvent,
+        SessionProtocolStream,
     },
     protocol_select::ProtocolInfo,
-    session::{Session, SessionEvent, SessionMeta},
+    reachability::{Reachability, ReachabilityTracker},
+    session::{channel_ready, Session, SessionEvent, SessionMeta},
     traits::{ProtocolMeta, ServiceHandle, ServiceProtocol, SessionProtocol},
-    utils::{dns::DNSResolver, extract_peer_id, multiaddr_to_socketaddr},
+    utils::{
+        dns::DNSResolver, expand_listen_addrs, extract_peer_id, is_relayed, multiaddr_to_socketaddr,
+    },
     ProtocolId, SessionId, StreamId,
 };
 
@@ -45,10 +63,29 @@ pub(crate) enum ProtocolHandle {
     Session(Box<dyn SessionProtocol + Send + 'static>),
 }
 
+/// Where the protocol handles run
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum HandleExecutor {
+    /// Spawn on the service runtime
+    Runtime,
+    /// Spawn on the service runtime, but every callback runs through `tokio_threadpool::blocking`,
+    /// only effective on the multi-thread runtime
+    Blocking,
+    /// Run on a dedicated thread of the protocol with its own current thread runtime,
+    /// the service level handle and all session level handles of the protocol share it
+    Thread,
+}
+
+impl Default for HandleExecutor {
+    fn default() -> Self {
+        HandleExecutor::Runtime
+    }
+}
+
 /// Error generated by the Service
 #[derive(Debug)]
 pub enum ServiceError {
-    /// When dial remote error
+    /// When dial remote error, reported for each failed attempt of the retried dial
     DialerError {
         /// Remote address
         address: Multiaddr,
@@ -73,6 +110,132 @@ pub enum ServiceError {
     },
 }
 
+/// Why a session is closed
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Closed by local, such as `disconnect` or service shutdown
+    LocalDisconnect,
+    /// Got EOF from remote
+    RemoteEof,
+    /// Remote sent yamux GoAway frame
+    RemoteGoAway(GoAwayCode),
+    /// Remote didn't answer the keepalive ping in time
+    KeepAliveTimeout,
+    /// Data can't be written to remote in time
+    WriteTimeout,
+    /// IO error of the underlying connection
+    IoError(io::ErrorKind),
+    /// The last open protocol closed by codec error
+    CodecError(ProtocolId),
+    /// All open protocols closed
+    AllProtocolsClosed,
+    /// Buffered messages out of budget, under `OverflowPolicy::CloseSession`
+    BufferOverflow,
+    /// Evicted for a new inbound session when inbound slots are full
+    Evicted,
+    /// Replaced by a duplicate connection to the same peer, which wins the tie-break
+    Duplicate,
+    /// Remote disconnected with the reason, see `ServiceControl::disconnect_with_reason`
+    RemoteDisconnect(DisconnectReason),
+}
+
+impl From<YamuxCloseReason> for CloseReason {
+    fn from(reason: YamuxCloseReason) -> Self {
+        match reason {
+            YamuxCloseReason::LocalGoAway => CloseReason::LocalDisconnect,
+            YamuxCloseReason::RemoteGoAway(code) => CloseReason::RemoteGoAway(code),
+            YamuxCloseReason::RemoteEof => CloseReason::RemoteEof,
+            YamuxCloseReason::KeepAliveTimeout => CloseReason::KeepAliveTimeout,
+            YamuxCloseReason::WriteTimeout => CloseReason::WriteTimeout,
+            YamuxCloseReason::IoError(kind) => CloseReason::IoError(kind),
+        }
+    }
+}
+
+/// Why we disconnect, told to remote before the session is closed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DisconnectCode {
+    /// No specific reason
+    Normal,
+    /// Remote is banned
+    Banned,
+    /// We have too many peers
+    TooManyPeers,
+    /// Remote runs an incompatible version
+    IncompatibleVersion,
+    /// Codes not listed above, defined by users
+    Other(u32),
+}
+
+impl From<u32> for DisconnectCode {
+    fn from(code: u32) -> Self {
+        match code {
+            0 => DisconnectCode::Normal,
+            1 => DisconnectCode::Banned,
+            2 => DisconnectCode::TooManyPeers,
+            3 => DisconnectCode::IncompatibleVersion,
+            code => DisconnectCode::Other(code),
+        }
+    }
+}
+
+impl From<DisconnectCode> for u32 {
+    fn from(code: DisconnectCode) -> Self {
+        match code {
+            DisconnectCode::Normal => 0,
+            DisconnectCode::Banned => 1,
+            DisconnectCode::TooManyPeers => 2,
+            DisconnectCode::IncompatibleVersion => 3,
+            DisconnectCode::Other(code) => code,
+        }
+    }
+}
+
+/// Reason code and a short message sent to remote before disconnect
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DisconnectReason {
+    /// Reason code
+    pub code: DisconnectCode,
+    /// Short message for human, truncated to 256 bytes when sent
+    pub message: String,
+}
+
+impl DisconnectReason {
+    /// New a reason
+    pub fn new<T: Into<String>>(code: DisconnectCode, message: T) -> Self {
+        DisconnectReason {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Encode as code and message, the message is truncated to
+    /// `MAX_DISCONNECT_MESSAGE_LENGTH` bytes
+    pub(crate) fn encode(&self) -> bytes::Bytes {
+        let mut end = self.message.len().min(MAX_DISCONNECT_MESSAGE_LENGTH);
+        while !self.message.is_char_boundary(end) {
+            end -= 1;
+        }
+        let mut buf = Vec::with_capacity(4 + end);
+        buf.extend_from_slice(&u32::from(self.code).to_be_bytes());
+        buf.extend_from_slice(&self.message.as_bytes()[..end]);
+        buf.into()
+    }
+
+    /// Decode the encoded reason, None if it's too short
+    pub(crate) fn decode(data: &[u8]) -> Option<Self> {
+        if data.len() < 4 {
+            return None;
+        }
+        let mut code = [0; 4];
+        code.copy_from_slice(&data[..4]);
+        Some(DisconnectReason {
+            code: u32::from_be_bytes(code).into(),
+            message: String::from_utf8_lossy(&data[4..]).into_owned(),
+        })
+    }
+}
+
 /// Event generated by the Service
 #[derive(Debug)]
 pub enum ServiceEvent {
@@ -80,6 +243,8 @@ pub enum ServiceEvent {
     SessionClose {
         /// Session id
         id: SessionId,
+        /// Close reason
+        reason: CloseReason,
     },
     /// A session open
     SessionOpen {
@@ -92,8 +257,109 @@ pub enum ServiceEvent {
         /// Remote public key
         public_key: Option<PublicKey>,
     },
+    /// Start listening on an address
+    ListenStarted {
+        /// Listen address
+        address: Multiaddr,
+    },
+    /// A listener is closed
+    ListenClosed {
+        /// Listen address
+        address: Multiaddr,
+    },
+    /// Start dialing an address, a DNS address starts before resolving, then
+    /// every resolved address starts when it's dialed
+    DialStarted {
+        /// Remote address
+        address: Multiaddr,
+    },
+    /// A pending dial is canceled
+    DialCanceled {
+        /// Remote address
+        address: Multiaddr,
+    },
+    /// A protocol opened on a session
+    ProtocolOpened {
+        /// Session id
+        id: SessionId,
+        /// Protocol id
+        proto_id: ProtocolId,
+        /// Protocol version
+        version: String,
+    },
+    /// A protocol closed on a session
+    ProtocolClosed {
+        /// Session id
+        id: SessionId,
+        /// Protocol id
+        proto_id: ProtocolId,
+    },
+    /// A handle callback took longer than the slow handler threshold
+    SlowHandler {
+        /// Protocol id, None means the service handle
+        proto_id: Option<ProtocolId>,
+        /// Session id, only session level protocol handle has
+        session_id: Option<SessionId>,
+        /// Callback name
+        callback: &'static str,
+        /// Time spent in the callback
+        elapsed: Duration,
+    },
+    /// State of a persistent peer changed
+    PersistentPeerUpdate {
+        /// Peer id
+        peer_id: PeerId,
+        /// Dial address
+        address: Multiaddr,
+        /// New state
+        state: PersistentPeerState,
+    },
+    /// Our reachability changed
+    ReachabilityChanged {
+        /// New reachability
+        reachability: Reachability,
+    },
 }
 
+/// State of a persistent peer
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum PersistentPeerState {
+    /// Connected by the session, maybe under another address
+    Connected(SessionId),
+    /// Wait to redial
+    BackingOff {
+        /// Failed dial attempts since the last connection
+        attempts: usize,
+        /// Delay before the next dial
+        delay: Duration,
+    },
+    /// Out of retry attempts, or the error is not worth a retry
+    GaveUp,
+}
+
+/// Default threshold to report slow handle callbacks
+pub(crate) const DEFAULT_SLOW_HANDLER_THRESHOLD: Duration = Duration::from_millis(100);
+
+/// Default observers from different ips needed to confirm an observed address
+const DEFAULT_OBSERVED_ADDR_THRESHOLD: usize = 3;
+
+/// Default dial back results needed to decide the reachability
+const DEFAULT_REACHABILITY_THRESHOLD: usize = 3;
+
+/// Dial back results are dropped after this, then the reachability falls back to unknown
+const REACHABILITY_RESULT_TTL: Duration = Duration::from_secs(30 * 60);
+
+/// Max bytes of the message sent with the disconnect reason
+const MAX_DISCONNECT_MESSAGE_LENGTH: usize = 256;
+
+/// Result of `ServiceControl::dial_with_result`, the session id and the remote peer id
+pub type DialResult = Result<(SessionId, Option<PeerId>), Error<ServiceTask>>;
+
+/// A byte stream carried by another transport, such as a relayed circuit
+pub trait RawStream: AsyncRead + AsyncWrite {}
+
+impl<T: AsyncRead + AsyncWrite> RawStream for T {}
+
 /// Task received by the Service.
 ///
 /// An instruction that the outside world can send to the service
@@ -134,16 +400,115 @@ pub enum ServiceTask {
         /// Session id
         session_id: SessionId,
     },
+    /// Disconnect task, the reason is sent to remote first
+    DisconnectWithReason {
+        /// Session id
+        session_id: SessionId,
+        /// Reason
+        reason: DisconnectReason,
+    },
     /// Dial task
     Dial {
         /// Remote address
         address: Multiaddr,
     },
+    /// Dial any of the addresses, the first one completes the handshake wins
+    DialAny {
+        /// Candidate addresses
+        addresses: Vec<Multiaddr>,
+    },
+    /// Dial task, the result is sent back by sender
+    DialWithResult {
+        /// Remote address
+        address: Multiaddr,
+        /// Result sender
+        sender: oneshot::Sender<DialResult>,
+    },
+    /// Dial task, failed dial is retried by the policy
+    DialWithPolicy {
+        /// Remote address
+        address: Multiaddr,
+        /// Retry policy
+        policy: RetryPolicy,
+    },
     /// Listen task
     Listen {
         /// Listen address
         address: Multiaddr,
     },
+    /// Stop listening task
+    Unlisten {
+        /// Listen address
+        address: Multiaddr,
+    },
+    /// Cancel dial task
+    CancelDial {
+        /// Remote address
+        address: Multiaddr,
+    },
+    /// Keep connected to the peer
+    AddPersistentPeer {
+        /// Remote address with peer id
+        address: Multiaddr,
+    },
+    /// Stop keeping connected to the peer
+    RemovePersistentPeer {
+        /// Remote address with peer id
+        address: Multiaddr,
+    },
+    /// Never evict sessions of the peer
+    ProtectPeer {
+        /// Peer id
+        peer_id: PeerId,
+    },
+    /// Sessions of the peer can be evicted again
+    UnprotectPeer {
+        /// Peer id
+        peer_id: PeerId,
+    },
+    /// Update ping round trip time of the session, used by eviction
+    SetSessionRtt {
+        /// Session id
+        session_id: SessionId,
+        /// Round trip time
+        rtt: Duration,
+    },
+    /// The session is useful recently, used by eviction
+    MarkSessionUseful {
+        /// Session id
+        session_id: SessionId,
+    },
+    /// Add a public address
+    AddExternalAddr {
+        /// Public address
+        address: Multiaddr,
+    },
+    /// Remove a public address
+    RemoveExternalAddr {
+        /// Public address
+        address: Multiaddr,
+    },
+    /// Our address observed by the remote of session
+    ObservedAddr {
+        /// Session id
+        session_id: SessionId,
+        /// Observed address
+        address: Multiaddr,
+    },
+    /// A peer tried to dial us back
+    ReachabilityProbe {
+        /// Whether the peer connected to us
+        reachable: bool,
+    },
+    /// Open a session on the stream, through secio handshake and yamux
+    UpgradeStream {
+        /// Raw stream
+        stream: Box<dyn RawStream + Send>,
+        /// Session address
+        address: Multiaddr,
+        /// Session type, client if we initiated the stream
+        ty: SessionType,
+    },
 }
 
 impl fmt::Debug for ServiceTask {
@@ -174,8 +539,44 @@ impl fmt::Debug for ServiceTask {
             ),
             FutureTask { .. } => write!(f, "Future task"),
             Disconnect { session_id } => write!(f, "Disconnect session [{}]", session_id),
+            DisconnectWithReason { session_id, reason } => write!(
+                f,
+                "Disconnect session [{}], reason: {:?}",
+                session_id, reason
+            ),
             Dial { address } => write!(f, "Dial address: {}", address),
+            DialAny { addresses } => write!(f, "Dial any address: {:?}", addresses),
+            DialWithPolicy { address, policy } => write!(
+                f,
+                "Dial with policy address: {}, policy: {:?}",
+                address, policy
+            ),
+            DialWithResult { address, .. } => write!(f, "Dial with result address: {}", address),
             Listen { address } => write!(f, "Listen address: {}", address),
+            Unlisten { address } => write!(f, "Unlisten address: {}", address),
+            AddPersistentPeer { address } => write!(f, "Add persistent peer: {}", address),
+            RemovePersistentPeer { address } => {
+                write!(f, "Remove persistent peer: {}", address)
+            }
+            CancelDial { address } => write!(f, "Cancel dial address: {}", address),
+            ProtectPeer { peer_id } => write!(f, "Protect peer: {:?}", peer_id),
+            UnprotectPeer { peer_id } => write!(f, "Unprotect peer: {:?}", peer_id),
+            SetSessionRtt { session_id, rtt } => {
+                write!(f, "Set session [{}] rtt: {:?}", session_id, rtt)
+            }
+            MarkSessionUseful { session_id } => {
+                write!(f, "Mark session [{}] useful", session_id)
+            }
+            AddExternalAddr { address } => write!(f, "Add external address: {}", address),
+            RemoveExternalAddr { address } => write!(f, "Remove external address: {}", address),
+            ObservedAddr {
+                session_id,
+                address,
+            } => write!(f, "Session [{}] observed address: {}", session_id, address),
+            ReachabilityProbe { reachable } => write!(f, "Reachability probe: {}", reachable),
+            UpgradeStream { address, ty, .. } => {
+                write!(f, "Upgrade stream, address: {}, type: {:?}", address, ty)
+            }
         }
     }
 }
@@ -189,6 +590,38 @@ pub struct Service<T, U> {
     listens: Vec<(Multiaddr, Incoming)>,
 
     dial: Vec<(Multiaddr, Timeout<ConnectFuture>)>,
+    /// Dials over multiple candidate addresses
+    dial_groups: Vec<DialGroup>,
+    /// Addresses waiting on DNS resolver, the value is the resolve type
+    dns_pending: HashMap<Multiaddr, SessionType>,
+    /// The real listen addresses of resolved DNS listen addresses, key is the DNS address
+    dns_listens: HashMap<Multiaddr, Multiaddr>,
+    /// Dials waiting for result or retry
+    dial_tracker: DialTracker,
+    /// Peers keep connected
+    persistent_peers: PersistentPeers,
+    /// Max inbound sessions, None means no limit
+    max_inbound: Option<usize>,
+    /// Choose the inbound session to evict when inbound slots are full
+    eviction_scorer: Box<dyn EvictionScorer>,
+    /// Inbound sessions
+    inbound: HashMap<SessionId, EvictionCandidate>,
+    /// Sessions of these peers are never evicted
+    protected_peers: HashSet<PeerId>,
+    /// Max sessions to the same peer
+    max_connections_per_peer: usize,
+    /// Refuse dial addresses before connect
+    dial_filter: DialFilter,
+    /// TCP socket options
+    tcp_config: TcpConfig,
+    /// Ips of local interfaces, used to expand unspecified listen ip
+    interfaces: InterfaceWatcher,
+    /// Our public addresses
+    address_book: AddressBook,
+    /// Dial back results of peers
+    reachability: ReachabilityTracker,
+    /// Exchanged after the secio handshake
+    hello: Option<Hello>,
     timeout: Duration,
     /// Calculate the number of connection requests that need to be sent externally,
     /// if run forever, it will default to 1, else it default to 0
@@ -202,6 +635,16 @@ pub struct Service<T, U> {
 
     max_frame_length: usize,
 
+    /// Report callbacks that take longer than this
+    slow_handler_threshold: Option<Duration>,
+    /// Task senders of the dedicated threads, for protocols with `HandleExecutor::Thread`
+    handle_threads: HashMap<ProtocolId, HandleThread>,
+
+    /// Capacities of the internal channels
+    buffer_config: BufferConfig,
+    /// Bytes of buffered messages, shared with sessions
+    budget: BufferBudget,
+
     /// Can be upgrade to list service level protocols
     handle: T,
 
@@ -218,6 +661,8 @@ pub struct Service<T, U> {
     session_service_protos: HashMap<SessionId, HashSet<ProtocolId>>,
 
     service_proto_handles: HashMap<ProtocolId, mpsc::Sender<ServiceProtocolEvent>>,
+    /// The service level protocol handles are started on the first poll
+    service_proto_handles_started: bool,
 
     session_proto_handles: HashMap<(SessionId, ProtocolId), mpsc::Sender<SessionProtocolEvent>>,
 
@@ -248,9 +693,12 @@ where
         key_pair: Option<SecioKeyPair>,
         forever: bool,
         timeout: Duration,
+        buffer_config: BufferConfig,
     ) -> Self {
-        let (session_event_sender, session_event_receiver) = mpsc::channel(256);
-        let (service_task_sender, service_task_receiver) = mpsc::channel(256);
+        let (session_event_sender, session_event_receiver) =
+            mpsc::channel(buffer_config.session_event_channel_size);
+        let (service_task_sender, service_task_receiver) =
+            mpsc::channel(buffer_config.service_task_channel_size);
         let proto_infos = protocol_configs
             .values()
             .map(|meta| {
@@ -266,12 +714,39 @@ where
             sessions: HashMap::default(),
             session_service_protos: HashMap::default(),
             service_proto_handles: HashMap::default(),
+            service_proto_handles_started: false,
             session_proto_handles: HashMap::default(),
             listens: Vec::new(),
             dial: Vec::new(),
+            dial_groups: Vec::new(),
+            dns_pending: HashMap::default(),
+            dns_listens: HashMap::default(),
+            dial_tracker: DialTracker::new(),
+            persistent_peers: PersistentPeers::new(RetryPolicy::persistent()),
+            max_inbound: None,
+            eviction_scorer: Box::new(DefaultEvictionScorer::default()),
+            inbound: HashMap::default(),
+            protected_peers: HashSet::default(),
+            max_connections_per_peer: 1,
+            dial_filter: DialFilter::default(),
+            tcp_config: TcpConfig::default(),
+            interfaces: InterfaceWatcher::new(),
+            address_book: AddressBook::new(DEFAULT_OBSERVED_ADDR_THRESHOLD),
+            reachability: ReachabilityTracker::new(
+                DEFAULT_REACHABILITY_THRESHOLD,
+                REACHABILITY_RESULT_TTL,
+            ),
+            hello: None,
             timeout,
             yamux_config: YamuxConfig::default(),
             max_frame_length: 1024 * 1024 * 8,
+            slow_handler_threshold: Some(DEFAULT_SLOW_HANDLER_THRESHOLD),
+            handle_threads: HashMap::default(),
+            buffer_config,
+            budget: BufferBudget::new(
+                buffer_config.max_buffered_bytes,
+                buffer_config.overflow_policy,
+            ),
             task_count: if forever { 1 } else { 0 },
             next_session: 0,
             write_buf: VecDeque::default(),
@@ -304,26 +779,104 @@ where
         self
     }
 
+    /// Report handle callbacks that take longer than threshold by `ServiceEvent::SlowHandler`,
+    /// None means no check
+    pub fn slow_handler_threshold(mut self, threshold: Option<Duration>) -> Self {
+        self.slow_handler_threshold = threshold;
+        self
+    }
+
+    /// Retry policy to reconnect persistent peers
+    pub fn persistent_peer_policy(mut self, policy: RetryPolicy) -> Self {
+        self.persistent_peers.set_policy(policy);
+        self
+    }
+
+    /// Max inbound sessions, None means no limit
+    pub fn max_inbound(mut self, max: Option<usize>) -> Self {
+        self.max_inbound = max;
+        self
+    }
+
+    /// Choose the inbound session to evict when inbound slots are full
+    pub fn eviction_scorer(mut self, scorer: Box<dyn EvictionScorer>) -> Self {
+        self.eviction_scorer = scorer;
+        self
+    }
+
+    /// Max sessions to the same peer
+    ///
+    /// Panic when max is 0
+    pub fn max_connections_per_peer(mut self, max: usize) -> Self {
+        assert!(max > 0);
+        self.max_connections_per_peer = max;
+        self
+    }
+
+    /// Refuse dial addresses before connect
+    pub fn dial_filter(mut self, filter: DialFilter) -> Self {
+        self.dial_filter = filter;
+        self
+    }
+
+    /// TCP socket options of listeners and connections
+    pub fn tcp_config(mut self, config: TcpConfig) -> Self {
+        self.service_context.set_reuse_port(config.reuse_port);
+        self.tcp_config = config;
+        self
+    }
+
+    /// Observers from different ips needed to confirm an observed address
+    pub fn observed_addr_threshold(mut self, threshold: usize) -> Self {
+        self.address_book.set_threshold(threshold);
+        self
+    }
+
+    /// Dial back results needed to decide the reachability
+    pub fn reachability_threshold(mut self, threshold: usize) -> Self {
+        self.reachability.set_threshold(threshold);
+        self
+    }
+
+    /// Hello exchanged after the secio handshake, None means no hello
+    ///
+    /// Panic when hello is set without a key pair
+    pub fn hello(mut self, hello: Option<Hello>) -> Self {
+        assert!(
+            hello.is_none() || self.key_pair.is_some(),
+            "hello is exchanged after the secio handshake, it needs a key pair"
+        );
+        self.hello = hello;
+        self
+    }
+
     /// Listen on the given address.
     ///
     /// Return really listen multiaddr, but if use `/dns4/localhost/tcp/80`,
     /// it will return original value, and create a future task to DNS resolver later.
     pub fn listen(&mut self, address: Multiaddr) -> Result<Multiaddr, io::Error> {
         let listen_addr = if let Ok(socket_address) = multiaddr_to_socketaddr(&address) {
-            let tcp = TcpListener::bind(&socket_address)?;
+            let tcp = self.tcp_config.listen(&socket_address)?;
             let listen_addr = tcp.local_addr()?.to_multiaddr().unwrap();
             self.listens.push((listen_addr.clone(), tcp.incoming()));
+            // The handle may read the listens of context on the event
+            self.update_listens();
+            self.handle_event(ServiceEvent::ListenStarted {
+                address: listen_addr.clone(),
+            });
             listen_addr
         } else {
             match DNSResolver::new(address.clone()) {
                 Ok(dns) => {
                     let sender = self.session_event_sender.clone();
+                    let source_address = address.clone();
                     let future_task = dns.then(move |result| match result {
-                        Ok(address) => tokio::spawn(
+                        Ok(addresses) => tokio::spawn(
                             sender
                                 .send(SessionEvent::DNSResolverSuccess {
                                     ty: SessionType::Server,
-                                    address,
+                                    addresses,
+                                    source_address,
                                 })
                                 .map(|_| ())
                                 .map_err(|err| {
@@ -342,6 +895,8 @@ where
                     self.pending_task.push(ServiceTask::FutureTask {
                         task: Box::new(future_task),
                     });
+                    self.dns_pending
+                        .insert(address.clone(), SessionType::Server);
                     self.task_count += 1;
                 }
                 Err(_) => return Err(io::ErrorKind::InvalidInput.into()),
@@ -352,28 +907,99 @@ where
     }
 
     /// Dial the given address, doesn't actually make a request, just generate a future
-    pub fn dial(&mut self, address: Multiaddr) -> Result<&mut Self, io::Error> {
+    pub fn dial(&mut self, address: Multiaddr) -> Result<&mut Self, Error<ServiceTask>> {
         self.dial_inner(address)?;
         Ok(self)
     }
 
+    /// Dial the given address, failed dial is retried by the policy
+    pub fn dial_with_policy(
+        &mut self,
+        address: Multiaddr,
+        policy: RetryPolicy,
+    ) -> Result<&mut Self, Error<ServiceTask>> {
+        self.dial_inner(address.clone())?;
+        self.dial_tracker
+            .insert(address.clone(), DialTrack::new(address, None, Some(policy)));
+        Ok(self)
+    }
+
+    /// Dial the candidate addresses of one peer, IPv6 and IPv4 attempts are staggered
+    /// as RFC 8305, the first one completes the handshake wins and the others are canceled.
+    ///
+    /// Only IP addresses are accepted
+    pub fn dial_any(&mut self, addresses: Vec<Multiaddr>) -> Result<&mut Self, Error<ServiceTask>> {
+        self.dial_any_inner(addresses, None)?;
+        Ok(self)
+    }
+
+    /// Use by inner, dial result is reported by key, default is the first address
+    fn dial_any_inner(
+        &mut self,
+        mut addresses: Vec<Multiaddr>,
+        key: Option<Multiaddr>,
+    ) -> Result<(), Error<ServiceTask>> {
+        if addresses.is_empty()
+            || addresses
+                .iter()
+                .any(|address| multiaddr_to_socketaddr(address).is_err())
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
+        let key = key.unwrap_or_else(|| addresses[0].clone());
+
+        if addresses.len() == 1 {
+            let address = addresses.remove(0);
+            self.dial_tracker.move_track(&key, &address);
+            return self.dial_inner(address);
+        }
+
+        self.dial_groups.push(DialGroup::new(key, addresses));
+        self.task_count += 1;
+        Ok(())
+    }
+
     /// Use by inner
     #[inline(always)]
-    fn dial_inner(&mut self, address: Multiaddr) -> Result<(), io::Error> {
+    fn dial_inner(&mut self, address: Multiaddr) -> Result<(), Error<ServiceTask>> {
+        if let Err(denied) = self
+            .dial_filter
+            .check(&address, self.service_context.listens())
+        {
+            debug!("dial {} refused: {}", address, denied);
+            return Err(Error::DialDenied(denied));
+        }
+        if is_relayed(&address) {
+            debug!("relayed address {} can't be dialed directly", address);
+            return Err(io::Error::from(io::ErrorKind::InvalidInput).into());
+        }
         if let Ok(socket_address) = multiaddr_to_socketaddr(&address) {
-            let dial = TcpStream::connect(&socket_address).timeout(self.timeout);
-            self.dial.push((address, dial));
+            // Used to dial from the listen port
+            let listen_port = self
+                .listens
+                .iter()
+                .filter_map(|(listen, _)| multiaddr_to_socketaddr(listen).ok())
+                .find(|listen| listen.is_ipv4() == socket_address.is_ipv4())
+                .map(|listen| listen.port());
+            let dial = self
+                .tcp_config
+                .connect(&socket_address, listen_port)?
+                .timeout(self.timeout);
+            self.dial.push((address.clone(), dial));
             self.task_count += 1;
+            self.handle_event(ServiceEvent::DialStarted { address });
         } else {
-            match DNSResolver::new(address) {
+            match DNSResolver::new(address.clone()) {
                 Ok(dns) => {
                     let sender = self.session_event_sender.clone();
+                    let source_address = address.clone();
                     let future_task = dns.then(move |result| match result {
-                        Ok(address) => tokio::spawn(
+                        Ok(addresses) => tokio::spawn(
                             sender
                                 .send(SessionEvent::DNSResolverSuccess {
                                     ty: SessionType::Client,
-                                    address,
+                                    addresses,
+                                    source_address,
                                 })
                                 .map(|_| ())
                                 .map_err(|err| {
@@ -392,15 +1018,285 @@ where
                     self.pending_task.push(ServiceTask::FutureTask {
                         task: Box::new(future_task),
                     });
+                    self.dns_pending
+                        .insert(address.clone(), SessionType::Client);
                     self.task_count += 1;
+                    self.handle_event(ServiceEvent::DialStarted { address });
                 }
-                Err(_) => return Err(io::ErrorKind::InvalidInput.into()),
+                Err(_) => return Err(io::Error::from(io::ErrorKind::InvalidInput).into()),
             }
         }
 
         Ok(())
     }
 
+    /// Stop listening on the given address, or cancel its DNS resolving.
+    ///
+    /// The DNS address that has been resolved stops its real listen address
+    fn unlisten(&mut self, address: Multiaddr) {
+        let address = self.dns_listens.remove(&address).unwrap_or(address);
+        self.dns_listens.retain(|_, listen| listen != &address);
+        let len = self.listens.len();
+        self.listens.retain(|(addr, _)| addr != &address);
+        if self.listens.len() != len {
+            self.update_listens();
+        } else if self.dns_pending.get(&address) == Some(&SessionType::Server) {
+            self.dns_pending.remove(&address);
+            self.task_count -= 1;
+        } else {
+            debug!("Can't find listen address {} to stop", address);
+            return;
+        }
+        self.handle_event(ServiceEvent::ListenClosed { address });
+    }
+
+    /// Cancel the dial which waits on connect or DNS resolving, or the whole `dial_any` group.
+    ///
+    /// The dial already connected is not affected, use `disconnect` to close it
+    fn cancel_dial(&mut self, address: Multiaddr) {
+        let track = self.dial_tracker.cancel_retry(&address);
+        if track.is_some() {
+            self.task_count -= 1;
+        } else if let Some(index) = self
+            .dial_groups
+            .iter()
+            .position(|group| !group.is_done() && group.key() == &address)
+        {
+            self.dial_group_stop(index);
+        } else {
+            let len = self.dial.len();
+            self.dial.retain(|(addr, _)| addr != &address);
+            if self.dial.len() != len {
+                self.task_count -= 1;
+                for group in self.dial_groups.iter_mut() {
+                    group.remove_attempt(&address);
+                }
+            } else if self.dns_pending.get(&address) == Some(&SessionType::Client) {
+                self.dns_pending.remove(&address);
+                self.task_count -= 1;
+            } else {
+                debug!("Can't find dial address {} to cancel", address);
+                return;
+            }
+        }
+        if let Some(sender) = track
+            .or_else(|| self.dial_tracker.remove(&address))
+            .and_then(|track| track.sender)
+        {
+            let error = io::Error::new(io::ErrorKind::Interrupted, "dial canceled");
+            let _ = sender.send(Err(error.into()));
+        }
+        self.handle_event(ServiceEvent::DialCanceled { address });
+    }
+
+    /// Dial with the result sender or retry policy
+    fn dial_with_track(
+        &mut self,
+        address: Multiaddr,
+        sender: Option<oneshot::Sender<DialResult>>,
+        retry: Option<RetryPolicy>,
+    ) {
+        match self.dial_tracker.get_mut(&address) {
+            // Attach to the dial in progress
+            Some(track) => track.attach(sender, retry),
+            None => {
+                self.dial_tracker.insert(
+                    address.clone(),
+                    DialTrack::new(address.clone(), sender, retry),
+                );
+                self.handle_service_task(ServiceTask::Dial { address });
+            }
+        }
+    }
+
+    /// Dial failed, schedule a retry if the policy allows,
+    /// the result sender is returned with the error if no more retry
+    fn dial_track_fail(
+        &mut self,
+        address: &Multiaddr,
+        error: Error<ServiceTask>,
+    ) -> (Error<ServiceTask>, Option<oneshot::Sender<DialResult>>) {
+        let (origin, fail) = match self.dial_tracker.fail(address, &error) {
+            Some(result) => result,
+            None => return (error, None),
+        };
+        let persistent_peer = self.persistent_peers.peer(&origin);
+        match fail {
+            DialFail::Retry { attempts, delay } => {
+                debug!(
+                    "dial {} failed: {:?}, retry after {:?}",
+                    origin, error, delay
+                );
+                self.task_count += 1;
+                self.notify();
+                if let Some(peer_id) = persistent_peer {
+                    self.persistent_peer_update(
+                        peer_id,
+                        PersistentPeerState::BackingOff { attempts, delay },
+                    );
+                }
+                (error, None)
+            }
+            DialFail::GiveUp(sender) => {
+                if let Some(peer_id) = persistent_peer {
+                    let state = match self.peer_session(&peer_id) {
+                        // Such as repeated connection
+                        Some(id) => PersistentPeerState::Connected(id),
+                        None => PersistentPeerState::GaveUp,
+                    };
+                    self.persistent_peer_update(peer_id, state);
+                }
+                (error, sender)
+            }
+        }
+    }
+
+    /// Keep connected to the peer, the address must contain peer id
+    fn add_persistent_peer(&mut self, address: Multiaddr) {
+        match self.persistent_peers.add(address.clone()) {
+            Some(peer_id) => self.persistent_peer_dial(peer_id, false),
+            None => self.handle_error(ServiceError::DialerError {
+                address,
+                error: io::Error::new(io::ErrorKind::InvalidInput, "no peer id").into(),
+            }),
+        }
+    }
+
+    /// Stop keeping connected to the peer, the connection and the dial in progress are kept
+    fn remove_persistent_peer(&mut self, address: Multiaddr) {
+        if let Some(address) = self.persistent_peers.remove(&address) {
+            if self.dial_tracker.is_retry_pending(&address) {
+                self.cancel_dial(address);
+            } else if let Some(track) = self.dial_tracker.get_mut(&address) {
+                track.retry = None;
+            }
+        }
+    }
+
+    /// Dial the persistent peer if it's not connected,
+    /// wait a backoff delay first if `backoff` is true
+    fn persistent_peer_dial(&mut self, peer_id: PeerId, backoff: bool) {
+        let address = match self.persistent_peers.address(&peer_id) {
+            Some(address) => address.clone(),
+            None => return,
+        };
+        if let Some(id) = self.peer_session(&peer_id) {
+            self.persistent_peer_update(peer_id, PersistentPeerState::Connected(id));
+            return;
+        }
+        if self.dial_tracker.contains(&address) {
+            // Dial in progress
+            return;
+        }
+
+        let (track, delay) = self.persistent_peers.dial_track(address.clone());
+        if backoff {
+            self.dial_tracker.retry_later(track, delay);
+            self.task_count += 1;
+            self.notify();
+            self.persistent_peer_update(
+                peer_id,
+                PersistentPeerState::BackingOff { attempts: 0, delay },
+            );
+        } else {
+            self.dial_tracker.insert(address.clone(), track);
+            if let Err(e) = self.dial_inner(address.clone()) {
+                self.handle_error(ServiceError::DialerError { address, error: e });
+            }
+        }
+    }
+
+    /// The session connected to the peer
+    #[inline]
+    fn peer_session(&self, peer_id: &PeerId) -> Option<SessionId> {
+        self.sessions
+            .values()
+            .find(|session| {
+                session
+                    .remote_pubkey
+                    .as_ref()
+                    .map(|key| &key.peer_id() == peer_id)
+                    .unwrap_or(false)
+            })
+            .map(|session| session.id)
+    }
+
+    #[inline]
+    fn persistent_peer_update(&mut self, peer_id: PeerId, state: PersistentPeerState) {
+        if let Some(address) = self.persistent_peers.address(&peer_id).cloned() {
+            self.handle_event(ServiceEvent::PersistentPeerUpdate {
+                peer_id,
+                address,
+                state,
+            });
+        }
+    }
+
+    /// Poll the dials waiting for retry
+    #[inline]
+    fn retry_poll(&mut self) {
+        for track in self.dial_tracker.poll_retry() {
+            self.task_count -= 1;
+            let address = track.origin.clone();
+            if let Some(peer_id) = self.persistent_peers.peer(&address) {
+                if let Some(id) = self.peer_session(&peer_id) {
+                    // Connected under another address
+                    self.persistent_peer_update(peer_id, PersistentPeerState::Connected(id));
+                    continue;
+                }
+            }
+            self.dial_tracker.insert(address.clone(), track);
+            if let Err(e) = self.dial_inner(address.clone()) {
+                self.handle_error(ServiceError::DialerError { address, error: e });
+            }
+        }
+    }
+
+    /// Check that the DNS resolving of source address is not canceled
+    #[inline]
+    fn dns_resolved(&mut self, source_address: &Multiaddr) -> bool {
+        if self.dns_pending.remove(source_address).is_some() {
+            self.task_count -= 1;
+            true
+        } else {
+            debug!("DNS resolving of {} is canceled", source_address);
+            false
+        }
+    }
+
+    /// Make room for a new inbound session, evict one if inbound slots are full.
+    ///
+    /// Return false if no session can be evicted
+    fn inbound_reserve(&mut self) -> bool {
+        match self.max_inbound {
+            Some(max) if self.inbound.len() >= max => (),
+            _ => return true,
+        }
+
+        let protected_peers = &self.protected_peers;
+        let candidates = self
+            .inbound
+            .values()
+            .filter(|candidate| {
+                candidate
+                    .peer_id
+                    .as_ref()
+                    .map(|peer_id| !protected_peers.contains(peer_id))
+                    .unwrap_or(true)
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+
+        match self.eviction_scorer.select(&candidates) {
+            Some(id) if self.inbound.remove(&id).is_some() => {
+                debug!("evict inbound session [{}]", id);
+                self.session_close(id, CloseReason::Evicted, Source::External);
+                true
+            }
+            _ => false,
+        }
+    }
+
     /// Get service current protocol configure
     pub fn protocol_configs(
         &self,
@@ -413,53 +1309,114 @@ where
         self.service_context.control()
     }
 
+    /// Call service handle to handle error, every failed dial attempt is reported,
+    /// except the last one of `dial_with_result`, it goes to the caller
+    #[inline]
+    fn handle_error(&mut self, error: ServiceError) {
+        let error = match error {
+            ServiceError::DialerError { address, error } => {
+                match self.dial_track_fail(&address, error) {
+                    (error, Some(sender)) => {
+                        let _ = sender.send(Err(error));
+                        return;
+                    }
+                    // Including the attempts to be retried
+                    (error, None) => ServiceError::DialerError { address, error },
+                }
+            }
+            error => error,
+        };
+        let start = Instant::now();
+        self.handle.handle_error(&mut self.service_context, error);
+        self.check_slow_handle(start, "handle_error");
+    }
+
+    /// Call service handle to handle event
+    #[inline]
+    fn handle_event(&mut self, event: ServiceEvent) {
+        let start = Instant::now();
+        self.handle.handle_event(&mut self.service_context, event);
+        self.check_slow_handle(start, "handle_event");
+    }
+
+    /// Report slow service handle callback, the report itself is not measured
+    #[inline]
+    fn check_slow_handle(&mut self, start: Instant, callback: &'static str) {
+        if let Some(threshold) = self.slow_handler_threshold {
+            let elapsed = start.elapsed();
+            if elapsed >= threshold {
+                warn!("service handle callback [{}] took {:?}", callback, elapsed);
+                self.handle.handle_event(
+                    &mut self.service_context,
+                    ServiceEvent::SlowHandler {
+                        proto_id: None,
+                        session_id: None,
+                        callback,
+                        elapsed,
+                    },
+                );
+            }
+        }
+    }
+
     /// Distribute event to sessions
     #[inline]
     fn distribute_to_session(&mut self) {
         for event in self.write_buf.split_off(0) {
             match event {
                 SessionEvent::ProtocolMessage { id, proto_id, data } => {
+                    let len = data.len();
                     if let Some(session) = self.sessions.get_mut(&id) {
-                        if let Err(e) = session
+                        match session
                             .event_sender
                             .try_send(SessionEvent::ProtocolMessage { id, proto_id, data })
                         {
-                            if e.is_full() {
-                                debug!("session [{}] is full", id);
-                                self.write_buf.push_back(e.into_inner());
-                                self.notify();
-                            } else {
-                                error!("channel shutdown, message can't send")
+                            Ok(_) => self.budget.release(len),
+                            Err(e) => {
+                                if e.is_full() {
+                                    debug!("session [{}] is full", id);
+                                    self.write_buf.push_back(e.into_inner());
+                                } else {
+                                    self.budget.release(len);
+                                    error!("channel shutdown, message can't send")
+                                }
                             }
                         }
                     } else {
+                        self.budget.release(len);
                         debug!("Can't find session {}, proto {} to send data", id, proto_id);
                     }
                 }
-                SessionEvent::SessionClose { id } => {
-                    if let Some(session) = self.sessions.get_mut(&id) {
-                        if let Err(e) = session
-                            .event_sender
-                            .try_send(SessionEvent::SessionClose { id })
-                        {
-                            if e.is_full() {
-                                debug!("session [{}] is full", id);
-                                self.write_buf.push_back(e.into_inner());
-                                self.notify();
-                            } else {
-                                error!("channel shutdown, message can't send")
-                            }
-                        }
-                    } else {
-                        debug!("Can't find session {} to close", id);
-                    }
+                SessionEvent::SessionClose { id, reason } => {
+                    self.close_to_session(id, SessionEvent::SessionClose { id, reason })
+                }
+                SessionEvent::SessionDisconnect { id, reason } => {
+                    self.close_to_session(id, SessionEvent::SessionDisconnect { id, reason })
                 }
                 _ => (),
             }
         }
     }
 
-    /// Distribute event to user level
+    /// Send the close event to session, it's kept to send later if the channel is full
+    #[inline]
+    fn close_to_session(&mut self, id: SessionId, event: SessionEvent) {
+        if let Some(session) = self.sessions.get_mut(&id) {
+            if let Err(e) = session.event_sender.try_send(event) {
+                if e.is_full() {
+                    debug!("session [{}] is full", id);
+                    self.write_buf.push_back(e.into_inner());
+                } else {
+                    error!("channel shutdown, message can't send")
+                }
+            }
+        } else {
+            debug!("Can't find session {} to close", id);
+        }
+    }
+
+    /// Distribute event to user level, the budget of a message is given back
+    /// once its handles process it
     #[inline]
     fn distribute_to_user_level(&mut self) {
         for (proto_id, event) in self.read_service_buf.split_off(0) {
@@ -468,7 +1425,6 @@ where
                     if e.is_full() {
                         debug!("service proto [{}] handle is full", proto_id);
                         self.read_service_buf.push_back((proto_id, e.into_inner()));
-                        self.notify();
                     } else {
                         error!(
                             "channel shutdown, proto [{}] message can't send to user",
@@ -489,7 +1445,6 @@ where
                         );
                         self.read_session_buf
                             .push_back((session_id, proto_id, e.into_inner()));
-                        self.notify();
                     } else {
                         error!(
                             "channel shutdown, proto [{}] session [{}] message can't send to user",
@@ -501,16 +1456,46 @@ where
         }
     }
 
+    /// Park the service task on the full channels of the buffered events, it's woken
+    /// once any of them has room again, return true if one is ready already
+    fn poll_buffered_channels(&mut self) -> bool {
+        let mut ready = false;
+        for event in self.write_buf.iter() {
+            let id = match event {
+                SessionEvent::ProtocolMessage { id, .. }
+                | SessionEvent::SessionClose { id, .. }
+                | SessionEvent::SessionDisconnect { id, .. } => *id,
+                _ => continue,
+            };
+            ready |= self
+                .sessions
+                .get_mut(&id)
+                .map(|session| channel_ready(&mut session.event_sender))
+                .unwrap_or(true);
+        }
+        for (proto_id, _) in self.read_service_buf.iter() {
+            ready |= self
+                .service_proto_handles
+                .get_mut(proto_id)
+                .map(channel_ready)
+                .unwrap_or(true);
+        }
+        for (session_id, proto_id, _) in self.read_session_buf.iter() {
+            ready |= self
+                .session_proto_handles
+                .get_mut(&(*session_id, *proto_id))
+                .map(channel_ready)
+                .unwrap_or(true);
+        }
+        ready
+    }
+
     /// Send data to the specified protocol for the specified session.
     ///
     /// Valid after Service starts
     #[inline]
     pub fn send_message(&mut self, session_id: SessionId, proto_id: ProtocolId, data: &[u8]) {
-        self.write_buf.push_back(SessionEvent::ProtocolMessage {
-            id: session_id,
-            proto_id,
-            data: data.into(),
-        });
+        self.push_message(session_id, proto_id, data.into());
         self.distribute_to_session();
     }
 
@@ -528,20 +1513,20 @@ where
             None => self.broadcast(proto_id, data),
             Some(ids) => {
                 let data: bytes::Bytes = data.into();
-                for id in self.sessions.keys() {
-                    if ids.contains(id) {
-                        debug!(
-                            "send message to session [{}], proto [{}], data len: {}",
-                            id,
-                            proto_id,
-                            data.len()
-                        );
-                        self.write_buf.push_back(SessionEvent::ProtocolMessage {
-                            id: *id,
-                            proto_id,
-                            data: data.clone(),
-                        });
-                    }
+                let ids = self
+                    .sessions
+                    .keys()
+                    .filter(|id| ids.contains(id))
+                    .cloned()
+                    .collect::<Vec<SessionId>>();
+                for id in ids {
+                    debug!(
+                        "send message to session [{}], proto [{}], data len: {}",
+                        id,
+                        proto_id,
+                        data.len()
+                    );
+                    self.push_message(id, proto_id, data.clone());
                 }
                 self.distribute_to_session();
             }
@@ -560,16 +1545,44 @@ where
             data.len()
         );
         let data: bytes::Bytes = data.into();
-        for id in self.sessions.keys() {
-            self.write_buf.push_back(SessionEvent::ProtocolMessage {
-                id: *id,
-                proto_id,
-                data: data.clone(),
-            });
+        let ids = self.sessions.keys().cloned().collect::<Vec<SessionId>>();
+        for id in ids {
+            self.push_message(id, proto_id, data.clone());
         }
         self.distribute_to_session();
     }
 
+    /// Buffer a message which will be sent to session, apply overflow policy if out of budget
+    #[inline]
+    fn push_message(&mut self, id: SessionId, proto_id: ProtocolId, data: bytes::Bytes) {
+        if self.budget.acquire(data.len()) {
+            self.write_buf
+                .push_back(SessionEvent::ProtocolMessage { id, proto_id, data });
+        } else {
+            self.buffer_overflow(id, proto_id);
+        }
+    }
+
+    /// Buffered messages out of budget
+    #[inline]
+    fn buffer_overflow(&mut self, id: SessionId, proto_id: ProtocolId) {
+        match self.budget.policy() {
+            OverflowPolicy::DropMessage => {
+                warn!(
+                    "buffer out of budget, drop session [{}] proto [{}] message",
+                    id, proto_id
+                );
+            }
+            OverflowPolicy::CloseSession => {
+                warn!(
+                    "buffer out of budget, close session [{}] because of proto [{}]",
+                    id, proto_id
+                );
+                self.session_close(id, CloseReason::BufferOverflow, Source::External);
+            }
+        }
+    }
+
     /// Get the callback handle of the specified protocol
     #[inline]
     fn proto_handle(&self, session: bool, proto_id: ProtocolId) -> Option<ProtocolHandle> {
@@ -598,23 +1611,47 @@ where
         handle
     }
 
+    /// Get the handle executor of the specified protocol
+    #[inline]
+    fn proto_executor(&self, proto_id: ProtocolId) -> HandleExecutor {
+        self.protocol_configs
+            .values()
+            .find(|proto| proto.id() == proto_id)
+            .map(|proto| proto.handle_executor())
+            .unwrap_or_default()
+    }
+
     /// Handshake
     #[inline]
-    fn handshake(&mut self, socket: TcpStream, ty: SessionType, remote_address: Multiaddr) {
+    fn handshake<H>(&mut self, socket: H, ty: SessionType, remote_address: Multiaddr)
+    where
+        H: AsyncRead + AsyncWrite + Send + 'static,
+    {
         if let Some(ref key_pair) = self.key_pair {
             let key_pair = key_pair.clone();
             let sender = self.session_event_sender.clone();
+            let local_hello = self.hello.clone();
 
             let task = Config::new(key_pair)
                 .max_frame_length(self.max_frame_length)
+                .channel_size(self.buffer_config.secure_stream_channel_size)
                 .handshake(socket)
+                .map_err(Error::from)
+                .and_then(move |(handle, public_key, _)| match local_hello {
+                    Some(local_hello) => future::Either::A(
+                        hello::exchange(handle, local_hello)
+                            .map(move |(handle, hello)| (handle, public_key, Some(hello))),
+                    ),
+                    None => future::Either::B(future::ok((handle, public_key, None))),
+                })
                 .timeout(self.timeout)
                 .then(move |result| {
                     let send_task = match result {
-                        Ok((handle, public_key, _)) => {
+                        Ok((handle, public_key, hello)) => {
                             sender.send(SessionEvent::HandshakeSuccess {
                                 handle,
                                 public_key,
+                                hello,
                                 address: remote_address,
                                 ty,
                             })
@@ -627,8 +1664,8 @@ where
                                 // time out error
                                 io::Error::new(io::ErrorKind::TimedOut, err.description()).into()
                             } else {
-                                // dialer error
-                                err.into_inner().unwrap().into()
+                                // dialer or hello error
+                                err.into_inner().unwrap()
                             };
 
                             debug!(
@@ -653,10 +1690,13 @@ where
 
             tokio::spawn(task);
         } else {
-            self.session_open(socket, None, remote_address, ty);
             if ty == SessionType::Client {
                 self.task_count -= 1;
+                if !self.dial_attempt_accept(&remote_address) {
+                    return;
+                }
             }
+            self.session_open(socket, None, None, remote_address, ty);
         }
     }
 
@@ -666,82 +1706,120 @@ where
         &mut self,
         mut handle: H,
         remote_pubkey: Option<PublicKey>,
+        remote_hello: Option<Hello>,
         mut address: Multiaddr,
         ty: SessionType,
     ) where
         H: AsyncRead + AsyncWrite + Send + 'static,
     {
+        // The address may be appended with peer id later
+        let dial_address = address.clone();
         if let Some(ref key) = remote_pubkey {
+            let remote_peer_id = key.peer_id();
+            // if peer id doesn't match return an error
+            let append_peer_id = match extract_peer_id(&address) {
+                Some(peer_id) => {
+                    if remote_peer_id != peer_id {
+                        trace!("Peer id not match");
+                        self.handle_error(ServiceError::DialerError {
+                            error: Error::PeerIdNotMatch,
+                            address,
+                        });
+                        return;
+                    }
+                    false
+                }
+                None => true,
+            };
+
             // If the public key exists, the connection has been established
             // and then the useless connection needs to be closed.
-            match self
+            let duplicates = self
                 .sessions
                 .values()
-                .find(|&context| context.remote_pubkey.as_ref() == Some(key))
-            {
-                Some(context) => {
-                    trace!("Connected to the connected node");
-                    let _ = handle.shutdown();
-                    if ty == SessionType::Client {
-                        self.handle.handle_error(
-                            &mut self.service_context,
-                            ServiceError::DialerError {
-                                error: Error::RepeatedConnection(context.id),
+                .filter(|&context| context.remote_pubkey.as_ref() == Some(key))
+                .map(|context| (context.id, context.ty))
+                .collect::<Vec<_>>();
+            if duplicates.len() >= self.max_connections_per_peer {
+                let keep_ty = self.tie_break_type(&remote_peer_id);
+                match duplicates.iter().find(|(_, dup_ty)| *dup_ty != keep_ty) {
+                    Some(&(id, _)) if ty == keep_ty => {
+                        debug!("session [{}] is replaced by the duplicate connection", id);
+                        self.session_close(id, CloseReason::Duplicate, Source::External);
+                    }
+                    _ => {
+                        trace!("Connected to the connected node");
+                        let id = duplicates[0].0;
+                        let _ = handle.shutdown();
+                        if ty == SessionType::Client {
+                            self.handle_error(ServiceError::DialerError {
+                                error: Error::RepeatedConnection(id),
                                 address,
-                            },
-                        );
-                    } else {
-                        self.handle.handle_error(
-                            &mut self.service_context,
-                            ServiceError::ListenError {
-                                error: Error::RepeatedConnection(context.id),
+                            });
+                        } else {
+                            self.handle_error(ServiceError::ListenError {
+                                error: Error::RepeatedConnection(id),
                                 address,
-                            },
-                        );
-                    }
-                    return;
-                }
-                None => {
-                    // if peer id doesn't match return an error
-                    if let Some(peer_id) = extract_peer_id(&address) {
-                        if key.peer_id() != peer_id {
-                            trace!("Peer id not match");
-                            self.handle.handle_error(
-                                &mut self.service_context,
-                                ServiceError::DialerError {
-                                    error: Error::PeerIdNotMatch,
-                                    address,
-                                },
-                            );
-                            return;
+                            });
                         }
-                    } else {
-                        address.append(Protocol::P2p(
-                            Multihash::from_bytes(key.peer_id().as_bytes().to_vec())
-                                .expect("Invalid peer id"),
-                        ))
+                        return;
                     }
-
-                    self.next_session += 1
                 }
             }
+
+            if append_peer_id {
+                address.append(Protocol::P2p(
+                    Multihash::from_bytes(remote_peer_id.as_bytes().to_vec())
+                        .expect("Invalid peer id"),
+                ))
+            }
+
+            self.next_session += 1
         } else {
             self.next_session += 1;
         }
 
-        let (service_event_sender, service_event_receiver) = mpsc::channel(32);
+        if ty == SessionType::Server && !self.inbound_reserve() {
+            trace!("Inbound slots are full, refuse {}", address);
+            let _ = handle.shutdown();
+            self.handle_error(ServiceError::ListenError {
+                error: Error::InboundFull,
+                address,
+            });
+            return;
+        }
+
+        let (service_event_sender, service_event_receiver) =
+            mpsc::channel(self.buffer_config.session_channel_size);
         let session = SessionContext {
             event_sender: service_event_sender,
             id: self.next_session,
             address: address.clone(),
             ty,
             remote_pubkey: remote_pubkey.clone(),
+            remote_hello,
         };
         self.sessions.insert(session.id, session);
 
+        let peer_id = remote_pubkey.as_ref().map(PublicKey::peer_id);
+        if ty == SessionType::Server {
+            self.inbound.insert(
+                self.next_session,
+                EvictionCandidate {
+                    id: self.next_session,
+                    address: address.clone(),
+                    peer_id: peer_id.clone(),
+                    connected_at: Instant::now(),
+                    ping_rtt: None,
+                    last_useful: None,
+                },
+            );
+        }
+
         let meta = SessionMeta::new(self.next_session, ty, self.timeout)
             .protocol(self.protocol_configs.clone())
-            .config(self.yamux_config);
+            .config(self.yamux_config)
+            .buffer(self.buffer_config, self.budget.clone());
 
         let mut session = Session::new(
             handle,
@@ -758,42 +1836,119 @@ where
 
         tokio::spawn(session.for_each(|_| Ok(())).map_err(|_| ()));
 
-        self.handle.handle_event(
-            &mut self.service_context,
-            ServiceEvent::SessionOpen {
-                id: self.next_session,
-                address,
-                ty,
-                public_key: remote_pubkey,
-            },
-        );
+        if ty == SessionType::Client {
+            if let Some(sender) = self
+                .dial_tracker
+                .remove(&dial_address)
+                .and_then(|track| track.sender)
+            {
+                let _ = sender.send(Ok((self.next_session, peer_id.clone())));
+            }
+        }
+
+        self.handle_event(ServiceEvent::SessionOpen {
+            id: self.next_session,
+            address,
+            ty,
+            public_key: remote_pubkey,
+        });
+
+        if let Some(peer_id) = peer_id {
+            if self.persistent_peers.contains(&peer_id) {
+                self.persistent_peer_update(
+                    peer_id,
+                    PersistentPeerState::Connected(self.next_session),
+                );
+            }
+        }
+    }
+
+    /// Session type of the connection kept when duplicate connections exceed the limit.
+    ///
+    /// Both sides keep the connection dialed by the lower peer id
+    #[inline]
+    fn tie_break_type(&self, remote_peer_id: &PeerId) -> SessionType {
+        match self.key_pair {
+            Some(ref key_pair) if key_pair.to_peer_id() < *remote_peer_id => SessionType::Client,
+            _ => SessionType::Server,
+        }
     }
 
     /// Close the specified session, clean up the handle
     #[inline]
-    fn session_close(&mut self, id: SessionId, source: Source) {
+    fn session_close(&mut self, id: SessionId, reason: CloseReason, source: Source) {
         if source == Source::External {
             debug!("try close service session [{}] ", id);
-            self.write_buf.push_back(SessionEvent::SessionClose { id });
+            self.write_buf
+                .push_back(SessionEvent::SessionClose { id, reason });
             self.distribute_to_session();
             return;
         }
 
-        debug!("close service session [{}]", id);
+        debug!("close service session [{}], reason: {:?}", id, reason);
 
         // Close all open proto
         let close_proto_ids = self.session_service_protos.remove(&id).unwrap_or_default();
         debug!("session [{}] close proto [{:?}]", id, close_proto_ids);
 
-        close_proto_ids.into_iter().for_each(|proto_id| {
-            self.protocol_close(id, proto_id);
-        });
+        close_proto_ids.into_iter().for_each(|proto_id| {
+            self.protocol_close(id, proto_id);
+        });
+
+        self.inbound.remove(&id);
+        let peer_id = self
+            .sessions
+            .remove(&id)
+            .and_then(|session| session.remote_pubkey)
+            .map(|key| key.peer_id());
+
+        // Service handle processing flow
+        self.handle_event(ServiceEvent::SessionClose { id, reason });
+
+        if let Some(peer_id) = peer_id {
+            if self.persistent_peers.contains(&peer_id) {
+                self.persistent_peer_dial(peer_id, true);
+            }
+        }
+    }
+
+    /// Start the service level handles of all protocols, they are inited when the
+    /// service starts instead of waiting for a session to open the protocol
+    fn start_service_proto_handles(&mut self) {
+        self.service_proto_handles_started = true;
+        let proto_ids: Vec<ProtocolId> = self
+            .protocol_configs
+            .values()
+            .map(|proto| proto.id())
+            .collect();
+
+        for proto_id in proto_ids {
+            if let Some(ProtocolHandle::Service(handle)) = self.proto_handle(false, proto_id) {
+                debug!("init service level [{}] proto handle", proto_id);
+                let executor = self.proto_executor(proto_id);
+                let timer = HandleTimer::new(
+                    self.slow_handler_threshold,
+                    self.session_event_sender.clone(),
+                );
+                let (sender, receiver) =
+                    mpsc::channel(self.buffer_config.proto_handle_channel_size);
+                let stream = ServiceProtocolStream::new(
+                    handle,
+                    self.service_context.clone(),
+                    receiver,
+                    proto_id,
+                    timer,
+                    executor == HandleExecutor::Blocking,
+                );
 
-        self.sessions.remove(&id);
+                self.service_proto_handles.insert(proto_id, sender);
 
-        // Service handle processing flow
-        self.handle
-            .handle_event(&mut self.service_context, ServiceEvent::SessionClose { id });
+                spawn_handle(&mut self.handle_threads, proto_id, executor, stream);
+
+                self.read_service_buf
+                    .push_back((proto_id, ServiceProtocolEvent::Init));
+            }
+        }
     }
 
     /// Open the handle corresponding to the protocol
@@ -812,27 +1967,13 @@ where
             .or_default()
             .insert(proto_id);
 
-        // Service proto handle processing flow
-        if !self.service_proto_handles.contains_key(&proto_id) {
-            if let Some(ProtocolHandle::Service(handle)) = self.proto_handle(false, proto_id) {
-                debug!("init service level [{}] proto handle", proto_id);
-                let (sender, receiver) = mpsc::channel(32);
-                let stream = ServiceProtocolStream::new(
-                    handle,
-                    self.service_context.clone(),
-                    receiver,
-                    proto_id,
-                );
-
-                self.service_proto_handles.insert(proto_id, sender);
-
-                tokio::spawn(stream.for_each(|_| Ok(())).map_err(|_| ()));
-
-                self.read_service_buf
-                    .push_back((proto_id, ServiceProtocolEvent::Init));
-            }
-        }
+        let executor = self.proto_executor(proto_id);
+        let timer = HandleTimer::new(
+            self.slow_handler_threshold,
+            self.session_event_sender.clone(),
+        );
 
+        // Service proto handle processing flow
         if self.service_proto_handles.contains_key(&proto_id) {
             self.read_service_buf.push_back((
                 proto_id,
@@ -846,16 +1987,18 @@ where
         // Session proto handle processing flow
         if let Some(ProtocolHandle::Session(handle)) = self.proto_handle(true, proto_id) {
             debug!("init session [{}] level proto [{}] handle", id, proto_id);
-            let (sender, receiver) = mpsc::channel(32);
+            let (sender, receiver) = mpsc::channel(self.buffer_config.proto_handle_channel_size);
             let stream = SessionProtocolStream::new(
                 handle,
                 self.service_context.clone(),
                 session_context.clone(),
                 receiver,
                 proto_id,
+                timer,
+                executor == HandleExecutor::Blocking,
             );
 
-            tokio::spawn(stream.for_each(|_| Ok(())).map_err(|_| ()));
+            spawn_handle(&mut self.handle_threads, proto_id, executor, stream);
 
             self.session_proto_handles
                 .entry((id, proto_id))
@@ -864,11 +2007,19 @@ where
             self.read_session_buf.push_back((
                 id,
                 proto_id,
-                SessionProtocolEvent::Connected { version },
+                SessionProtocolEvent::Connected {
+                    version: version.clone(),
+                },
             ));
         }
 
         self.distribute_to_user_level();
+
+        self.handle_event(ServiceEvent::ProtocolOpened {
+            id,
+            proto_id,
+            version,
+        });
     }
 
     /// Processing the received data
@@ -886,27 +2037,38 @@ where
             data.len()
         );
 
-        // Service proto handle processing flow
-        if self.service_proto_handles.contains_key(&proto_id) {
-            self.read_service_buf.push_back((
-                proto_id,
-                ServiceProtocolEvent::Received {
-                    id: session_id,
-                    data: data.clone(),
-                },
-            ));
-        }
-
-        // Session proto handle processing flow
-        if self
+        let to_service = self.service_proto_handles.contains_key(&proto_id);
+        let to_session = self
             .session_proto_handles
-            .contains_key(&(session_id, proto_id))
-        {
-            self.read_session_buf.push_back((
-                session_id,
-                proto_id,
-                SessionProtocolEvent::Received { data },
-            ));
+            .contains_key(&(session_id, proto_id));
+
+        // The handles share the data, so it's charged once
+        if to_service || to_session {
+            match self.budget.charge(data.len()) {
+                Some(charge) => {
+                    // Service proto handle processing flow
+                    if to_service {
+                        self.read_service_buf.push_back((
+                            proto_id,
+                            ServiceProtocolEvent::Received {
+                                id: session_id,
+                                data: data.clone(),
+                                charge: Arc::clone(&charge),
+                            },
+                        ));
+                    }
+
+                    // Session proto handle processing flow
+                    if to_session {
+                        self.read_session_buf.push_back((
+                            session_id,
+                            proto_id,
+                            SessionProtocolEvent::Received { data, charge },
+                        ));
+                    }
+                }
+                None => self.buffer_overflow(session_id, proto_id),
+            }
         }
 
         self.distribute_to_user_level();
@@ -948,6 +2110,11 @@ where
         // Close notify sender
         self.service_context
             .remove_session_notify_senders(session_id, proto_id);
+
+        self.handle_event(ServiceEvent::ProtocolClosed {
+            id: session_id,
+            proto_id,
+        });
     }
 
     fn send_pending_task(&mut self) {
@@ -959,18 +2126,24 @@ where
     /// When listen update, call here
     #[inline]
     fn update_listens(&mut self) {
-        let new_listens = self
+        let bind_addrs = self
             .listens
             .iter()
             .map(|(address, _)| address.clone())
             .collect::<Vec<Multiaddr>>();
-        self.service_context.update_listens(new_listens.clone());
+        if self.has_unspecified_listen() {
+            self.interfaces.refresh();
+        }
+        let new_listens = expand_listen_addrs(&bind_addrs, self.interfaces.ips());
+        self.service_context
+            .update_listens(new_listens.clone(), bind_addrs.clone());
 
         for proto_id in self.service_proto_handles.keys() {
             self.read_service_buf.push_back((
                 *proto_id,
                 ServiceProtocolEvent::Update {
                     listen_addrs: new_listens.clone(),
+                    bind_addrs: bind_addrs.clone(),
                 },
             ));
         }
@@ -981,6 +2154,44 @@ where
                 *proto_id,
                 SessionProtocolEvent::Update {
                     listen_addrs: new_listens.clone(),
+                    bind_addrs: bind_addrs.clone(),
+                },
+            ));
+        }
+
+        self.distribute_to_user_level();
+    }
+
+    /// Change the address book, notify protocols if public addresses changed
+    fn update_external_addrs<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut AddressBook),
+    {
+        let old_addrs = self.address_book.addrs();
+        f(&mut self.address_book);
+        let new_addrs = self.address_book.addrs();
+        if new_addrs == old_addrs {
+            return;
+        }
+        debug!("external addresses changed: {:?}", new_addrs);
+        self.service_context
+            .update_external_addrs(new_addrs.clone());
+
+        for proto_id in self.service_proto_handles.keys() {
+            self.read_service_buf.push_back((
+                *proto_id,
+                ServiceProtocolEvent::UpdateExternalAddrs {
+                    external_addrs: new_addrs.clone(),
+                },
+            ));
+        }
+
+        for (session_id, proto_id) in self.session_proto_handles.keys() {
+            self.read_session_buf.push_back((
+                *session_id,
+                *proto_id,
+                SessionProtocolEvent::UpdateExternalAddrs {
+                    external_addrs: new_addrs.clone(),
                 },
             ));
         }
@@ -988,28 +2199,92 @@ where
         self.distribute_to_user_level();
     }
 
+    /// Update reachability, tell the handles if it changed
+    fn update_reachability<F>(&mut self, f: F)
+    where
+        F: FnOnce(&mut ReachabilityTracker),
+    {
+        let old_status = self.reachability.status();
+        f(&mut self.reachability);
+        let reachability = self.reachability.status();
+        if reachability == old_status {
+            return;
+        }
+        debug!("reachability changed: {:?}", reachability);
+        self.service_context.update_reachability(reachability);
+
+        for proto_id in self.service_proto_handles.keys() {
+            self.read_service_buf.push_back((
+                *proto_id,
+                ServiceProtocolEvent::UpdateReachability { reachability },
+            ));
+        }
+
+        for (session_id, proto_id) in self.session_proto_handles.keys() {
+            self.read_session_buf.push_back((
+                *session_id,
+                *proto_id,
+                SessionProtocolEvent::UpdateReachability { reachability },
+            ));
+        }
+
+        self.distribute_to_user_level();
+        self.handle_event(ServiceEvent::ReachabilityChanged { reachability });
+    }
+
+    /// Drop the expired dial back results
+    fn reachability_poll(&mut self) {
+        if self.reachability.poll_check() {
+            let now = Instant::now();
+            self.update_reachability(|tracker| tracker.expire(now));
+        }
+    }
+
+    /// Any listener bound to unspecified ip
+    #[inline]
+    fn has_unspecified_listen(&self) -> bool {
+        self.listens.iter().any(|(address, _)| {
+            multiaddr_to_socketaddr(address)
+                .map(|address| address.ip().is_unspecified())
+                .unwrap_or(false)
+        })
+    }
+
+    /// Update listen addresses when local interfaces changed
+    fn interface_poll(&mut self) {
+        if self.has_unspecified_listen() && self.interfaces.poll_changed() {
+            debug!("local interfaces changed: {:?}", self.interfaces.ips());
+            self.update_listens();
+        }
+    }
+
     /// Handling various events uploaded by the session
     fn handle_session_event(&mut self, event: SessionEvent) {
         match event {
-            SessionEvent::SessionClose { id } => self.session_close(id, Source::Internal),
+            SessionEvent::SessionClose { id, reason } => {
+                self.session_close(id, reason, Source::Internal)
+            }
             SessionEvent::HandshakeSuccess {
                 handle,
                 public_key,
+                hello,
                 address,
                 ty,
             } => {
-                self.session_open(handle, Some(public_key), address, ty);
                 if ty == SessionType::Client {
                     self.task_count -= 1;
+                    if !self.dial_attempt_accept(&address) {
+                        return;
+                    }
                 }
+                self.session_open(handle, Some(public_key), hello, address, ty);
             }
             SessionEvent::HandshakeFail { ty, error, address } => {
                 if ty == SessionType::Client {
                     self.task_count -= 1;
-                    self.handle.handle_error(
-                        &mut self.service_context,
-                        ServiceError::DialerError { error, address },
-                    )
+                    self.dial_attempt_fail(address, error)
+                } else if let Error::HelloMismatch(_) = error {
+                    self.handle_error(ServiceError::ListenError { address, error })
                 }
             }
             SessionEvent::ProtocolMessage { id, proto_id, data } => {
@@ -1026,29 +2301,66 @@ where
                 id,
                 proto_id,
                 error,
-            } => self.handle.handle_error(
-                &mut self.service_context,
-                ServiceError::ProtocolError {
-                    id,
-                    proto_id,
-                    error,
-                },
-            ),
-            SessionEvent::DialError { address, error } => self.handle.handle_error(
-                &mut self.service_context,
-                ServiceError::DialerError { address, error },
-            ),
-            SessionEvent::ListenError { address, error } => self.handle.handle_error(
-                &mut self.service_context,
-                ServiceError::ListenError { address, error },
-            ),
-            SessionEvent::DNSResolverSuccess { ty, address } => {
-                self.task_count -= 1;
+            } => self.handle_error(ServiceError::ProtocolError {
+                id,
+                proto_id,
+                error,
+            }),
+            SessionEvent::DialError { address, error } => {
+                if self.dns_resolved(&address) {
+                    self.handle_error(ServiceError::DialerError { address, error })
+                }
+            }
+            SessionEvent::ListenError { address, error } => {
+                if self.dns_resolved(&address) {
+                    self.handle_error(ServiceError::ListenError { address, error })
+                }
+            }
+            SessionEvent::SlowHandler {
+                proto_id,
+                session_id,
+                callback,
+                elapsed,
+            } => self.handle_event(ServiceEvent::SlowHandler {
+                proto_id: Some(proto_id),
+                session_id,
+                callback,
+                elapsed,
+            }),
+            SessionEvent::DNSResolverSuccess {
+                ty,
+                addresses,
+                source_address,
+            } => {
+                if !self.dns_resolved(&source_address) {
+                    return;
+                }
                 match ty {
                     SessionType::Server => {
-                        self.handle_service_task(ServiceTask::Listen { address })
+                        // Listen on the first address
+                        if let Some(address) = addresses.into_iter().next() {
+                            match self.listen(address.clone()) {
+                                Ok(listen_addr) => {
+                                    self.dns_listens.insert(source_address, listen_addr);
+                                    self.listen_poll();
+                                }
+                                Err(e) => self.handle_error(ServiceError::ListenError {
+                                    address,
+                                    error: e.into(),
+                                }),
+                            }
+                        }
+                    }
+                    SessionType::Client => {
+                        if let Err(e) = self.dial_any_inner(addresses, Some(source_address.clone()))
+                        {
+                            self.handle_error(ServiceError::DialerError {
+                                address: source_address,
+                                error: e,
+                            });
+                        }
+                        self.client_poll();
                     }
-                    SessionType::Client => self.handle_service_task(ServiceTask::Dial { address }),
                 }
             }
         }
@@ -1063,42 +2375,118 @@ where
                 data,
             } => self.filter_broadcast(session_ids, proto_id, &data),
             ServiceTask::Dial { address } => {
-                if !self.dial.iter().any(|(addr, _)| addr == &address) {
+                if !self.dial.iter().any(|(addr, _)| addr == &address)
+                    && !self.dns_pending.contains_key(&address)
+                {
                     if let Err(e) = self.dial_inner(address.clone()) {
-                        self.handle.handle_error(
-                            &mut self.service_context,
-                            ServiceError::DialerError {
-                                address,
-                                error: e.into(),
-                            },
-                        );
+                        self.handle_error(ServiceError::DialerError { address, error: e });
                     }
                 }
                 if !self.dial.is_empty() {
                     self.client_poll();
                 }
             }
+            ServiceTask::DialAny { addresses } => {
+                if let Some(address) = addresses.first().cloned() {
+                    if let Err(e) = self.dial_any_inner(addresses, None) {
+                        self.handle_error(ServiceError::DialerError { address, error: e });
+                    }
+                    self.client_poll();
+                }
+            }
+            ServiceTask::DialWithResult { address, sender } => {
+                self.dial_with_track(address, Some(sender), None)
+            }
+            ServiceTask::DialWithPolicy { address, policy } => {
+                self.dial_with_track(address, None, Some(policy))
+            }
             ServiceTask::Listen { address } => {
-                if !self.listens.iter().any(|(addr, _)| addr == &address) {
+                if !self.listens.iter().any(|(addr, _)| addr == &address)
+                    && !self.dns_pending.contains_key(&address)
+                {
                     match self.listen(address.clone()) {
-                        Ok(_) => {
-                            self.update_listens();
-                            self.listen_poll();
-                        }
+                        Ok(_) => self.listen_poll(),
                         Err(e) => {
-                            self.handle.handle_error(
-                                &mut self.service_context,
-                                ServiceError::ListenError {
-                                    address,
-                                    error: e.into(),
-                                },
-                            );
+                            self.handle_error(ServiceError::ListenError {
+                                address,
+                                error: e.into(),
+                            });
                         }
                     }
                 }
             }
+            ServiceTask::Unlisten { address } => self.unlisten(address),
+            ServiceTask::AddPersistentPeer { address } => {
+                self.add_persistent_peer(address);
+                self.client_poll();
+            }
+            ServiceTask::RemovePersistentPeer { address } => self.remove_persistent_peer(address),
+            ServiceTask::CancelDial { address } => self.cancel_dial(address),
+            ServiceTask::ProtectPeer { peer_id } => {
+                self.protected_peers.insert(peer_id);
+            }
+            ServiceTask::UnprotectPeer { peer_id } => {
+                self.protected_peers.remove(&peer_id);
+            }
+            ServiceTask::SetSessionRtt { session_id, rtt } => {
+                if let Some(candidate) = self.inbound.get_mut(&session_id) {
+                    candidate.ping_rtt = Some(rtt);
+                }
+            }
+            ServiceTask::MarkSessionUseful { session_id } => {
+                if let Some(candidate) = self.inbound.get_mut(&session_id) {
+                    candidate.last_useful = Some(Instant::now());
+                }
+            }
+            ServiceTask::AddExternalAddr { address } => {
+                self.update_external_addrs(|book| book.add(address))
+            }
+            ServiceTask::RemoveExternalAddr { address } => {
+                self.update_external_addrs(|book| book.remove(&address))
+            }
+            ServiceTask::ObservedAddr {
+                session_id,
+                address,
+            } => {
+                // The relayed session can't tell our address
+                let observer = self
+                    .sessions
+                    .get(&session_id)
+                    .filter(|session| !is_relayed(&session.address))
+                    .and_then(|session| multiaddr_to_socketaddr(&session.address).ok())
+                    .map(|address| address.ip());
+                if let Some(observer) = observer {
+                    self.update_external_addrs(|book| book.observe(address, observer))
+                }
+            }
+            ServiceTask::ReachabilityProbe { reachable } => {
+                let now = Instant::now();
+                self.update_reachability(|tracker| {
+                    tracker.expire(now);
+                    tracker.report(reachable, now);
+                })
+            }
+            ServiceTask::UpgradeStream {
+                stream,
+                address,
+                ty,
+            } => {
+                // Accounted as a dial, the result goes through the handshake as tcp
+                if ty == SessionType::Client {
+                    self.task_count += 1;
+                }
+                self.handshake(stream, ty, address);
+            }
             ServiceTask::Disconnect { session_id } => {
-                self.session_close(session_id, Source::External)
+                self.session_close(session_id, CloseReason::LocalDisconnect, Source::External)
+            }
+            ServiceTask::DisconnectWithReason { session_id, reason } => {
+                debug!("disconnect session [{}], reason: {:?}", session_id, reason);
+                self.write_buf.push_back(SessionEvent::SessionDisconnect {
+                    id: session_id,
+                    reason,
+                });
+                self.distribute_to_session();
             }
             ServiceTask::FutureTask { task } => {
                 tokio::spawn(task);
@@ -1136,9 +2524,25 @@ where
     /// Poll client requests
     #[inline]
     fn client_poll(&mut self) {
+        if self.dial_tracker.has_retry_pending() {
+            self.retry_poll();
+        }
+        self.dial_poll();
+        if !self.dial_groups.is_empty() && self.dial_group_poll() {
+            // Poll the new attempts
+            self.dial_poll();
+        }
+    }
+
+    /// Poll connecting dials
+    #[inline]
+    fn dial_poll(&mut self) {
         for (address, mut dialer) in self.dial.split_off(0) {
             match dialer.poll() {
                 Ok(Async::Ready(socket)) => {
+                    if let Err(err) = self.tcp_config.apply(&socket) {
+                        warn!("set socket options of {} failed: {:?}", address, err);
+                    }
                     self.handshake(socket, SessionType::Client, address);
                 }
                 Ok(Async::NotReady) => {
@@ -1157,18 +2561,99 @@ where
                         // dialer error
                         err.into_inner().unwrap()
                     };
-                    self.handle.handle_error(
-                        &mut self.service_context,
-                        ServiceError::DialerError {
-                            address,
-                            error: error.into(),
-                        },
-                    );
+                    self.dial_attempt_fail(address, error.into());
+                }
+            }
+        }
+    }
+
+    /// Start the next attempts of dial groups, and clean up finished groups.
+    ///
+    /// Return true if any attempt started
+    fn dial_group_poll(&mut self) -> bool {
+        let mut started = false;
+        for mut group in self.dial_groups.split_off(0) {
+            while let Some(address) = group.next_candidate() {
+                match self.dial_inner(address.clone()) {
+                    Ok(_) => {
+                        started = true;
+                        group.attempt_started(address);
+                    }
+                    Err(e) => group.attempt_failed(&address, e),
+                }
+            }
+
+            if group.is_finished() {
+                self.task_count -= 1;
+                let address = group.key().clone();
+                if let Some(error) = group.into_error() {
+                    self.handle_error(ServiceError::DialerError { address, error });
                 }
+            } else {
+                self.dial_groups.push(group);
+            }
+        }
+        started
+    }
+
+    /// A connection attempt completes the handshake,
+    /// return false if it's not the first one of its group
+    fn dial_attempt_accept(&mut self, address: &Multiaddr) -> bool {
+        let index = match self
+            .dial_groups
+            .iter()
+            .position(|group| group.contains(address))
+        {
+            Some(index) => index,
+            None => return true,
+        };
+        self.dial_groups[index].remove_attempt(address);
+        if self.dial_groups[index].is_done() {
+            debug!("dial group has a winner, drop connection to {}", address);
+            self.notify();
+            return false;
+        }
+        self.dial_group_stop(index);
+
+        let key = self.dial_groups[index].key().clone();
+        self.dial_tracker.move_track(&key, address);
+        true
+    }
+
+    /// A connection attempt failed, report the error if it doesn't belong to a group
+    fn dial_attempt_fail(&mut self, address: Multiaddr, error: Error<ServiceTask>) {
+        match self
+            .dial_groups
+            .iter_mut()
+            .find(|group| group.contains(&address))
+        {
+            Some(group) => {
+                debug!("dial attempt {} failed: {:?}", address, error);
+                group.attempt_failed(&address, error);
+                self.notify();
             }
+            None => self.handle_error(ServiceError::DialerError { address, error }),
         }
     }
 
+    /// Stop the group starting new attempts and cancel its connecting attempts,
+    /// the attempts in handshake are dropped when they finish
+    fn dial_group_stop(&mut self, index: usize) {
+        let group = &mut self.dial_groups[index];
+        group.stop();
+        let mut canceled = 0;
+        self.dial.retain(|(address, _)| {
+            if group.remove_attempt(address) {
+                canceled += 1;
+                false
+            } else {
+                true
+            }
+        });
+        self.task_count -= canceled;
+        self.notify();
+    }
+
     /// Poll listen connections
     #[inline]
     fn listen_poll(&mut self) {
@@ -1178,22 +2663,28 @@ where
                 Ok(Async::Ready(Some(socket))) => {
                     let remote_address: Multiaddr =
                         socket.peer_addr().unwrap().to_multiaddr().unwrap();
+                    if let Err(err) = self.tcp_config.apply(&socket) {
+                        warn!("set socket options of {} failed: {:?}", remote_address, err);
+                    }
                     self.handshake(socket, SessionType::Server, remote_address);
                     self.listens.push((address, listen));
                 }
-                Ok(Async::Ready(None)) => (),
+                Ok(Async::Ready(None)) => {
+                    update = true;
+                    self.dns_listens.retain(|_, listen| listen != &address);
+                    self.handle_event(ServiceEvent::ListenClosed { address });
+                }
                 Ok(Async::NotReady) => {
                     self.listens.push((address, listen));
                 }
                 Err(err) => {
                     update = true;
-                    self.handle.handle_error(
-                        &mut self.service_context,
-                        ServiceError::ListenError {
-                            address,
-                            error: err.into(),
-                        },
-                    );
+                    self.dns_listens.retain(|_, listen| listen != &address);
+                    self.handle_error(ServiceError::ListenError {
+                        address: address.clone(),
+                        error: err.into(),
+                    });
+                    self.handle_event(ServiceEvent::ListenClosed { address });
                 }
             }
         }
@@ -1230,6 +2721,10 @@ where
             return Ok(Async::Ready(None));
         }
 
+        if !self.service_proto_handles_started {
+            self.start_service_proto_handles();
+        }
+
         if !self.pending_task.is_empty() {
             self.send_pending_task();
         }
@@ -1246,6 +2741,10 @@ where
 
         self.listen_poll();
 
+        self.interface_poll();
+
+        self.reachability_poll();
+
         loop {
             match self.session_event_receiver.poll() {
                 Ok(Async::Ready(Some(event))) => self.handle_session_event(event),
@@ -1287,10 +2786,55 @@ where
         );
 
         self.notify = Some(task::current());
+        if self.poll_buffered_channels() {
+            self.notify();
+        }
         Ok(Async::NotReady)
     }
 }
 
+/// Send handle tasks to the dedicated thread of a protocol
+type HandleThread = mpsc::UnboundedSender<Box<dyn Future<Item = (), Error = ()> + Send>>;
+
+/// Spawn a protocol handle stream on the executor it asks for, the dedicated thread
+/// of the protocol is started by its first handle
+fn spawn_handle<S>(
+    threads: &mut HashMap<ProtocolId, HandleThread>,
+    proto_id: ProtocolId,
+    executor: HandleExecutor,
+    stream: S,
+) where
+    S: Stream<Item = (), Error = ()> + Send + 'static,
+{
+    let task = stream.for_each(|_| Ok(()));
+    match executor {
+        HandleExecutor::Runtime | HandleExecutor::Blocking => {
+            tokio::spawn(task);
+        }
+        HandleExecutor::Thread => {
+            let sender = threads.entry(proto_id).or_insert_with(|| {
+                let (sender, receiver) = mpsc::unbounded();
+                // Exits when the service is dropped and all handles finish
+                let result = thread::Builder::new()
+                    .name(format!("proto-{}-handle", proto_id))
+                    .spawn(move || {
+                        current_thread::run(receiver.for_each(|task| {
+                            current_thread::spawn(task);
+                            Ok(())
+                        }))
+                    });
+                if let Err(err) = result {
+                    error!("spawn proto [{}] handle thread error: {:?}", proto_id, err);
+                }
+                sender
+            });
+            if sender.unbounded_send(Box::new(task)).is_err() {
+                error!("proto [{}] handle thread has exited", proto_id);
+            }
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 enum Source {
     /// Event from user
@@ -1,4 +1,5 @@
 use futures::{
+    future,
     prelude::*,
     sync::mpsc,
     task::{self, Task},
@@ -8,20 +9,30 @@ use multiaddr::Multiaddr;
 use secio::{codec::stream_handle::StreamHandle as SecureHandle, PublicKey};
 use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::{error, io, time::Duration};
-use tokio::codec::{Decoder, Encoder, Framed, FramedParts};
+use std::{
+    error, io,
+    time::{Duration, Instant},
+};
+use tokio::codec::{length_delimited::LengthDelimitedCodec, Decoder, Encoder, Framed, FramedParts};
 use tokio::prelude::{AsyncRead, AsyncWrite, FutureExt};
+use tokio::timer::Delay;
 use yamux::{session::SessionType, Config, Session as YamuxSession, StreamHandle};
 
 use crate::{
+    config::{BufferBudget, BufferConfig, OverflowPolicy},
     error::Error,
+    hello::Hello,
     protocol_select::{client_select, server_select, ProtocolInfo},
-    service::ServiceTask,
+    service::{CloseReason, DisconnectReason, ServiceTask},
     substream::{ProtocolEvent, SubStream},
     traits::ProtocolMeta,
     ProtocolId, SessionId, StreamId,
 };
 
+/// Built-in protocol to send the reason before disconnect
+const DISCONNECT_PROTOCOL: &str = "/p2p/disconnect";
+const DISCONNECT_VERSION: &str = "1";
+
 /// Event generated/received by the Session
 #[derive(Debug)]
 pub(crate) enum SessionEvent {
@@ -29,18 +40,31 @@ pub(crate) enum SessionEvent {
     SessionClose {
         /// Session id
         id: SessionId,
+        /// Close reason
+        reason: CloseReason,
+    },
+    /// Send the reason to remote, then close the session
+    SessionDisconnect {
+        /// Session id
+        id: SessionId,
+        /// Disconnect reason
+        reason: DisconnectReason,
     },
     DNSResolverSuccess {
         /// DNS type
         ty: SessionType,
-        /// address
-        address: Multiaddr,
+        /// Resolved addresses
+        addresses: Vec<Multiaddr>,
+        /// The address before resolved
+        source_address: Multiaddr,
     },
     HandshakeSuccess {
         /// Secure handle
         handle: SecureHandle,
         /// Remote Public key
         public_key: PublicKey,
+        /// Remote hello
+        hello: Option<Hello>,
         /// Remote address
         address: Multiaddr,
         /// Session type
@@ -104,6 +128,17 @@ pub(crate) enum SessionEvent {
         /// Codec error
         error: Error<ServiceTask>,
     },
+    /// Protocol handle callback took too long
+    SlowHandler {
+        /// Protocol id
+        proto_id: ProtocolId,
+        /// Session id, only session level handle has
+        session_id: Option<SessionId>,
+        /// Callback name
+        callback: &'static str,
+        /// Time spent
+        elapsed: Duration,
+    },
 }
 
 /// Wrapper for real data streams, such as TCP stream
@@ -116,6 +151,14 @@ pub(crate) struct Session<T, U> {
     timeout: Duration,
 
     dead: bool,
+    /// Why the session is closed, the first reason is kept
+    close_reason: Option<CloseReason>,
+    /// The protocol which closed by codec error
+    codec_error: Option<ProtocolId>,
+    /// Close the session when the disconnect reason can't be sent in time
+    disconnect_delay: Option<Delay>,
+    /// The stream remote sends the disconnect reason on
+    disconnect_stream: Option<Framed<StreamHandle, LengthDelimitedCodec>>,
 
     // NOTE: Not used yet, may useful later
     // remote_address: ::std::net::SocketAddr,
@@ -131,6 +174,10 @@ pub(crate) struct Session<T, U> {
     write_buf: VecDeque<ProtocolEvent>,
     /// The buffer which will send to service
     read_buf: VecDeque<SessionEvent>,
+    /// Bytes of buffered messages, shared with service
+    budget: BufferBudget,
+    /// Capacity of the channel to each sub stream
+    sub_stream_channel_size: usize,
 
     /// Clone to new sub stream
     proto_event_sender: mpsc::Sender<ProtocolEvent>,
@@ -160,7 +207,8 @@ where
         meta: SessionMeta<U>,
     ) -> Self {
         let socket = YamuxSession::new(socket, meta.config, meta.ty);
-        let (proto_event_sender, proto_event_receiver) = mpsc::channel(256);
+        let (proto_event_sender, proto_event_receiver) =
+            mpsc::channel(meta.buffer_config.proto_event_channel_size);
         Session {
             socket,
             protocol_configs: meta.protocol_configs,
@@ -172,12 +220,18 @@ where
             proto_streams: HashMap::default(),
             write_buf: VecDeque::default(),
             read_buf: VecDeque::default(),
+            budget: meta.budget,
+            sub_stream_channel_size: meta.buffer_config.sub_stream_channel_size,
             proto_event_sender,
             proto_event_receiver,
             service_sender,
             service_receiver,
             notify: None,
             dead: false,
+            close_reason: None,
+            codec_error: None,
+            disconnect_delay: None,
+            disconnect_stream: None,
         }
     }
 
@@ -218,6 +272,76 @@ where
         tokio::spawn(task);
     }
 
+    /// Send the reason to remote, the session is closed when it's sent or failed to send
+    fn disconnect_with_reason(&mut self, reason: DisconnectReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(CloseReason::LocalDisconnect);
+        }
+        if self.disconnect_delay.is_some() {
+            return;
+        }
+        let handle = match self.socket.open_stream() {
+            Ok(handle) => handle,
+            Err(_) => {
+                self.set_dead(CloseReason::LocalDisconnect);
+                return;
+            }
+        };
+        debug!("session [{}] disconnect with reason: {:?}", self.id, reason);
+        let proto_info =
+            ProtocolInfo::new(DISCONNECT_PROTOCOL, vec![DISCONNECT_VERSION.to_owned()]);
+        let event_sender = self.proto_event_sender.clone();
+
+        let task = client_select(handle, proto_info)
+            .and_then(move |(handle, _, version)| match version {
+                Some(_) => future::Either::A(handle.send(reason.encode()).map(|_| ())),
+                None => {
+                    debug!("remote doesn't support disconnect reason");
+                    future::Either::B(future::ok(()))
+                }
+            })
+            .timeout(self.timeout)
+            .then(move |result| {
+                if let Err(err) = result {
+                    trace!("send disconnect reason err: {:?}", err);
+                }
+                event_sender
+                    .send(ProtocolEvent::DisconnectReasonSent)
+                    .map(|_| ())
+                    .map_err(|err| {
+                        trace!("disconnect reason sent event send back error: {:?}", err);
+                    })
+            });
+
+        tokio::spawn(task);
+        self.disconnect_delay = Some(Delay::new(Instant::now() + self.timeout));
+    }
+
+    /// Read the disconnect reason sent by remote, the session is closed when it's read.
+    ///
+    /// It's read by the session instead of a spawned task. The open event of the
+    /// disconnect sub stream is queued by the select task before the remote can send
+    /// the reason, and the proto events are drained before the socket end is acted on,
+    /// so the reason wins over the EOF that follows it.
+    fn recv_disconnect_reason(&mut self) {
+        let data = match self.disconnect_stream.as_mut().map(Stream::poll) {
+            Some(Ok(Async::NotReady)) | None => return,
+            Some(Ok(Async::Ready(data))) => data,
+            Some(Err(err)) => {
+                trace!("read disconnect reason err: {:?}", err);
+                None
+            }
+        };
+        self.disconnect_stream = None;
+        if let Some(reason) = data.and_then(|data| DisconnectReason::decode(&data)) {
+            debug!(
+                "session [{}] remote disconnect, reason: {:?}",
+                self.id, reason
+            );
+            self.set_dead(CloseReason::RemoteDisconnect(reason));
+        }
+    }
+
     /// Push the generated event to the Service
     #[inline]
     fn event_output(&mut self, event: SessionEvent) {
@@ -228,42 +352,90 @@ where
     #[inline]
     fn output(&mut self) {
         while let Some(event) = self.read_buf.pop_front() {
-            if let Err(e) = self.service_sender.try_send(event) {
-                if e.is_full() {
-                    self.read_buf.push_front(e.into_inner());
-                    self.notify();
-                    return;
-                } else {
-                    error!("session send to service error: {}", e);
+            let len = match event {
+                SessionEvent::ProtocolMessage { ref data, .. } => data.len(),
+                _ => 0,
+            };
+            match self.service_sender.try_send(event) {
+                Ok(_) => self.budget.release(len),
+                Err(e) => {
+                    if e.is_full() {
+                        self.read_buf.push_front(e.into_inner());
+                        return;
+                    } else {
+                        self.budget.release(len);
+                        error!("session send to service error: {}", e);
+                    }
                 }
             }
         }
     }
 
+    /// Buffered messages out of budget
+    #[inline]
+    fn buffer_overflow(&mut self, proto_id: ProtocolId) {
+        match self.budget.policy() {
+            OverflowPolicy::DropMessage => {
+                warn!(
+                    "buffer out of budget, drop session [{}] proto [{}] message",
+                    self.id, proto_id
+                );
+            }
+            OverflowPolicy::CloseSession => {
+                warn!(
+                    "buffer out of budget, close session [{}] because of proto [{}]",
+                    self.id, proto_id
+                );
+                self.set_dead(CloseReason::BufferOverflow);
+            }
+        }
+    }
+
+    /// Mark the session dead, only the first reason is kept
+    #[inline]
+    fn set_dead(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+        self.dead = true;
+    }
+
+    /// The reason comes from yamux session, or the fallback
+    #[inline]
+    fn socket_close_reason(&self, fallback: CloseReason) -> CloseReason {
+        self.socket
+            .close_reason()
+            .map(Into::into)
+            .unwrap_or(fallback)
+    }
+
     #[inline]
     fn distribute_to_substream(&mut self) {
         for event in self.write_buf.split_off(0) {
             match event {
                 ProtocolEvent::Message { id, proto_id, data } => {
+                    let len = data.len();
                     if let Some(sender) = self.sub_streams.get_mut(&id) {
-                        if let Err(e) =
-                            sender.try_send(ProtocolEvent::Message { id, proto_id, data })
-                        {
-                            if e.is_full() {
-                                self.write_buf.push_back(e.into_inner());
-                                self.notify();
-                            } else {
-                                error!("session send to sub stream error: {}", e);
+                        match sender.try_send(ProtocolEvent::Message { id, proto_id, data }) {
+                            Ok(_) => self.budget.release(len),
+                            Err(e) => {
+                                if e.is_full() {
+                                    self.write_buf.push_back(e.into_inner());
+                                } else {
+                                    self.budget.release(len);
+                                    error!("session send to sub stream error: {}", e);
+                                }
                             }
                         }
-                    };
+                    } else {
+                        self.budget.release(len);
+                    }
                 }
                 ProtocolEvent::Close { id, proto_id } => {
                     if let Some(sender) = self.sub_streams.get_mut(&id) {
                         if let Err(e) = sender.try_send(ProtocolEvent::Close { id, proto_id }) {
                             if e.is_full() {
                                 self.write_buf.push_back(e.into_inner());
-                                self.notify();
                             } else {
                                 error!("session send to sub stream error: {}", e);
                             }
@@ -278,7 +450,7 @@ where
     /// Handling client-initiated open protocol sub stream requests
     fn handle_sub_stream(&mut self, sub_stream: StreamHandle) {
         let event_sender = self.proto_event_sender.clone();
-        let proto_metas = self
+        let mut proto_metas: HashMap<String, ProtocolInfo> = self
             .protocol_configs
             .values()
             .map(|proto_meta| {
@@ -287,28 +459,36 @@ where
                 (name, proto_info)
             })
             .collect();
+        proto_metas.insert(
+            DISCONNECT_PROTOCOL.to_owned(),
+            ProtocolInfo::new(DISCONNECT_PROTOCOL, vec![DISCONNECT_VERSION.to_owned()]),
+        );
 
         let task = server_select(sub_stream, proto_metas)
             .and_then(|(handle, name, version)| {
                 match version {
-                    Some(version) => {
-                        let send_task = event_sender.send(ProtocolEvent::Open {
-                            sub_stream: Box::new(handle),
-                            proto_name: name,
-                            version,
-                        });
-
-                        tokio::spawn(send_task.map(|_| ()).map_err(|err| {
-                            error!("stream send back error: {:?}", err);
-                        }));
-                    }
+                    // Send in the select task itself, the event is queued before the remote
+                    // can write anything to the sub stream, such as a disconnect reason
+                    Some(version) => future::Either::A(
+                        event_sender
+                            .send(ProtocolEvent::Open {
+                                sub_stream: Box::new(handle),
+                                proto_name: name,
+                                version,
+                            })
+                            .map(|_| ())
+                            .map_err(|err| {
+                                error!("stream send back error: {:?}", err);
+                                io::Error::from(io::ErrorKind::BrokenPipe)
+                            }),
+                    ),
                     None => {
                         // server close the connect
                         let _ = handle.into_inner().shutdown();
                         debug!("negotiation to open the protocol [{}] failed", name);
+                        future::Either::B(future::ok(()))
                     }
                 }
-                Ok(())
             })
             .timeout(self.timeout)
             .map_err(|err| {
@@ -326,6 +506,11 @@ where
                 sub_stream,
                 version,
             } => {
+                if proto_name == DISCONNECT_PROTOCOL {
+                    self.disconnect_stream = Some(*sub_stream);
+                    self.recv_disconnect_reason();
+                    return;
+                }
                 let proto = match self.protocol_configs.get(&proto_name) {
                     Some(proto) => proto,
                     None => unreachable!(),
@@ -338,7 +523,8 @@ where
                 part.read_buf = raw_part.read_buf;
                 part.write_buf = raw_part.write_buf;
                 let frame = Framed::from_parts(part);
-                let (session_to_proto_sender, session_to_proto_receiver) = mpsc::channel(32);
+                let (session_to_proto_sender, session_to_proto_receiver) =
+                    mpsc::channel(self.sub_stream_channel_size);
                 let proto_stream = SubStream::new(
                     frame,
                     self.proto_event_sender.clone(),
@@ -373,27 +559,41 @@ where
                 });
                 if self.sub_streams.is_empty() {
                     debug!("Session no longer has protocol open, session closed");
-                    self.dead = true;
+                    let reason = match self.codec_error {
+                        Some(error_proto_id) if error_proto_id == proto_id => {
+                            CloseReason::CodecError(proto_id)
+                        }
+                        _ => CloseReason::AllProtocolsClosed,
+                    };
+                    self.set_dead(reason);
                 }
             }
             ProtocolEvent::Message { data, proto_id, .. } => {
                 debug!("get proto [{}] data len: {}", proto_id, data.len());
-                self.event_output(SessionEvent::ProtocolMessage {
-                    id: self.id,
-                    proto_id,
-                    data,
-                })
+                if self.budget.acquire(data.len()) {
+                    self.event_output(SessionEvent::ProtocolMessage {
+                        id: self.id,
+                        proto_id,
+                        data,
+                    })
+                } else {
+                    self.buffer_overflow(proto_id);
+                }
             }
             ProtocolEvent::Error {
                 proto_id, error, ..
             } => {
                 debug!("Codec error: {:?}", error);
+                self.codec_error = Some(proto_id);
                 self.event_output(SessionEvent::ProtocolError {
                     id: self.id,
                     proto_id,
                     error,
                 })
             }
+            ProtocolEvent::DisconnectReasonSent => {
+                self.set_dead(CloseReason::LocalDisconnect);
+            }
         }
     }
 
@@ -402,16 +602,23 @@ where
         match event {
             SessionEvent::ProtocolMessage { proto_id, data, .. } => {
                 if let Some(stream_id) = self.proto_streams.get(&proto_id) {
-                    self.write_buf.push_back(ProtocolEvent::Message {
-                        id: *stream_id,
-                        proto_id,
-                        data,
-                    });
+                    if self.budget.acquire(data.len()) {
+                        self.write_buf.push_back(ProtocolEvent::Message {
+                            id: *stream_id,
+                            proto_id,
+                            data,
+                        });
+                    } else {
+                        self.buffer_overflow(proto_id);
+                    }
                 } else {
                     trace!("protocol {} not ready", proto_id);
                 }
             }
-            SessionEvent::SessionClose { .. } => {
+            SessionEvent::SessionClose { reason, .. } => {
+                if self.close_reason.is_none() {
+                    self.close_reason = Some(reason);
+                }
                 if self.sub_streams.is_empty() {
                     // if no proto open, just close session
                     self.close_session();
@@ -424,6 +631,7 @@ where
                     }
                 }
             }
+            SessionEvent::SessionDisconnect { reason, .. } => self.disconnect_with_reason(reason),
             _ => (),
         }
         self.distribute_to_substream();
@@ -431,9 +639,14 @@ where
 
     /// Close session
     fn close_session(&mut self) {
-        let _ = self
-            .service_sender
-            .try_send(SessionEvent::SessionClose { id: self.id });
+        let reason = self
+            .close_reason
+            .clone()
+            .unwrap_or(CloseReason::LocalDisconnect);
+        let _ = self.service_sender.try_send(SessionEvent::SessionClose {
+            id: self.id,
+            reason,
+        });
         self.sub_streams.clear();
         self.service_receiver.close();
         self.proto_event_receiver.close();
@@ -447,6 +660,24 @@ where
         self.output();
     }
 
+    /// Park the session task on the full channels of the buffered events, it's woken
+    /// once any of them has room again, return true if one is ready already
+    fn poll_buffered_channels(&mut self) -> bool {
+        let mut ready = !self.read_buf.is_empty() && channel_ready(&mut self.service_sender);
+        for event in self.write_buf.iter() {
+            let id = match event {
+                ProtocolEvent::Message { id, .. } | ProtocolEvent::Close { id, .. } => *id,
+                _ => continue,
+            };
+            ready |= self
+                .sub_streams
+                .get_mut(&id)
+                .map(channel_ready)
+                .unwrap_or(true);
+        }
+        ready
+    }
+
     #[inline]
     fn notify(&mut self) {
         if let Some(task) = self.notify.take() {
@@ -477,17 +708,20 @@ where
             self.flush();
         }
 
+        // The socket end is acted on after the proto events are drained, so a disconnect
+        // sub stream opened just before the end is still read
+        let mut socket_end = None;
         loop {
             match self.socket.poll() {
                 Ok(Async::Ready(Some(sub_stream))) => self.handle_sub_stream(sub_stream),
                 Ok(Async::Ready(None)) => {
-                    self.dead = true;
+                    socket_end = Some(CloseReason::RemoteEof);
                     break;
                 }
                 Ok(Async::NotReady) => break,
                 Err(err) => {
                     warn!("session poll error: {:?}", err);
-                    self.dead = true;
+                    socket_end = Some(CloseReason::IoError(err.kind()));
                     break;
                 }
             }
@@ -508,12 +742,19 @@ where
             }
         }
 
+        self.recv_disconnect_reason();
+
+        if let Some(reason) = socket_end {
+            let reason = self.socket_close_reason(reason);
+            self.set_dead(reason);
+        }
+
         loop {
             match self.service_receiver.poll() {
                 Ok(Async::Ready(Some(event))) => self.handle_session_event(event),
                 Ok(Async::Ready(None)) => {
                     // Must drop by service
-                    self.dead = true;
+                    self.set_dead(CloseReason::LocalDisconnect);
                     break;
                 }
                 Ok(Async::NotReady) => break,
@@ -524,16 +765,57 @@ where
             }
         }
 
+        let expired = match self.disconnect_delay {
+            Some(ref mut delay) => match delay.poll() {
+                Ok(Async::NotReady) => false,
+                _ => true,
+            },
+            None => false,
+        };
+        if expired {
+            debug!("session [{}] disconnect reason isn't sent in time", self.id);
+            self.set_dead(CloseReason::LocalDisconnect);
+        }
+
         if self.dead {
             self.close_session();
             return Ok(Async::Ready(None));
         }
 
         self.notify = Some(task::current());
+        if self.poll_buffered_channels() {
+            self.notify();
+        }
         Ok(Async::NotReady)
     }
 }
 
+/// Whether the channel has room, the current task is parked on it if not
+#[inline]
+pub(crate) fn channel_ready<T>(sender: &mut mpsc::Sender<T>) -> bool {
+    match sender.poll_ready() {
+        Ok(Async::NotReady) => false,
+        // Room, or closed and the event will be dropped
+        _ => true,
+    }
+}
+
+impl<T, U> Drop for Session<T, U> {
+    fn drop(&mut self) {
+        // Give back the budget of messages that will never be delivered
+        for event in self.write_buf.drain(..) {
+            if let ProtocolEvent::Message { data, .. } = event {
+                self.budget.release(data.len());
+            }
+        }
+        for event in self.read_buf.drain(..) {
+            if let SessionEvent::ProtocolMessage { data, .. } = event {
+                self.budget.release(data.len());
+            }
+        }
+    }
+}
+
 pub(crate) struct SessionMeta<U> {
     config: Config,
     id: SessionId,
@@ -542,6 +824,8 @@ pub(crate) struct SessionMeta<U> {
     // remote_address: ::std::net::SocketAddr,
     // remote_public_key: Option<PublicKey>,
     timeout: Duration,
+    buffer_config: BufferConfig,
+    budget: BufferBudget,
 }
 
 impl<U> SessionMeta<U>
@@ -557,6 +841,8 @@ where
             ty,
             protocol_configs: Arc::new(HashMap::new()),
             timeout,
+            buffer_config: BufferConfig::default(),
+            budget: BufferBudget::new(None, OverflowPolicy::DropMessage),
         }
     }
 
@@ -572,4 +858,10 @@ where
         self.config = config;
         self
     }
+
+    pub fn buffer(mut self, config: BufferConfig, budget: BufferBudget) -> Self {
+        self.buffer_config = config;
+        self.budget = budget;
+        self
+    }
 }
@@ -54,6 +54,8 @@ pub(crate) enum ProtocolEvent {
         /// Codec error
         error: Error<ServiceTask>,
     },
+    /// The disconnect reason is sent to remote, or failed to send
+    DisconnectReasonSent,
 }
 
 /// Each custom protocol in a session corresponds to a sub stream
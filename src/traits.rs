@@ -3,7 +3,7 @@ use tokio::codec::{Decoder, Encoder};
 
 use crate::{
     context::{ServiceContext, SessionContext},
-    service::{ServiceError, ServiceEvent},
+    service::{HandleExecutor, ServiceError, ServiceEvent},
     ProtocolId,
 };
 
@@ -14,17 +14,22 @@ use crate::{
 /// All functions on this trait will block the entire server running, do not insert long-time tasks,
 /// you can use the futures task instead.
 ///
+/// Callbacks that take longer than the slow handler threshold are reported
+/// by `ServiceEvent::SlowHandler`.
+///
 /// #### Behavior
 ///
 /// The handle that exists when the Service is created.
 ///
 /// Mainly handle some Service-level errors thrown at runtime, such as listening errors.
 ///
-/// At the same time, the session establishment and disconnection messages will also be perceived here.
+/// At the same time, the lifecycle of the whole node will also be perceived here, such as
+/// listen started/closed, dial started, session establishment and disconnection,
+/// protocol opened/closed.
 pub trait ServiceHandle {
     /// Handling runtime errors
     fn handle_error(&mut self, _control: &mut ServiceContext, _error: ServiceError) {}
-    /// Handling session establishment and disconnection events
+    /// Handling lifecycle events, such as session establishment and disconnection
     fn handle_event(&mut self, _control: &mut ServiceContext, _event: ServiceEvent) {}
 }
 
@@ -33,7 +38,11 @@ pub trait ServiceHandle {
 /// #### Note
 ///
 /// All functions on this trait will block the entire server running, do not insert long-time tasks,
-/// you can use the futures task instead.
+/// you can use the futures task instead, or let the handle run on a dedicated thread
+/// with [`ProtocolMeta::handle_executor`](trait.ProtocolMeta.html#method.handle_executor).
+///
+/// Callbacks that take longer than the slow handler threshold are reported
+/// by `ServiceEvent::SlowHandler`.
 ///
 /// #### Behavior
 ///
@@ -50,7 +59,7 @@ pub trait ServiceHandle {
 /// to the session, but the service handle will remain in the state until the service is closed.
 ///
 pub trait ServiceProtocol {
-    /// This function is called when the protocol is opened.
+    /// This function is called when the service starts, before any session opens the protocol.
     ///
     /// The service handle will only be called once
     fn init(&mut self, service: &mut ServiceContext);
@@ -143,6 +152,14 @@ where
     fn session_handle(&self) -> Option<Box<dyn SessionProtocol + Send + 'static>> {
         None
     }
+
+    /// Where the service level and session level handles of this protocol run.
+    ///
+    /// Default is spawned on the service runtime, a CPU-heavy protocol can choose
+    /// the blocking pool or a dedicated thread, so it can't stall the other protocols.
+    fn handle_executor(&self) -> HandleExecutor {
+        HandleExecutor::Runtime
+    }
 }
 
 impl ServiceHandle for Box<dyn ServiceHandle + Send + 'static> {
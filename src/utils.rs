@@ -1,6 +1,10 @@
-use multiaddr::{Multiaddr, Protocol};
+use multiaddr::{Multiaddr, Protocol, ToMultiaddr};
 use secio::PeerId;
-use std::net::SocketAddr;
+use std::{
+    collections::VecDeque,
+    io,
+    net::{IpAddr, SocketAddr},
+};
 
 /// This module create a `DNSResolver` future task to DNS resolver
 pub mod dns;
@@ -32,21 +36,131 @@ pub fn multiaddr_to_socketaddr(addr: &Multiaddr) -> Result<SocketAddr, ()> {
 }
 
 /// Get peer id from multiaddr
+///
+/// For relayed address, it's the peer id after `/p2p-circuit`
 pub fn extract_peer_id(addr: &Multiaddr) -> Option<PeerId> {
-    let mut iter = addr.iter();
-
-    iter.find_map(|proto| {
-        if let Protocol::P2p(raw_bytes) = proto {
-            PeerId::from_bytes(raw_bytes.into_bytes()).ok()
-        } else {
-            None
+    addr.iter().fold(None, |peer_id, proto| match proto {
+        Protocol::P2p(raw_bytes) => {
+            peer_id.or_else(|| PeerId::from_bytes(raw_bytes.into_bytes()).ok())
         }
+        Protocol::P2pCircuit => None,
+        _ => peer_id,
     })
 }
 
+/// Whether the address is relayed through another peer, such as
+/// `/p2p/<relay>/p2p-circuit/p2p/<target>`
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|proto| proto == Protocol::P2pCircuit)
+}
+
+/// Whether the ip is globally reachable, copy from std::net::IpAddr::is_global
+pub fn is_reachable(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ipv4) => {
+            !ipv4.is_private()
+                && !ipv4.is_loopback()
+                && !ipv4.is_link_local()
+                && !ipv4.is_broadcast()
+                && !ipv4.is_documentation()
+                && !ipv4.is_unspecified()
+        }
+        IpAddr::V6(ipv6) => {
+            let scope = if ipv6.is_multicast() {
+                match ipv6.segments()[0] & 0x000f {
+                    1 => Some(false),
+                    2 => Some(false),
+                    3 => Some(false),
+                    4 => Some(false),
+                    5 => Some(false),
+                    8 => Some(false),
+                    14 => Some(true),
+                    _ => None,
+                }
+            } else {
+                None
+            };
+            match scope {
+                Some(true) => true,
+                None => {
+                    !(ipv6.is_multicast()
+                      || ipv6.is_loopback()
+                      // && !ipv6.is_unicast_link_local()
+                      || ((ipv6.segments()[0] & 0xffc0) == 0xfe80)
+                      // && !ipv6.is_unicast_site_local()
+                      || ((ipv6.segments()[0] & 0xffc0) == 0xfec0)
+                      // && !ipv6.is_unique_local()
+                      || ((ipv6.segments()[0] & 0xfe00) == 0xfc00)
+                      || ipv6.is_unspecified()
+                      // && !ipv6.is_documentation()
+                      || ((ipv6.segments()[0] == 0x2001) && (ipv6.segments()[1] == 0xdb8)))
+                }
+                _ => false,
+            }
+        }
+    }
+}
+
+/// Ips of local interfaces, IPv6 link local ips are skipped because they need scope id
+pub(crate) fn interface_ips() -> io::Result<Vec<IpAddr>> {
+    let mut ips = get_if_addrs::get_if_addrs()?
+        .into_iter()
+        .map(|interface| interface.ip())
+        .filter(|ip| match ip {
+            IpAddr::V4(_) => true,
+            IpAddr::V6(ipv6) => (ipv6.segments()[0] & 0xffc0) != 0xfe80,
+        })
+        .collect::<Vec<_>>();
+    ips.sort();
+    ips.dedup();
+    Ok(ips)
+}
+
+/// Replace the unspecified ip of listen addresses with the interface ips of the same version
+pub(crate) fn expand_listen_addrs(addrs: &[Multiaddr], interface_ips: &[IpAddr]) -> Vec<Multiaddr> {
+    let mut expanded = Vec::with_capacity(addrs.len());
+    for addr in addrs {
+        let candidates = match multiaddr_to_socketaddr(addr) {
+            Ok(socket_addr) if socket_addr.ip().is_unspecified() => interface_ips
+                .iter()
+                .filter(|ip| ip.is_ipv4() == socket_addr.is_ipv4())
+                .filter_map(|ip| SocketAddr::new(*ip, socket_addr.port()).to_multiaddr().ok())
+                .collect(),
+            _ => vec![addr.clone()],
+        };
+        for candidate in candidates {
+            if !expanded.contains(&candidate) {
+                expanded.push(candidate);
+            }
+        }
+    }
+    expanded
+}
+
+/// Sort addresses as RFC 8305 section 4, IPv6 first, then interleave IPv6 and IPv4
+pub(crate) fn happy_eyeballs_sort(addresses: Vec<Multiaddr>) -> Vec<Multiaddr> {
+    let (mut ipv6, mut ipv4): (VecDeque<_>, VecDeque<_>) =
+        addresses.into_iter().partition(|address| {
+            multiaddr_to_socketaddr(address)
+                .map(|address| address.is_ipv6())
+                .unwrap_or(false)
+        });
+    let mut sorted = Vec::with_capacity(ipv6.len() + ipv4.len());
+    loop {
+        match (ipv6.pop_front(), ipv4.pop_front()) {
+            (None, None) => break,
+            (v6, v4) => sorted.extend(v6.into_iter().chain(v4)),
+        }
+    }
+    sorted
+}
+
 #[cfg(test)]
 mod test {
-    use crate::utils::{extract_peer_id, multiaddr_to_socketaddr};
+    use crate::utils::{
+        expand_listen_addrs, extract_peer_id, happy_eyeballs_sort, is_relayed,
+        multiaddr_to_socketaddr,
+    };
     use multiaddr::Multiaddr;
     use secio::SecioKeyPair;
 
@@ -64,6 +178,29 @@ mod test {
         assert_eq!(peer_id, third);
     }
 
+    #[test]
+    fn parser_peer_id_from_relayed_multiaddr() {
+        let relay = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let target = SecioKeyPair::secp256k1_generated().to_peer_id();
+        let addr_1: Multiaddr = format!(
+            "/ip4/127.0.0.1/tcp/1337/p2p/{}/p2p-circuit/p2p/{}",
+            relay.to_base58(),
+            target.to_base58()
+        )
+        .parse()
+        .unwrap();
+        let addr_2: Multiaddr = format!("/p2p/{}/p2p-circuit", relay.to_base58())
+            .parse()
+            .unwrap();
+        let addr_3: Multiaddr = format!("/p2p/{}", relay.to_base58()).parse().unwrap();
+
+        assert_eq!(extract_peer_id(&addr_1), Some(target));
+        assert_eq!(extract_peer_id(&addr_2), None);
+        assert!(is_relayed(&addr_1));
+        assert!(is_relayed(&addr_2));
+        assert!(!is_relayed(&addr_3));
+    }
+
     #[test]
     fn parser_socket_addr_from_multiaddr() {
         let peer_id = SecioKeyPair::secp256k1_generated().to_peer_id();
@@ -92,4 +229,61 @@ mod test {
             .unwrap();
         multiaddr_to_socketaddr(&addr).unwrap();
     }
+
+    #[test]
+    fn happy_eyeballs_order() {
+        let addrs = vec![
+            "/ip4/127.0.0.1/tcp/1",
+            "/ip4/127.0.0.2/tcp/1",
+            "/ip4/127.0.0.3/tcp/1",
+            "/ip6/::1/tcp/1",
+            "/ip6/::2/tcp/1",
+        ]
+        .into_iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect::<Vec<Multiaddr>>();
+
+        let sorted = happy_eyeballs_sort(addrs.clone());
+        assert_eq!(
+            sorted,
+            vec![
+                addrs[3].clone(),
+                addrs[0].clone(),
+                addrs[4].clone(),
+                addrs[1].clone(),
+                addrs[2].clone()
+            ]
+        );
+    }
+
+    #[test]
+    fn expand_unspecified_listen_addrs() {
+        let addrs = vec![
+            "/ip4/0.0.0.0/tcp/1337",
+            "/ip6/::/tcp/1338",
+            "/ip4/127.0.0.1/tcp/1339",
+        ]
+        .into_iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect::<Vec<Multiaddr>>();
+        let ips = vec![
+            "127.0.0.1".parse().unwrap(),
+            "192.168.1.2".parse().unwrap(),
+            "::1".parse().unwrap(),
+        ];
+
+        let expanded = expand_listen_addrs(&addrs, &ips)
+            .into_iter()
+            .map(|addr| addr.to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            expanded,
+            vec![
+                "/ip4/127.0.0.1/tcp/1337",
+                "/ip4/192.168.1.2/tcp/1337",
+                "/ip6/::1/tcp/1338",
+                "/ip4/127.0.0.1/tcp/1339",
+            ]
+        );
+    }
 }
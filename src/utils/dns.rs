@@ -11,6 +11,8 @@ pub struct DNSResolver<T> {
     peer_id: Option<PeerId>,
     port: u16,
     domain: String,
+    /// `/dns4` only accepts IPv4, `/dns6` only accepts IPv6
+    ipv6: bool,
     phantom: PhantomData<T>,
 }
 
@@ -20,9 +22,9 @@ impl<T> DNSResolver<T> {
     pub fn new(source_address: Multiaddr) -> Result<Self, ()> {
         let mut iter = source_address.iter().peekable();
 
-        let (domain, port) = loop {
+        let (domain, port, ipv6) = loop {
             if iter.peek().is_none() {
-                break (None, None);
+                break (None, None, false);
             }
             match iter.peek() {
                 Some(Protocol::Dns4(_)) | Some(Protocol::Dns6(_)) => (),
@@ -36,8 +38,12 @@ impl<T> DNSResolver<T> {
             let proto2 = iter.next().ok_or(())?;
 
             match (proto1, proto2) {
-                (Protocol::Dns4(domain), Protocol::Tcp(port)) => break (Some(domain), Some(port)),
-                (Protocol::Dns6(domain), Protocol::Tcp(port)) => break (Some(domain), Some(port)),
+                (Protocol::Dns4(domain), Protocol::Tcp(port)) => {
+                    break (Some(domain), Some(port), false)
+                }
+                (Protocol::Dns6(domain), Protocol::Tcp(port)) => {
+                    break (Some(domain), Some(port), true)
+                }
                 _ => (),
             }
         };
@@ -48,6 +54,7 @@ impl<T> DNSResolver<T> {
                 domain: domain.to_string(),
                 source_address,
                 port,
+                ipv6,
                 phantom: PhantomData,
             }),
             _ => Err(()),
@@ -59,27 +66,37 @@ impl<T> Future for DNSResolver<T>
 where
     T: Send + ::std::fmt::Debug,
 {
-    type Item = Multiaddr;
+    /// All resolved addresses of the right IP version, in resolver order
+    type Item = Vec<Multiaddr>;
     type Error = (Multiaddr, Error<T>);
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
         match tokio_threadpool::blocking(|| (self.domain.as_str(), self.port).to_socket_addrs()) {
-            Ok(Async::Ready(Ok(mut iter))) => match iter.next() {
-                Some(address) => {
-                    let mut address = address.to_multiaddr().unwrap();
-                    if let Some(peer_id) = self.peer_id.take() {
-                        address.append(Protocol::P2p(
-                            Multihash::from_bytes(peer_id.as_bytes().to_vec())
-                                .expect("Invalid peer id"),
-                        ))
-                    }
-                    Ok(Async::Ready(address))
+            Ok(Async::Ready(Ok(iter))) => {
+                let ipv6 = self.ipv6;
+                let peer_id = self.peer_id.as_ref();
+                let addresses = iter
+                    .filter(|address| address.is_ipv6() == ipv6)
+                    .map(|address| {
+                        let mut address = address.to_multiaddr().unwrap();
+                        if let Some(peer_id) = peer_id {
+                            address.append(Protocol::P2p(
+                                Multihash::from_bytes(peer_id.as_bytes().to_vec())
+                                    .expect("Invalid peer id"),
+                            ))
+                        }
+                        address
+                    })
+                    .collect::<Vec<Multiaddr>>();
+                if addresses.is_empty() {
+                    Err((
+                        self.source_address.clone(),
+                        Error::DNSResolverError(io::ErrorKind::InvalidData.into()),
+                    ))
+                } else {
+                    Ok(Async::Ready(addresses))
                 }
-                None => Err((
-                    self.source_address.clone(),
-                    Error::DNSResolverError(io::ErrorKind::InvalidData.into()),
-                )),
-            },
+            }
             Ok(Async::Ready(Err(e))) => {
                 Err((self.source_address.clone(), Error::DNSResolverError(e)))
             }
@@ -102,7 +119,7 @@ mod test {
         let future: DNSResolver<()> =
             DNSResolver::new("/dns4/localhost/tcp/80".parse().unwrap()).unwrap();
         let mut rt = tokio::runtime::Runtime::new().unwrap();
-        let addr = rt.block_on(future).unwrap();
-        assert_eq!("/ip4/127.0.0.1/tcp/80".parse::<Multiaddr>().unwrap(), addr)
+        let addrs = rt.block_on(future).unwrap();
+        assert!(addrs.contains(&"/ip4/127.0.0.1/tcp/80".parse::<Multiaddr>().unwrap()))
     }
 }
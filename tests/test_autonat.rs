@@ -0,0 +1,95 @@
+use autonat::{AutonatConfig, AutonatProtocol};
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    reachability::Reachability,
+    service::{Service, ServiceEvent},
+    traits::ServiceHandle,
+    SecioKeyPair,
+};
+use std::{net::TcpListener, thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<(Reachability, Reachability)>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::ReachabilityChanged { reachability } = event {
+            let _ = self.sender.try_send((reachability, env.reachability()));
+        }
+    }
+}
+
+fn create(
+    sender: crossbeam_channel::Sender<(Reachability, Reachability)>,
+) -> Service<SHandle, LengthDelimitedCodec> {
+    let config = AutonatConfig {
+        probe_interval: Duration::from_millis(200),
+        dial_timeout: Duration::from_secs(2),
+        allow_private: true,
+        throttle: Duration::from_secs(0),
+        ..Default::default()
+    };
+    ServiceBuilder::default()
+        .insert_protocol(AutonatProtocol::new(1, config))
+        .key_pair(SecioKeyPair::secp256k1_generated())
+        .reachability_threshold(2)
+        .forever(true)
+        .build(SHandle { sender })
+}
+
+/// Start a server, then the node dials it, return the reachability decided by the node
+fn test_reachability(
+    listen: bool,
+    external_addr: Option<Multiaddr>,
+) -> (Reachability, Reachability) {
+    // Server events are not checked
+    let mut server = create(crossbeam_channel::unbounded().0);
+    let server_addr = server
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(server.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut node = create(sender);
+    if listen {
+        node.listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+            .unwrap();
+    }
+    if let Some(address) = external_addr {
+        node.control().add_external_addr(address).unwrap();
+    }
+    node.dial(server_addr).unwrap();
+    thread::spawn(|| tokio::run(node.for_each(|_| Ok(()))));
+
+    receiver
+        .recv_timeout(Duration::from_secs(20))
+        .expect("reachability not changed")
+}
+
+#[test]
+fn test_public_reachability() {
+    let (event, current) = test_reachability(true, None);
+    assert_eq!(event, Reachability::Public);
+    assert_eq!(current, Reachability::Public);
+}
+
+#[test]
+fn test_private_reachability() {
+    // Nobody listens on it
+    let closed_port = TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+    let address = format!("/ip4/127.0.0.1/tcp/{}", closed_port)
+        .parse()
+        .unwrap();
+    let (event, current) = test_reachability(false, Some(address));
+    assert_eq!(event, Reachability::Private);
+    assert_eq!(current, Reachability::Private);
+}
@@ -0,0 +1,192 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    config::{BufferConfig, OverflowPolicy},
+    context::{ServiceContext, SessionContext},
+    service::{CloseReason, HandleExecutor, Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle, ServiceProtocol, SessionProtocol},
+    ProtocolId,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+const MESSAGES: usize = 1000;
+const MESSAGE_SIZE: usize = 1024;
+
+/// Flood the remote when the protocol opens
+#[derive(Clone)]
+struct Flood {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Flood {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+    fn session_handle(&self) -> Option<Box<dyn SessionProtocol + Send + 'static>> {
+        Some(Box::new(FloodHandle))
+    }
+}
+
+struct FloodHandle;
+
+impl SessionProtocol for FloodHandle {
+    fn connected(&mut self, env: &mut ServiceContext, session: &SessionContext, _version: &str) {
+        for _ in 0..MESSAGES {
+            let _ = env.send_message(session.id, 1, vec![0; MESSAGE_SIZE]);
+        }
+        // An empty message always fits in the budget, it marks the end of the flood
+        let _ = env.send_message(session.id, 1, Vec::new());
+    }
+}
+
+/// Stall the session handle on the first message until released, then count the
+/// received messages, the service handle reports the end of the flood
+#[derive(Clone)]
+struct Stall {
+    id: ProtocolId,
+    release: crossbeam_channel::Receiver<()>,
+    received: crossbeam_channel::Sender<Vec<u8>>,
+    flooded: crossbeam_channel::Sender<()>,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Stall {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+    fn session_handle(&self) -> Option<Box<dyn SessionProtocol + Send + 'static>> {
+        Some(Box::new(StallHandle {
+            stalled: false,
+            release: self.release.clone(),
+            received: self.received.clone(),
+        }))
+    }
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        Some(Box::new(EndHandle {
+            flooded: self.flooded.clone(),
+        }))
+    }
+    fn handle_executor(&self) -> HandleExecutor {
+        // The stalled session handle must not block the service handle
+        HandleExecutor::Blocking
+    }
+}
+
+struct EndHandle {
+    flooded: crossbeam_channel::Sender<()>,
+}
+
+impl ServiceProtocol for EndHandle {
+    fn init(&mut self, _env: &mut ServiceContext) {}
+
+    fn received(&mut self, _env: &mut ServiceContext, _session: &SessionContext, data: Vec<u8>) {
+        if data.is_empty() {
+            let _ = self.flooded.send(());
+        }
+    }
+}
+
+struct StallHandle {
+    stalled: bool,
+    release: crossbeam_channel::Receiver<()>,
+    received: crossbeam_channel::Sender<Vec<u8>>,
+}
+
+impl SessionProtocol for StallHandle {
+    fn received(&mut self, _env: &mut ServiceContext, data: Vec<u8>) {
+        if !self.stalled {
+            self.stalled = true;
+            let _ = self.release.recv();
+        }
+        let _ = self.received.send(data);
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<CloseReason>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionClose { reason, .. } = event {
+            let _ = self.sender.send(reason);
+        }
+    }
+}
+
+fn test_buffer_overflow(policy: OverflowPolicy) {
+    let (release_sender, release) = crossbeam_channel::bounded(1);
+    let (received_sender, received) = crossbeam_channel::unbounded();
+    let (flooded_sender, flooded) = crossbeam_channel::unbounded();
+    let (close_sender, close_receiver) = crossbeam_channel::unbounded();
+    let mut service: Service<_, LengthDelimitedCodec> = ServiceBuilder::default()
+        .insert_protocol(Stall {
+            id: 1,
+            release,
+            received: received_sender,
+            flooded: flooded_sender,
+        })
+        .buffer_config(BufferConfig {
+            proto_handle_channel_size: 1,
+            max_buffered_bytes: Some(16 * MESSAGE_SIZE),
+            overflow_policy: policy,
+            ..Default::default()
+        })
+        .forever(true)
+        .build(SHandle {
+            sender: close_sender,
+        });
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut service: Service<_, LengthDelimitedCodec> = ServiceBuilder::default()
+        .insert_protocol(Flood { id: 1 })
+        .buffer_config(BufferConfig {
+            service_task_channel_size: MESSAGES * 2,
+            ..Default::default()
+        })
+        .forever(true)
+        .build(());
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    match policy {
+        OverflowPolicy::DropMessage => {
+            // The whole flood has arrived while the session handle stalls, the budget
+            // is held by the messages waiting for it, though the service handle is done
+            assert_eq!(flooded.recv_timeout(Duration::from_secs(10)), Ok(()));
+            let _ = release_sender.send(());
+            // Only the messages buffered before the budget ran out are left before the end
+            let count = received.iter().take_while(|data| !data.is_empty()).count();
+            assert!(count > 0);
+            assert!(count < MESSAGES, "no message is dropped");
+            // The session keeps running
+            assert!(close_receiver.try_recv().is_err());
+        }
+        OverflowPolicy::CloseSession => {
+            assert_eq!(
+                close_receiver.recv_timeout(Duration::from_secs(10)),
+                Ok(CloseReason::BufferOverflow)
+            );
+            let _ = release_sender.send(());
+        }
+    }
+}
+
+#[test]
+fn test_buffer_overflow_drop_message() {
+    test_buffer_overflow(OverflowPolicy::DropMessage)
+}
+
+#[test]
+fn test_buffer_overflow_close_session() {
+    test_buffer_overflow(OverflowPolicy::CloseSession)
+}
@@ -0,0 +1,142 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{CloseReason, DisconnectCode, DisconnectReason, Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(secio: bool, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    let builder = ServiceBuilder::default()
+        .insert_protocol(meta)
+        .forever(true);
+
+    if secio {
+        builder
+            .key_pair(SecioKeyPair::secp256k1_generated())
+            .build(shandle)
+    } else {
+        builder.build(shandle)
+    }
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+struct SHandle {
+    disconnect: bool,
+    on_session_open: bool,
+    reason: Option<DisconnectReason>,
+    sender: crossbeam_channel::Sender<CloseReason>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        match event {
+            ServiceEvent::SessionOpen { id, .. } if self.on_session_open => {
+                let _ = env.disconnect_with_reason(id, self.reason.clone().unwrap());
+            }
+            ServiceEvent::ProtocolOpened { id, .. } => {
+                if self.disconnect && !self.on_session_open {
+                    let _ = match self.reason.clone() {
+                        Some(reason) => env.disconnect_with_reason(id, reason),
+                        None => env.disconnect(id),
+                    };
+                }
+            }
+            ServiceEvent::SessionClose { reason, .. } => {
+                let _ = self.sender.try_send(reason);
+            }
+            _ => (),
+        }
+    }
+}
+
+fn test_close_reason(secio: bool, reason: Option<DisconnectReason>, on_session_open: bool) {
+    let (sender, listen_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(
+        secio,
+        Protocol { id: 1 },
+        SHandle {
+            disconnect: false,
+            on_session_open: false,
+            reason: None,
+            sender,
+        },
+    );
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, dial_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(
+        secio,
+        Protocol { id: 1 },
+        SHandle {
+            disconnect: true,
+            on_session_open,
+            reason: reason.clone(),
+            sender,
+        },
+    );
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    // Closed once the reason is sent, without waiting for the 10s timeout
+    assert_eq!(
+        dial_receiver.recv_timeout(Duration::from_secs(3)),
+        Ok(CloseReason::LocalDisconnect)
+    );
+    let listen_reason = listen_receiver.recv().unwrap();
+    match reason {
+        Some(reason) => assert_eq!(listen_reason, CloseReason::RemoteDisconnect(reason)),
+        None => assert_ne!(listen_reason, CloseReason::LocalDisconnect),
+    }
+}
+
+#[test]
+fn test_close_reason_with_secio() {
+    test_close_reason(true, None, false)
+}
+
+#[test]
+fn test_close_reason_with_no_secio() {
+    test_close_reason(false, None, false)
+}
+
+#[test]
+fn test_disconnect_with_reason() {
+    let reason = DisconnectReason::new(DisconnectCode::Banned, "banned for misbehavior");
+    test_close_reason(true, Some(reason.clone()), false);
+    test_close_reason(false, Some(reason), false);
+}
+
+#[test]
+fn test_disconnect_with_reason_on_session_open() {
+    // The reason is sent and the session is closed in the same poll, the remote must
+    // still see the reason instead of the EOF, every time
+    let reason = DisconnectReason::new(DisconnectCode::Banned, "banned on open");
+    for _ in 0..10 {
+        test_close_reason(true, Some(reason.clone()), true);
+        test_close_reason(false, Some(reason.clone()), true);
+    }
+}
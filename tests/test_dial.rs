@@ -171,7 +171,12 @@ impl ServiceProtocol for PHandle {
     }
 
     fn notify(&mut self, control: &mut ServiceContext, _token: u64) {
-        if let Err(e) = control.dial(self.dial_addr.as_ref().unwrap().clone()) {
+        // The handle starts with the service, before the protocol opens
+        let dial_addr = match self.dial_addr.clone() {
+            Some(addr) => addr,
+            None => return,
+        };
+        if let Err(e) = control.dial(dial_addr) {
             panic!("dial err: {}", e)
         }
         self.dial_count += 1;
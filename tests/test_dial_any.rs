@@ -0,0 +1,93 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::{Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<Multiaddr>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionOpen { address, .. } = event {
+            let _ = self.sender.try_send(address);
+        }
+    }
+}
+
+fn listen() -> (Service<(), LengthDelimitedCodec>, Multiaddr) {
+    let mut service = create(Protocol { id: 1 }, ());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    (service, listen_addr)
+}
+
+#[test]
+fn test_dial_any_fallback() {
+    // Get a free port and close it
+    let (service, dead_addr) = listen();
+    drop(service);
+
+    let (service, listen_addr) = listen();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(Protocol { id: 1 }, SHandle { sender });
+    service
+        .dial_any(vec![dead_addr, listen_addr.clone()])
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    assert_eq!(receiver.recv(), Ok(listen_addr));
+}
+
+#[test]
+fn test_dial_any_one_winner() {
+    let (service_1, listen_addr_1) = listen();
+    thread::spawn(|| tokio::run(service_1.for_each(|_| Ok(()))));
+    let (service_2, listen_addr_2) = listen();
+    thread::spawn(|| tokio::run(service_2.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(Protocol { id: 1 }, SHandle { sender });
+    service
+        .dial_any(vec![listen_addr_1.clone(), listen_addr_2])
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    assert_eq!(receiver.recv(), Ok(listen_addr_1));
+    assert!(receiver.recv_timeout(Duration::from_secs(1)).is_err());
+}
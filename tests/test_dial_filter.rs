@@ -0,0 +1,77 @@
+use futures::prelude::{Future, Stream};
+use p2p::{
+    builder::ServiceBuilder,
+    config::{DialDenied, DialFilter},
+    error::Error,
+    service::Service,
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(filter: DialFilter, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .dial_filter(filter)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+#[test]
+fn test_dial_filter() {
+    let filter = DialFilter::default()
+        .allow_self(false)
+        .predicate(|address| !address.to_string().ends_with("/tcp/1"));
+    let mut service = create(filter, Protocol { id: 1 }, ());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let mut control = service.control().clone();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let cases = vec![
+        (listen_addr, DialDenied::SelfAddress),
+        (
+            "/ip4/127.0.0.1/tcp/0".parse().unwrap(),
+            DialDenied::Unroutable,
+        ),
+        (
+            "/ip4/127.0.0.1/tcp/1".parse().unwrap(),
+            DialDenied::Predicate,
+        ),
+    ];
+    for (address, denied) in cases {
+        match control.dial_with_result(address).wait() {
+            Err(error) => assert_eq!(error, Error::DialDenied(denied)),
+            Ok(_) => panic!("dial should be refused"),
+        }
+    }
+}
+
+#[test]
+fn test_dial_filter_reachable() {
+    let mut service = create(DialFilter::reachable(), Protocol { id: 1 }, ());
+    match service.dial("/ip4/192.168.0.1/tcp/1337".parse().unwrap()) {
+        Err(error) => assert_eq!(error, Error::DialDenied(DialDenied::Private)),
+        Ok(_) => panic!("dial should be refused"),
+    }
+}
@@ -0,0 +1,73 @@
+use futures::prelude::{Future, Stream};
+use p2p::{
+    builder::ServiceBuilder,
+    error::Error,
+    service::Service,
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(key_pair: SecioKeyPair, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+#[test]
+fn test_dial_with_result() {
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let mut service = create(key_pair, Protocol { id: 1 }, ());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut service = create(SecioKeyPair::secp256k1_generated(), Protocol { id: 1 }, ());
+    let mut control = service.control().clone();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (_, remote_peer_id) = control.dial_with_result(listen_addr).wait().unwrap();
+    assert_eq!(remote_peer_id, Some(peer_id));
+}
+
+#[test]
+fn test_dial_with_result_error() {
+    // Get a free port and close it
+    let mut service = create(SecioKeyPair::secp256k1_generated(), Protocol { id: 1 }, ());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    drop(service);
+
+    let mut service = create(SecioKeyPair::secp256k1_generated(), Protocol { id: 1 }, ());
+    let mut control = service.control().clone();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    match control.dial_with_result(listen_addr).wait() {
+        Err(Error::IoError(_)) => (),
+        other => panic!("unexpected dial result: {:?}", other),
+    }
+}
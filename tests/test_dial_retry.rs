@@ -0,0 +1,101 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    config::RetryPolicy,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::{Service, ServiceError, ServiceEvent},
+    traits::ServiceHandle,
+};
+use std::time::Duration;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<F>(shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    F: ServiceHandle,
+{
+    ServiceBuilder::default().build(shandle)
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<&'static str>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        if let ServiceError::DialerError { .. } = error {
+            let _ = self.sender.try_send("dial error");
+        }
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::DialStarted { .. } = event {
+            let _ = self.sender.try_send("dial started");
+        }
+    }
+}
+
+fn dead_address() -> Multiaddr {
+    // Get a free port and close it
+    let mut service = create(());
+    service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap()
+}
+
+fn policy() -> RetryPolicy {
+    RetryPolicy {
+        max_attempts: 3,
+        base_delay: Duration::from_millis(50),
+        max_delay: Duration::from_millis(100),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn test_dial_retry() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(SHandle { sender });
+    service.dial_with_policy(dead_address(), policy()).unwrap();
+
+    // Service is not forever, it ends after all attempts failed
+    tokio::run(service.for_each(|_| Ok(())));
+
+    assert_eq!(
+        receiver.try_iter().collect::<Vec<_>>(),
+        vec![
+            "dial started",
+            "dial error",
+            "dial started",
+            "dial error",
+            "dial started",
+            "dial error"
+        ]
+    );
+}
+
+#[test]
+fn test_dial_retry_cancel() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(SHandle { sender });
+    let address = dead_address();
+    let policy = RetryPolicy {
+        base_delay: Duration::from_secs(10),
+        max_delay: Duration::from_secs(10),
+        ..policy()
+    };
+    service.dial_with_policy(address.clone(), policy).unwrap();
+    let mut control = service.control().clone();
+    std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(500));
+        control.cancel_dial(address).unwrap();
+    });
+
+    // Service ends when the retry canceled
+    tokio::run(service.for_each(|_| Ok(())));
+
+    assert_eq!(
+        receiver.try_iter().collect::<Vec<_>>(),
+        vec!["dial started", "dial error"]
+    );
+}
@@ -0,0 +1,175 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    error::Error,
+    service::{CloseReason, Service, ServiceError, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(
+    key_pair: SecioKeyPair,
+    max_connections: usize,
+    meta: T,
+    shandle: F,
+) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .key_pair(key_pair)
+        .max_connections_per_peer(max_connections)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Notify {
+    Open,
+    Close(CloseReason),
+    Repeated,
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<Notify>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        match error {
+            ServiceError::DialerError {
+                error: Error::RepeatedConnection(_),
+                ..
+            }
+            | ServiceError::ListenError {
+                error: Error::RepeatedConnection(_),
+                ..
+            } => {
+                let _ = self.sender.try_send(Notify::Repeated);
+            }
+            _ => (),
+        }
+    }
+
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        match event {
+            ServiceEvent::SessionOpen { .. } => {
+                let _ = self.sender.try_send(Notify::Open);
+            }
+            ServiceEvent::SessionClose { reason, .. } => {
+                let _ = self.sender.try_send(Notify::Close(reason));
+            }
+            _ => (),
+        }
+    }
+}
+
+/// Key pairs sorted by peer id
+fn key_pairs() -> (SecioKeyPair, SecioKeyPair) {
+    let key_1 = SecioKeyPair::secp256k1_generated();
+    let key_2 = SecioKeyPair::secp256k1_generated();
+    if key_1.to_peer_id() < key_2.to_peer_id() {
+        (key_1, key_2)
+    } else {
+        (key_2, key_1)
+    }
+}
+
+/// `first` dials `second`, then `second` dials back
+fn test_duplicate_connection(
+    first: SecioKeyPair,
+    second: SecioKeyPair,
+    max_connections: usize,
+) -> (
+    crossbeam_channel::Receiver<Notify>,
+    crossbeam_channel::Receiver<Notify>,
+) {
+    let (sender, second_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(
+        second,
+        max_connections,
+        Protocol { id: 1 },
+        SHandle { sender },
+    );
+    let second_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let mut second_control = service.control().clone();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, first_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(
+        first,
+        max_connections,
+        Protocol { id: 1 },
+        SHandle { sender },
+    );
+    let first_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    service.dial(second_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    assert_eq!(first_receiver.recv(), Ok(Notify::Open));
+    assert_eq!(second_receiver.recv(), Ok(Notify::Open));
+
+    second_control.dial(first_addr).unwrap();
+    (first_receiver, second_receiver)
+}
+
+#[test]
+fn test_keep_connection_dialed_by_lower_peer_id() {
+    let (low, high) = key_pairs();
+    let (first_receiver, second_receiver) = test_duplicate_connection(low, high, 1);
+
+    // Both sides refuse the connection dialed by the higher peer id
+    assert_eq!(first_receiver.recv(), Ok(Notify::Repeated));
+    assert_eq!(second_receiver.recv(), Ok(Notify::Repeated));
+}
+
+#[test]
+fn test_replace_connection_dialed_by_higher_peer_id() {
+    let (low, high) = key_pairs();
+    let (first_receiver, second_receiver) = test_duplicate_connection(high, low, 1);
+
+    // Both sides keep the new connection, the old one may be closed by remote first
+    let mut reasons = Vec::new();
+    for receiver in &[first_receiver, second_receiver] {
+        let notifies = vec![receiver.recv().unwrap(), receiver.recv().unwrap()];
+        assert!(notifies.contains(&Notify::Open));
+        reasons.extend(notifies.into_iter().filter_map(|notify| match notify {
+            Notify::Close(reason) => Some(reason),
+            _ => None,
+        }));
+    }
+    assert_eq!(reasons.len(), 2);
+    assert!(reasons.contains(&CloseReason::Duplicate));
+}
+
+#[test]
+fn test_multiple_connections_per_peer() {
+    let (low, high) = key_pairs();
+    let (first_receiver, second_receiver) = test_duplicate_connection(low, high, 2);
+
+    assert_eq!(first_receiver.recv(), Ok(Notify::Open));
+    assert_eq!(second_receiver.recv(), Ok(Notify::Open));
+}
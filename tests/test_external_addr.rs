@@ -0,0 +1,110 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::{ServiceContext, SessionContext},
+    multiaddr::Multiaddr,
+    service::Service,
+    traits::{ProtocolMeta, ServiceHandle, ServiceProtocol},
+    ProtocolId,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .observed_addr_threshold(1)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+    sender: Option<crossbeam_channel::Sender<Vec<Multiaddr>>>,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        self.sender.clone().map(|sender| {
+            Box::new(PHandle {
+                proto_id: self.id,
+                sender,
+            }) as Box<_>
+        })
+    }
+}
+
+struct PHandle {
+    proto_id: ProtocolId,
+    sender: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl ServiceProtocol for PHandle {
+    fn init(&mut self, control: &mut ServiceContext) {
+        control.set_service_notify(self.proto_id, Duration::from_millis(100), 1);
+    }
+
+    fn connected(
+        &mut self,
+        control: &mut ServiceContext,
+        session: &SessionContext,
+        _version: &str,
+    ) {
+        control
+            .add_external_addr("/ip4/1.1.1.1/tcp/1337".parse().unwrap())
+            .unwrap();
+        control
+            .observed_addr(session.id, "/ip4/2.2.2.2/tcp/1337".parse().unwrap())
+            .unwrap();
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, _token: u64) {
+        if control.external_addrs().len() == 2 {
+            let _ = self.sender.try_send(control.external_addrs().clone());
+        }
+    }
+}
+
+#[test]
+fn test_external_addrs() {
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let mut service = create(
+        Protocol {
+            id: 1,
+            sender: Some(sender),
+        },
+        (),
+    );
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut service = create(
+        Protocol {
+            id: 1,
+            sender: None,
+        },
+        (),
+    );
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let expected: Vec<Multiaddr> = vec![
+        "/ip4/1.1.1.1/tcp/1337".parse().unwrap(),
+        "/ip4/2.2.2.2/tcp/1337".parse().unwrap(),
+    ];
+    assert_eq!(receiver.recv(), Ok(expected));
+}
@@ -0,0 +1,176 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::{ServiceContext, SessionContext},
+    error::Error,
+    hello::{Hello, HelloMismatch},
+    service::{Service, ServiceError},
+    traits::{ProtocolMeta, ServiceHandle, SessionProtocol},
+    ProtocolId, SecioKeyPair,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+#[derive(Debug, PartialEq)]
+enum Notify {
+    Connected(Option<Hello>),
+    Refused(HelloMismatch),
+}
+
+#[derive(Clone)]
+struct Protocol {
+    id: ProtocolId,
+    sender: crossbeam_channel::Sender<Notify>,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+    fn session_handle(&self) -> Option<Box<dyn SessionProtocol + Send + 'static>> {
+        Some(Box::new(PHandle {
+            sender: self.sender.clone(),
+        }))
+    }
+}
+
+struct PHandle {
+    sender: crossbeam_channel::Sender<Notify>,
+}
+
+impl SessionProtocol for PHandle {
+    fn connected(&mut self, _env: &mut ServiceContext, session: &SessionContext, _version: &str) {
+        let _ = self
+            .sender
+            .send(Notify::Connected(session.remote_hello.clone()));
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<Notify>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        match error {
+            ServiceError::DialerError {
+                error: Error::HelloMismatch(mismatch),
+                ..
+            }
+            | ServiceError::ListenError {
+                error: Error::HelloMismatch(mismatch),
+                ..
+            } => {
+                let _ = self.sender.send(Notify::Refused(mismatch));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn create(
+    hello: Hello,
+) -> (
+    Service<SHandle, LengthDelimitedCodec>,
+    crossbeam_channel::Receiver<Notify>,
+) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let service = ServiceBuilder::default()
+        .insert_protocol(Protocol {
+            id: 1,
+            sender: sender.clone(),
+        })
+        .key_pair(SecioKeyPair::secp256k1_generated())
+        .hello(hello)
+        .forever(true)
+        .build(SHandle { sender });
+    (service, receiver)
+}
+
+fn test_hello(remote: Hello, result: Result<(), HelloMismatch>) {
+    let timeout = Duration::from_secs(10);
+    let local = Hello::new(&b"mainnet"[..]).version(1).capabilities(1);
+    let (mut server, server_receiver) = create(remote.clone());
+    let listen_addr = server
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(server.for_each(|_| Ok(()))));
+
+    let (mut client, client_receiver) = create(local.clone());
+    client.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(client.for_each(|_| Ok(()))));
+
+    match result {
+        Ok(()) => {
+            assert_eq!(
+                client_receiver.recv_timeout(timeout),
+                Ok(Notify::Connected(Some(remote)))
+            );
+            assert_eq!(
+                server_receiver.recv_timeout(timeout),
+                Ok(Notify::Connected(Some(local)))
+            );
+        }
+        Err(mismatch) => {
+            assert_eq!(
+                client_receiver.recv_timeout(timeout),
+                Ok(Notify::Refused(mismatch))
+            );
+            // Mismatch of the server side is reversed
+            let mismatch = match mismatch {
+                HelloMismatch::Version { local, remote } => HelloMismatch::Version {
+                    local: remote,
+                    remote: local,
+                },
+                mismatch => mismatch,
+            };
+            assert_eq!(
+                server_receiver.recv_timeout(timeout),
+                Ok(Notify::Refused(mismatch))
+            );
+            // No protocol is opened
+            assert!(client_receiver
+                .recv_timeout(Duration::from_secs(1))
+                .is_err());
+        }
+    }
+}
+
+#[test]
+fn test_hello_match() {
+    // Capabilities are not checked
+    test_hello(
+        Hello::new(&b"mainnet"[..]).version(1).capabilities(2),
+        Ok(()),
+    )
+}
+
+#[test]
+fn test_hello_network_mismatch() {
+    test_hello(
+        Hello::new(&b"testnet"[..]).version(1),
+        Err(HelloMismatch::NetworkId),
+    )
+}
+
+#[test]
+fn test_hello_version_mismatch() {
+    test_hello(
+        Hello::new(&b"mainnet"[..]).version(2),
+        Err(HelloMismatch::Version {
+            local: 1,
+            remote: 2,
+        }),
+    )
+}
+
+#[test]
+#[should_panic]
+fn test_hello_without_key_pair() {
+    let _: Service<(), LengthDelimitedCodec> = ServiceBuilder::default()
+        .hello(Hello::new(&b"mainnet"[..]))
+        .build(());
+}
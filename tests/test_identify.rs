@@ -0,0 +1,159 @@
+use futures::prelude::Stream;
+use identify::{Event, IdentifyInfo, IdentifyProtocol};
+use p2p::{
+    builder::ServiceBuilder,
+    config::TcpConfig,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::Service,
+    traits::{ProtocolMeta, ServiceProtocol},
+    ProtocolId,
+};
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::codec::LengthDelimitedCodec;
+
+/// Report the external addresses periodically
+#[derive(Clone)]
+struct Protocol {
+    id: ProtocolId,
+    sender: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        Some(Box::new(PHandle {
+            proto_id: self.id,
+            sender: self.sender.clone(),
+        }))
+    }
+}
+
+struct PHandle {
+    proto_id: ProtocolId,
+    sender: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl ServiceProtocol for PHandle {
+    fn init(&mut self, control: &mut ServiceContext) {
+        control.set_service_notify(self.proto_id, Duration::from_millis(100), 1);
+    }
+
+    fn notify(&mut self, control: &mut ServiceContext, _token: u64) {
+        let _ = self.sender.try_send(control.external_addrs().clone());
+    }
+}
+
+struct Node {
+    events: crossbeam_channel::Receiver<Event>,
+    external_addrs: crossbeam_channel::Receiver<Vec<Multiaddr>>,
+}
+
+impl Node {
+    fn identified(&self) -> IdentifyInfo {
+        match self.events.recv_timeout(Duration::from_secs(10)) {
+            Ok(Event::Identified(_, info)) => info,
+            other => panic!("not identified: {:?}", other),
+        }
+    }
+
+    /// The external addresses after a while
+    fn external_addrs(&self, wait: Duration) -> Vec<Multiaddr> {
+        let deadline = Instant::now() + wait;
+        loop {
+            let addrs = self.external_addrs.recv_timeout(wait).unwrap();
+            if !addrs.is_empty() || Instant::now() >= deadline {
+                return addrs;
+            }
+        }
+    }
+}
+
+fn create(reuse_port: bool) -> (Service<(), LengthDelimitedCodec>, Node) {
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    let (addrs_sender, external_addrs) = crossbeam_channel::bounded(1);
+    let service = ServiceBuilder::default()
+        .insert_protocol(IdentifyProtocol::new(
+            1,
+            "test/0.1.0".to_owned(),
+            event_sender,
+        ))
+        .insert_protocol(Protocol {
+            id: 2,
+            sender: addrs_sender,
+        })
+        .tcp_config(TcpConfig {
+            reuse_port,
+            ..Default::default()
+        })
+        .observed_addr_threshold(1)
+        .forever(true)
+        .build(());
+    (
+        service,
+        Node {
+            events,
+            external_addrs,
+        },
+    )
+}
+
+/// Client dials server, return the listen addresses and the nodes of client and server
+fn start(reuse_port: bool) -> (Multiaddr, Multiaddr, Node, Node) {
+    let (mut server_service, server) = create(false);
+    let server_addr = server_service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    let (mut client_service, client) = create(reuse_port);
+    let client_addr = client_service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    client_service.dial(server_addr.clone()).unwrap();
+
+    thread::spawn(|| tokio::run(server_service.for_each(|_| Ok(()))));
+    thread::spawn(|| tokio::run(client_service.for_each(|_| Ok(()))));
+    (client_addr, server_addr, client, server)
+}
+
+#[test]
+fn test_identify_with_reuse_port() {
+    let (client_addr, server_addr, client, server) = start(true);
+
+    let info = server.identified();
+    assert_eq!(info.listen_addrs, vec![client_addr.clone()]);
+    assert_eq!(info.observed_addr, server_addr);
+    assert_eq!(info.agent_version, "test/0.1.0");
+    assert_eq!(info.protocols.len(), 2);
+
+    let info = client.identified();
+    assert_eq!(info.listen_addrs, vec![server_addr.clone()]);
+    // Outbound connection is bound to the listen port
+    assert_eq!(info.observed_addr, client_addr);
+
+    let wait = Duration::from_secs(5);
+    assert_eq!(server.external_addrs(wait), vec![server_addr]);
+    assert_eq!(client.external_addrs(wait), vec![client_addr]);
+}
+
+#[test]
+fn test_identify_without_reuse_port() {
+    let (client_addr, server_addr, client, server) = start(false);
+
+    server.identified();
+    let info = client.identified();
+    // The random port of outbound connection
+    assert_ne!(info.observed_addr, client_addr);
+
+    let wait = Duration::from_secs(2);
+    assert_eq!(server.external_addrs(wait), vec![server_addr]);
+    assert!(client.external_addrs(wait).is_empty());
+}
@@ -0,0 +1,120 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    error::Error,
+    eviction::DefaultEvictionScorer,
+    service::{CloseReason, Service, ServiceError, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .key_pair(SecioKeyPair::secp256k1_generated())
+        .max_inbound(Some(1))
+        .eviction_scorer(DefaultEvictionScorer::new(0, 0, 0))
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Notify {
+    Open,
+    Close(CloseReason),
+    Full,
+}
+
+struct SHandle {
+    protect: bool,
+    sender: crossbeam_channel::Sender<Notify>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_error(&mut self, _env: &mut ServiceContext, error: ServiceError) {
+        if let ServiceError::ListenError {
+            error: Error::InboundFull,
+            ..
+        } = error
+        {
+            let _ = self.sender.try_send(Notify::Full);
+        }
+    }
+
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        match event {
+            ServiceEvent::SessionOpen { public_key, .. } => {
+                if self.protect {
+                    let _ = env.protect_peer(public_key.unwrap().peer_id());
+                }
+                let _ = self.sender.try_send(Notify::Open);
+            }
+            ServiceEvent::SessionClose { reason, .. } => {
+                let _ = self.sender.try_send(Notify::Close(reason));
+            }
+            _ => (),
+        }
+    }
+}
+
+fn test_inbound_eviction(protect: bool) {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(Protocol { id: 1 }, SHandle { protect, sender });
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let dial = || {
+        let mut service = ServiceBuilder::default()
+            .insert_protocol(Protocol { id: 1 })
+            .key_pair(SecioKeyPair::secp256k1_generated())
+            .forever(true)
+            .build(());
+        service.dial(listen_addr.clone()).unwrap();
+        thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+    };
+
+    dial();
+    assert_eq!(receiver.recv(), Ok(Notify::Open));
+
+    // Inbound slots are full
+    dial();
+    if protect {
+        assert_eq!(receiver.recv(), Ok(Notify::Full));
+    } else {
+        assert_eq!(receiver.recv(), Ok(Notify::Open));
+        assert_eq!(receiver.recv(), Ok(Notify::Close(CloseReason::Evicted)));
+    }
+}
+
+#[test]
+fn test_evict_inbound() {
+    test_inbound_eviction(false)
+}
+
+#[test]
+fn test_protected_peer_not_evicted() {
+    test_inbound_eviction(true)
+}
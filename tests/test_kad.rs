@@ -0,0 +1,100 @@
+use futures::{prelude::Stream, Future};
+use kad::{KadConfig, KadError, KadHandle, KadProtocol};
+use p2p::{builder::ServiceBuilder, multiaddr::Multiaddr, service::Service, PeerId, SecioKeyPair};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+struct Node {
+    peer_id: PeerId,
+    address: Multiaddr,
+    service: Service<(), LengthDelimitedCodec>,
+    handle: KadHandle,
+}
+
+fn create() -> Node {
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let protocol = KadProtocol::new(1, peer_id.clone(), KadConfig::default());
+    let handle = protocol.handle();
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(());
+    let address = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    Node {
+        peer_id,
+        address,
+        service,
+        handle,
+    }
+}
+
+fn run(node: Node) -> KadHandle {
+    let service = node.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+    node.handle
+}
+
+fn wait<F, T>(query: F) -> T
+where
+    F: Future<Item = T, Error = KadError> + Send + 'static,
+    T: Send + 'static,
+{
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    thread::spawn(move || {
+        let _ = sender.send(query.wait());
+    });
+    receiver
+        .recv_timeout(Duration::from_secs(30))
+        .expect("query timeout")
+        .expect("query error")
+}
+
+#[test]
+fn test_kad_queries() {
+    let mut a = create();
+    let mut b = create();
+    let mut c = create();
+
+    // b knows everyone, as if identify told it, a and c only know b
+    b.handle
+        .add_address(a.peer_id.clone(), a.address.clone())
+        .unwrap();
+    b.handle
+        .add_address(c.peer_id.clone(), c.address.clone())
+        .unwrap();
+    a.handle
+        .add_address(b.peer_id.clone(), b.address.clone())
+        .unwrap();
+    c.handle
+        .add_address(b.peer_id.clone(), b.address.clone())
+        .unwrap();
+
+    let (a_id, b_id, c_id) = (a.peer_id.clone(), b.peer_id.clone(), c.peer_id.clone());
+    let c_address = c.address.clone();
+    let mut a = run(a);
+    let _b = run(b);
+    let mut c = run(c);
+
+    // a learns c from b
+    let peers = wait(a.bootstrap())
+        .into_iter()
+        .map(|peer| peer.peer_id)
+        .collect::<Vec<_>>();
+    assert!(peers.contains(&b_id));
+    assert!(peers.contains(&c_id));
+
+    let peer = wait(a.find_peer(c_id.clone())).expect("peer not found");
+    assert!(peer.addrs.contains(&c_address));
+
+    let count = wait(a.put_value(b"hello".to_vec(), b"kad".to_vec()));
+    assert_eq!(count, 2);
+
+    // c doesn't know a, the record is found on a through b if it isn't stored to c yet
+    let record = wait(c.get_value(b"hello".to_vec())).expect("record not found");
+    assert_eq!(record.value, b"kad".to_vec());
+    assert_eq!(record.publisher, Some(a_id));
+}
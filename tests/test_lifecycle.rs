@@ -0,0 +1,94 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    service::{Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(secio: bool, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    let builder = ServiceBuilder::default()
+        .insert_protocol(meta)
+        .forever(true);
+
+    if secio {
+        builder
+            .key_pair(SecioKeyPair::secp256k1_generated())
+            .build(shandle)
+    } else {
+        builder.build(shandle)
+    }
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<&'static str>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        let name = match event {
+            ServiceEvent::ListenStarted { .. } => "listen started",
+            ServiceEvent::DialStarted { .. } => "dial started",
+            ServiceEvent::SessionOpen { .. } => "session open",
+            ServiceEvent::ProtocolOpened { proto_id, .. } => {
+                assert_eq!(proto_id, 1);
+                "protocol opened"
+            }
+            _ => return,
+        };
+        let _ = self.sender.try_send(name);
+    }
+}
+
+fn test_lifecycle(secio: bool) {
+    let (sender, listen_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(secio, Protocol { id: 1 }, SHandle { sender });
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, dial_receiver) = crossbeam_channel::unbounded();
+    let mut service = create(secio, Protocol { id: 1 }, SHandle { sender });
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    assert_eq!(listen_receiver.recv(), Ok("listen started"));
+    assert_eq!(listen_receiver.recv(), Ok("session open"));
+    assert_eq!(listen_receiver.recv(), Ok("protocol opened"));
+
+    assert_eq!(dial_receiver.recv(), Ok("dial started"));
+    assert_eq!(dial_receiver.recv(), Ok("session open"));
+    assert_eq!(dial_receiver.recv(), Ok("protocol opened"));
+}
+
+#[test]
+fn test_lifecycle_with_secio() {
+    test_lifecycle(true)
+}
+
+#[test]
+fn test_lifecycle_with_no_secio() {
+    test_lifecycle(false)
+}
@@ -0,0 +1,76 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::{Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    utils::multiaddr_to_socketaddr,
+    ProtocolId,
+};
+use std::thread;
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<(Vec<Multiaddr>, Vec<Multiaddr>)>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionOpen { .. } = event {
+            let _ = self
+                .sender
+                .try_send((env.listens().clone(), env.bind_addrs().clone()));
+        }
+    }
+}
+
+#[test]
+fn test_expand_unspecified_listen() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(Protocol { id: 1 }, SHandle { sender });
+    let bind_addr = service
+        .listen("/ip4/0.0.0.0/tcp/0".parse().unwrap())
+        .unwrap();
+    let port = multiaddr_to_socketaddr(&bind_addr).unwrap().port();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut service = create(Protocol { id: 1 }, ());
+    service
+        .dial(format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (listens, bind_addrs) = receiver.recv().unwrap();
+    assert_eq!(bind_addrs, vec![bind_addr]);
+    assert!(listens.iter().all(|listen| {
+        let listen = multiaddr_to_socketaddr(listen).unwrap();
+        !listen.ip().is_unspecified() && listen.port() == port
+    }));
+    assert!(listens.contains(&format!("/ip4/127.0.0.1/tcp/{}", port).parse().unwrap()));
+}
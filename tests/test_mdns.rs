@@ -0,0 +1,138 @@
+use discovery::{AddressManager, MdnsConfig, MdnsEvent, MdnsProtocol};
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::{Service, ServiceEvent},
+    traits::ServiceHandle,
+    utils::extract_peer_id,
+    PeerId, SecioKeyPair,
+};
+use std::{
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    thread,
+    time::Duration,
+};
+use tokio::codec::LengthDelimitedCodec;
+
+#[derive(Clone)]
+struct AddrManager {
+    sender: crossbeam_channel::Sender<Multiaddr>,
+}
+
+impl AddressManager for AddrManager {
+    fn add_new(&mut self, addr: Multiaddr) {
+        let _ = self.sender.send(addr);
+    }
+
+    fn misbehave(&mut self, _addr: Multiaddr, _ty: u64) -> i32 {
+        0
+    }
+
+    fn get_random(&mut self, _n: usize) -> Vec<Multiaddr> {
+        Vec::new()
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<PeerId>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionOpen {
+            public_key: Some(key),
+            ..
+        } = event
+        {
+            let _ = self.sender.send(key.peer_id());
+        }
+    }
+}
+
+struct Node {
+    service: Service<SHandle, LengthDelimitedCodec>,
+    peer_id: PeerId,
+    events: crossbeam_channel::Receiver<MdnsEvent>,
+    addrs: crossbeam_channel::Receiver<Multiaddr>,
+    sessions: crossbeam_channel::Receiver<PeerId>,
+}
+
+fn create(port: u16, auto_dial: bool) -> Node {
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    let (addr_sender, addrs) = crossbeam_channel::unbounded();
+    let (session_sender, sessions) = crossbeam_channel::unbounded();
+    let config = MdnsConfig {
+        multicast_addr: SocketAddrV4::new(Ipv4Addr::new(224, 0, 0, 251), port),
+        interface: Ipv4Addr::LOCALHOST,
+        query_interval: Duration::from_millis(500),
+        auto_dial,
+        ..Default::default()
+    };
+    let protocol = MdnsProtocol::new(
+        1,
+        peer_id.clone(),
+        config,
+        AddrManager {
+            sender: addr_sender,
+        },
+        event_sender,
+    );
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(SHandle {
+            sender: session_sender,
+        });
+    service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    Node {
+        service,
+        peer_id,
+        events,
+        addrs,
+        sessions,
+    }
+}
+
+#[test]
+fn test_mdns_discovery() {
+    let timeout = Duration::from_secs(10);
+    // Not the mDNS port, so the responders on host are not disturbed
+    let port = UdpSocket::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+        .port();
+
+    let a = create(port, true);
+    let b = create(port, false);
+    let (a_id, b_id) = (a.peer_id, b.peer_id);
+    let service = a.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+    let service = b.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    match a.events.recv_timeout(timeout) {
+        Ok(MdnsEvent::Discovered { peer_id, addresses }) => {
+            assert_eq!(peer_id, b_id);
+            assert!(!addresses.is_empty());
+        }
+        event => panic!("not discovered: {:?}", event),
+    }
+    let address = a.addrs.recv_timeout(timeout).unwrap();
+    assert_eq!(extract_peer_id(&address), Some(b_id.clone()));
+    match b.events.recv_timeout(timeout) {
+        Ok(MdnsEvent::Discovered { peer_id, .. }) => assert_eq!(peer_id, a_id),
+        event => panic!("not discovered: {:?}", event),
+    }
+
+    // Only a dials
+    assert_eq!(b.sessions.recv_timeout(timeout), Ok(a_id));
+    assert_eq!(a.sessions.recv_timeout(timeout), Ok(b_id));
+}
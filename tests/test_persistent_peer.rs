@@ -0,0 +1,112 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    config::RetryPolicy,
+    context::ServiceContext,
+    multiaddr::{multihash::Multihash, Protocol as MultiProtocol},
+    service::{PersistentPeerState, Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    ProtocolId, SecioKeyPair,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(key_pair: SecioKeyPair, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .key_pair(key_pair)
+        .persistent_peer_policy(RetryPolicy {
+            base_delay: Duration::from_millis(100),
+            ..RetryPolicy::persistent()
+        })
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+/// Disconnect the first session
+struct ListenHandle {
+    disconnected: bool,
+}
+
+impl ServiceHandle for ListenHandle {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::ProtocolOpened { id, .. } = event {
+            if !self.disconnected {
+                self.disconnected = true;
+                let _ = env.disconnect(id);
+            }
+        }
+    }
+}
+
+struct DialHandle {
+    sender: crossbeam_channel::Sender<PersistentPeerState>,
+}
+
+impl ServiceHandle for DialHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::PersistentPeerUpdate { state, .. } = event {
+            let _ = self.sender.try_send(state);
+        }
+    }
+}
+
+#[test]
+fn test_persistent_peer_reconnect() {
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let mut service = create(
+        key_pair,
+        Protocol { id: 1 },
+        ListenHandle {
+            disconnected: false,
+        },
+    );
+    let mut listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    listen_addr.append(MultiProtocol::P2p(
+        Multihash::from_bytes(peer_id.as_bytes().to_vec()).expect("Invalid peer id"),
+    ));
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(
+        SecioKeyPair::secp256k1_generated(),
+        Protocol { id: 1 },
+        DialHandle { sender },
+    );
+    service.control().add_persistent_peer(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    match receiver.recv().unwrap() {
+        PersistentPeerState::Connected(_) => (),
+        state => panic!("unexpected state: {:?}", state),
+    }
+    match receiver.recv().unwrap() {
+        PersistentPeerState::BackingOff { attempts: 0, .. } => (),
+        state => panic!("unexpected state: {:?}", state),
+    }
+    match receiver.recv().unwrap() {
+        PersistentPeerState::Connected(_) => (),
+        state => panic!("unexpected state: {:?}", state),
+    }
+}
@@ -0,0 +1,201 @@
+use futures::prelude::Stream;
+use p2p::{builder::ServiceBuilder, multiaddr::Multiaddr};
+use portmap::{Event, PortMapConfig, PortMapping};
+use std::{
+    io::{Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream, UdpSocket},
+    thread,
+    time::Duration,
+};
+
+/// Map the listen ports with the config, return the mapped address and the removed address
+/// after shutdown
+fn map_and_shutdown(config: PortMapConfig) -> (Multiaddr, Multiaddr) {
+    let timeout = Duration::from_secs(10);
+    let mut service = ServiceBuilder::default().forever(true).build(());
+    let control = service.control().clone();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let listens: Vec<Multiaddr> = vec![
+        "/ip4/0.0.0.0/tcp/1337".parse().unwrap(),
+        // Not mapped
+        "/ip4/127.0.0.1/tcp/1338".parse().unwrap(),
+    ];
+    let (mapping, handle) = PortMapping::new(config, control, &listens, sender);
+    thread::spawn(|| tokio::run(mapping));
+
+    let mapped = match receiver.recv_timeout(timeout) {
+        Ok(Event::Mapped {
+            port: 1337,
+            address,
+        }) => address,
+        event => panic!("not mapped: {:?}", event),
+    };
+    handle.shutdown();
+    let unmapped = match receiver.recv_timeout(timeout) {
+        Ok(Event::Unmapped {
+            port: 1337,
+            address,
+        }) => address,
+        event => panic!("not unmapped: {:?}", event),
+    };
+    (mapped, unmapped)
+}
+
+/// Answer the external address and map requests, send the map requests received
+fn natpmp_responder(requests: crossbeam_channel::Sender<(u16, u16, u32)>) -> SocketAddr {
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 16];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            let response = match &buf[..len] {
+                [0, 0] => vec![0, 128, 0, 0, 0, 0, 0, 1, 1, 2, 3, 4],
+                [0, 2, 0, 0, a, b, c, d, e, f, g, h] => {
+                    let internal_port = u16::from_be_bytes([*a, *b]);
+                    let external_port = u16::from_be_bytes([*c, *d]);
+                    let lifetime = u32::from_be_bytes([*e, *f, *g, *h]);
+                    let _ = requests.send((internal_port, external_port, lifetime));
+                    // Another external port given
+                    let mapped_port = if lifetime == 0 { 0 } else { internal_port + 1 };
+                    let mut response = vec![0, 130, 0, 0, 0, 0, 0, 1, *a, *b];
+                    response.extend_from_slice(&mapped_port.to_be_bytes());
+                    response.extend_from_slice(&[*e, *f, *g, *h]);
+                    response
+                }
+                _ => continue,
+            };
+            socket.send_to(&response, from).unwrap();
+        }
+    });
+    addr
+}
+
+#[test]
+fn test_natpmp_mapping() {
+    let (sender, requests) = crossbeam_channel::unbounded();
+    let config = PortMapConfig {
+        natpmp_gateway: Some(natpmp_responder(sender)),
+        upnp: false,
+        ..Default::default()
+    };
+    let (mapped, unmapped) = map_and_shutdown(config);
+    let expected: Multiaddr = "/ip4/1.2.3.4/tcp/1338".parse().unwrap();
+    assert_eq!(mapped, expected);
+    assert_eq!(unmapped, expected);
+
+    assert_eq!(requests.recv(), Ok((1337, 1337, 3600)));
+    // Removed on shutdown
+    assert_eq!(requests.recv(), Ok((1337, 0, 0)));
+}
+
+/// Read a HTTP request, return the head and body
+fn read_request(stream: &mut TcpStream) -> (String, String) {
+    let mut data = Vec::new();
+    let mut buf = [0; 1024];
+    loop {
+        let len = stream.read(&mut buf).unwrap();
+        data.extend_from_slice(&buf[..len]);
+        let request = String::from_utf8_lossy(&data).to_string();
+        if let Some(index) = request.find("\r\n\r\n") {
+            let (head, body) = (&request[..index], &request[index + 4..]);
+            let length = head
+                .lines()
+                .filter_map(|line| {
+                    let mut parts = line.splitn(2, ':');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(value))
+                            if name.eq_ignore_ascii_case("content-length") =>
+                        {
+                            value.trim().parse::<usize>().ok()
+                        }
+                        _ => None,
+                    }
+                })
+                .next()
+                .unwrap_or(0);
+            if body.len() >= length || len == 0 {
+                return (head.to_owned(), body.to_owned());
+            }
+        } else if len == 0 {
+            panic!("incomplete request");
+        }
+    }
+}
+
+/// Answer SSDP search and the actions of WAN connection service, send the actions received
+fn upnp_responder(actions: crossbeam_channel::Sender<String>) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let http_addr = listener.local_addr().unwrap();
+    thread::spawn(move || {
+        for mut stream in listener.incoming().filter_map(Result::ok) {
+            let (head, body) = read_request(&mut stream);
+            let response = if head.starts_with("GET /rootDesc.xml") {
+                "<root><device><serviceList><service>\
+                 <serviceType>urn:schemas-upnp-org:service:WANIPConnection:1</serviceType>\
+                 <controlURL>/ctl/IPConn</controlURL>\
+                 </service></serviceList></device></root>"
+                    .to_owned()
+            } else if head.contains("#GetExternalIPAddress") {
+                "<s:Envelope><s:Body><u:GetExternalIPAddressResponse>\
+                 <NewExternalIPAddress>1.2.3.4</NewExternalIPAddress>\
+                 </u:GetExternalIPAddressResponse></s:Body></s:Envelope>"
+                    .to_owned()
+            } else if head.contains("#AddPortMapping") {
+                assert!(body.contains("<NewInternalClient>127.0.0.1</NewInternalClient>"));
+                assert!(body.contains("<NewInternalPort>1337</NewInternalPort>"));
+                let _ = actions.send("AddPortMapping".to_owned());
+                String::new()
+            } else if head.contains("#DeletePortMapping") {
+                assert!(body.contains("<NewExternalPort>1337</NewExternalPort>"));
+                let _ = actions.send("DeletePortMapping".to_owned());
+                String::new()
+            } else {
+                panic!("unexpected request: {}", head);
+            };
+            let _ = write!(
+                stream,
+                "HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n{}",
+                response.len(),
+                response
+            );
+        }
+    });
+
+    let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+    let ssdp_addr = socket.local_addr().unwrap();
+    thread::spawn(move || {
+        let mut buf = [0; 1024];
+        while let Ok((len, from)) = socket.recv_from(&mut buf) {
+            if !String::from_utf8_lossy(&buf[..len]).starts_with("M-SEARCH") {
+                continue;
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nST: urn:schemas-upnp-org:device:InternetGatewayDevice:1\r\n\
+                 LOCATION: http://{}/rootDesc.xml\r\n\r\n",
+                http_addr
+            );
+            socket.send_to(response.as_bytes(), from).unwrap();
+        }
+    });
+    ssdp_addr
+}
+
+#[test]
+fn test_upnp_mapping() {
+    let (sender, actions) = crossbeam_channel::unbounded();
+    let config = PortMapConfig {
+        natpmp: false,
+        ssdp_addr: upnp_responder(sender),
+        ..Default::default()
+    };
+    let (mapped, unmapped) = map_and_shutdown(config);
+    let expected: Multiaddr = "/ip4/1.2.3.4/tcp/1337".parse().unwrap();
+    assert_eq!(mapped, expected);
+    assert_eq!(unmapped, expected);
+
+    assert_eq!(actions.recv(), Ok("AddPortMapping".to_owned()));
+    // Removed on shutdown
+    assert_eq!(actions.recv(), Ok("DeletePortMapping".to_owned()));
+}
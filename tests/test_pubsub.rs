@@ -0,0 +1,158 @@
+use futures::prelude::Stream;
+use p2p::{builder::ServiceBuilder, multiaddr::Multiaddr, service::Service, PeerId, SecioKeyPair};
+use pubsub::{Event, Message, PubsubConfig, PubsubHandle, PubsubProtocol};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+const TOPIC: &str = "test";
+
+struct Node {
+    service: Service<(), LengthDelimitedCodec>,
+    peer: Peer,
+}
+
+/// A started node
+struct Peer {
+    handle: PubsubHandle,
+    events: crossbeam_channel::Receiver<Event>,
+    peer_id: PeerId,
+}
+
+impl Peer {
+    /// Wait until the number of peers subscribed the topic
+    fn wait_subscribed(&self, count: usize) {
+        let mut subscribed = 0;
+        while subscribed < count {
+            match self.events.recv_timeout(Duration::from_secs(10)) {
+                Ok(Event::Subscribed(_, topic)) => {
+                    assert_eq!(topic, TOPIC);
+                    subscribed += 1;
+                }
+                Ok(event) => panic!("unexpected event: {:?}", event),
+                Err(_) => panic!("peers not subscribed"),
+            }
+        }
+    }
+
+    fn recv_message(&self, timeout: Duration) -> Option<Message> {
+        match self.events.recv_timeout(timeout) {
+            Ok(Event::Message(message)) => Some(message),
+            Ok(event) => panic!("unexpected event: {:?}", event),
+            Err(_) => None,
+        }
+    }
+}
+
+fn create(config: PubsubConfig) -> Node {
+    let key_pair = SecioKeyPair::secp256k1_generated();
+    let peer_id = key_pair.to_peer_id();
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    let protocol = PubsubProtocol::new(1, key_pair.clone(), config, event_sender);
+    let mut handle = protocol.handle();
+    // Handled when the service starts
+    handle.subscribe(TOPIC.to_owned()).unwrap();
+    let service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(());
+    Node {
+        service,
+        peer: Peer {
+            handle,
+            events,
+            peer_id,
+        },
+    }
+}
+
+fn listen(node: &mut Node) -> Multiaddr {
+    node.service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap()
+}
+
+fn start(node: Node) -> Peer {
+    let service = node.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+    node.peer
+}
+
+/// Three fully connected nodes, messages only go through the mesh
+#[test]
+fn test_mesh_propagation_and_dedup() {
+    let config = PubsubConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        // No IHAVE, a message received must be forwarded by the mesh
+        gossip_lazy: 0,
+        ..Default::default()
+    };
+
+    let mut a = create(config.clone());
+    let a_addr = listen(&mut a);
+    let mut b = create(config.clone());
+    let b_addr = listen(&mut b);
+    b.service.dial(a_addr.clone()).unwrap();
+    let mut c = create(config);
+    c.service.dial(a_addr).unwrap();
+    c.service.dial(b_addr).unwrap();
+
+    let peers = vec![start(a), start(b), start(c)];
+    for peer in &peers {
+        peer.wait_subscribed(2);
+    }
+    // Grafted on heartbeat
+    thread::sleep(Duration::from_secs(1));
+
+    let mut publisher = peers[0].handle.clone();
+    publisher
+        .publish(TOPIC.to_owned(), b"hello".to_vec())
+        .unwrap();
+
+    // Every node relays the message to the others, each delivers it once
+    for peer in &peers[1..] {
+        let message = peer
+            .recv_message(Duration::from_secs(10))
+            .expect("message not received");
+        assert_eq!(message.source, peers[0].peer_id);
+        assert_eq!(message.topic, TOPIC);
+        assert_eq!(message.data, b"hello".to_vec());
+    }
+    for peer in &peers {
+        assert_eq!(peer.recv_message(Duration::from_secs(1)), None);
+    }
+}
+
+/// Two nodes never mesh, the message is announced by IHAVE and pulled by IWANT
+#[test]
+fn test_iwant_recovery() {
+    let config = PubsubConfig {
+        heartbeat_interval: Duration::from_millis(100),
+        mesh_n: 0,
+        mesh_n_low: 0,
+        mesh_n_high: 0,
+        ..Default::default()
+    };
+
+    let mut a = create(config.clone());
+    let a_addr = listen(&mut a);
+    let mut b = create(config);
+    b.service.dial(a_addr).unwrap();
+
+    let mut a = start(a);
+    let b = start(b);
+    a.wait_subscribed(1);
+    b.wait_subscribed(1);
+
+    a.handle
+        .publish(TOPIC.to_owned(), b"hello".to_vec())
+        .unwrap();
+
+    let message = b
+        .recv_message(Duration::from_secs(10))
+        .expect("message not recovered");
+    assert_eq!(message.source, a.peer_id);
+    assert_eq!(message.data, b"hello".to_vec());
+    // Later IHAVEs of the same message are ignored
+    assert_eq!(b.recv_message(Duration::from_secs(1)), None);
+}
@@ -0,0 +1,106 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::{multihash::Multihash, Multiaddr, Protocol},
+    service::{Service, ServiceEvent},
+    traits::ServiceHandle,
+    utils::is_relayed,
+    SecioKeyPair,
+};
+use relay::{Event, RelayConfig, RelayHandle, RelayProtocol};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<Multiaddr>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionOpen { address, .. } = event {
+            if is_relayed(&address) {
+                let _ = self.sender.try_send(address);
+            }
+        }
+    }
+}
+
+struct Node {
+    service: Service<SHandle, LengthDelimitedCodec>,
+    handle: RelayHandle,
+    events: crossbeam_channel::Receiver<Event>,
+    sessions: crossbeam_channel::Receiver<Multiaddr>,
+}
+
+fn create(key_pair: SecioKeyPair, hop: bool) -> Node {
+    let (event_sender, events) = crossbeam_channel::unbounded();
+    let (session_sender, sessions) = crossbeam_channel::unbounded();
+    let config = RelayConfig {
+        hop,
+        ..Default::default()
+    };
+    let protocol = RelayProtocol::new(1, key_pair.to_peer_id(), config, event_sender);
+    let handle = protocol.handle();
+    let service = ServiceBuilder::default()
+        .insert_protocol(protocol)
+        .key_pair(key_pair)
+        .forever(true)
+        .build(SHandle {
+            sender: session_sender,
+        });
+    Node {
+        service,
+        handle,
+        events,
+        sessions,
+    }
+}
+
+#[test]
+fn test_relayed_session() {
+    let timeout = Duration::from_secs(10);
+    let relay_key = SecioKeyPair::secp256k1_generated();
+    let relay_id = relay_key.to_peer_id();
+    let mut relay = create(relay_key, true);
+    let mut relay_addr = relay
+        .service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    relay_addr.append(Protocol::P2p(
+        Multihash::from_bytes(relay_id.as_bytes().to_vec()).unwrap(),
+    ));
+    let service = relay.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut target = create(SecioKeyPair::secp256k1_generated(), false);
+    target.handle.reserve(relay_addr).unwrap();
+    let service = target.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+    let relayed_addr = match target.events.recv_timeout(timeout) {
+        Ok(Event::Reserved { address, .. }) => address,
+        event => panic!("reserve failed: {:?}", event),
+    };
+
+    let source_key = SecioKeyPair::secp256k1_generated();
+    let source_id = source_key.to_peer_id();
+    let mut source = create(source_key, false);
+    source.handle.dial(relayed_addr.clone()).unwrap();
+    let service = source.service;
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    // Both sides open the session through secio and yamux over the circuit
+    assert_eq!(source.sessions.recv_timeout(timeout), Ok(relayed_addr));
+    let expected: Multiaddr = format!(
+        "/p2p/{}/p2p-circuit/p2p/{}",
+        relay_id.to_base58(),
+        source_id.to_base58()
+    )
+    .parse()
+    .unwrap();
+    assert_eq!(target.sessions.recv_timeout(timeout), Ok(expected));
+    match relay.events.recv_timeout(timeout) {
+        Ok(Event::CircuitOpened { source, .. }) => assert_eq!(source, source_id),
+        event => panic!("circuit not opened: {:?}", event),
+    }
+}
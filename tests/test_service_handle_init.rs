@@ -0,0 +1,77 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    traits::{ProtocolMeta, ServiceProtocol},
+    ProtocolId,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+#[derive(Clone)]
+struct Protocol {
+    id: ProtocolId,
+    sender: crossbeam_channel::Sender<&'static str>,
+    listens: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        Some(Box::new(PHandle {
+            id: self.id,
+            sender: self.sender.clone(),
+            listens: self.listens.clone(),
+        }))
+    }
+}
+
+struct PHandle {
+    id: ProtocolId,
+    sender: crossbeam_channel::Sender<&'static str>,
+    listens: crossbeam_channel::Sender<Vec<Multiaddr>>,
+}
+
+impl ServiceProtocol for PHandle {
+    fn init(&mut self, env: &mut ServiceContext) {
+        let _ = self.listens.send(env.listens().clone());
+        let _ = self.sender.send("init");
+        env.set_service_notify(self.id, Duration::from_millis(100), 1);
+    }
+
+    fn notify(&mut self, _env: &mut ServiceContext, _token: u64) {
+        let _ = self.sender.try_send("notify");
+    }
+}
+
+#[test]
+fn test_service_handle_init_without_session() {
+    let (sender, receiver) = crossbeam_channel::bounded(2);
+    let (listens_sender, listens_receiver) = crossbeam_channel::unbounded();
+    let mut service = ServiceBuilder::default()
+        .insert_protocol(Protocol {
+            id: 1,
+            sender,
+            listens: listens_sender,
+        })
+        .forever(true)
+        .build(());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    // No session is ever opened, the handle is started with the service
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(3)), Ok("init"));
+    assert_eq!(
+        listens_receiver.recv_timeout(Duration::from_secs(3)),
+        Ok(vec![listen_addr])
+    );
+    assert_eq!(receiver.recv_timeout(Duration::from_secs(3)), Ok("notify"));
+}
@@ -0,0 +1,106 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::{ServiceContext, SessionContext},
+    service::{HandleExecutor, Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle, ServiceProtocol},
+    ProtocolId,
+};
+use std::{thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .slow_handler_threshold(Some(Duration::from_millis(50)))
+        .forever(true)
+        .build(shandle)
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<(Option<ProtocolId>, &'static str)>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SlowHandler {
+            proto_id, callback, ..
+        } = event
+        {
+            let _ = self.sender.try_send((proto_id, callback));
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+    executor: HandleExecutor,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+
+    fn service_handle(&self) -> Option<Box<dyn ServiceProtocol + Send + 'static>> {
+        Some(Box::new(PHandle))
+    }
+
+    fn handle_executor(&self) -> HandleExecutor {
+        self.executor
+    }
+}
+
+struct PHandle;
+
+impl ServiceProtocol for PHandle {
+    fn init(&mut self, _control: &mut ServiceContext) {}
+
+    fn connected(
+        &mut self,
+        _control: &mut ServiceContext,
+        _session: &SessionContext,
+        _version: &str,
+    ) {
+        thread::sleep(Duration::from_millis(200));
+    }
+}
+
+fn test_slow_handler(executor: HandleExecutor) {
+    let meta = Protocol { id: 1, executor };
+    let mut service = create(meta.clone(), ());
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let (sender, receiver) = crossbeam_channel::bounded(1);
+    let mut service = create(meta, SHandle { sender });
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    assert_eq!(receiver.recv(), Ok((Some(1), "connected")));
+}
+
+#[test]
+fn test_slow_handler_on_runtime() {
+    test_slow_handler(HandleExecutor::Runtime)
+}
+
+#[test]
+fn test_slow_handler_on_blocking_pool() {
+    test_slow_handler(HandleExecutor::Blocking)
+}
+
+#[test]
+fn test_slow_handler_on_thread() {
+    test_slow_handler(HandleExecutor::Thread)
+}
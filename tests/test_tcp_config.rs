@@ -0,0 +1,101 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    config::TcpConfig,
+    context::ServiceContext,
+    service::{Service, ServiceEvent},
+    traits::{ProtocolMeta, ServiceHandle},
+    utils::multiaddr_to_socketaddr,
+    ProtocolId, SessionType,
+};
+use std::{net::SocketAddr, thread, time::Duration};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<T, F>(config: TcpConfig, meta: T, shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    T: ProtocolMeta<LengthDelimitedCodec> + Send + Sync + 'static,
+    F: ServiceHandle,
+{
+    ServiceBuilder::default()
+        .insert_protocol(meta)
+        .tcp_config(config)
+        .forever(true)
+        .build(shandle)
+}
+
+#[derive(Clone)]
+pub struct Protocol {
+    id: ProtocolId,
+}
+
+impl ProtocolMeta<LengthDelimitedCodec> for Protocol {
+    fn id(&self) -> ProtocolId {
+        self.id
+    }
+    fn codec(&self) -> LengthDelimitedCodec {
+        LengthDelimitedCodec::new()
+    }
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<SocketAddr>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, _env: &mut ServiceContext, event: ServiceEvent) {
+        if let ServiceEvent::SessionOpen {
+            address,
+            ty: SessionType::Server,
+            ..
+        } = event
+        {
+            let _ = self
+                .sender
+                .try_send(multiaddr_to_socketaddr(&address).unwrap());
+        }
+    }
+}
+
+fn test_tcp_config(reuse_port: bool) {
+    let config = TcpConfig {
+        nodelay: true,
+        keepalive: Some(Duration::from_secs(30)),
+        send_buffer_size: Some(64 * 1024),
+        recv_buffer_size: Some(64 * 1024),
+        bind_ip: Some("127.0.0.1".parse().unwrap()),
+        reuse_port,
+    };
+
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(config.clone(), Protocol { id: 1 }, SHandle { sender });
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let mut service = create(config, Protocol { id: 1 }, ());
+    let client_listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    service.dial(listen_addr).unwrap();
+    thread::spawn(|| tokio::run(service.for_each(|_| Ok(()))));
+
+    let remote_addr = receiver.recv().unwrap();
+    let client_listen_addr = multiaddr_to_socketaddr(&client_listen_addr).unwrap();
+    assert_eq!(remote_addr.ip(), client_listen_addr.ip());
+    if reuse_port {
+        // Dial from the listen port
+        assert_eq!(remote_addr.port(), client_listen_addr.port());
+    }
+}
+
+#[test]
+fn test_tcp_options() {
+    test_tcp_config(false)
+}
+
+#[cfg(unix)]
+#[test]
+fn test_dial_from_listen_port() {
+    test_tcp_config(true)
+}
@@ -0,0 +1,126 @@
+use futures::prelude::Stream;
+use p2p::{
+    builder::ServiceBuilder,
+    context::ServiceContext,
+    multiaddr::Multiaddr,
+    service::{Service, ServiceEvent},
+    traits::ServiceHandle,
+};
+use tokio::codec::LengthDelimitedCodec;
+
+pub fn create<F>(shandle: F) -> Service<F, LengthDelimitedCodec>
+where
+    F: ServiceHandle,
+{
+    ServiceBuilder::default().build(shandle)
+}
+
+struct SHandle {
+    sender: crossbeam_channel::Sender<ServiceEvent>,
+    // Unlisten it when the listen started
+    unlisten: Option<Multiaddr>,
+}
+
+impl ServiceHandle for SHandle {
+    fn handle_event(&mut self, env: &mut ServiceContext, event: ServiceEvent) {
+        match event {
+            ServiceEvent::ListenStarted { ref address } => {
+                assert!(env.listens().contains(address));
+                if let Some(address) = self.unlisten.take() {
+                    env.unlisten(address).unwrap();
+                }
+            }
+            ServiceEvent::ListenClosed { .. } => assert!(env.listens().is_empty()),
+            _ => (),
+        }
+        let _ = self.sender.try_send(event);
+    }
+}
+
+#[test]
+fn test_unlisten() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(SHandle {
+        sender,
+        unlisten: None,
+    });
+    let listen_addr = service
+        .listen("/ip4/127.0.0.1/tcp/0".parse().unwrap())
+        .unwrap();
+    service.control().unlisten(listen_addr.clone()).unwrap();
+
+    // Service is not forever, it ends after the listener removed
+    tokio::run(service.for_each(|_| Ok(())));
+
+    let closed = receiver
+        .try_iter()
+        .filter_map(|event| match event {
+            ServiceEvent::ListenClosed { address } => Some(address),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(closed, vec![listen_addr]);
+}
+
+#[test]
+fn test_unlisten_resolved_dns_address() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let address: Multiaddr = "/dns4/localhost/tcp/0".parse().unwrap();
+    let mut service = create(SHandle {
+        sender,
+        unlisten: Some(address.clone()),
+    });
+    assert_eq!(service.listen(address.clone()).unwrap(), address);
+
+    // Service is not forever, it ends after the resolved listener removed
+    tokio::run(service.for_each(|_| Ok(())));
+
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    let started = events
+        .iter()
+        .filter_map(|event| match event {
+            ServiceEvent::ListenStarted { address } => Some(address.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    let closed = events
+        .iter()
+        .filter_map(|event| match event {
+            ServiceEvent::ListenClosed { address } => Some(address.clone()),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(started.len(), 1);
+    assert_ne!(started[0], address);
+    assert_eq!(closed, started);
+}
+
+#[test]
+fn test_cancel_dial() {
+    let (sender, receiver) = crossbeam_channel::unbounded();
+    let mut service = create(SHandle {
+        sender,
+        unlisten: None,
+    });
+    let address: Multiaddr = "/dns4/localhost/tcp/1".parse().unwrap();
+    service.dial(address.clone()).unwrap();
+    service.control().cancel_dial(address.clone()).unwrap();
+
+    // Service is not forever, it ends after the dial canceled
+    tokio::run(service.for_each(|_| Ok(())));
+
+    let events = receiver.try_iter().collect::<Vec<_>>();
+    // Started before DNS resolving
+    assert!(events.iter().any(|event| match event {
+        ServiceEvent::DialStarted { address: started } => started == &address,
+        _ => false,
+    }));
+    let canceled = events
+        .into_iter()
+        .filter_map(|event| match event {
+            ServiceEvent::DialCanceled { address } => Some(address),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(canceled, vec![address]);
+}
@@ -13,6 +13,10 @@ pub const DEFAULT_MAX_STREAM_COUNT: usize = 65535;
 pub const DEFAULT_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(6);
 /// Default write timeout duration
 pub const DEFAULT_WRITE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Default capacity of the channel from streams to session
+pub const DEFAULT_SESSION_CHANNEL_SIZE: usize = 32;
+/// Default capacity of the channel from session to each stream
+pub const DEFAULT_STREAM_CHANNEL_SIZE: usize = 8;
 
 /// Configuration of session and stream
 #[derive(Clone, Copy)]
@@ -22,7 +26,9 @@ pub struct Config {
     pub accept_backlog: usize,
 
     /// EnableKeepalive is used to do a period keep alive
-    /// messages using a ping.
+    /// messages using a ping. The session is closed with
+    /// `CloseReason::KeepAliveTimeout` if a ping is not answered
+    /// within connection write timeout.
     pub enable_keepalive: bool,
 
     /// KeepAliveInterval is how often to perform the keep alive
@@ -32,6 +38,12 @@ pub struct Config {
     /// we which will suspect a problem with the underlying connection and
     /// close it. This is only applied to writes, where's there's generally
     /// an expectation that things will move along quickly.
+    ///
+    /// The session is closed with `CloseReason::WriteTimeout` if the
+    /// underlying stream can't accept writes for longer than this, and with
+    /// `CloseReason::KeepAliveTimeout` if a keepalive ping is not answered
+    /// within it. The write timeout is checked when the session is polled,
+    /// so it relies on keepalive to be checked on an idle connection.
     pub connection_write_timeout: Duration,
 
     /// Max stream count
@@ -40,6 +52,14 @@ pub struct Config {
     /// MaxStreamWindowSize is used to control the maximum
     /// window size that we allow for a stream.
     pub max_stream_window_size: u32,
+
+    /// SessionChannelSize is the capacity of the channel
+    /// which all streams send events to the session.
+    pub session_channel_size: usize,
+
+    /// StreamChannelSize is the capacity of the channel
+    /// which the session sends frames to each stream.
+    pub stream_channel_size: usize,
 }
 
 impl Default for Config {
@@ -51,6 +71,8 @@ impl Default for Config {
             connection_write_timeout: DEFAULT_WRITE_TIMEOUT,
             max_stream_count: DEFAULT_MAX_STREAM_COUNT,
             max_stream_window_size: INITIAL_STREAM_WINDOW,
+            session_channel_size: DEFAULT_SESSION_CHANNEL_SIZE,
+            stream_channel_size: DEFAULT_STREAM_CHANNEL_SIZE,
         }
     }
 }
@@ -68,10 +68,33 @@ pub struct Session<T> {
     event_receiver: Receiver<StreamEvent>,
 
     keepalive_future: Option<Interval>,
+    // Keepalive timeout or write timeout
+    timeout: bool,
+    // The first time the underlying stream can't accept writes
+    write_blocked_at: Option<Instant>,
+    // Why the session is closed
+    close_reason: Option<CloseReason>,
 
     notify: Option<Task>,
 }
 
+/// Why the session is closed
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CloseReason {
+    /// Local side shutdown the session
+    LocalGoAway,
+    /// Remote side sent a GoAway frame
+    RemoteGoAway(GoAwayCode),
+    /// Got EOF from the underlying stream
+    RemoteEof,
+    /// The keepalive ping is not answered within connection write timeout
+    KeepAliveTimeout,
+    /// The underlying stream can't accept writes within connection write timeout
+    WriteTimeout,
+    /// IO error of the underlying stream
+    IoError(io::ErrorKind),
+}
+
 /// Session type, client or server
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Ord, PartialOrd)]
 pub enum SessionType {
@@ -91,7 +114,7 @@ where
             SessionType::Client => 1,
             SessionType::Server => 2,
         };
-        let (event_sender, event_receiver) = channel(32);
+        let (event_sender, event_receiver) = channel(config.session_channel_size);
         let framed_stream = Framed::new(raw_stream, FrameCodec::default());
         let keepalive_future = if config.enable_keepalive {
             Some(Interval::new_interval(config.keepalive_interval))
@@ -117,6 +140,9 @@ where
             event_sender,
             event_receiver,
             keepalive_future,
+            timeout: false,
+            write_blocked_at: None,
+            close_reason: None,
             notify: None,
         }
     }
@@ -137,6 +163,9 @@ where
         if self.is_dead() {
             return Ok(Async::Ready(()));
         }
+        self.set_close_reason(CloseReason::LocalGoAway);
+        // Send the data written by streams before the GoAway
+        self.recv_events()?;
         if !self.write_pending_frames.is_empty() {
             self.send_all()?;
         }
@@ -144,6 +173,22 @@ where
         Ok(Async::Ready(()))
     }
 
+    /// Why the session is closed, None means it is still alive
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        if self.is_dead() {
+            self.close_reason
+        } else {
+            None
+        }
+    }
+
+    // Only the first reason is kept
+    fn set_close_reason(&mut self, reason: CloseReason) {
+        if self.close_reason.is_none() {
+            self.close_reason = Some(reason);
+        }
+    }
+
     // Send all pending frames to remote streams
     fn flush(&mut self) -> Result<(), io::Error> {
         self.recv_events()?;
@@ -153,7 +198,15 @@ where
     }
 
     fn is_dead(&self) -> bool {
-        self.remote_go_away && self.local_go_away || self.eof
+        self.remote_go_away && self.local_go_away || self.eof || self.timeout
+    }
+
+    // Close the session because of keepalive timeout or write timeout
+    fn close_by_timeout(&mut self, reason: CloseReason) {
+        warn!("[{:?}] session closed: {:?}", self.ty, reason);
+        self.set_close_reason(reason);
+        self.timeout = true;
+        self.notify();
     }
 
     fn send_ping(&mut self, ping_id: Option<u32>) -> Poll<u32, io::Error> {
@@ -190,6 +243,15 @@ where
     }
 
     fn keep_alive(&mut self, ping_at: Instant) -> Poll<(), io::Error> {
+        let timeout = self.config.connection_write_timeout;
+        if self
+            .pings
+            .values()
+            .any(|sent_at| ping_at.duration_since(*sent_at) > timeout)
+        {
+            self.close_by_timeout(CloseReason::KeepAliveTimeout);
+            return Ok(Async::Ready(()));
+        }
         let ping_id = try_ready!(self.send_ping(None));
         debug!("[{:?}] sent keep_alive ping (id={:?})", self.ty, ping_id);
         self.pings.insert(ping_id, ping_at);
@@ -205,7 +267,7 @@ where
                 (next_id, StreamState::Init)
             }
         };
-        let (frame_sender, frame_receiver) = channel(8);
+        let (frame_sender, frame_receiver) = channel(self.config.stream_channel_size);
         self.streams.entry(stream_id).or_insert(frame_sender);
         let mut stream = StreamHandle::new(
             stream_id,
@@ -232,6 +294,9 @@ where
                 Ok(AsyncSink::NotReady(frame)) => {
                     debug!("[{:?}] framed_stream NotReady, frame: {:?}", self.ty, frame);
                     self.write_pending_frames.push_front(frame);
+                    if self.write_blocked_at.is_none() {
+                        self.write_blocked_at = Some(Instant::now());
+                    }
                     self.notify();
                     return Ok(Async::NotReady);
                 }
@@ -244,9 +309,22 @@ where
         }
         // TODO: not ready???
         self.framed_stream.poll_complete()?;
+        self.write_blocked_at = None;
         Ok(Async::Ready(()))
     }
 
+    // Check if the writes are blocked longer than connection write timeout
+    fn check_write_timeout(&mut self) {
+        let timeout = self.config.connection_write_timeout;
+        if self
+            .write_blocked_at
+            .map(|blocked_at| blocked_at.elapsed() > timeout)
+            .unwrap_or(false)
+        {
+            self.close_by_timeout(CloseReason::WriteTimeout);
+        }
+    }
+
     fn send_frame(&mut self, frame: Frame) -> Poll<(), io::Error> {
         debug!("[{:?}] Session::send_frame({:?})", self.ty, frame);
         self.write_pending_frames.push_back(frame);
@@ -343,6 +421,8 @@ where
     }
 
     fn handle_go_away(&mut self, frame: &Frame) -> Result<(), io::Error> {
+        let code = GoAwayCode::from(frame.length());
+        self.set_close_reason(CloseReason::RemoteGoAway(code));
         let mut close = || -> Result<(), io::Error> {
             self.remote_go_away = true;
             self.write_pending_frames.clear();
@@ -351,7 +431,7 @@ where
             }
             Ok(())
         };
-        match GoAwayCode::from(frame.length()) {
+        match code {
             GoAwayCode::Normal => close(),
             GoAwayCode::ProtocolError => {
                 // TODO: report error
@@ -377,6 +457,7 @@ where
                     self.handle_frame(frame)?;
                 }
                 Ok(Async::Ready(None)) => {
+                    self.set_close_reason(CloseReason::RemoteEof);
                     self.eof = true;
                 }
                 Ok(Async::NotReady) => {
@@ -385,6 +466,7 @@ where
                 }
                 Err(err) => {
                     warn!("[{:?}] Session recv_frames error: {:?}", self.ty, err);
+                    self.set_close_reason(CloseReason::IoError(err.kind()));
                     return Err(err);
                 }
             }
@@ -469,20 +551,30 @@ where
             self.flush()?;
         }
 
-        if let Some(ref mut fut) = self.keepalive_future {
+        // Poll the interval until NotReady, so the next tick wakes the session
+        // even when nothing else happens on it
+        while let Some(fut) = self.keepalive_future.as_mut() {
             match fut.poll() {
                 Ok(Async::Ready(Some(ping_at))) => {
                     // TODO: Handle not ready
                     let _ = self.keep_alive(ping_at)?;
+                    if self.is_dead() {
+                        break;
+                    }
                 }
-                Ok(Async::Ready(None)) => {}
-                Ok(Async::NotReady) => {}
+                Ok(Async::Ready(None)) | Ok(Async::NotReady) => break,
                 Err(err) => {
                     warn!("poll keepalive_future error: {}", err);
+                    break;
                 }
             }
         }
 
+        self.check_write_timeout();
+        if self.is_dead() {
+            return Ok(Async::Ready(None));
+        }
+
         if self.recv_frames()? == Async::NotReady {
             debug!("[{:?}] recv_frames NotReady", self.ty);
         }
@@ -502,3 +594,109 @@ where
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{CloseReason, Session, SessionType};
+    use crate::config::Config;
+    use futures::{future, try_ready, Async, Poll, Stream};
+    use std::{
+        io::{self, Read, Write},
+        time::Duration,
+    };
+    use tokio::{
+        prelude::{AsyncRead, AsyncWrite},
+        runtime::current_thread::Runtime,
+    };
+
+    /// Never readable, writes are accepted or blocked forever
+    struct MockStream {
+        writable: bool,
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::WouldBlock.into())
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.writable {
+                Ok(buf.len())
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            if self.writable {
+                Ok(())
+            } else {
+                Err(io::ErrorKind::WouldBlock.into())
+            }
+        }
+    }
+
+    impl AsyncRead for MockStream {}
+
+    impl AsyncWrite for MockStream {
+        fn shutdown(&mut self) -> Poll<(), io::Error> {
+            Ok(Async::Ready(()))
+        }
+    }
+
+    // Poll the session until it's closed
+    fn close_reason(mut session: Session<MockStream>) -> Option<CloseReason> {
+        Runtime::new()
+            .unwrap()
+            .block_on(future::poll_fn(move || {
+                while let Some(_stream) = try_ready!(session.poll()) {}
+                Ok::<_, io::Error>(Async::Ready(session.close_reason()))
+            }))
+            .unwrap()
+    }
+
+    #[test]
+    fn keepalive_timeout() {
+        let config = Config {
+            keepalive_interval: Duration::from_millis(50),
+            connection_write_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        // Pings are sent but never answered
+        let session = Session::new(MockStream { writable: true }, config, SessionType::Client);
+        assert_eq!(close_reason(session), Some(CloseReason::KeepAliveTimeout));
+    }
+
+    #[test]
+    fn write_timeout() {
+        // Keepalive ticks wake the session to check the write timeout, the
+        // pings are blocked like the data so they never time out
+        let config = Config {
+            keepalive_interval: Duration::from_millis(50),
+            connection_write_timeout: Duration::from_millis(200),
+            ..Default::default()
+        };
+        let mut session = Session::new(MockStream { writable: false }, config, SessionType::Client);
+        let mut stream = session.open_stream().unwrap();
+        let data = vec![0; 16 * 1024];
+        let mut writes = 0;
+        let reason = Runtime::new()
+            .unwrap()
+            .block_on(future::poll_fn(move || {
+                // Two frames fill up the write buffer of the underlying stream
+                while writes < 2 {
+                    match stream.write(&data) {
+                        Ok(_) => writes += 1,
+                        Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+                while let Some(_stream) = try_ready!(session.poll()) {}
+                Ok(Async::Ready(session.close_reason()))
+            }))
+            .unwrap();
+        assert_eq!(reason, Some(CloseReason::WriteTimeout));
+    }
+}